@@ -0,0 +1,114 @@
+use futures::stream::{SplitSink, SplitStream};
+use futures::{SinkExt, StreamExt};
+use std::env;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+use tungstenite::Message;
+
+type WsSender = SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>;
+type WsReceiver = SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>;
+
+/// How long the benchmark waits for the connection or the reply before it decides that the server
+/// cannot sustain one more connection.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+/// How often all the opened connections are checked, not only the newest one.
+const CHECK_ALL_INTERVAL: usize = 100;
+
+/// Opens WebSocket connections to a running server one by one and keeps them open. Every new
+/// connection sends a request that the server answers without authentication, and every
+/// CHECK_ALL_INTERVAL connections all of them do. The benchmark stops at the first connection or
+/// reply that does not come in time and prints how many concurrent connections the server has
+/// sustained.
+///
+/// Usage: connection-benchmark [url] [maximum connections]
+#[tokio::main]
+async fn main() {
+    let url = env::args()
+        .nth(1)
+        .unwrap_or_else(|| "ws://127.0.0.1:8080".to_string());
+    let maximum_connections: usize = env::args()
+        .nth(2)
+        .map(|maximum_connections| maximum_connections.parse().expect("Invalid number"))
+        .unwrap_or(5000);
+
+    let start = Instant::now();
+    let mut connections: Vec<(WsSender, WsReceiver)> = Vec::new();
+    let mut reply_times = Vec::new();
+    let mut failure = None;
+    while connections.len() < maximum_connections {
+        let mut connection = match timeout(REPLY_TIMEOUT, connect_async(url.as_str())).await {
+            Ok(Ok((ws_stream, _))) => ws_stream.split(),
+            Ok(Err(e)) => {
+                failure = Some(format!("failed to connect: {}", e));
+                break;
+            }
+            Err(_) => {
+                failure = Some("the connection has not been accepted in time".to_string());
+                break;
+            }
+        };
+        match request_and_wait_for_reply(&mut connection).await {
+            Ok(reply_time) => reply_times.push(reply_time),
+            Err(e) => {
+                failure = Some(e);
+                break;
+            }
+        }
+        connections.push(connection);
+        if connections.len().is_multiple_of(CHECK_ALL_INTERVAL) {
+            if let Err(e) = check_all(&mut connections).await {
+                failure = Some(e);
+                break;
+            }
+            println!("{} connections are open", connections.len());
+        }
+    }
+    if failure.is_none() {
+        failure = check_all(&mut connections).await.err();
+    }
+
+    reply_times.sort();
+    println!(
+        "Sustained {} concurrent connections in {:.1} s",
+        connections.len(),
+        start.elapsed().as_secs_f64()
+    );
+    if !reply_times.is_empty() {
+        println!(
+            "Reply time of the new connections: median {:?}, 99th percentile {:?}",
+            reply_times[reply_times.len() / 2],
+            reply_times[reply_times.len() * 99 / 100]
+        );
+    }
+    if let Some(failure) = failure {
+        println!("Stopped because {}", failure);
+    }
+}
+
+/// Sends a request that needs no authentication and waits for the error reply.
+async fn request_and_wait_for_reply(
+    (ws_sender, ws_receiver): &mut (WsSender, WsReceiver),
+) -> Result<Duration, String> {
+    let sent_at = Instant::now();
+    ws_sender
+        .send(Message::Text(r#"{"subject":"unread-counts"}"#.to_string()))
+        .await
+        .map_err(|e| format!("failed to send a request: {}", e))?;
+    match timeout(REPLY_TIMEOUT, ws_receiver.next()).await {
+        Ok(Some(Ok(Message::Text(_)))) => Ok(sent_at.elapsed()),
+        Ok(other) => Err(format!("an unexpected reply {:?}", other)),
+        Err(_) => Err("the reply has not come in time".to_string()),
+    }
+}
+
+/// Checks that every opened connection still gets replies.
+async fn check_all(connections: &mut [(WsSender, WsReceiver)]) -> Result<(), String> {
+    let results =
+        futures::future::join_all(connections.iter_mut().map(request_and_wait_for_reply)).await;
+    results
+        .into_iter()
+        .collect::<Result<Vec<Duration>, String>>()?;
+    Ok(())
+}
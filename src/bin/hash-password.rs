@@ -0,0 +1,13 @@
+use rust_pr::user_service::hash_password;
+use std::io::{self, BufRead};
+
+/// Reads passwords from the standard input, one per line, and prints their hashes in the format
+/// that the users file expects.
+fn main() {
+    for password in io::stdin().lock().lines() {
+        println!(
+            "{}",
+            hash_password(&password.expect("Failed to read a password"))
+        );
+    }
+}
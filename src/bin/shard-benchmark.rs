@@ -0,0 +1,184 @@
+use rust_pr::config::ServerConfig;
+use rust_pr::connection_handler::{handle_connection_commands, ConnectionCommand};
+use rust_pr::dto::{Subject, MESSAGE_ACCEPTED_SUBJECT, MESSAGE_SUBJECT, UNREAD_COUNTS_SUBJECT};
+use rust_pr::outbound_queue::{messages_channel, MessagesReceiver, MessagesSender};
+use rust_pr::shard::ConnectionCommandRouter;
+use rust_pr::storage::{MemoryStorage, StoredUser};
+use rust_pr::user_context::ApplicationScope;
+use rust_pr::user_service;
+use std::env;
+use std::thread;
+use std::time::{Duration, Instant};
+use tungstenite::Message;
+
+/// Measures how many private messages per second the connection command handlers process when the
+/// state is split into 1, 2, 4, ... shards. Every pair of users has a conversation of its own, so
+/// the conversations and the users are spread over all the shards. The sessions are in-process
+/// channels, so the WebSocket connections do not take their share of the CPU.
+///
+/// Usage: shard-benchmark [pairs of users] [messages per pair] [maximum shard count]
+#[tokio::main]
+async fn main() {
+    let pair_count: usize = parse_arg(1, 1000);
+    let messages_per_pair: usize = parse_arg(2, 100);
+    let maximum_shard_count: usize = parse_arg(3, 8);
+
+    println!(
+        "{} pairs of users, {} messages per pair, {} CPUs",
+        pair_count,
+        messages_per_pair,
+        thread::available_parallelism().map_or(1, |parallelism| parallelism.get())
+    );
+    let mut single_shard_throughput = None;
+    let mut shard_count = 1;
+    while shard_count <= maximum_shard_count {
+        let elapsed = run(shard_count, pair_count, messages_per_pair).await;
+        let throughput = (pair_count * messages_per_pair) as f64 / elapsed.as_secs_f64();
+        let single_shard_throughput = *single_shard_throughput.get_or_insert(throughput);
+        println!(
+            "{} shards: {:.0} messages/s, {:.2}x the throughput of 1 shard",
+            shard_count,
+            throughput,
+            throughput / single_shard_throughput
+        );
+        shard_count *= 2;
+    }
+}
+
+fn parse_arg(position: usize, default: usize) -> usize {
+    env::args()
+        .nth(position)
+        .map(|value| value.parse().expect("Invalid number"))
+        .unwrap_or(default)
+}
+
+/// Starts the shards, opens the sessions of the users and returns how long it has taken the shards
+/// to accept and deliver all the messages.
+async fn run(shard_count: usize, pair_count: usize, messages_per_pair: usize) -> Duration {
+    let config = ServerConfig {
+        // the sessions must not be disconnected because the benchmark reads them slowly
+        outbound_queue_capacity: messages_per_pair * 2 + 16,
+        shard_count,
+        ..ServerConfig::default()
+    };
+    let (router, shard_receivers) = ConnectionCommandRouter::with_shards(shard_count);
+    let application_scopes = ApplicationScope::load_shards(Box::new(MemoryStorage), &router)
+        .expect("Failed to create the shards");
+    for (shard_receiver, application_scope) in shard_receivers.into_iter().zip(application_scopes) {
+        let handler_config = config.clone();
+        // the shards stay idle once the run is over
+        thread::spawn(move || {
+            handle_connection_commands(shard_receiver, application_scope, handler_config)
+        });
+    }
+
+    let mut senders = Vec::new();
+    let mut receivers = Vec::new();
+    for pair in 0..pair_count {
+        let sender = open_session(&router, &config, format!("sender-{}-{}", shard_count, pair));
+        let receiver = open_session(
+            &router,
+            &config,
+            format!("receiver-{}-{}", shard_count, pair),
+        );
+        router
+            .send(ConnectionCommand::InitiateNewPrivateMessageSequence {
+                sender_username: sender.0.clone(),
+                receiver_username: receiver.0.clone(),
+                messages_sender: sender.1.clone(),
+                request_id: None,
+            })
+            .unwrap();
+        senders.push(sender);
+        receivers.push(receiver);
+    }
+    // the shard of the receiver answers after it has registered his session
+    for (username, messages_sender, messages_receiver) in receivers.iter_mut() {
+        router
+            .send(ConnectionCommand::GetUnreadCounts {
+                username: username.clone(),
+                messages_sender: messages_sender.clone(),
+                request_id: None,
+            })
+            .unwrap();
+        wait_for(messages_receiver, UNREAD_COUNTS_SUBJECT, 1).await;
+    }
+
+    let start = Instant::now();
+    for index in 1..=messages_per_pair {
+        for ((sender_username, messages_sender, _), (receiver_username, _, _)) in
+            senders.iter().zip(&receivers)
+        {
+            router
+                .send(ConnectionCommand::SendMessageToAnotherUser {
+                    sender_username: sender_username.clone(),
+                    receiver_username: receiver_username.clone(),
+                    content: format!("message {}", index),
+                    // the first sequence of a new conversation
+                    message_sequence_id: 0,
+                    message_sequence_index: index as u16,
+                    messages_sender: messages_sender.clone(),
+                    request_id: None,
+                })
+                .unwrap();
+        }
+    }
+    let mut waiting_sessions = Vec::new();
+    for ((_, _, mut messages_receiver), subject) in senders
+        .into_iter()
+        .map(|sender| (sender, MESSAGE_ACCEPTED_SUBJECT))
+        .chain(
+            receivers
+                .into_iter()
+                .map(|receiver| (receiver, MESSAGE_SUBJECT)),
+        )
+    {
+        waiting_sessions.push(tokio::spawn(async move {
+            wait_for(&mut messages_receiver, subject, messages_per_pair).await
+        }));
+    }
+    for waiting_session in waiting_sessions {
+        waiting_session.await.unwrap();
+    }
+    start.elapsed()
+}
+
+fn open_session(
+    router: &ConnectionCommandRouter,
+    config: &ServerConfig,
+    username: String,
+) -> (String, MessagesSender, MessagesReceiver) {
+    // the messages are only accepted for the users of the server. Nobody logs in as them.
+    let _ = user_service::add_user(StoredUser {
+        username: username.clone(),
+        password_hash: String::new(),
+        is_admin: false,
+        is_disabled: false,
+    });
+    let (messages_sender, messages_receiver) = messages_channel(config);
+    router
+        .send(ConnectionCommand::AssignConnectionToUser {
+            username: username.clone(),
+            messages_sender: messages_sender.clone(),
+            acceptance: None,
+        })
+        .unwrap();
+    (username, messages_sender, messages_receiver)
+}
+
+/// Reads the messages of a session until `count` of them have the subject. The other messages,
+/// e.g. the presence notifications, are skipped.
+async fn wait_for(messages_receiver: &mut MessagesReceiver, subject: &str, count: usize) {
+    let mut received = 0;
+    while received < count {
+        match messages_receiver.recv().await {
+            Some(Message::Text(text)) => {
+                if serde_json::from_str::<Subject>(&text).unwrap().subject == subject {
+                    received += 1;
+                }
+            }
+            Some(_) => {}
+            None => panic!("the session has been closed"),
+        }
+    }
+}
@@ -1,8 +1,9 @@
 use rust_pr::dto::{
-    LoginCredentials, MessageFromSomeone, NewPrivateMessageSequenceRequest,
+    ErrorResponse, LoginCredentials, MessageFromSomeone, NewPrivateMessageSequenceRequest,
     NewPrivateMessageSequenceResponse,
 };
 use rust_pr::dto;
+use rust_pr::user_service::is_valid_username;
 
 use crossbeam_channel::{unbounded, Sender};
 use futures::stream::SplitStream;
@@ -35,7 +36,7 @@ async fn main() {
     rt.spawn(read_ws_messages(state_change_sender.clone(), ws_receiver));
 
     let mut app_state = AppState::WaitingForUsername;
-    print!("{}", "Please enter your login: ");
+    print!("Please enter your login: ");
     io::stdout().flush().unwrap();
 
     // we will send this struct
@@ -51,7 +52,7 @@ async fn main() {
             StateChange::NewReadlineMessage { message } => {
                 match app_state {
                     AppState::WaitingForUsername => {
-                        print!("{}", "Please enter your password: ");
+                        print!("Please enter your password: ");
                         io::stdout().flush().unwrap();
                         app_state = AppState::WaitingForPassword { username: message };
                     }
@@ -64,7 +65,7 @@ async fn main() {
                             Box::new(login_credentials),
                             dto::AUTHENTICATE_SUBJECT.to_string(),
                         );
-                        let _ = ws_sender
+                        ws_sender
                             .send(Message::Text(authorization_message))
                             .await
                             .unwrap();
@@ -81,7 +82,7 @@ async fn main() {
                         // message should contain the username of the receiver
                         if is_valid_username(&message) {
                             // send a request for a new message sequence id
-                            let _ = ws_sender
+                            ws_sender
                                 .send(Message::Text(dto::attach_subject_and_serialize(
                                     Box::new(NewPrivateMessageSequenceRequest {
                                         receiver_username: message.clone()
@@ -94,10 +95,7 @@ async fn main() {
                             message_from_someone.receiver = message;
                             app_state = AppState::WaitingForMessageSequenceId;
                         } else {
-                            print!(
-                                "{}",
-                                "Please enter a valid username (only alphanumeric characters): "
-                            );
+                            print!("Please enter a valid username (only alphanumeric characters): ");
                             std::io::stdout().flush().unwrap();
                         }
                     }
@@ -114,15 +112,12 @@ async fn main() {
                             content: "".to_string(),
                             receiver: "".to_string(),
                         };
-                        let _ = ws_sender
+                        ws_sender
                             .send(Message::Text(new_message_str))
                             .await
                             .unwrap();
                         println!("The message has been sent.");
-                        print!(
-                            "{}",
-                            "Please enter the login of a user to whom you want to send a message: "
-                        );
+                        print!("Please enter the login of a user to whom you want to send a message: ");
                         std::io::stdout().flush().unwrap();
                         app_state = AppState::WaitingForReceiverName;
                     }
@@ -131,25 +126,59 @@ async fn main() {
             StateChange::NewWebSocketMessage { message } => {
                 match app_state {
                     AppState::WaitingForServerAuthorizationResponse => {
-                        if message == "authentication successful" {
-                            print!("{}", "Please enter the login of a user to whom you want to send a message: ");
+                        let is_authenticated = serde_json::from_str::<dto::Subject>(&message)
+                            .map(|subject| subject.subject == dto::AUTHENTICATE_SUBJECT)
+                            .unwrap_or(false);
+                        if is_authenticated {
+                            print!("Please enter the login of a user to whom you want to send a message: ");
                             std::io::stdout().flush().unwrap();
                             app_state = AppState::WaitingForReceiverName;
                         } else {
-                            println!("We were waiting for \"authentication successful\" but received something else: {}", &message);
-                            print!("{}", "Please enter the username again: ");
+                            println!("We were waiting for a successful authentication but received something else: {}", &message);
+                            print!("Please enter the username again: ");
                             io::stdout().flush().unwrap();
                             app_state = AppState::WaitingForUsername;
                         }
                     }
                     AppState::WaitingForMessageSequenceId => {
-                        // parse NewPrivateMessageSequenceResponse
-                        let private_message_sequence_response: NewPrivateMessageSequenceResponse =
-                            serde_json::from_str(&message).expect("JSON was not well-formatted");
+                        let subject = serde_json::from_str::<dto::Subject>(&message)
+                            .map(|subject| subject.subject)
+                            .unwrap_or_default();
+                        let error_response = serde_json::from_str::<ErrorResponse>(&message)
+                            .ok()
+                            .filter(|error_response| {
+                                subject == dto::ERROR_SUBJECT
+                                    && error_response.request_subject
+                                        == dto::NEW_PRIVATE_MESSAGE_SEQUENCE_SUBJECT
+                            });
+                        if let Some(error_response) = error_response {
+                            println!("The server could not create a message sequence: {}", error_response.message);
+                            print!("Please enter the login of a user to whom you want to send a message: ");
+                            io::stdout().flush().unwrap();
+                            app_state = AppState::WaitingForReceiverName;
+                            continue;
+                        }
+                        // the presence events and the incoming messages may come before the reply
+                        let private_message_sequence_response = if subject
+                            == dto::NEW_PRIVATE_MESSAGE_SEQUENCE_SUBJECT
+                        {
+                            serde_json::from_str::<NewPrivateMessageSequenceResponse>(&message).ok()
+                        } else {
+                            None
+                        };
+                        let Some(private_message_sequence_response) =
+                            private_message_sequence_response
+                        else {
+                            println!(
+                                "we have just received this message from the server: {}",
+                                &message
+                            );
+                            continue;
+                        };
                         message_from_someone.message_sequence_index += 1;
                         message_from_someone.message_sequence_id =
                             private_message_sequence_response.sequence_id;
-                        print!("{}", "Please type the text that you want to send: ");
+                        print!("Please type the text that you want to send: ");
                         io::stdout().flush().unwrap();
                         app_state = AppState::WaitingForText;
                     }
@@ -165,14 +194,7 @@ async fn main() {
     }
 }
 
-fn is_valid_username(username: &str) -> bool {
-    // Check if the string is not empty and contains only alphanumeric characters
-    !username.is_empty()
-        && username
-            .chars()
-            .all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.')
-}
-
+#[allow(clippy::enum_variant_names)]
 enum AppState {
     WaitingForUsername,
     WaitingForPassword { username: String },
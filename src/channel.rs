@@ -0,0 +1,204 @@
+use crate::dto::{ChannelInfo, MessageToSomeone};
+use chrono::{DateTime, Utc};
+use std::collections::BTreeSet;
+use std::fmt;
+
+/// The longest allowed name of a channel.
+pub const MAXIMUM_CHANNEL_NAME_LENGTH: usize = 64;
+
+/// Represents a message of a channel in the server internal memory.
+struct ChannelMessage {
+    /// the author of the message.
+    sender: String,
+    content: String,
+    /// the datetime when the message was registered on the server.
+    server_time: DateTime<Utc>,
+}
+
+/// A named conversation that any user can join. The messages are delivered only to the users who
+/// have joined the channel.
+pub struct Channel {
+    topic: String,
+    created_by: String,
+    created_at: DateTime<Utc>,
+    subscribers: BTreeSet<String>,
+    /// Defines from which index the array "messages" start. The same as in PrivateConversation.
+    id_offset: u32,
+    messages: Vec<ChannelMessage>,
+}
+
+/// The reason why a request to a channel cannot be fulfilled.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ChannelError {
+    ChannelNotFound,
+    /// a channel with the same name already exists.
+    ChannelAlreadyExists,
+    /// the name is empty, too long or contains characters other than lowercase letters, digits,
+    /// '-' and '_'.
+    InvalidChannelName,
+    /// the request requires the user to join the channel first.
+    NotChannelSubscriber,
+}
+
+impl fmt::Display for ChannelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChannelError::ChannelNotFound => write!(f, "the channel does not exist"),
+            ChannelError::ChannelAlreadyExists => write!(f, "the channel already exists"),
+            ChannelError::InvalidChannelName => write!(
+                f,
+                "the channel name must consist of 1 to {} lowercase letters, digits, '-' and '_'",
+                MAXIMUM_CHANNEL_NAME_LENGTH
+            ),
+            ChannelError::NotChannelSubscriber => write!(f, "join the channel first"),
+        }
+    }
+}
+
+/// Checks that the name can be used as the name of a new channel.
+pub fn is_valid_channel_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAXIMUM_CHANNEL_NAME_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_')
+}
+
+impl Channel {
+    /// Creates a channel that its creator has already joined.
+    pub fn new(created_by: String, topic: String) -> Self {
+        Channel {
+            topic,
+            created_by: created_by.clone(),
+            created_at: Utc::now(),
+            subscribers: BTreeSet::from([created_by]),
+            id_offset: 0,
+            messages: Vec::new(),
+        }
+    }
+
+    /// Converts the metadata of the channel to the form that is sent to the clients.
+    pub fn to_channel_info(&self, name: String) -> ChannelInfo {
+        ChannelInfo {
+            name,
+            topic: self.topic.clone(),
+            created_by: self.created_by.clone(),
+            created_at: self.created_at.to_string(),
+            subscriber_count: self.subscribers.len() as u32,
+        }
+    }
+
+    pub fn is_subscriber(&self, username: &String) -> bool {
+        self.subscribers.contains(username)
+    }
+
+    pub fn subscribers(&self) -> impl Iterator<Item = &String> {
+        self.subscribers.iter()
+    }
+
+    /// Subscribes the user to the channel. Joining a channel twice changes nothing.
+    pub fn join(&mut self, username: String) {
+        self.subscribers.insert(username);
+    }
+
+    pub fn leave(&mut self, username: &String) -> Result<(), ChannelError> {
+        if self.subscribers.remove(username) {
+            Ok(())
+        } else {
+            Err(ChannelError::NotChannelSubscriber)
+        }
+    }
+
+    /// Stores a message of a subscriber and returns it in the form that is sent to the clients.
+    pub fn add_message(
+        &mut self,
+        sender: String,
+        content: String,
+    ) -> Result<MessageToSomeone, ChannelError> {
+        if !self.is_subscriber(&sender) {
+            return Err(ChannelError::NotChannelSubscriber);
+        }
+        let channel_message = ChannelMessage {
+            sender,
+            content,
+            server_time: Utc::now(),
+        };
+        let message_to_someone = self.to_message_to_someone(self.messages.len(), &channel_message);
+        self.messages.push(channel_message);
+        Ok(message_to_someone)
+    }
+
+    /// Returns the last `limit` messages of the channel, oldest first.
+    pub fn read_last_messages(&self, limit: usize) -> Vec<MessageToSomeone> {
+        let start_index = self.messages.len().saturating_sub(limit);
+        self.messages[start_index..]
+            .iter()
+            .enumerate()
+            .map(|(index, channel_message)| {
+                self.to_message_to_someone(start_index + index, channel_message)
+            })
+            .collect()
+    }
+
+    /// The message with id N is stored at the index N - id_offset - 1.
+    fn to_message_to_someone(
+        &self,
+        index: usize,
+        channel_message: &ChannelMessage,
+    ) -> MessageToSomeone {
+        MessageToSomeone {
+            id: self.id_offset + index as u32 + 1,
+            content: channel_message.content.clone(),
+            sender_username: channel_message.sender.clone(),
+            datetime: channel_message.server_time.to_string(),
+            edited_datetime: None,
+            is_deleted: false,
+        }
+    }
+}
+
+#[test]
+fn test_channel() {
+    let (ian, dan) = ("ian".to_string(), "dan".to_string());
+    assert!(is_valid_channel_name("rust-lang_2"));
+    assert!(!is_valid_channel_name(""));
+    assert!(!is_valid_channel_name("Rust"));
+    assert!(!is_valid_channel_name("rust lang"));
+    assert!(!is_valid_channel_name(
+        &"a".repeat(MAXIMUM_CHANNEL_NAME_LENGTH + 1)
+    ));
+
+    let mut channel = Channel::new(ian.clone(), "all about rust".to_string());
+    assert_eq!(
+        channel
+            .add_message(dan.clone(), "hi".to_string())
+            .map(|_| ()),
+        Err(ChannelError::NotChannelSubscriber)
+    );
+    for content in ["first", "second", "third"] {
+        channel
+            .add_message(ian.clone(), content.to_string())
+            .unwrap();
+    }
+    channel.join(dan.clone());
+    channel.join(dan.clone());
+    assert_eq!(
+        channel.to_channel_info("rust".to_string()).subscriber_count,
+        2
+    );
+    // late joiners can catch up with the recent messages
+    let messages = channel.read_last_messages(2);
+    assert_eq!(
+        messages
+            .iter()
+            .map(|message| (message.id, message.content.as_str()))
+            .collect::<Vec<(u32, &str)>>(),
+        vec![(2, "second"), (3, "third")]
+    );
+    assert_eq!(channel.read_last_messages(0).len(), 0);
+    assert_eq!(channel.read_last_messages(10).len(), 3);
+
+    assert_eq!(channel.leave(&dan), Ok(()));
+    assert_eq!(channel.leave(&dan), Err(ChannelError::NotChannelSubscriber));
+    assert_eq!(channel.subscribers().collect::<Vec<&String>>(), vec![&ian]);
+}
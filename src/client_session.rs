@@ -0,0 +1,1933 @@
+use crate::config::{RegistrationMode, ServerConfig};
+use crate::connection_handler;
+use crate::connection_handler::ConnectionCommand;
+use crate::dto;
+use crate::dto::{
+    attach_reply_subject_and_serialize, prepare_error_response, AddUserRequest,
+    AuthenticationResponse, ChannelRequest, CreateChannelRequest, CreateGroupRequest,
+    DeleteMessageRequest, EditMessageRequest, ErrorCode, GetPresenceRequest, GroupHistoryRequest,
+    GroupMemberRequest, GroupRequest, JoinChannelRequest, LoginCredentials, MarkGroupReadRequest,
+    MarkReadRequest, MessageFromSomeone, MessageRevisionsRequest, MessageToChannel, MessageToGroup,
+    NewPrivateMessageSequenceRequest, OutboundQueueMetricsResponse, PrivateHistoryRequest,
+    RegistrationRequest, RenameGroupRequest, ResumeRequest, SetGroupRoleRequest,
+    SetUserDisabledRequest, Subject, TypingRequest, UserOutboundQueueMetrics,
+};
+use crate::login_throttle;
+use crate::outbound_queue;
+use crate::outbound_queue::MessagesSender;
+#[cfg(test)]
+use crate::outbound_queue::{drain_messages, messages_channel, MessagesReceiver};
+use crate::session_token::{SessionToken, SessionTokenError};
+use crate::shard::ConnectionCommandRouter;
+use crate::storage::StoredUser;
+use crate::user_service;
+use crate::util::to_chrono_duration;
+use chrono::Utc;
+use log::{debug, warn};
+use serde::de::DeserializeOwned;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use subtle::{Choice, ConstantTimeEq};
+use tokio::runtime::Handle;
+use tokio::sync::mpsc;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+use tungstenite::Message;
+
+/// What the connection should do after a frame has been handled.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FrameHandlingResult {
+    /// Keep reading frames from the client.
+    KeepOpen,
+    /// A close frame has been queued. The connection should stop reading frames.
+    Close,
+}
+
+/// The close code of the connections that the server closes because the client has not answered
+/// its pings.
+pub const HEARTBEAT_TIMEOUT_CLOSE_CODE: CloseCode = CloseCode::Library(4009);
+
+/// The rest of a request that the session has handed over to another thread. It is applied to the
+/// session once that thread is done.
+pub type SessionCallback = Box<dyn FnOnce(&mut ClientSession) -> FrameHandlingResult + Send>;
+
+/// Lets the shard of the user answer an authentication request once it has decided whether the
+/// connection may become a session of the user.
+pub struct SessionAcceptance {
+    session_token: SessionToken,
+    /// the reply with the signed session token.
+    reply: String,
+    /// the subject and the id of the authentication request.
+    pub subject: Subject,
+    callback_sender: mpsc::UnboundedSender<SessionCallback>,
+}
+
+impl SessionAcceptance {
+    /// Sends the client its session token and lets the session handle the requests of the user.
+    pub fn accept(self, messages_sender: &MessagesSender) {
+        let _ = messages_sender.send(Message::Text(self.reply));
+        let session_token = self.session_token;
+        let _ = self.callback_sender.send(Box::new(move |client_session| {
+            client_session.accept_session(session_token)
+        }));
+    }
+
+    /// Tells the session that the shard has refused it. The client has been told why.
+    pub fn refuse(self) {
+        let _ = self
+            .callback_sender
+            .send(Box::new(|client_session| client_session.refuse_session()));
+    }
+}
+
+/// The state of one WebSocket connection. It turns the frames of the client into commands.
+pub struct ClientSession {
+    /// empty until the client authenticates.
+    current_username: String,
+    /// the user whose session the shard has neither accepted nor refused yet.
+    awaited_username: Option<String>,
+    /// the token that the client has received or presented when it authenticated.
+    session_token: Option<SessionToken>,
+    /// it lets this connection receive messages from other connections.
+    messages_sender: MessagesSender,
+    /// sends the commands to the shards that own the state they concern.
+    connection_command_router: ConnectionCommandRouter,
+    config: Arc<ServerConfig>,
+    /// the address of the client. The failed authentication attempts are counted for it.
+    peer_address: SocketAddr,
+    /// how many authentication attempts have failed on this connection.
+    failed_logins: u32,
+    /// how many pings in a row the client has not answered.
+    missed_pongs: u32,
+    /// when the next ping is due.
+    next_ping_at: Instant,
+    /// when the client has sent its last request. It tells how long an unauthenticated connection
+    /// has been idle.
+    last_request_at: Instant,
+    /// the threads that finish the requests of the session send the callbacks there.
+    callback_sender: mpsc::UnboundedSender<SessionCallback>,
+    callback_receiver: mpsc::UnboundedReceiver<SessionCallback>,
+    /// true while a request waits for its callback. The next frames are not handled meanwhile,
+    /// so the requests are handled in order.
+    is_waiting_for_callback: bool,
+}
+
+impl ClientSession {
+    pub fn new(
+        messages_sender: MessagesSender,
+        connection_command_router: ConnectionCommandRouter,
+        config: Arc<ServerConfig>,
+        peer_address: SocketAddr,
+    ) -> Self {
+        let (callback_sender, callback_receiver) = mpsc::unbounded_channel();
+        ClientSession {
+            current_username: String::new(),
+            awaited_username: None,
+            session_token: None,
+            messages_sender,
+            connection_command_router,
+            next_ping_at: Instant::now() + config.heartbeat_interval,
+            last_request_at: Instant::now(),
+            config,
+            peer_address,
+            failed_logins: 0,
+            missed_pongs: 0,
+            callback_sender,
+            callback_receiver,
+            is_waiting_for_callback: false,
+        }
+    }
+
+    /// Handles a text frame received from the client. A malformed frame never ends the session.
+    pub fn handle_text_frame(&mut self, content: &str) -> FrameHandlingResult {
+        self.last_request_at = Instant::now();
+        // a request proves that the connection is alive as well as a pong does
+        self.missed_pongs = 0;
+        let subject: Subject = match serde_json::from_str(content) {
+            Ok(subject) => subject,
+            Err(e) => {
+                debug!("Failed to parse the subject: {}", e);
+                self.send_error(
+                    ErrorCode::MalformedRequest,
+                    &e.to_string(),
+                    &Subject {
+                        subject: String::new(),
+                        request_id: None,
+                    },
+                );
+                return FrameHandlingResult::KeepOpen;
+            }
+        };
+        // the passwords must not appear in the logs
+        if ![
+            dto::AUTHENTICATE_SUBJECT,
+            dto::RESUME_SUBJECT,
+            dto::REGISTER_SUBJECT,
+            dto::ADD_USER_SUBJECT,
+        ]
+        .contains(&subject.subject.as_str())
+        {
+            debug!("Incoming message {:?}", content);
+        }
+        debug!("subject: {:?}", &subject.subject);
+        match subject.subject.as_str() {
+            dto::AUTHENTICATE_SUBJECT => {
+                let Some(login_credentials) =
+                    self.parse_request::<LoginCredentials>(content, &subject)
+                else {
+                    return FrameHandlingResult::KeepOpen;
+                };
+                if !self.is_signed_out_or_send_error(&subject) {
+                    return FrameHandlingResult::KeepOpen;
+                }
+                // the password is not even checked, so guessing costs the server nothing
+                if let Some(remaining_block) =
+                    login_throttle::login_block(&login_credentials.login, self.peer_address.ip())
+                {
+                    self.send_error(
+                        ErrorCode::TooManyFailedLogins,
+                        &format!(
+                            "too many failed authentication attempts, try again in {} seconds",
+                            remaining_block.as_secs_f64().ceil()
+                        ),
+                        &subject,
+                    );
+                    return self.count_failed_login();
+                }
+                let login = login_credentials.login.clone();
+                return self.run_blocking(
+                    move || {
+                        user_service::are_credentials_correct(
+                            &login_credentials.login,
+                            &login_credentials.password,
+                        )
+                    },
+                    move |client_session, is_password_correct| {
+                        client_session.finish_authentication(login, is_password_correct, subject)
+                    },
+                );
+            }
+            dto::RESUME_SUBJECT => {
+                let Some(request) = self.parse_request::<ResumeRequest>(content, &subject) else {
+                    return FrameHandlingResult::KeepOpen;
+                };
+                if !self.is_signed_out_or_send_error(&subject) {
+                    return FrameHandlingResult::KeepOpen;
+                }
+                match SessionToken::verify(
+                    &request.session_token,
+                    &self.config.session_token_key,
+                    Utc::now(),
+                ) {
+                    // the user might have been disabled after the token was issued
+                    Ok(session_token) if user_service::is_user_enabled(&session_token.username) => {
+                        self.start_user_session(session_token, subject);
+                    }
+                    Ok(_) => self.send_error(
+                        ErrorCode::InvalidSessionToken,
+                        "the user cannot authenticate any more",
+                        &subject,
+                    ),
+                    Err(SessionTokenError::Expired) => self.send_error(
+                        ErrorCode::SessionTokenExpired,
+                        &SessionTokenError::Expired.to_string(),
+                        &subject,
+                    ),
+                    Err(e) => {
+                        self.send_error(ErrorCode::InvalidSessionToken, &e.to_string(), &subject)
+                    }
+                }
+            }
+            dto::LOGOUT_SUBJECT => {
+                if self.is_authenticated_or_send_error(&subject) {
+                    let revoked_session_token = self
+                        .session_token
+                        .take()
+                        .map(|session_token| session_token.revoke(Utc::now()));
+                    self.messages_sender.set_username(None);
+                    let _ = self
+                        .connection_command_router
+                        .send(ConnectionCommand::Logout {
+                            username: std::mem::take(&mut self.current_username),
+                            revoked_session_token,
+                            messages_sender: self.messages_sender.clone(),
+                            request_id: subject.request_id,
+                        });
+                }
+            }
+            dto::NEW_MESSAGE_SUBJECT => {
+                debug!("NEW_MESSAGE_SUBJECT request");
+                if self.current_username.is_empty() {
+                    self.send_error(
+                        ErrorCode::NotAuthenticated,
+                        "you should authorize before sending messages to other users",
+                        &subject,
+                    );
+                } else if let Some(new_message) =
+                    self.parse_request::<MessageFromSomeone>(content, &subject)
+                {
+                    let _ = self.connection_command_router.send(
+                        ConnectionCommand::SendMessageToAnotherUser {
+                            sender_username: self.current_username.clone(),
+                            receiver_username: new_message.receiver,
+                            content: new_message.content,
+                            message_sequence_id: new_message.message_sequence_id,
+                            message_sequence_index: new_message.message_sequence_index,
+                            messages_sender: self.messages_sender.clone(),
+                            request_id: subject.request_id,
+                        },
+                    );
+                }
+            }
+            dto::NEW_PRIVATE_MESSAGE_SEQUENCE_SUBJECT => {
+                debug!("NEW_PRIVATE_MESSAGE_SEQUENCE_SUBJECT request");
+                if self.current_username.is_empty() {
+                    self.send_error(
+                        ErrorCode::NotAuthenticated,
+                        "you should authorize before making this type of request",
+                        &subject,
+                    );
+                } else if let Some(request) =
+                    self.parse_request::<NewPrivateMessageSequenceRequest>(content, &subject)
+                {
+                    let _ = self.connection_command_router.send(
+                        ConnectionCommand::InitiateNewPrivateMessageSequence {
+                            sender_username: self.current_username.clone(),
+                            receiver_username: request.receiver_username,
+                            messages_sender: self.messages_sender.clone(),
+                            request_id: subject.request_id,
+                        },
+                    );
+                }
+            }
+            dto::GET_PRIVATE_HISTORY_SUBJECT => {
+                if self.is_authenticated_or_send_error(&subject) {
+                    if let Some(request) =
+                        self.parse_request::<PrivateHistoryRequest>(content, &subject)
+                    {
+                        let _ = self.connection_command_router.send(
+                            ConnectionCommand::GetPrivateHistory {
+                                reader_username: self.current_username.clone(),
+                                partner_username: request.partner_username,
+                                before_id: request.before_id,
+                                limit: request.limit,
+                                messages_sender: self.messages_sender.clone(),
+                                request_id: subject.request_id,
+                            },
+                        );
+                    }
+                }
+            }
+            dto::EDIT_MESSAGE_SUBJECT => {
+                if self.is_authenticated_or_send_error(&subject) {
+                    if let Some(request) =
+                        self.parse_request::<EditMessageRequest>(content, &subject)
+                    {
+                        let _ = self.connection_command_router.send(
+                            ConnectionCommand::EditPrivateMessage {
+                                author_username: self.current_username.clone(),
+                                partner_username: request.partner_username,
+                                id: request.id,
+                                content: request.content,
+                                messages_sender: self.messages_sender.clone(),
+                                request_id: subject.request_id,
+                            },
+                        );
+                    }
+                }
+            }
+            dto::DELETE_MESSAGE_SUBJECT => {
+                if self.is_authenticated_or_send_error(&subject) {
+                    if let Some(request) =
+                        self.parse_request::<DeleteMessageRequest>(content, &subject)
+                    {
+                        let _ = self.connection_command_router.send(
+                            ConnectionCommand::DeletePrivateMessage {
+                                author_username: self.current_username.clone(),
+                                partner_username: request.partner_username,
+                                id: request.id,
+                                messages_sender: self.messages_sender.clone(),
+                                request_id: subject.request_id,
+                            },
+                        );
+                    }
+                }
+            }
+            dto::GET_MESSAGE_REVISIONS_SUBJECT => {
+                if self.is_authenticated_or_send_error(&subject) {
+                    if let Some(request) =
+                        self.parse_request::<MessageRevisionsRequest>(content, &subject)
+                    {
+                        let _ = self.connection_command_router.send(
+                            ConnectionCommand::GetMessageRevisions {
+                                reader_username: self.current_username.clone(),
+                                partner_username: request.partner_username,
+                                id: request.id,
+                                messages_sender: self.messages_sender.clone(),
+                                request_id: subject.request_id,
+                            },
+                        );
+                    }
+                }
+            }
+            dto::MARK_READ_SUBJECT => {
+                if self.is_authenticated_or_send_error(&subject) {
+                    if let Some(request) = self.parse_request::<MarkReadRequest>(content, &subject)
+                    {
+                        let _ = self.connection_command_router.send(
+                            ConnectionCommand::MarkPrivateMessagesRead {
+                                reader_username: self.current_username.clone(),
+                                partner_username: request.partner_username,
+                                id: request.id,
+                                messages_sender: self.messages_sender.clone(),
+                                request_id: subject.request_id,
+                            },
+                        );
+                    }
+                }
+            }
+            dto::UNREAD_COUNTS_SUBJECT => {
+                if self.is_authenticated_or_send_error(&subject) {
+                    let _ =
+                        self.connection_command_router
+                            .send(ConnectionCommand::GetUnreadCounts {
+                                username: self.current_username.clone(),
+                                messages_sender: self.messages_sender.clone(),
+                                request_id: subject.request_id,
+                            });
+                }
+            }
+            dto::TYPING_STARTED_SUBJECT | dto::TYPING_STOPPED_SUBJECT => {
+                if self.is_authenticated_or_send_error(&subject) {
+                    if let Some(request) = self.parse_request::<TypingRequest>(content, &subject) {
+                        let _ = self.connection_command_router.send(
+                            ConnectionCommand::UpdateTypingStatus {
+                                typist_username: self.current_username.clone(),
+                                partner_username: request.partner_username,
+                                is_typing: subject.subject == dto::TYPING_STARTED_SUBJECT,
+                            },
+                        );
+                    }
+                }
+            }
+            dto::GET_PRESENCE_SUBJECT => {
+                if self.is_authenticated_or_send_error(&subject) {
+                    if let Some(request) =
+                        self.parse_request::<GetPresenceRequest>(content, &subject)
+                    {
+                        let _ =
+                            self.connection_command_router
+                                .send(ConnectionCommand::GetPresence {
+                                    username: self.current_username.clone(),
+                                    usernames: request.usernames,
+                                    messages_sender: self.messages_sender.clone(),
+                                    request_id: subject.request_id,
+                                });
+                    }
+                }
+            }
+            dto::CREATE_GROUP_SUBJECT => {
+                if self.is_authenticated_or_send_error(&subject) {
+                    if let Some(request) =
+                        self.parse_request::<CreateGroupRequest>(content, &subject)
+                    {
+                        let _ =
+                            self.connection_command_router
+                                .send(ConnectionCommand::CreateGroup {
+                                    owner_username: self.current_username.clone(),
+                                    name: request.name,
+                                    member_usernames: request.member_usernames,
+                                    messages_sender: self.messages_sender.clone(),
+                                    request_id: subject.request_id,
+                                });
+                    }
+                }
+            }
+            dto::INVITE_TO_GROUP_SUBJECT => {
+                if self.is_authenticated_or_send_error(&subject) {
+                    if let Some(request) =
+                        self.parse_request::<GroupMemberRequest>(content, &subject)
+                    {
+                        let _ =
+                            self.connection_command_router
+                                .send(ConnectionCommand::InviteToGroup {
+                                    inviter_username: self.current_username.clone(),
+                                    group_id: request.group_id,
+                                    username: request.username,
+                                    messages_sender: self.messages_sender.clone(),
+                                    request_id: subject.request_id,
+                                });
+                    }
+                }
+            }
+            dto::REMOVE_FROM_GROUP_SUBJECT => {
+                if self.is_authenticated_or_send_error(&subject) {
+                    if let Some(request) =
+                        self.parse_request::<GroupMemberRequest>(content, &subject)
+                    {
+                        let _ = self.connection_command_router.send(
+                            ConnectionCommand::RemoveFromGroup {
+                                remover_username: self.current_username.clone(),
+                                group_id: request.group_id,
+                                username: request.username,
+                                messages_sender: self.messages_sender.clone(),
+                                request_id: subject.request_id,
+                            },
+                        );
+                    }
+                }
+            }
+            dto::LEAVE_GROUP_SUBJECT => {
+                if self.is_authenticated_or_send_error(&subject) {
+                    if let Some(request) = self.parse_request::<GroupRequest>(content, &subject) {
+                        let _ =
+                            self.connection_command_router
+                                .send(ConnectionCommand::LeaveGroup {
+                                    username: self.current_username.clone(),
+                                    group_id: request.group_id,
+                                    messages_sender: self.messages_sender.clone(),
+                                    request_id: subject.request_id,
+                                });
+                    }
+                }
+            }
+            dto::RENAME_GROUP_SUBJECT => {
+                if self.is_authenticated_or_send_error(&subject) {
+                    if let Some(request) =
+                        self.parse_request::<RenameGroupRequest>(content, &subject)
+                    {
+                        let _ =
+                            self.connection_command_router
+                                .send(ConnectionCommand::RenameGroup {
+                                    renamer_username: self.current_username.clone(),
+                                    group_id: request.group_id,
+                                    name: request.name,
+                                    messages_sender: self.messages_sender.clone(),
+                                    request_id: subject.request_id,
+                                });
+                    }
+                }
+            }
+            dto::SET_GROUP_ROLE_SUBJECT => {
+                if self.is_authenticated_or_send_error(&subject) {
+                    if let Some(request) =
+                        self.parse_request::<SetGroupRoleRequest>(content, &subject)
+                    {
+                        let _ =
+                            self.connection_command_router
+                                .send(ConnectionCommand::SetGroupRole {
+                                    setter_username: self.current_username.clone(),
+                                    group_id: request.group_id,
+                                    username: request.username,
+                                    role: request.role,
+                                    messages_sender: self.messages_sender.clone(),
+                                    request_id: subject.request_id,
+                                });
+                    }
+                }
+            }
+            dto::NEW_GROUP_MESSAGE_SEQUENCE_SUBJECT => {
+                if self.is_authenticated_or_send_error(&subject) {
+                    if let Some(request) = self.parse_request::<GroupRequest>(content, &subject) {
+                        let _ = self.connection_command_router.send(
+                            ConnectionCommand::InitiateNewGroupMessageSequence {
+                                sender_username: self.current_username.clone(),
+                                group_id: request.group_id,
+                                messages_sender: self.messages_sender.clone(),
+                                request_id: subject.request_id,
+                            },
+                        );
+                    }
+                }
+            }
+            dto::NEW_GROUP_MESSAGE_SUBJECT => {
+                if self.is_authenticated_or_send_error(&subject) {
+                    if let Some(request) = self.parse_request::<MessageToGroup>(content, &subject) {
+                        let _ = self.connection_command_router.send(
+                            ConnectionCommand::SendMessageToGroup {
+                                sender_username: self.current_username.clone(),
+                                group_id: request.group_id,
+                                content: request.content,
+                                message_sequence_id: request.message_sequence_id,
+                                message_sequence_index: request.message_sequence_index,
+                                messages_sender: self.messages_sender.clone(),
+                                request_id: subject.request_id,
+                            },
+                        );
+                    }
+                }
+            }
+            dto::GET_GROUP_HISTORY_SUBJECT => {
+                if self.is_authenticated_or_send_error(&subject) {
+                    if let Some(request) =
+                        self.parse_request::<GroupHistoryRequest>(content, &subject)
+                    {
+                        let _ = self.connection_command_router.send(
+                            ConnectionCommand::GetGroupHistory {
+                                reader_username: self.current_username.clone(),
+                                group_id: request.group_id,
+                                before_id: request.before_id,
+                                limit: request.limit,
+                                messages_sender: self.messages_sender.clone(),
+                                request_id: subject.request_id,
+                            },
+                        );
+                    }
+                }
+            }
+            dto::MARK_GROUP_READ_SUBJECT => {
+                if self.is_authenticated_or_send_error(&subject) {
+                    if let Some(request) =
+                        self.parse_request::<MarkGroupReadRequest>(content, &subject)
+                    {
+                        let _ = self.connection_command_router.send(
+                            ConnectionCommand::MarkGroupMessagesRead {
+                                reader_username: self.current_username.clone(),
+                                group_id: request.group_id,
+                                id: request.id,
+                                messages_sender: self.messages_sender.clone(),
+                                request_id: subject.request_id,
+                            },
+                        );
+                    }
+                }
+            }
+            dto::LIST_CHANNELS_SUBJECT => {
+                if self.is_authenticated_or_send_error(&subject) {
+                    let _ = self
+                        .connection_command_router
+                        .send(ConnectionCommand::ListChannels {
+                            username: self.current_username.clone(),
+                            messages_sender: self.messages_sender.clone(),
+                            request_id: subject.request_id,
+                        });
+                }
+            }
+            dto::CREATE_CHANNEL_SUBJECT => {
+                if self.is_authenticated_or_send_error(&subject) {
+                    if let Some(request) =
+                        self.parse_request::<CreateChannelRequest>(content, &subject)
+                    {
+                        let _ =
+                            self.connection_command_router
+                                .send(ConnectionCommand::CreateChannel {
+                                    creator_username: self.current_username.clone(),
+                                    name: request.name,
+                                    topic: request.topic,
+                                    messages_sender: self.messages_sender.clone(),
+                                    request_id: subject.request_id,
+                                });
+                    }
+                }
+            }
+            dto::GET_CHANNEL_INFO_SUBJECT => {
+                if self.is_authenticated_or_send_error(&subject) {
+                    if let Some(request) = self.parse_request::<ChannelRequest>(content, &subject) {
+                        let _ = self.connection_command_router.send(
+                            ConnectionCommand::GetChannelInfo {
+                                username: self.current_username.clone(),
+                                name: request.name,
+                                messages_sender: self.messages_sender.clone(),
+                                request_id: subject.request_id,
+                            },
+                        );
+                    }
+                }
+            }
+            dto::JOIN_CHANNEL_SUBJECT => {
+                if self.is_authenticated_or_send_error(&subject) {
+                    if let Some(request) =
+                        self.parse_request::<JoinChannelRequest>(content, &subject)
+                    {
+                        let _ =
+                            self.connection_command_router
+                                .send(ConnectionCommand::JoinChannel {
+                                    username: self.current_username.clone(),
+                                    name: request.name,
+                                    history_limit: request.history_limit,
+                                    messages_sender: self.messages_sender.clone(),
+                                    request_id: subject.request_id,
+                                });
+                    }
+                }
+            }
+            dto::LEAVE_CHANNEL_SUBJECT => {
+                if self.is_authenticated_or_send_error(&subject) {
+                    if let Some(request) = self.parse_request::<ChannelRequest>(content, &subject) {
+                        let _ =
+                            self.connection_command_router
+                                .send(ConnectionCommand::LeaveChannel {
+                                    username: self.current_username.clone(),
+                                    name: request.name,
+                                    messages_sender: self.messages_sender.clone(),
+                                    request_id: subject.request_id,
+                                });
+                    }
+                }
+            }
+            dto::NEW_CHANNEL_MESSAGE_SUBJECT => {
+                if self.is_authenticated_or_send_error(&subject) {
+                    if let Some(request) = self.parse_request::<MessageToChannel>(content, &subject)
+                    {
+                        let _ = self.connection_command_router.send(
+                            ConnectionCommand::SendMessageToChannel {
+                                sender_username: self.current_username.clone(),
+                                channel_name: request.channel_name,
+                                content: request.content,
+                                messages_sender: self.messages_sender.clone(),
+                                request_id: subject.request_id,
+                            },
+                        );
+                    }
+                }
+            }
+            dto::REGISTER_SUBJECT => {
+                if let Some(request) = self.parse_request::<RegistrationRequest>(content, &subject)
+                {
+                    return self.register(request, subject);
+                }
+            }
+            dto::ADD_USER_SUBJECT => {
+                if self.is_admin_or_send_error(&subject) {
+                    if let Some(request) = self.parse_request::<AddUserRequest>(content, &subject) {
+                        let password = request.password;
+                        return self.run_blocking(
+                            move || user_service::hash_password(&password),
+                            move |client_session, password_hash| {
+                                let _ = client_session.connection_command_router.send(
+                                    ConnectionCommand::AddUser {
+                                        admin_username: client_session.current_username.clone(),
+                                        user: StoredUser {
+                                            username: request.username,
+                                            password_hash,
+                                            is_admin: request.is_admin,
+                                            is_disabled: false,
+                                        },
+                                        messages_sender: client_session.messages_sender.clone(),
+                                        request_id: subject.request_id,
+                                    },
+                                );
+                                FrameHandlingResult::KeepOpen
+                            },
+                        );
+                    }
+                }
+            }
+            dto::SET_USER_DISABLED_SUBJECT => {
+                if self.is_admin_or_send_error(&subject) {
+                    if let Some(request) =
+                        self.parse_request::<SetUserDisabledRequest>(content, &subject)
+                    {
+                        let _ = self.connection_command_router.send(
+                            ConnectionCommand::SetUserDisabled {
+                                admin_username: self.current_username.clone(),
+                                username: request.username,
+                                is_disabled: request.is_disabled,
+                                messages_sender: self.messages_sender.clone(),
+                                request_id: subject.request_id,
+                            },
+                        );
+                    }
+                }
+            }
+            dto::GET_OUTBOUND_QUEUE_METRICS_SUBJECT => {
+                if self.is_admin_or_send_error(&subject) {
+                    let _ = self.messages_sender.send(Message::Text(
+                        attach_reply_subject_and_serialize(
+                            Box::new(OutboundQueueMetricsResponse {
+                                users: outbound_queue::outbound_queue_metrics()
+                                    .into_iter()
+                                    .map(|(username, metrics)| UserOutboundQueueMetrics {
+                                        username,
+                                        dropped_frames: metrics.dropped_frames,
+                                        forced_disconnects: metrics.forced_disconnects,
+                                    })
+                                    .collect(),
+                            }),
+                            subject.subject,
+                            subject.request_id,
+                        ),
+                    ));
+                }
+            }
+            _ => {
+                self.send_error(ErrorCode::UnknownSubject, "unknown subject", &subject);
+                // Close the WebSocket connection gracefully
+                let _ = self.messages_sender.send(Message::Close(Some(CloseFrame {
+                    code: CloseCode::Protocol,
+                    reason: "unknown subject".into(),
+                })));
+                debug!("Close frame sent because the subject was unknown");
+                return FrameHandlingResult::Close;
+            }
+        }
+        FrameHandlingResult::KeepOpen
+    }
+
+    /// Unregisters the connection from its user. Must be called whenever the connection ends.
+    pub fn unsubscribe(self) {
+        // the shard may still accept the session that it has not answered yet
+        let username = match self.awaited_username {
+            Some(awaited_username) => awaited_username,
+            None => self.current_username,
+        };
+        if !username.is_empty() {
+            // send a command to unsubscribe
+            let _ = self.connection_command_router.send(
+                ConnectionCommand::UnassignConnectionFromUser {
+                    username,
+                    messages_sender: self.messages_sender,
+                },
+            );
+        }
+    }
+
+    /// Tells the client that the server is shutting down and closes the connection. The shard of an
+    /// authenticated user does it for all his sessions, so only the sessions that have not
+    /// authenticated do it themselves.
+    pub fn shut_down(&self) {
+        if self.current_username.is_empty() {
+            connection_handler::close_for_shutdown(&self.messages_sender, &self.config);
+        }
+    }
+
+    /// The client has answered a ping.
+    pub fn handle_pong(&mut self) {
+        self.missed_pongs = 0;
+    }
+
+    /// Returns when handle_timer should be called next.
+    pub fn next_timer(&self) -> Instant {
+        if self.current_username.is_empty() {
+            self.next_ping_at
+                .min(self.last_request_at + self.config.unauthenticated_idle_timeout)
+        } else {
+            self.next_ping_at
+        }
+    }
+
+    /// Pings the client when the next ping is due. Closes the connection if the client has not
+    /// answered the previous pings or if it has not authenticated and has been idle for too long.
+    pub fn handle_timer(&mut self, now: Instant) -> FrameHandlingResult {
+        if self.current_username.is_empty()
+            && now >= self.last_request_at + self.config.unauthenticated_idle_timeout
+        {
+            warn!(
+                "Closing the idle connection of {} that has not authenticated",
+                self.peer_address
+            );
+            let _ = self.messages_sender.send(Message::Close(Some(CloseFrame {
+                code: CloseCode::Policy,
+                reason: "no authentication before the idle timeout".into(),
+            })));
+            return FrameHandlingResult::Close;
+        }
+        if now < self.next_ping_at {
+            return FrameHandlingResult::KeepOpen;
+        }
+        if self.missed_pongs >= self.config.maximum_missed_pongs {
+            warn!(
+                "Closing the connection of {} after {} unanswered pings",
+                self.peer_address, self.missed_pongs
+            );
+            let _ = self.messages_sender.send(Message::Close(Some(CloseFrame {
+                code: HEARTBEAT_TIMEOUT_CLOSE_CODE,
+                reason: "the pings have not been answered".into(),
+            })));
+            return FrameHandlingResult::Close;
+        }
+        self.missed_pongs += 1;
+        self.next_ping_at = now + self.config.heartbeat_interval;
+        let _ = self.messages_sender.send(Message::Ping(Vec::new()));
+        FrameHandlingResult::KeepOpen
+    }
+
+    /// Returns true while a request waits for another thread. The next frames must not be handled
+    /// before its callback has run.
+    pub fn is_waiting_for_callback(&self) -> bool {
+        self.is_waiting_for_callback
+    }
+
+    /// Waits for the next callback and applies it to the session.
+    pub async fn run_next_callback(&mut self) -> FrameHandlingResult {
+        // the session keeps a sender, so the channel is never closed
+        let callback = self
+            .callback_receiver
+            .recv()
+            .await
+            .expect("the callback channel has been closed");
+        callback(self)
+    }
+
+    /// Applies the callbacks that have already been sent, as the loop of the connection would.
+    #[cfg(test)]
+    fn run_ready_callbacks(&mut self) {
+        while let Ok(callback) = self.callback_receiver.try_recv() {
+            callback(self);
+        }
+    }
+
+    /// Runs CPU-heavy work, like hashing a password, on the blocking thread pool, so that it does
+    /// not hold up the other connections of the runtime worker. `then` finishes the request with
+    /// the result on the session. Without a runtime the work is done in place.
+    fn run_blocking<T: Send + 'static>(
+        &mut self,
+        work: impl FnOnce() -> T + Send + 'static,
+        then: impl FnOnce(&mut ClientSession, T) -> FrameHandlingResult + Send + 'static,
+    ) -> FrameHandlingResult {
+        let Ok(runtime) = Handle::try_current() else {
+            let result = work();
+            return then(self, result);
+        };
+        self.is_waiting_for_callback = true;
+        let callback_sender = self.callback_sender.clone();
+        runtime.spawn_blocking(move || {
+            let result = work();
+            let _ = callback_sender.send(Box::new(move |client_session: &mut ClientSession| {
+                client_session.is_waiting_for_callback = false;
+                then(client_session, result)
+            }));
+        });
+        FrameHandlingResult::KeepOpen
+    }
+
+    /// Starts the session of the user if the password is correct. Otherwise the failure counts
+    /// against the username, the address and the connection.
+    fn finish_authentication(
+        &mut self,
+        login: String,
+        is_password_correct: bool,
+        subject: Subject,
+    ) -> FrameHandlingResult {
+        if !is_password_correct {
+            login_throttle::record_failed_login(&login, self.peer_address.ip(), &self.config);
+            self.send_error(
+                ErrorCode::InvalidCredentials,
+                "provide correct login and password for authentication",
+                &subject,
+            );
+            return self.count_failed_login();
+        }
+        login_throttle::record_successful_login(&login);
+        let session_token = SessionToken::new(
+            &login,
+            Utc::now(),
+            to_chrono_duration(self.config.session_token_lifetime),
+        );
+        self.start_user_session(session_token, subject);
+        FrameHandlingResult::KeepOpen
+    }
+
+    /// Closes the connection if too many authentication attempts have failed on it.
+    fn count_failed_login(&mut self) -> FrameHandlingResult {
+        self.failed_logins += 1;
+        if self.failed_logins < self.config.maximum_failed_logins_per_connection {
+            return FrameHandlingResult::KeepOpen;
+        }
+        warn!(
+            "Closing the connection of {} after {} failed authentication attempts",
+            self.peer_address, self.failed_logins
+        );
+        let _ = self.messages_sender.send(Message::Close(Some(CloseFrame {
+            code: CloseCode::Policy,
+            reason: "too many failed authentication attempts".into(),
+        })));
+        FrameHandlingResult::Close
+    }
+
+    /// Asks the shard of the user to accept the connection as a session of the user. The shard
+    /// replies with the signed token if it does. The next frames wait for its answer.
+    fn start_user_session(&mut self, session_token: SessionToken, subject: Subject) {
+        let reply = attach_reply_subject_and_serialize(
+            Box::new(AuthenticationResponse {
+                login: session_token.username.clone(),
+                session_token: session_token.sign(&self.config.session_token_key),
+                session_token_expires_at: session_token.expires_at,
+            }),
+            subject.subject.clone(),
+            subject.request_id.clone(),
+        );
+        self.is_waiting_for_callback = true;
+        self.awaited_username = Some(session_token.username.clone());
+        let _ = self
+            .connection_command_router
+            .send(ConnectionCommand::AssignConnectionToUser {
+                username: session_token.username.clone(),
+                messages_sender: self.messages_sender.clone(),
+                acceptance: Some(Box::new(SessionAcceptance {
+                    session_token,
+                    reply,
+                    subject,
+                    callback_sender: self.callback_sender.clone(),
+                })),
+            });
+    }
+
+    /// The shard has accepted the session, so the requests are made on behalf of the user.
+    fn accept_session(&mut self, session_token: SessionToken) -> FrameHandlingResult {
+        self.is_waiting_for_callback = false;
+        self.awaited_username = None;
+        self.current_username = session_token.username.clone();
+        self.messages_sender
+            .set_username(Some(self.current_username.clone()));
+        self.session_token = Some(session_token);
+        FrameHandlingResult::KeepOpen
+    }
+
+    /// The shard has refused the session and queued a close frame.
+    fn refuse_session(&mut self) -> FrameHandlingResult {
+        self.is_waiting_for_callback = false;
+        self.awaited_username = None;
+        FrameHandlingResult::Close
+    }
+
+    /// Checks the registration request and, if it is acceptable, asks the connection handler to add
+    /// the user. The password is only hashed after all the checks pass.
+    fn register(&mut self, request: RegistrationRequest, subject: Subject) -> FrameHandlingResult {
+        let rejection = match self.config.registration_mode {
+            RegistrationMode::Closed => Some((
+                ErrorCode::RegistrationClosed,
+                "the registration is closed".to_string(),
+            )),
+            RegistrationMode::InviteCode
+                if !request
+                    .invite_code
+                    .as_deref()
+                    .is_some_and(|invite_code| self.is_valid_invite_code(invite_code)) =>
+            {
+                Some((
+                    ErrorCode::InvalidInviteCode,
+                    "the invite code is not valid".to_string(),
+                ))
+            }
+            _ if !user_service::is_valid_username(&request.username) => Some((
+                ErrorCode::InvalidUsername,
+                format!(
+                    "the username should consist of up to {} letters, digits, '-', '_' and '.'",
+                    user_service::MAXIMUM_USERNAME_LENGTH
+                ),
+            )),
+            _ if !user_service::is_valid_password(&request.password) => Some((
+                ErrorCode::InvalidPassword,
+                format!(
+                    "the password should be from {} to {} characters long",
+                    user_service::MINIMUM_PASSWORD_LENGTH,
+                    user_service::MAXIMUM_PASSWORD_LENGTH
+                ),
+            )),
+            _ if user_service::user_exists(&request.username) => Some((
+                ErrorCode::UserAlreadyExists,
+                "the user already exists".to_string(),
+            )),
+            _ => None,
+        };
+        if let Some((code, message)) = rejection {
+            self.send_error(code, &message, &subject);
+            return FrameHandlingResult::KeepOpen;
+        }
+        let password = request.password;
+        self.run_blocking(
+            move || user_service::hash_password(&password),
+            move |client_session, password_hash| {
+                let _ = client_session.connection_command_router.send(
+                    ConnectionCommand::RegisterUser {
+                        user: StoredUser {
+                            username: request.username,
+                            password_hash,
+                            is_admin: false,
+                            is_disabled: false,
+                        },
+                        messages_sender: client_session.messages_sender.clone(),
+                        request_id: subject.request_id,
+                    },
+                );
+                FrameHandlingResult::KeepOpen
+            },
+        )
+    }
+
+    /// Compares the invite code with every configured one in constant time.
+    fn is_valid_invite_code(&self, invite_code: &str) -> bool {
+        self.config
+            .invite_codes
+            .iter()
+            .fold(Choice::from(0), |is_valid, configured_code| {
+                is_valid | configured_code.as_bytes().ct_eq(invite_code.as_bytes())
+            })
+            .into()
+    }
+
+    /// Returns true if the client has authenticated. Otherwise the client receives an error.
+    fn is_authenticated_or_send_error(&self, subject: &Subject) -> bool {
+        if self.current_username.is_empty() {
+            self.send_error(
+                ErrorCode::NotAuthenticated,
+                "you should authorize before making this type of request",
+                subject,
+            );
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Returns true if the client has neither authenticated nor started to. Otherwise the client
+    /// receives an error, because a connection holds the session of a single user.
+    fn is_signed_out_or_send_error(&self, subject: &Subject) -> bool {
+        if self.current_username.is_empty() && self.awaited_username.is_none() {
+            true
+        } else {
+            self.send_error(
+                ErrorCode::AlreadyAuthenticated,
+                "you should log out before authenticating again",
+                subject,
+            );
+            false
+        }
+    }
+
+    /// Returns true if the client has authenticated as an administrator. Otherwise the client
+    /// receives an error.
+    fn is_admin_or_send_error(&self, subject: &Subject) -> bool {
+        if !self.is_authenticated_or_send_error(subject) {
+            false
+        } else if !user_service::is_admin(&self.current_username) {
+            self.send_error(
+                ErrorCode::NotAdmin,
+                "only administrators can manage users",
+                subject,
+            );
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Parses the payload of a request. If it is malformed, the client receives an error.
+    fn parse_request<T: DeserializeOwned>(&self, content: &str, subject: &Subject) -> Option<T> {
+        match serde_json::from_str::<T>(content) {
+            Ok(request) => Some(request),
+            Err(e) => {
+                debug!("Failed to parse JSON: {}", e);
+                self.send_error(ErrorCode::MalformedRequest, &e.to_string(), subject);
+                None
+            }
+        }
+    }
+
+    /// Sends an error to the client. The error echoes the subject and the id of the request.
+    fn send_error(&self, code: ErrorCode, message: &str, request: &Subject) {
+        let _ = self
+            .messages_sender
+            .send(Message::Text(prepare_error_response(
+                code,
+                message,
+                &request.subject,
+                request.request_id.clone(),
+            )));
+    }
+}
+
+#[cfg(test)]
+fn test_peer_address() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 50000))
+}
+
+#[cfg(test)]
+fn apply_commands(
+    application_scope: &mut crate::user_context::ApplicationScope,
+    connection_command_receiver: &crossbeam_channel::Receiver<ConnectionCommand>,
+) {
+    for command in connection_command_receiver.try_iter() {
+        crate::connection_handler::process_connection_command(
+            application_scope,
+            &crate::config::ServerConfig::default(),
+            command,
+        );
+    }
+}
+
+#[test]
+fn test_garbage_frames_do_not_break_the_session() {
+    use crate::user_context::ApplicationScope;
+
+    let (messages_sender, mut messages_receiver) = messages_channel(&ServerConfig::default());
+    let (connection_command_sender, connection_command_receiver) =
+        crossbeam_channel::unbounded::<ConnectionCommand>();
+    let mut application_scope = ApplicationScope::new();
+    let mut client_session = ClientSession::new(
+        messages_sender,
+        ConnectionCommandRouter::new(vec![connection_command_sender]),
+        Arc::new(ServerConfig::default()),
+        test_peer_address(),
+    );
+
+    client_session
+        .handle_text_frame(r#"{"subject":"authenticate","login":"ian","password":"ian"}"#);
+    apply_commands(&mut application_scope, &connection_command_receiver);
+    client_session.run_ready_callbacks();
+    assert_eq!(
+        application_scope.chat_users["ian"]
+            .opened_sessions_senders
+            .len(),
+        1
+    );
+    let _ = drain_messages(&mut messages_receiver).len();
+
+    let garbage_frames = [
+        "",
+        "not a json",
+        "{\"subject\":",
+        r#"{"subject":42}"#,
+        r#"["authenticate"]"#,
+        r#"{"subject":"authenticate","login":"ian"}"#,
+        r#"{"subject":"new-message","receiver":"dan"}"#,
+        r#"{"subject":"new-message","message_sequence_id":-1,"message_sequence_index":1,"content":"hi","receiver":"dan"}"#,
+        r#"{"subject":"new-private-message-sequence","receiver_username":null}"#,
+    ];
+    for garbage_frame in garbage_frames {
+        assert_eq!(
+            client_session.handle_text_frame(garbage_frame),
+            FrameHandlingResult::KeepOpen
+        );
+        match messages_receiver.try_recv() {
+            Some(Message::Text(text)) => {
+                let error_response: dto::ErrorResponse = serde_json::from_str(&text).unwrap();
+                assert_eq!(error_response.code, ErrorCode::MalformedRequest);
+            }
+            other => panic!(
+                "an error response expected for {:?}, got {:?}",
+                garbage_frame, other
+            ),
+        }
+    }
+    assert_eq!(connection_command_receiver.try_iter().count(), 0);
+
+    // the session still works after the garbage
+    client_session.handle_text_frame(
+        r#"{"subject":"new-private-message-sequence","receiver_username":"dan"}"#,
+    );
+    assert_eq!(connection_command_receiver.len(), 1);
+    apply_commands(&mut application_scope, &connection_command_receiver);
+
+    client_session.unsubscribe();
+    apply_commands(&mut application_scope, &connection_command_receiver);
+    assert!(application_scope.chat_users["ian"]
+        .opened_sessions_senders
+        .is_empty());
+}
+
+#[test]
+fn test_unknown_subject_closes_the_session() {
+    use crate::user_context::ApplicationScope;
+
+    let (messages_sender, mut messages_receiver) = messages_channel(&ServerConfig::default());
+    let (connection_command_sender, connection_command_receiver) =
+        crossbeam_channel::unbounded::<ConnectionCommand>();
+    let mut application_scope = ApplicationScope::new();
+    let mut client_session = ClientSession::new(
+        messages_sender,
+        ConnectionCommandRouter::new(vec![connection_command_sender]),
+        Arc::new(ServerConfig::default()),
+        test_peer_address(),
+    );
+
+    client_session
+        .handle_text_frame(r#"{"subject":"authenticate","login":"dan","password":"dan"}"#);
+    assert_eq!(
+        client_session.handle_text_frame(r#"{"subject":"say-hello"}"#),
+        FrameHandlingResult::Close
+    );
+    let last_frame = drain_messages(&mut messages_receiver).into_iter().last();
+    match last_frame {
+        Some(Message::Close(Some(close_frame))) => {
+            assert_eq!(close_frame.code, CloseCode::Protocol)
+        }
+        other => panic!("a close frame expected, got {:?}", other),
+    }
+
+    client_session.unsubscribe();
+    apply_commands(&mut application_scope, &connection_command_receiver);
+    assert!(application_scope.chat_users["dan"]
+        .opened_sessions_senders
+        .is_empty());
+}
+
+#[test]
+fn test_replies_echo_request_id() {
+    use crate::user_context::ApplicationScope;
+
+    let (messages_sender, mut messages_receiver) = messages_channel(&ServerConfig::default());
+    let (connection_command_sender, connection_command_receiver) =
+        crossbeam_channel::unbounded::<ConnectionCommand>();
+    let mut application_scope = ApplicationScope::new();
+    let mut client_session = ClientSession::new(
+        messages_sender,
+        ConnectionCommandRouter::new(vec![connection_command_sender]),
+        Arc::new(ServerConfig::default()),
+        test_peer_address(),
+    );
+
+    client_session.handle_text_frame(
+        r#"{"subject":"new-private-message-sequence","request_id":"r1","receiver_username":"dan"}"#,
+    );
+    client_session.handle_text_frame(
+        r#"{"subject":"authenticate","request_id":"r2","login":"ian","password":"ian"}"#,
+    );
+    apply_commands(&mut application_scope, &connection_command_receiver);
+    client_session.run_ready_callbacks();
+    let request_ids: Vec<Option<String>> = drain_messages(&mut messages_receiver)
+        .into_iter()
+        .map(|message| match message {
+            Message::Text(text) => serde_json::from_str::<Subject>(&text).unwrap().request_id,
+            other => panic!("a text frame expected, got {:?}", other),
+        })
+        .collect();
+    assert_eq!(
+        request_ids,
+        vec![Some("r1".to_string()), Some("r2".to_string())]
+    );
+}
+
+#[test]
+fn test_only_admins_manage_users() {
+    use crate::user_context::ApplicationScope;
+
+    let (messages_sender, mut messages_receiver) = messages_channel(&ServerConfig::default());
+    let (connection_command_sender, connection_command_receiver) =
+        crossbeam_channel::unbounded::<ConnectionCommand>();
+    let mut application_scope = ApplicationScope::new();
+    let mut last_reply = |client_session: &mut ClientSession, frame: &str| -> serde_json::Value {
+        client_session.handle_text_frame(frame);
+        apply_commands(&mut application_scope, &connection_command_receiver);
+        client_session.run_ready_callbacks();
+        match drain_messages(&mut messages_receiver).into_iter().last() {
+            Some(Message::Text(text)) => serde_json::from_str(&text).unwrap(),
+            other => panic!("a text frame expected, got {:?}", other),
+        }
+    };
+    user_service::add_user(StoredUser {
+        username: "session-admin".to_string(),
+        password_hash: user_service::hash_password("admin-password"),
+        is_admin: true,
+        is_disabled: false,
+    })
+    .unwrap();
+
+    let mut client_session = ClientSession::new(
+        messages_sender.clone(),
+        ConnectionCommandRouter::new(vec![connection_command_sender.clone()]),
+        Arc::new(ServerConfig::default()),
+        test_peer_address(),
+    );
+    last_reply(
+        &mut client_session,
+        r#"{"subject":"authenticate","login":"chris","password":"chris"}"#,
+    );
+    let reply = last_reply(
+        &mut client_session,
+        r#"{"subject":"add-user","username":"session-user","password":"secret"}"#,
+    );
+    assert_eq!(reply["code"], "not-admin");
+    client_session.unsubscribe();
+
+    let mut client_session = ClientSession::new(
+        messages_sender.clone(),
+        ConnectionCommandRouter::new(vec![connection_command_sender.clone()]),
+        Arc::new(ServerConfig::default()),
+        test_peer_address(),
+    );
+    last_reply(
+        &mut client_session,
+        r#"{"subject":"authenticate","login":"session-admin","password":"admin-password"}"#,
+    );
+    let reply = last_reply(
+        &mut client_session,
+        r#"{"subject":"add-user","username":"session-user","password":"secret"}"#,
+    );
+    assert_eq!(reply["subject"], dto::ADD_USER_SUBJECT);
+    assert_eq!(reply["is_admin"], false);
+    assert!(user_service::are_credentials_correct(
+        "session-user",
+        "secret"
+    ));
+    let reply = last_reply(
+        &mut client_session,
+        r#"{"subject":"add-user","username":"session-user","password":"other"}"#,
+    );
+    assert_eq!(reply["code"], "user-already-exists");
+
+    let reply = last_reply(
+        &mut client_session,
+        r#"{"subject":"set-user-disabled","username":"session-user","is_disabled":true}"#,
+    );
+    assert_eq!(reply["is_disabled"], true);
+    assert!(!user_service::are_credentials_correct(
+        "session-user",
+        "secret"
+    ));
+    let reply = last_reply(
+        &mut client_session,
+        r#"{"subject":"set-user-disabled","username":"nobody","is_disabled":true}"#,
+    );
+    assert_eq!(reply["code"], "user-not-found");
+    client_session.unsubscribe();
+}
+
+#[test]
+fn test_self_service_registration() {
+    use crate::user_context::ApplicationScope;
+
+    let (messages_sender, mut messages_receiver) = messages_channel(&ServerConfig::default());
+    let (connection_command_sender, connection_command_receiver) =
+        crossbeam_channel::unbounded::<ConnectionCommand>();
+    let mut application_scope = ApplicationScope::new();
+    let mut last_reply = |client_session: &mut ClientSession, frame: &str| -> serde_json::Value {
+        client_session.handle_text_frame(frame);
+        apply_commands(&mut application_scope, &connection_command_receiver);
+        client_session.run_ready_callbacks();
+        match drain_messages(&mut messages_receiver).into_iter().last() {
+            Some(Message::Text(text)) => serde_json::from_str(&text).unwrap(),
+            other => panic!("a text frame expected, got {:?}", other),
+        }
+    };
+    let new_session = |registration_mode: RegistrationMode| {
+        ClientSession::new(
+            messages_sender.clone(),
+            ConnectionCommandRouter::new(vec![connection_command_sender.clone()]),
+            Arc::new(ServerConfig {
+                registration_mode,
+                invite_codes: vec!["first-code".to_string(), "second-code".to_string()],
+                ..ServerConfig::default()
+            }),
+            test_peer_address(),
+        )
+    };
+
+    let mut client_session = new_session(RegistrationMode::Closed);
+    let reply = last_reply(
+        &mut client_session,
+        r#"{"subject":"register","username":"registered-user","password":"long-password"}"#,
+    );
+    assert_eq!(reply["code"], "registration-closed");
+
+    let mut client_session = new_session(RegistrationMode::InviteCode);
+    let reply = last_reply(
+        &mut client_session,
+        r#"{"subject":"register","username":"registered-user","password":"long-password"}"#,
+    );
+    assert_eq!(reply["code"], "invalid-invite-code");
+    let reply = last_reply(
+        &mut client_session,
+        r#"{"subject":"register","username":"registered-user","password":"long-password","invite_code":"third-code"}"#,
+    );
+    assert_eq!(reply["code"], "invalid-invite-code");
+    let reply = last_reply(
+        &mut client_session,
+        r#"{"subject":"register","username":"invited-user","password":"long-password","invite_code":"second-code"}"#,
+    );
+    assert_eq!(reply["subject"], dto::REGISTER_SUBJECT);
+    assert_eq!(reply["username"], "invited-user");
+
+    let mut client_session = new_session(RegistrationMode::Open);
+    let reply = last_reply(
+        &mut client_session,
+        r#"{"subject":"register","username":"registered user","password":"long-password"}"#,
+    );
+    assert_eq!(reply["code"], "invalid-username");
+    let reply = last_reply(
+        &mut client_session,
+        r#"{"subject":"register","username":"registered-user","password":"short"}"#,
+    );
+    assert_eq!(reply["code"], "invalid-password");
+    let reply = last_reply(
+        &mut client_session,
+        r#"{"subject":"register","username":"registered-user","password":"long-password","request_id":"1"}"#,
+    );
+    assert_eq!(reply["subject"], dto::REGISTER_SUBJECT);
+    assert_eq!(reply["request_id"], "1");
+    let reply = last_reply(
+        &mut client_session,
+        r#"{"subject":"register","username":"registered-user","password":"other-password"}"#,
+    );
+    assert_eq!(reply["code"], "user-already-exists");
+
+    let reply = last_reply(
+        &mut client_session,
+        r#"{"subject":"authenticate","login":"registered-user","password":"long-password"}"#,
+    );
+    assert_eq!(reply["subject"], dto::AUTHENTICATE_SUBJECT);
+    assert!(reply.get("code").is_none());
+    assert!(!user_service::is_admin("registered-user"));
+    client_session.unsubscribe();
+}
+
+#[test]
+fn test_resume_with_session_token_and_logout() {
+    use crate::user_context::ApplicationScope;
+
+    let (messages_sender, mut messages_receiver) = messages_channel(&ServerConfig::default());
+    let (connection_command_sender, connection_command_receiver) =
+        crossbeam_channel::unbounded::<ConnectionCommand>();
+    let mut application_scope = ApplicationScope::new();
+    let mut last_reply = |client_session: &mut ClientSession, frame: &str| -> serde_json::Value {
+        client_session.handle_text_frame(frame);
+        apply_commands(&mut application_scope, &connection_command_receiver);
+        client_session.run_ready_callbacks();
+        match drain_messages(&mut messages_receiver).into_iter().last() {
+            Some(Message::Text(text)) => serde_json::from_str(&text).unwrap(),
+            other => panic!("a text frame expected, got {:?}", other),
+        }
+    };
+    let config = Arc::new(ServerConfig::default());
+    let new_session = || {
+        ClientSession::new(
+            messages_sender.clone(),
+            ConnectionCommandRouter::new(vec![connection_command_sender.clone()]),
+            config.clone(),
+            test_peer_address(),
+        )
+    };
+    user_service::add_user(StoredUser {
+        username: "token-user".to_string(),
+        password_hash: user_service::hash_password("token-password"),
+        is_admin: false,
+        is_disabled: false,
+    })
+    .unwrap();
+
+    let mut client_session = new_session();
+    let reply = last_reply(
+        &mut client_session,
+        r#"{"subject":"authenticate","login":"token-user","password":"token-password"}"#,
+    );
+    let session_token = reply["session_token"].as_str().unwrap().to_string();
+    let resume_frame = format!(
+        r#"{{"subject":"resume","session_token":"{}","request_id":"7"}}"#,
+        session_token
+    );
+    client_session.unsubscribe();
+
+    // the token authenticates a new connection without the password
+    let mut client_session = new_session();
+    let reply = last_reply(&mut client_session, &resume_frame);
+    assert_eq!(reply["subject"], dto::RESUME_SUBJECT);
+    assert_eq!(reply["request_id"], "7");
+    assert_eq!(reply["login"], "token-user");
+    assert_eq!(reply["session_token"], session_token.as_str());
+    let reply = last_reply(&mut client_session, r#"{"subject":"unread-counts"}"#);
+    assert!(reply.get("code").is_none());
+
+    let reply = last_reply(&mut client_session, r#"{"subject":"logout"}"#);
+    assert_eq!(reply["subject"], dto::LOGOUT_SUBJECT);
+    assert_eq!(reply["login"], "token-user");
+    let reply = last_reply(&mut client_session, r#"{"subject":"unread-counts"}"#);
+    assert_eq!(reply["code"], "not-authenticated");
+    let reply = last_reply(&mut client_session, r#"{"subject":"logout"}"#);
+    assert_eq!(reply["code"], "not-authenticated");
+
+    // the token has been revoked by the logout
+    let reply = last_reply(&mut client_session, &resume_frame);
+    assert_eq!(reply["code"], "invalid-session-token");
+    let reply = last_reply(
+        &mut client_session,
+        r#"{"subject":"resume","session_token":"forged"}"#,
+    );
+    assert_eq!(reply["code"], "invalid-session-token");
+
+    // a disabled user cannot resume with a token issued before
+    let reply = last_reply(
+        &mut client_session,
+        r#"{"subject":"authenticate","login":"token-user","password":"token-password"}"#,
+    );
+    let resume_frame = format!(
+        r#"{{"subject":"resume","session_token":"{}"}}"#,
+        reply["session_token"].as_str().unwrap()
+    );
+    client_session.unsubscribe();
+    user_service::set_user_disabled("token-user", true).unwrap();
+    let mut client_session = new_session();
+    let reply = last_reply(&mut client_session, &resume_frame);
+    assert_eq!(reply["code"], "invalid-session-token");
+}
+
+#[test]
+fn test_failed_logins_are_throttled() {
+    let (messages_sender, mut messages_receiver) = messages_channel(&ServerConfig::default());
+    let (connection_command_sender, _connection_command_receiver) =
+        crossbeam_channel::unbounded::<ConnectionCommand>();
+    let config = Arc::new(ServerConfig {
+        login_backoff_base: std::time::Duration::ZERO,
+        username_lockout_threshold: 2,
+        maximum_failed_logins_per_connection: 3,
+        ..ServerConfig::default()
+    });
+    let new_session = |peer_address: SocketAddr| {
+        ClientSession::new(
+            messages_sender.clone(),
+            ConnectionCommandRouter::new(vec![connection_command_sender.clone()]),
+            config.clone(),
+            peer_address,
+        )
+    };
+    let error_codes = |messages_receiver: &mut MessagesReceiver| -> Vec<String> {
+        drain_messages(messages_receiver)
+            .into_iter()
+            .filter_map(|message| match message {
+                Message::Text(text) => Some(
+                    serde_json::from_str::<serde_json::Value>(&text).unwrap()["code"]
+                        .as_str()?
+                        .to_string(),
+                ),
+                _ => None,
+            })
+            .collect()
+    };
+    user_service::add_user(StoredUser {
+        username: "throttled-user".to_string(),
+        password_hash: user_service::hash_password("throttled-password"),
+        is_admin: false,
+        is_disabled: false,
+    })
+    .unwrap();
+    let wrong_credentials =
+        r#"{"subject":"authenticate","login":"throttled-user","password":"wrong"}"#;
+    let correct_credentials =
+        r#"{"subject":"authenticate","login":"throttled-user","password":"throttled-password"}"#;
+
+    let mut client_session = new_session(SocketAddr::from(([192, 0, 2, 10], 50000)));
+    for _ in 0..2 {
+        assert_eq!(
+            client_session.handle_text_frame(wrong_credentials),
+            FrameHandlingResult::KeepOpen
+        );
+    }
+    assert_eq!(
+        error_codes(&mut messages_receiver),
+        vec!["invalid-credentials", "invalid-credentials"]
+    );
+    // the username is locked out even for the correct password, and the third failure on the
+    // connection closes it
+    assert_eq!(
+        client_session.handle_text_frame(correct_credentials),
+        FrameHandlingResult::Close
+    );
+    assert_eq!(
+        drain_messages(&mut messages_receiver).into_iter().last(),
+        Some(Message::Close(Some(CloseFrame {
+            code: CloseCode::Policy,
+            reason: "too many failed authentication attempts".into(),
+        })))
+    );
+
+    let mut client_session = new_session(SocketAddr::from(([192, 0, 2, 11], 50000)));
+    assert_eq!(
+        client_session.handle_text_frame(correct_credentials),
+        FrameHandlingResult::KeepOpen
+    );
+    assert_eq!(
+        error_codes(&mut messages_receiver),
+        vec!["too-many-failed-logins"]
+    );
+}
+
+#[test]
+fn test_heartbeat_reaps_unresponsive_and_idle_sessions() {
+    use crate::user_context::ApplicationScope;
+    use std::time::Duration;
+
+    let config = Arc::new(ServerConfig {
+        heartbeat_interval: Duration::from_secs(10),
+        maximum_missed_pongs: 2,
+        unauthenticated_idle_timeout: Duration::from_secs(25),
+        ..ServerConfig::default()
+    });
+    let (connection_command_sender, connection_command_receiver) =
+        crossbeam_channel::unbounded::<ConnectionCommand>();
+    let mut application_scope = ApplicationScope::new();
+    let new_session = |messages_sender| {
+        ClientSession::new(
+            messages_sender,
+            ConnectionCommandRouter::new(vec![connection_command_sender.clone()]),
+            config.clone(),
+            test_peer_address(),
+        )
+    };
+
+    // an authenticated session that stops answering is closed after the missed pongs
+    let (messages_sender, mut messages_receiver) = messages_channel(&config);
+    let mut client_session = new_session(messages_sender);
+    client_session
+        .handle_text_frame(r#"{"subject":"authenticate","login":"dan","password":"dan"}"#);
+    apply_commands(&mut application_scope, &connection_command_receiver);
+    client_session.run_ready_callbacks();
+    assert_eq!(
+        application_scope.chat_users["dan"]
+            .opened_sessions_senders
+            .len(),
+        1
+    );
+    drain_messages(&mut messages_receiver);
+    let start = Instant::now();
+    let pings = |messages_receiver: &mut MessagesReceiver| {
+        drain_messages(messages_receiver)
+            .into_iter()
+            .filter(|message| matches!(message, Message::Ping(_)))
+            .count()
+    };
+    // nothing is due before the first interval has passed
+    assert_eq!(
+        client_session.handle_timer(start),
+        FrameHandlingResult::KeepOpen
+    );
+    assert_eq!(pings(&mut messages_receiver), 0);
+    for seconds in [10, 20] {
+        assert_eq!(
+            client_session.handle_timer(start + Duration::from_secs(seconds)),
+            FrameHandlingResult::KeepOpen
+        );
+    }
+    assert_eq!(pings(&mut messages_receiver), 2);
+    // the answer gives the client another chance
+    client_session.handle_pong();
+    for seconds in [30, 40] {
+        assert_eq!(
+            client_session.handle_timer(start + Duration::from_secs(seconds)),
+            FrameHandlingResult::KeepOpen
+        );
+    }
+    assert_eq!(pings(&mut messages_receiver), 2);
+    assert_eq!(
+        client_session.handle_timer(start + Duration::from_secs(50)),
+        FrameHandlingResult::Close
+    );
+    match drain_messages(&mut messages_receiver).into_iter().last() {
+        Some(Message::Close(Some(close_frame))) => {
+            assert_eq!(close_frame.code, HEARTBEAT_TIMEOUT_CLOSE_CODE)
+        }
+        other => panic!("a close frame expected, got {:?}", other),
+    }
+    // the reaped session no longer counts against the limit of the sessions of the user
+    client_session.unsubscribe();
+    apply_commands(&mut application_scope, &connection_command_receiver);
+    assert!(application_scope.chat_users["dan"]
+        .opened_sessions_senders
+        .is_empty());
+
+    // a connection that does not authenticate is closed after the idle timeout even though it
+    // answers the pings
+    let (messages_sender, mut messages_receiver) = messages_channel(&config);
+    let mut client_session = new_session(messages_sender);
+    let start = Instant::now();
+    assert!(client_session.next_timer() <= start + Duration::from_secs(10));
+    for seconds in [10, 20] {
+        assert_eq!(
+            client_session.handle_timer(start + Duration::from_secs(seconds)),
+            FrameHandlingResult::KeepOpen
+        );
+        client_session.handle_pong();
+    }
+    assert!(client_session.next_timer() <= start + Duration::from_secs(25));
+    assert_eq!(
+        client_session.handle_timer(start + Duration::from_secs(25)),
+        FrameHandlingResult::Close
+    );
+    match drain_messages(&mut messages_receiver).into_iter().last() {
+        Some(Message::Close(Some(close_frame))) => {
+            assert_eq!(close_frame.code, CloseCode::Policy)
+        }
+        other => panic!("a close frame expected, got {:?}", other),
+    }
+    client_session.unsubscribe();
+    assert_eq!(connection_command_receiver.try_iter().count(), 0);
+}
+
+#[tokio::test]
+async fn test_passwords_are_checked_off_the_runtime() {
+    use crate::user_context::ApplicationScope;
+
+    let (messages_sender, mut messages_receiver) = messages_channel(&ServerConfig::default());
+    let (connection_command_sender, connection_command_receiver) =
+        crossbeam_channel::unbounded::<ConnectionCommand>();
+    let new_session = || {
+        ClientSession::new(
+            messages_sender.clone(),
+            ConnectionCommandRouter::new(vec![connection_command_sender.clone()]),
+            Arc::new(ServerConfig::default()),
+            // the failed attempt must not throttle the address of the other tests
+            SocketAddr::from(([192, 0, 2, 20], 50000)),
+        )
+    };
+    let mut client_session = new_session();
+    user_service::add_user(StoredUser {
+        username: "blocking-user".to_string(),
+        password_hash: user_service::hash_password("blocking-password"),
+        is_admin: false,
+        is_disabled: false,
+    })
+    .unwrap();
+
+    assert_eq!(
+        client_session.handle_text_frame(
+            r#"{"subject":"authenticate","login":"blocking-user","password":"blocking-password"}"#
+        ),
+        FrameHandlingResult::KeepOpen
+    );
+    // the session waits for the blocking thread pool before it handles the next frames
+    assert!(client_session.is_waiting_for_callback());
+    assert_eq!(connection_command_receiver.len(), 0);
+    assert_eq!(
+        client_session.run_next_callback().await,
+        FrameHandlingResult::KeepOpen
+    );
+    // then it waits for the shard to accept the session
+    assert!(client_session.is_waiting_for_callback());
+    assert_eq!(connection_command_receiver.len(), 1);
+    apply_commands(&mut ApplicationScope::new(), &connection_command_receiver);
+    assert_eq!(
+        client_session.run_next_callback().await,
+        FrameHandlingResult::KeepOpen
+    );
+    assert!(!client_session.is_waiting_for_callback());
+
+    let mut client_session = new_session();
+    client_session.handle_text_frame(
+        r#"{"subject":"authenticate","login":"blocking-user","password":"wrong"}"#,
+    );
+    client_session.run_next_callback().await;
+    assert_eq!(connection_command_receiver.len(), 0);
+    let last_frame = drain_messages(&mut messages_receiver).into_iter().last();
+    match last_frame {
+        Some(Message::Text(text)) => {
+            let error_response: dto::ErrorResponse = serde_json::from_str(&text).unwrap();
+            assert_eq!(error_response.code, ErrorCode::InvalidCredentials);
+        }
+        other => panic!("an error response expected, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_refused_session_gets_no_session_token() {
+    use crate::user_context::ApplicationScope;
+
+    let (connection_command_sender, connection_command_receiver) =
+        crossbeam_channel::unbounded::<ConnectionCommand>();
+    let mut application_scope = ApplicationScope::new();
+    let new_session = || {
+        let (messages_sender, messages_receiver) = messages_channel(&ServerConfig::default());
+        let client_session = ClientSession::new(
+            messages_sender,
+            ConnectionCommandRouter::new(vec![connection_command_sender.clone()]),
+            Arc::new(ServerConfig::default()),
+            test_peer_address(),
+        );
+        (client_session, messages_receiver)
+    };
+    let mut opened_sessions = Vec::new();
+    for _ in 0..crate::connection_handler::MAXIMUM_SESSIONS_PER_USER {
+        let (mut client_session, messages_receiver) = new_session();
+        client_session
+            .handle_text_frame(r#"{"subject":"authenticate","login":"chris","password":"chris"}"#);
+        apply_commands(&mut application_scope, &connection_command_receiver);
+        client_session.run_ready_callbacks();
+        opened_sessions.push((client_session, messages_receiver));
+    }
+
+    let (mut client_session, mut messages_receiver) = new_session();
+    client_session.handle_text_frame(
+        r#"{"subject":"authenticate","request_id":"a1","login":"chris","password":"chris"}"#,
+    );
+    assert!(client_session.is_waiting_for_callback());
+    apply_commands(&mut application_scope, &connection_command_receiver);
+    let messages = drain_messages(&mut messages_receiver);
+    assert_eq!(messages.len(), 2);
+    match &messages[0] {
+        Message::Text(text) => {
+            let reply: serde_json::Value = serde_json::from_str(text).unwrap();
+            assert_eq!(reply["code"], "too-many-sessions");
+            assert_eq!(reply["request_id"], "a1");
+        }
+        other => panic!("an error response expected, got {:?}", other),
+    }
+    assert_eq!(messages[1], Message::Close(None));
+    let callback = client_session.callback_receiver.try_recv().unwrap();
+    assert_eq!(callback(&mut client_session), FrameHandlingResult::Close);
+    assert!(!client_session.is_waiting_for_callback());
+    assert!(client_session.current_username.is_empty());
+
+    // the refused connection does not remove a session of the user
+    client_session.unsubscribe();
+    assert_eq!(connection_command_receiver.try_iter().count(), 0);
+    for (client_session, _) in opened_sessions {
+        client_session.unsubscribe();
+    }
+    apply_commands(&mut application_scope, &connection_command_receiver);
+    assert!(application_scope.chat_users["chris"]
+        .opened_sessions_senders
+        .is_empty());
+}
+
+#[test]
+fn test_signed_in_connection_cannot_authenticate_again() {
+    use crate::user_context::ApplicationScope;
+
+    let (messages_sender, mut messages_receiver) = messages_channel(&ServerConfig::default());
+    let (connection_command_sender, connection_command_receiver) =
+        crossbeam_channel::unbounded::<ConnectionCommand>();
+    let mut application_scope = ApplicationScope::new();
+    let mut client_session = ClientSession::new(
+        messages_sender,
+        ConnectionCommandRouter::new(vec![connection_command_sender]),
+        Arc::new(ServerConfig::default()),
+        test_peer_address(),
+    );
+    let mut last_reply = |client_session: &mut ClientSession,
+                          application_scope: &mut ApplicationScope,
+                          frame: &str|
+     -> serde_json::Value {
+        client_session.handle_text_frame(frame);
+        apply_commands(application_scope, &connection_command_receiver);
+        client_session.run_ready_callbacks();
+        match drain_messages(&mut messages_receiver).into_iter().last() {
+            Some(Message::Text(text)) => serde_json::from_str(&text).unwrap(),
+            other => panic!("a text frame expected, got {:?}", other),
+        }
+    };
+
+    let reply = last_reply(
+        &mut client_session,
+        &mut application_scope,
+        r#"{"subject":"authenticate","login":"ian","password":"ian"}"#,
+    );
+    let resume_frame = format!(
+        r#"{{"subject":"resume","session_token":"{}"}}"#,
+        reply["session_token"].as_str().unwrap()
+    );
+    let reply = last_reply(
+        &mut client_session,
+        &mut application_scope,
+        r#"{"subject":"authenticate","login":"dan","password":"dan"}"#,
+    );
+    assert_eq!(reply["code"], "already-authenticated");
+    let reply = last_reply(&mut client_session, &mut application_scope, &resume_frame);
+    assert_eq!(reply["code"], "already-authenticated");
+    assert_eq!(client_session.current_username, "ian");
+    assert!(application_scope
+        .chat_users
+        .get("dan")
+        .is_none_or(|user| user.opened_sessions_senders.is_empty()));
+    assert_eq!(
+        application_scope.chat_users["ian"]
+            .opened_sessions_senders
+            .len(),
+        1
+    );
+
+    // the connection leaves no session of the user behind
+    client_session.unsubscribe();
+    apply_commands(&mut application_scope, &connection_command_receiver);
+    assert!(application_scope.chat_users["ian"]
+        .opened_sessions_senders
+        .is_empty());
+}
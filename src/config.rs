@@ -0,0 +1,404 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use std::env;
+use std::env::VarError;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+/// Settings of the server. Each setting can be overridden with an environment variable.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// How many messages ahead of the expected one the server keeps for one message sequence.
+    /// 0 means that out-of-order messages are rejected.
+    pub reorder_buffer_capacity: u32,
+    /// How long the server waits for a missing message of a sequence before it gives up.
+    pub reorder_timeout: Duration,
+    /// How many message sequences of one sender the server keeps for one conversation. A new
+    /// sequence beyond the limit makes the server forget the oldest one with its buffered messages.
+    pub maximum_message_sequences: u32,
+    /// How long a typing indicator stays on when the typist does not stop it and does not repeat it.
+    pub typing_timeout: Duration,
+    /// The minimum time between two typing-started notifications relayed for the same conversation.
+    pub typing_rate_limit_interval: Duration,
+    /// How long a user with opened sessions may do nothing before he is shown as away.
+    pub away_timeout: Duration,
+    /// Where the users and the private conversations are kept.
+    pub storage: StorageKind,
+    /// The database file of the SQLite storage.
+    pub sqlite_path: PathBuf,
+    /// The directory of the journal and the snapshot of the journal storage.
+    pub journal_dir: PathBuf,
+    /// After how many journal events the journal storage writes a snapshot and empties the journal.
+    pub journal_snapshot_interval: u32,
+    /// A file with users that are added to the storage on startup. None if there is no such file.
+    pub users_file: Option<PathBuf>,
+    /// Who can create an account with the register request.
+    pub registration_mode: RegistrationMode,
+    /// The codes that allow registration in the invite-code mode. Each code can be used many times.
+    pub invite_codes: Vec<String>,
+    /// The HMAC key that signs the session tokens. Unless it is configured, a random key is
+    /// generated on startup and the tokens do not survive restarts.
+    pub session_token_key: Vec<u8>,
+    /// How long a session token lets the client resume its session without the password.
+    pub session_token_lifetime: Duration,
+    /// How long the server refuses to check the password after the first failed authentication
+    /// attempt. The delay doubles with every further failure.
+    pub login_backoff_base: Duration,
+    /// After how many failed authentication attempts a username is locked out.
+    pub username_lockout_threshold: u32,
+    /// After how many failed authentication attempts an IP address is locked out. Many users may
+    /// share an address, so the threshold should be higher than the one of the usernames.
+    pub ip_lockout_threshold: u32,
+    /// How long a lockout lasts. The failed attempts are forgotten if there are no new ones for
+    /// this long.
+    pub login_lockout_duration: Duration,
+    /// After how many failed authentication attempts the server closes the WebSocket connection.
+    pub maximum_failed_logins_per_connection: u32,
+    /// How many messages may wait in the outbound queue of a session before the session is
+    /// treated as a slow consumer.
+    pub outbound_queue_capacity: usize,
+    /// What happens to a session whose outbound queue is full.
+    pub slow_consumer_policy: SlowConsumerPolicy,
+    /// How long the outbound queue of a session may stay full before the session is disconnected.
+    pub slow_consumer_grace_period: Duration,
+    /// Into how many shards the state is split. Every shard applies its commands on a thread of
+    /// its own. 0 is treated as 1.
+    pub shard_count: usize,
+    /// How long the server waits for the sessions to send their queued messages and close their
+    /// connections when it is shutting down.
+    pub shutdown_timeout: Duration,
+    /// How often the server pings the clients to find out whether their connections are alive.
+    pub heartbeat_interval: Duration,
+    /// After how many unanswered pings in a row the server closes the connection.
+    pub maximum_missed_pongs: u32,
+    /// How long a connection that has not authenticated may go without sending a request before
+    /// the server closes it.
+    pub unauthenticated_idle_timeout: Duration,
+}
+
+/// The shortest session token key that is accepted. A shorter key would make the tokens easier to
+/// forge.
+pub const MINIMUM_SESSION_TOKEN_KEY_LENGTH: usize = 32;
+
+/// A setting whose value cannot be used.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ConfigError {
+    /// the environment variable of the setting.
+    pub name: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid {}: {}", self.name, self.message)
+    }
+}
+
+/// What the server does when a client does not read its messages as fast as they come.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowConsumerPolicy {
+    /// The oldest ephemeral events, like typing notifications, make room for the new messages.
+    /// If there are no such events, the session is disconnected after the grace period.
+    DropOldestEphemeral,
+    /// Nothing is dropped. The session is disconnected after the grace period.
+    Disconnect,
+}
+
+impl FromStr for SlowConsumerPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-oldest-ephemeral" => Ok(SlowConsumerPolicy::DropOldestEphemeral),
+            "disconnect" => Ok(SlowConsumerPolicy::Disconnect),
+            _ => Err(format!("unknown slow consumer policy {}", s)),
+        }
+    }
+}
+
+/// Who can create an account with the register request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistrationMode {
+    /// Anybody.
+    Open,
+    /// Nobody. The users are added by the administrators.
+    Closed,
+    /// Only the clients that know one of the invite codes.
+    InviteCode,
+}
+
+impl FromStr for RegistrationMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(RegistrationMode::Open),
+            "closed" => Ok(RegistrationMode::Closed),
+            "invite-code" => Ok(RegistrationMode::InviteCode),
+            _ => Err(format!("unknown registration mode {}", s)),
+        }
+    }
+}
+
+/// The kinds of storage that the server can keep its data in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageKind {
+    /// Nothing survives a restart.
+    Memory,
+    /// The data is kept in an SQLite database file.
+    Sqlite,
+    /// The changes are appended to a journal file that is replayed on startup.
+    Journal,
+}
+
+impl FromStr for StorageKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(StorageKind::Memory),
+            "sqlite" => Ok(StorageKind::Sqlite),
+            "journal" => Ok(StorageKind::Journal),
+            _ => Err(format!("unknown storage {}", s)),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            reorder_buffer_capacity: 32,
+            reorder_timeout: Duration::from_secs(30),
+            maximum_message_sequences: 64,
+            typing_timeout: Duration::from_secs(5),
+            typing_rate_limit_interval: Duration::from_secs(1),
+            away_timeout: Duration::from_secs(5 * 60),
+            storage: StorageKind::Memory,
+            sqlite_path: PathBuf::from("puchat.sqlite3"),
+            journal_dir: PathBuf::from("puchat-journal"),
+            journal_snapshot_interval: 1000,
+            users_file: None,
+            registration_mode: RegistrationMode::Closed,
+            invite_codes: Vec::new(),
+            session_token_key: random_session_token_key(),
+            session_token_lifetime: Duration::from_secs(30 * 24 * 60 * 60),
+            login_backoff_base: Duration::from_secs(1),
+            username_lockout_threshold: 5,
+            ip_lockout_threshold: 20,
+            login_lockout_duration: Duration::from_secs(15 * 60),
+            maximum_failed_logins_per_connection: 3,
+            outbound_queue_capacity: 1024,
+            slow_consumer_policy: SlowConsumerPolicy::DropOldestEphemeral,
+            slow_consumer_grace_period: Duration::from_secs(10),
+            shard_count: thread::available_parallelism().map_or(1, |parallelism| parallelism.get()),
+            shutdown_timeout: Duration::from_secs(5),
+            heartbeat_interval: Duration::from_secs(30),
+            maximum_missed_pongs: 2,
+            unauthenticated_idle_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Reads the settings from the environment variables. Missing settings get default values.
+    /// A setting with a value that cannot be parsed is an error, so a typo does not go unnoticed.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let default = ServerConfig::default();
+        Ok(ServerConfig {
+            reorder_buffer_capacity: env_or(
+                "PUCHAT_REORDER_BUFFER_CAPACITY",
+                default.reorder_buffer_capacity,
+            )?,
+            reorder_timeout: Duration::from_millis(env_or(
+                "PUCHAT_REORDER_TIMEOUT_MS",
+                default.reorder_timeout.as_millis() as u64,
+            )?),
+            maximum_message_sequences: nonzero_env_or(
+                "PUCHAT_MAXIMUM_MESSAGE_SEQUENCES",
+                default.maximum_message_sequences,
+            )?,
+            typing_timeout: Duration::from_millis(env_or(
+                "PUCHAT_TYPING_TIMEOUT_MS",
+                default.typing_timeout.as_millis() as u64,
+            )?),
+            typing_rate_limit_interval: Duration::from_millis(env_or(
+                "PUCHAT_TYPING_RATE_LIMIT_INTERVAL_MS",
+                default.typing_rate_limit_interval.as_millis() as u64,
+            )?),
+            away_timeout: Duration::from_millis(env_or(
+                "PUCHAT_AWAY_TIMEOUT_MS",
+                default.away_timeout.as_millis() as u64,
+            )?),
+            storage: env_or("PUCHAT_STORAGE", default.storage)?,
+            sqlite_path: env_or("PUCHAT_SQLITE_PATH", default.sqlite_path)?,
+            journal_dir: env_or("PUCHAT_JOURNAL_DIR", default.journal_dir)?,
+            journal_snapshot_interval: env_or(
+                "PUCHAT_JOURNAL_SNAPSHOT_INTERVAL",
+                default.journal_snapshot_interval,
+            )?,
+            users_file: env::var_os("PUCHAT_USERS_FILE").map(PathBuf::from),
+            registration_mode: env_or("PUCHAT_REGISTRATION_MODE", default.registration_mode)?,
+            invite_codes: env::var("PUCHAT_INVITE_CODES")
+                .map(|invite_codes| {
+                    invite_codes
+                        .split(',')
+                        .map(|invite_code| invite_code.trim().to_string())
+                        .filter(|invite_code| !invite_code.is_empty())
+                        .collect()
+                })
+                .unwrap_or(default.invite_codes),
+            session_token_key: session_token_key_or(
+                "PUCHAT_SESSION_TOKEN_KEY",
+                default.session_token_key,
+            )?,
+            session_token_lifetime: Duration::from_millis(env_or(
+                "PUCHAT_SESSION_TOKEN_LIFETIME_MS",
+                default.session_token_lifetime.as_millis() as u64,
+            )?),
+            login_backoff_base: Duration::from_millis(env_or(
+                "PUCHAT_LOGIN_BACKOFF_BASE_MS",
+                default.login_backoff_base.as_millis() as u64,
+            )?),
+            username_lockout_threshold: env_or(
+                "PUCHAT_USERNAME_LOCKOUT_THRESHOLD",
+                default.username_lockout_threshold,
+            )?,
+            ip_lockout_threshold: env_or(
+                "PUCHAT_IP_LOCKOUT_THRESHOLD",
+                default.ip_lockout_threshold,
+            )?,
+            login_lockout_duration: Duration::from_millis(env_or(
+                "PUCHAT_LOGIN_LOCKOUT_DURATION_MS",
+                default.login_lockout_duration.as_millis() as u64,
+            )?),
+            maximum_failed_logins_per_connection: env_or(
+                "PUCHAT_MAXIMUM_FAILED_LOGINS_PER_CONNECTION",
+                default.maximum_failed_logins_per_connection,
+            )?,
+            outbound_queue_capacity: env_or(
+                "PUCHAT_OUTBOUND_QUEUE_CAPACITY",
+                default.outbound_queue_capacity,
+            )?,
+            slow_consumer_policy: env_or(
+                "PUCHAT_SLOW_CONSUMER_POLICY",
+                default.slow_consumer_policy,
+            )?,
+            slow_consumer_grace_period: Duration::from_millis(env_or(
+                "PUCHAT_SLOW_CONSUMER_GRACE_PERIOD_MS",
+                default.slow_consumer_grace_period.as_millis() as u64,
+            )?),
+            shard_count: env_or("PUCHAT_SHARD_COUNT", default.shard_count)?,
+            shutdown_timeout: Duration::from_millis(env_or(
+                "PUCHAT_SHUTDOWN_TIMEOUT_MS",
+                default.shutdown_timeout.as_millis() as u64,
+            )?),
+            heartbeat_interval: Duration::from_millis(nonzero_env_or(
+                "PUCHAT_HEARTBEAT_INTERVAL_MS",
+                default.heartbeat_interval.as_millis() as u64,
+            )?),
+            maximum_missed_pongs: nonzero_env_or(
+                "PUCHAT_MAXIMUM_MISSED_PONGS",
+                default.maximum_missed_pongs,
+            )?,
+            unauthenticated_idle_timeout: Duration::from_millis(nonzero_env_or(
+                "PUCHAT_UNAUTHENTICATED_IDLE_TIMEOUT_MS",
+                default.unauthenticated_idle_timeout.as_millis() as u64,
+            )?),
+        })
+    }
+}
+
+/// Returns the parsed value of the environment variable or the default value if the variable is
+/// not set.
+fn env_or<T: FromStr>(name: &str, default: T) -> Result<T, ConfigError>
+where
+    T::Err: fmt::Display,
+{
+    match env::var(name) {
+        Ok(value) => value.parse().map_err(|e| ConfigError {
+            name: name.to_string(),
+            message: format!("{:?}: {}", value, e),
+        }),
+        Err(VarError::NotPresent) => Ok(default),
+        Err(VarError::NotUnicode(_)) => Err(ConfigError {
+            name: name.to_string(),
+            message: "the value is not valid Unicode".to_string(),
+        }),
+    }
+}
+
+/// Returns the session token key of the environment variable or the default key if the variable
+/// is not set. A key that is too short is an error.
+fn session_token_key_or(name: &str, default: Vec<u8>) -> Result<Vec<u8>, ConfigError> {
+    let key = match env::var_os(name) {
+        Some(_) => env_or(name, String::new())?,
+        None => return Ok(default),
+    };
+    if key.len() < MINIMUM_SESSION_TOKEN_KEY_LENGTH {
+        return Err(ConfigError {
+            name: name.to_string(),
+            message: format!(
+                "the key must be at least {} bytes long",
+                MINIMUM_SESSION_TOKEN_KEY_LENGTH
+            ),
+        });
+    }
+    Ok(key.into_bytes())
+}
+
+/// Like env_or, but 0 is an error too. It is for the intervals with which the connections would
+/// ping or close in a busy loop.
+fn nonzero_env_or<T: FromStr + Default + PartialEq>(
+    name: &str,
+    default: T,
+) -> Result<T, ConfigError>
+where
+    T::Err: fmt::Display,
+{
+    let value = env_or(name, default)?;
+    if value == T::default() {
+        return Err(ConfigError {
+            name: name.to_string(),
+            message: "the value must not be 0".to_string(),
+        });
+    }
+    Ok(value)
+}
+
+fn random_session_token_key() -> Vec<u8> {
+    let mut key = vec![0; 32];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+#[test]
+fn test_invalid_settings_are_errors() {
+    // the variables are not read by any other test
+    env::remove_var("PUCHAT_TEST_MISSING");
+    assert_eq!(env_or("PUCHAT_TEST_MISSING", 7u32), Ok(7));
+    env::set_var("PUCHAT_TEST_NUMBER", "12");
+    assert_eq!(env_or("PUCHAT_TEST_NUMBER", 7u32), Ok(12));
+    env::set_var("PUCHAT_TEST_STORAGE", "sqlit");
+    assert_eq!(
+        env_or("PUCHAT_TEST_STORAGE", StorageKind::Memory),
+        Err(ConfigError {
+            name: "PUCHAT_TEST_STORAGE".to_string(),
+            message: "\"sqlit\": unknown storage sqlit".to_string(),
+        })
+    );
+    env::set_var("PUCHAT_TEST_INTERVAL_MS", "0");
+    assert!(nonzero_env_or("PUCHAT_TEST_INTERVAL_MS", 1000u64).is_err());
+    // an empty key would let anybody forge the session tokens
+    env::set_var("PUCHAT_TEST_KEY", "");
+    assert!(session_token_key_or("PUCHAT_TEST_KEY", vec![1; 32]).is_err());
+    env::set_var(
+        "PUCHAT_TEST_KEY",
+        "k".repeat(MINIMUM_SESSION_TOKEN_KEY_LENGTH),
+    );
+    assert_eq!(
+        session_token_key_or("PUCHAT_TEST_KEY", vec![1; 32]),
+        Ok(vec![b'k'; MINIMUM_SESSION_TOKEN_KEY_LENGTH])
+    );
+}
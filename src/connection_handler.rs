@@ -1,22 +1,85 @@
+use crate::channel::ChannelError;
+use crate::client_session::SessionAcceptance;
+use crate::config::ServerConfig;
 use crate::dto;
 use crate::dto::{
-    attach_subject_and_serialize, MessageToSomeone
-    , NEW_PRIVATE_MESSAGE_SEQUENCE_SUBJECT,
+    attach_reply_subject_and_serialize, attach_subject_and_serialize, prepare_error_response,
+    ChannelInfo, ChannelListResponse, ChannelMessage, ChannelMessageAccepted, ErrorCode,
+    GetPresenceResponse, GroupHistoryResponse, GroupInfo, GroupMessage, GroupMessageAccepted,
+    GroupMessageSequenceGapExpired, GroupReadReceipt, GroupRole, JoinChannelResponse,
+    LogoutResponse, MessageAccepted, MessageDeleted, MessageEdited, MessageRevisionsResponse,
+    MessageSequenceGapExpired, MessageToSomeone, NewGroupMessageSequenceResponse, Presence,
+    ReadReceipt, RegistrationResponse, ServerShuttingDown, TypingNotification,
+    UnreadCountsResponse, UserInfo, ADD_USER_SUBJECT, AUTHENTICATE_SUBJECT,
+    CHANNEL_MESSAGE_ACCEPTED_SUBJECT, CHANNEL_MESSAGE_SUBJECT, CREATE_CHANNEL_SUBJECT,
+    CREATE_GROUP_SUBJECT, DELETE_MESSAGE_SUBJECT, EDIT_MESSAGE_SUBJECT, GET_CHANNEL_INFO_SUBJECT,
+    GET_GROUP_HISTORY_SUBJECT, GET_MESSAGE_REVISIONS_SUBJECT, GET_PRESENCE_SUBJECT,
+    GET_PRIVATE_HISTORY_SUBJECT, GROUP_MESSAGE_ACCEPTED_SUBJECT,
+    GROUP_MESSAGE_SEQUENCE_GAP_EXPIRED_SUBJECT, GROUP_MESSAGE_SUBJECT, GROUP_READ_RECEIPT_SUBJECT,
+    GROUP_UPDATED_SUBJECT, INVITE_TO_GROUP_SUBJECT, JOIN_CHANNEL_SUBJECT, LEAVE_CHANNEL_SUBJECT,
+    LEAVE_GROUP_SUBJECT, LIST_CHANNELS_SUBJECT, LOGOUT_SUBJECT, MARK_GROUP_READ_SUBJECT,
+    MARK_READ_SUBJECT, MESSAGE_ACCEPTED_SUBJECT, MESSAGE_DELETED_SUBJECT, MESSAGE_EDITED_SUBJECT,
+    MESSAGE_SEQUENCE_GAP_EXPIRED_SUBJECT, NEW_CHANNEL_MESSAGE_SUBJECT,
+    NEW_GROUP_MESSAGE_SEQUENCE_SUBJECT, NEW_GROUP_MESSAGE_SUBJECT, NEW_MESSAGE_SUBJECT,
+    NEW_PRIVATE_MESSAGE_SEQUENCE_SUBJECT, PRESENCE_SUBJECT, READ_RECEIPT_SUBJECT, REGISTER_SUBJECT,
+    REMOVE_FROM_GROUP_SUBJECT, RENAME_GROUP_SUBJECT, SERVER_SHUTTING_DOWN_SUBJECT,
+    SET_GROUP_ROLE_SUBJECT, SET_USER_DISABLED_SUBJECT, TYPING_STARTED_SUBJECT,
+    TYPING_STOPPED_SUBJECT, UNREAD_COUNTS_SUBJECT,
 };
-use crate::user_context::{AddSessionResult, ApplicationScope, PrivateMessageServerMetadata};
+use crate::group_conversation::GroupError;
+use crate::outbound_queue::MessagesSender;
+#[cfg(test)]
+use crate::outbound_queue::{drain_messages, messages_channel, MessagesReceiver};
+use crate::private_conversation_partners::PrivateConversationPartnersHashmapKey;
+use crate::shard::{PartialReply, ShardKey};
+use crate::storage::{StoredRevokedSessionToken, StoredUser};
+use crate::user_context::{
+    AddSessionResult, ApplicationScope, MessageModificationError, MessageSequenceError,
+    PrivateMessageServerMetadata, SequenceMessage, UndeliveredMessagesPage,
+};
+use crate::user_service;
+use crate::user_service::UserRegistryError;
+use crate::util::to_chrono_duration;
+use chrono::Utc;
+use crossbeam_channel::RecvTimeoutError;
+use log::{debug, log_enabled, Level};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
 use tungstenite::Message;
 
 /// Define the maximum allowed number of WebSocket connections per user.
 pub const MAXIMUM_SESSIONS_PER_USER: i32 = 2;
 
+/// The maximum number of messages that one history request can return.
+pub const MAXIMUM_HISTORY_PAGE_SIZE: usize = 100;
+
+/// How often the server looks for message sequences that wait too long for a missing message and
+/// for typing indicators that have not been repeated in time.
+const EXPIRATION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 pub enum ConnectionCommand {
     AssignConnectionToUser {
         username: String,
-        messages_sender: crossbeam_channel::Sender<Message>,
+        messages_sender: MessagesSender,
+        /// answers the authentication request. None if nobody waits for the answer.
+        acceptance: Option<Box<SessionAcceptance>>,
     },
     UnassignConnectionFromUser {
         username: String,
-        messages_sender: crossbeam_channel::Sender<Message>,
+        messages_sender: MessagesSender,
+    },
+    /// Unassigns the connection like UnassignConnectionFromUser but the client keeps the
+    /// connection and receives a reply.
+    Logout {
+        username: String,
+        /// the session token that the connection authenticated with. It has already been revoked
+        /// and only needs to be saved.
+        revoked_session_token: Option<StoredRevokedSessionToken>,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
     },
     SendMessageToAnotherUser {
         sender_username: String,
@@ -25,106 +88,3388 @@ pub enum ConnectionCommand {
         content: String,
         message_sequence_id: u32,
         message_sequence_index: u16,
+        /// the session of the sender. Errors are sent there.
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
     },
     InitiateNewPrivateMessageSequence {
         sender_username: String,
         receiver_username: String,
-        messages_sender: crossbeam_channel::Sender<Message>,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
+    },
+    GetPrivateHistory {
+        reader_username: String,
+        partner_username: String,
+        before_id: Option<u32>,
+        limit: u16,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
+    },
+    EditPrivateMessage {
+        author_username: String,
+        partner_username: String,
+        id: u32,
+        content: String,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
+    },
+    DeletePrivateMessage {
+        author_username: String,
+        partner_username: String,
+        id: u32,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
+    },
+    GetMessageRevisions {
+        reader_username: String,
+        partner_username: String,
+        id: u32,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
+    },
+    MarkPrivateMessagesRead {
+        reader_username: String,
+        partner_username: String,
+        id: u32,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
+    },
+    GetUnreadCounts {
+        username: String,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
+    },
+    /// The typing indicators are relayed to the partner and never stored.
+    UpdateTypingStatus {
+        typist_username: String,
+        partner_username: String,
+        is_typing: bool,
+    },
+    GetPresence {
+        username: String,
+        usernames: Vec<String>,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
+    },
+    CreateGroup {
+        owner_username: String,
+        name: String,
+        member_usernames: Vec<String>,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
+    },
+    InviteToGroup {
+        inviter_username: String,
+        group_id: u32,
+        username: String,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
+    },
+    RemoveFromGroup {
+        remover_username: String,
+        group_id: u32,
+        username: String,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
+    },
+    LeaveGroup {
+        username: String,
+        group_id: u32,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
+    },
+    RenameGroup {
+        renamer_username: String,
+        group_id: u32,
+        name: String,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
+    },
+    SetGroupRole {
+        setter_username: String,
+        group_id: u32,
+        username: String,
+        role: GroupRole,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
+    },
+    InitiateNewGroupMessageSequence {
+        sender_username: String,
+        group_id: u32,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
+    },
+    SendMessageToGroup {
+        sender_username: String,
+        group_id: u32,
+        content: String,
+        message_sequence_id: u32,
+        message_sequence_index: u16,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
+    },
+    GetGroupHistory {
+        reader_username: String,
+        group_id: u32,
+        before_id: Option<u32>,
+        limit: u16,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
+    },
+    MarkGroupMessagesRead {
+        reader_username: String,
+        group_id: u32,
+        id: u32,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
+    },
+    CreateChannel {
+        creator_username: String,
+        name: String,
+        topic: String,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
+    },
+    ListChannels {
+        username: String,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
+    },
+    GetChannelInfo {
+        username: String,
+        name: String,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
+    },
+    JoinChannel {
+        username: String,
+        name: String,
+        /// how many recent messages the user wants to receive.
+        history_limit: u16,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
+    },
+    LeaveChannel {
+        username: String,
+        name: String,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
+    },
+    SendMessageToChannel {
+        sender_username: String,
+        channel_name: String,
+        content: String,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
+    },
+    RegisterUser {
+        /// the user with the already hashed password.
+        user: StoredUser,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
+    },
+    AddUser {
+        admin_username: String,
+        /// the user with the already hashed password.
+        user: StoredUser,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
+    },
+    SetUserDisabled {
+        admin_username: String,
+        username: String,
+        is_disabled: bool,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
+    },
+    // The commands below are sent by the shards to each other.
+    /// Sends a message to the opened sessions of the users. All of them belong to the shard that
+    /// receives the command.
+    DeliverToUsers {
+        usernames: Vec<String>,
+        text: String,
+        /// the session that must not receive the message, e.g. the one that has caused it.
+        except_session: Option<MessagesSender>,
+        /// ephemeral events may be dropped for the slow sessions.
+        is_ephemeral: bool,
+    },
+    /// Sends a private message that another shard has stored to the opened sessions of the
+    /// receiver, or keeps it until he opens one.
+    DeliverPrivateMessage {
+        sender_username: String,
+        receiver_username: String,
+        id: u32,
+        message_obj: String,
+    },
+    /// Sends a session of the receiver the messages that have waited for it in the conversation
+    /// that the receiving shard owns. Then the shard of the receiver sends the next ones.
+    DeliverUndeliveredMessages {
+        receiver_username: String,
+        sender_username: String,
+        ids: Vec<u32>,
+        messages_sender: MessagesSender,
+    },
+    /// Sends the session the next page of the messages that have waited for its user, once the
+    /// client has read the previous page or another shard has sent it delivered_count of them.
+    FlushUndeliveredMessages {
+        username: String,
+        messages_sender: MessagesSender,
+        delivered_count: usize,
+    },
+    /// Tells the shard of the user that he has done something in a conversation, group or channel
+    /// that another shard owns.
+    RecordActivity { username: String },
+    /// Tells the partners of the conversations that the shard owns that the presence of a user has
+    /// changed.
+    NotifyPresenceChange {
+        shard_index: usize,
+        presence: Presence,
+    },
+    /// Adds the unread counts of the conversations and groups that the shard owns to the reply to
+    /// GetUnreadCounts.
+    CountUnreadMessages {
+        shard_index: usize,
+        username: String,
+        reply: Arc<PartialReply<UnreadCountsResponse>>,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
     },
+    /// Adds the channels that the shard owns to the reply to ListChannels.
+    ListShardChannels {
+        shard_index: usize,
+        reply: Arc<PartialReply<Vec<ChannelInfo>>>,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
+    },
+    /// Tells all the opened sessions of the shard that the server is shutting down and closes
+    /// them. The shard stops handling the commands after it.
+    Shutdown { shard_index: usize },
+    /// Adds the presences of the users that belong to the shard to the reply to GetPresence.
+    GetShardPresences {
+        /// the users by their positions in the request.
+        usernames: Vec<(usize, String)>,
+        reply: Arc<PartialReply<Vec<(usize, Presence)>>>,
+        messages_sender: MessagesSender,
+        request_id: Option<String>,
+    },
+}
+
+impl ConnectionCommand {
+    /// Returns the user who has issued the command or whose session it concerns. None for the
+    /// commands that the shards send to each other.
+    fn username(&self) -> Option<&String> {
+        let username = match self {
+            ConnectionCommand::AssignConnectionToUser { username, .. }
+            | ConnectionCommand::UnassignConnectionFromUser { username, .. }
+            | ConnectionCommand::Logout { username, .. }
+            | ConnectionCommand::GetUnreadCounts { username, .. }
+            | ConnectionCommand::GetPresence { username, .. }
+            | ConnectionCommand::LeaveGroup { username, .. }
+            | ConnectionCommand::ListChannels { username, .. }
+            | ConnectionCommand::GetChannelInfo { username, .. }
+            | ConnectionCommand::JoinChannel { username, .. }
+            | ConnectionCommand::LeaveChannel { username, .. } => username,
+            ConnectionCommand::CreateChannel {
+                creator_username, ..
+            } => creator_username,
+            ConnectionCommand::CreateGroup { owner_username, .. } => owner_username,
+            ConnectionCommand::InviteToGroup {
+                inviter_username, ..
+            } => inviter_username,
+            ConnectionCommand::RemoveFromGroup {
+                remover_username, ..
+            } => remover_username,
+            ConnectionCommand::RenameGroup {
+                renamer_username, ..
+            } => renamer_username,
+            ConnectionCommand::SetGroupRole {
+                setter_username, ..
+            } => setter_username,
+            ConnectionCommand::InitiateNewGroupMessageSequence {
+                sender_username, ..
+            }
+            | ConnectionCommand::SendMessageToGroup {
+                sender_username, ..
+            }
+            | ConnectionCommand::SendMessageToChannel {
+                sender_username, ..
+            } => sender_username,
+            ConnectionCommand::GetGroupHistory {
+                reader_username, ..
+            }
+            | ConnectionCommand::MarkGroupMessagesRead {
+                reader_username, ..
+            } => reader_username,
+            ConnectionCommand::SendMessageToAnotherUser {
+                sender_username, ..
+            }
+            | ConnectionCommand::InitiateNewPrivateMessageSequence {
+                sender_username, ..
+            } => sender_username,
+            ConnectionCommand::GetPrivateHistory {
+                reader_username, ..
+            }
+            | ConnectionCommand::GetMessageRevisions {
+                reader_username, ..
+            }
+            | ConnectionCommand::MarkPrivateMessagesRead {
+                reader_username, ..
+            } => reader_username,
+            ConnectionCommand::EditPrivateMessage {
+                author_username, ..
+            }
+            | ConnectionCommand::DeletePrivateMessage {
+                author_username, ..
+            } => author_username,
+            ConnectionCommand::UpdateTypingStatus {
+                typist_username, ..
+            } => typist_username,
+            ConnectionCommand::RegisterUser { user, .. } => &user.username,
+            ConnectionCommand::AddUser { admin_username, .. }
+            | ConnectionCommand::SetUserDisabled { admin_username, .. } => admin_username,
+            ConnectionCommand::DeliverToUsers { .. }
+            | ConnectionCommand::DeliverPrivateMessage { .. }
+            | ConnectionCommand::DeliverUndeliveredMessages { .. }
+            | ConnectionCommand::FlushUndeliveredMessages { .. }
+            | ConnectionCommand::RecordActivity { .. }
+            | ConnectionCommand::NotifyPresenceChange { .. }
+            | ConnectionCommand::CountUnreadMessages { .. }
+            | ConnectionCommand::ListShardChannels { .. }
+            | ConnectionCommand::GetShardPresences { .. }
+            | ConnectionCommand::Shutdown { .. } => return None,
+        };
+        Some(username)
+    }
+
+    /// Returns what decides which shard applies the command.
+    pub fn shard_key(&self) -> ShardKey<'_> {
+        match self {
+            ConnectionCommand::AssignConnectionToUser { username, .. }
+            | ConnectionCommand::UnassignConnectionFromUser { username, .. }
+            | ConnectionCommand::Logout { username, .. }
+            | ConnectionCommand::GetUnreadCounts { username, .. }
+            | ConnectionCommand::GetPresence { username, .. }
+            | ConnectionCommand::ListChannels { username, .. }
+            | ConnectionCommand::SetUserDisabled { username, .. }
+            | ConnectionCommand::RecordActivity { username }
+            | ConnectionCommand::FlushUndeliveredMessages { username, .. }
+            | ConnectionCommand::DeliverPrivateMessage {
+                receiver_username: username,
+                ..
+            } => ShardKey::User(username),
+            ConnectionCommand::RegisterUser { user, .. }
+            | ConnectionCommand::AddUser { user, .. } => ShardKey::User(&user.username),
+            // the new group belongs to the shard of its owner
+            ConnectionCommand::CreateGroup { owner_username, .. } => ShardKey::User(owner_username),
+            // all the users have the same shard
+            ConnectionCommand::DeliverToUsers { usernames, .. } => {
+                ShardKey::User(usernames.first().map_or("", String::as_str))
+            }
+            ConnectionCommand::GetShardPresences { usernames, .. } => ShardKey::User(
+                usernames
+                    .first()
+                    .map_or("", |(_, username)| username.as_str()),
+            ),
+            ConnectionCommand::SendMessageToAnotherUser {
+                sender_username: user1,
+                receiver_username: user2,
+                ..
+            }
+            | ConnectionCommand::InitiateNewPrivateMessageSequence {
+                sender_username: user1,
+                receiver_username: user2,
+                ..
+            }
+            | ConnectionCommand::GetPrivateHistory {
+                reader_username: user1,
+                partner_username: user2,
+                ..
+            }
+            | ConnectionCommand::EditPrivateMessage {
+                author_username: user1,
+                partner_username: user2,
+                ..
+            }
+            | ConnectionCommand::DeletePrivateMessage {
+                author_username: user1,
+                partner_username: user2,
+                ..
+            }
+            | ConnectionCommand::GetMessageRevisions {
+                reader_username: user1,
+                partner_username: user2,
+                ..
+            }
+            | ConnectionCommand::MarkPrivateMessagesRead {
+                reader_username: user1,
+                partner_username: user2,
+                ..
+            }
+            | ConnectionCommand::UpdateTypingStatus {
+                typist_username: user1,
+                partner_username: user2,
+                ..
+            }
+            | ConnectionCommand::DeliverUndeliveredMessages {
+                receiver_username: user1,
+                sender_username: user2,
+                ..
+            } => ShardKey::Conversation(PrivateConversationPartnersHashmapKey::new(user1, user2)),
+            ConnectionCommand::InviteToGroup { group_id, .. }
+            | ConnectionCommand::RemoveFromGroup { group_id, .. }
+            | ConnectionCommand::LeaveGroup { group_id, .. }
+            | ConnectionCommand::RenameGroup { group_id, .. }
+            | ConnectionCommand::SetGroupRole { group_id, .. }
+            | ConnectionCommand::InitiateNewGroupMessageSequence { group_id, .. }
+            | ConnectionCommand::SendMessageToGroup { group_id, .. }
+            | ConnectionCommand::GetGroupHistory { group_id, .. }
+            | ConnectionCommand::MarkGroupMessagesRead { group_id, .. } => {
+                ShardKey::Group(*group_id)
+            }
+            ConnectionCommand::CreateChannel { name, .. }
+            | ConnectionCommand::GetChannelInfo { name, .. }
+            | ConnectionCommand::JoinChannel { name, .. }
+            | ConnectionCommand::LeaveChannel { name, .. }
+            | ConnectionCommand::SendMessageToChannel {
+                channel_name: name, ..
+            } => ShardKey::Channel(name),
+            ConnectionCommand::NotifyPresenceChange { shard_index, .. }
+            | ConnectionCommand::CountUnreadMessages { shard_index, .. }
+            | ConnectionCommand::ListShardChannels { shard_index, .. }
+            | ConnectionCommand::Shutdown { shard_index } => ShardKey::Index(*shard_index),
+        }
+    }
 }
 
-/// Receives events from the connections and does something.
-pub async fn handle_connection_commands(
+/// Receives events from the connections and does something. It should run on a thread of its own:
+/// it waits for the commands and writes to the storage synchronously, which would block a worker
+/// thread of the async runtime. The sessions send the commands without blocking. Every shard of
+/// ApplicationScope has a loop of its own.
+pub fn handle_connection_commands(
     connection_command_receiver: crossbeam_channel::Receiver<ConnectionCommand>,
+    mut application_scope: ApplicationScope,
+    config: ServerConfig,
 ) {
-    let mut application_scope: ApplicationScope = ApplicationScope::new();
+    let mut last_expiration_check = Instant::now();
 
     // a lot should be added here
-    for received in connection_command_receiver {
-        match received {
-            ConnectionCommand::AssignConnectionToUser {
-                username,
-                messages_sender,
-            } => {
-                println!("AssignConnectionToUser, username={}", &username);
-                match application_scope.add_session_sender_if_not_exceeded(
-                    &username,
-                    messages_sender,
-                    MAXIMUM_SESSIONS_PER_USER,
-                ) {
-                    AddSessionResult::Success => {}
-                    AddSessionResult::TooManySessions { messages_sender } => {
+    loop {
+        match connection_command_receiver.recv_timeout(EXPIRATION_CHECK_INTERVAL) {
+            Ok(received) => {
+                let is_shutdown = matches!(received, ConnectionCommand::Shutdown { .. });
+                process_connection_command(&mut application_scope, &config, received);
+                if is_shutdown {
+                    break;
+                }
+                // printing to the shared stdout on every command would make the shards wait for
+                // each other
+                if log_enabled!(Level::Debug) {
+                    for (key, value) in &application_scope.chat_users {
+                        debug!(
+                            "User {} has {} opened connections.",
+                            key,
+                            value.opened_sessions_senders.len()
+                        );
+                    }
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if last_expiration_check.elapsed() >= EXPIRATION_CHECK_INTERVAL {
+            expire_reorder_buffers(&mut application_scope, &config);
+            expire_typing_statuses(&mut application_scope, &config);
+            refresh_presences(&mut application_scope, &config);
+            last_expiration_check = Instant::now();
+        }
+    }
+}
+
+/// Gives up waiting for the missing messages of the sequences that have waited longer than the
+/// timeout and tells the senders which messages are missing.
+pub fn expire_reorder_buffers(application_scope: &mut ApplicationScope, config: &ServerConfig) {
+    let deadline = Utc::now() - to_chrono_duration(config.reorder_timeout);
+    for expired in application_scope.expire_reorder_buffers(deadline) {
+        debug!(
+            "the sequence {} of {} has expired, missing indices: {:?}",
+            expired.message_sequence_id, expired.sender, expired.missing_indices
+        );
+        let notification = attach_subject_and_serialize(
+            Box::new(MessageSequenceGapExpired {
+                receiver_username: expired.receiver,
+                message_sequence_id: expired.message_sequence_id,
+                missing_indices: expired.missing_indices,
+                discarded_indices: expired.discarded_indices,
+            }),
+            MESSAGE_SEQUENCE_GAP_EXPIRED_SUBJECT.to_string(),
+        );
+        send_to_users(
+            application_scope,
+            [&expired.sender],
+            &notification,
+            None,
+            false,
+        );
+    }
+    for expired in application_scope.expire_group_reorder_buffers(deadline) {
+        let notification = attach_subject_and_serialize(
+            Box::new(GroupMessageSequenceGapExpired {
+                group_id: expired.group_id,
+                message_sequence_id: expired.message_sequence_id,
+                missing_indices: expired.missing_indices,
+                discarded_indices: expired.discarded_indices,
+            }),
+            GROUP_MESSAGE_SEQUENCE_GAP_EXPIRED_SUBJECT.to_string(),
+        );
+        send_to_users(
+            application_scope,
+            [&expired.sender],
+            &notification,
+            None,
+            false,
+        );
+    }
+}
+
+/// Turns off the typing indicators that have not been repeated in time and tells the partners.
+pub fn expire_typing_statuses(application_scope: &mut ApplicationScope, config: &ServerConfig) {
+    for (typist, partner) in application_scope.expire_typing_statuses(
+        Utc::now(),
+        to_chrono_duration(config.typing_rate_limit_interval),
+    ) {
+        relay_typing_status(application_scope, typist, &partner, TYPING_STOPPED_SUBJECT);
+    }
+}
+
+/// Turns the users who have not done anything for a while into away and the users without
+/// sessions into offline, and tells their conversation partners.
+pub fn refresh_presences(application_scope: &mut ApplicationScope, config: &ServerConfig) {
+    for presence in
+        application_scope.refresh_presences(Utc::now(), to_chrono_duration(config.away_timeout))
+    {
+        notify_presence_change(application_scope, presence);
+    }
+}
+
+/// Applies one command to the state of the application.
+pub fn process_connection_command(
+    application_scope: &mut ApplicationScope,
+    config: &ServerConfig,
+    received: ConnectionCommand,
+) {
+    let Some(username) = received.username().cloned() else {
+        // the shards send these commands to each other on behalf of nobody in particular
+        apply_connection_command(application_scope, config, received);
+        return;
+    };
+    let is_activity = !matches!(
+        received,
+        ConnectionCommand::UnassignConnectionFromUser { .. } | ConnectionCommand::Logout { .. }
+    );
+    apply_connection_command(application_scope, config, received);
+    if !application_scope.shard.owns(&ShardKey::User(&username)) {
+        // the presence of the user is kept by the shard that owns his sessions
+        if is_activity {
+            application_scope
+                .shard
+                .forward(ConnectionCommand::RecordActivity { username });
+        }
+        return;
+    }
+    refresh_presence(application_scope, config, &username, is_activity);
+}
+
+/// Brings the presence of a user of this shard up to date and tells his partners if it has
+/// changed.
+fn refresh_presence(
+    application_scope: &mut ApplicationScope,
+    config: &ServerConfig,
+    username: &String,
+    is_activity: bool,
+) {
+    let now = Utc::now();
+    if is_activity {
+        application_scope.record_activity(username, now);
+    }
+    if let Some(presence) =
+        application_scope.refresh_presence(username, now, to_chrono_duration(config.away_timeout))
+    {
+        notify_presence_change(application_scope, presence);
+    }
+}
+
+fn apply_connection_command(
+    application_scope: &mut ApplicationScope,
+    config: &ServerConfig,
+    received: ConnectionCommand,
+) {
+    match received {
+        ConnectionCommand::AssignConnectionToUser {
+            username,
+            messages_sender,
+            acceptance,
+        } => {
+            debug!("AssignConnectionToUser, username={}", &username);
+            match application_scope.add_session_sender_if_not_exceeded(
+                &username,
+                messages_sender.clone(),
+                MAXIMUM_SESSIONS_PER_USER,
+            ) {
+                AddSessionResult::Success => {
+                    // the client gets its session token before the messages of the user
+                    if let Some(acceptance) = acceptance {
+                        acceptance.accept(&messages_sender);
+                    }
+                    // the messages that were sent while the user was offline
+                    flush_undelivered_messages(application_scope, username, messages_sender);
+                }
+                AddSessionResult::TooManySessions { messages_sender } => {
+                    let (request_subject, request_id) =
+                        acceptance
+                            .as_ref()
+                            .map_or((AUTHENTICATE_SUBJECT, None), |acceptance| {
+                                (
+                                    acceptance.subject.subject.as_str(),
+                                    acceptance.subject.request_id.clone(),
+                                )
+                            });
+                    let _ = messages_sender.send(Message::Text(prepare_error_response(
+                        ErrorCode::TooManySessions,
+                        "Exceeded the limit of WebSocket connections",
+                        request_subject,
+                        request_id,
+                    )));
+                    let _ = messages_sender.send(Message::Close(None));
+                    if let Some(acceptance) = acceptance {
+                        acceptance.refuse();
+                    }
+                }
+            }
+        }
+        ConnectionCommand::UnassignConnectionFromUser {
+            username,
+            messages_sender,
+        } => {
+            debug!("UnassignConnectionFromUser, username={}", username);
+            application_scope.remove_session_sender(&username, &messages_sender);
+        }
+        ConnectionCommand::Logout {
+            username,
+            revoked_session_token,
+            messages_sender,
+            request_id,
+        } => {
+            if let Some(revoked_session_token) = revoked_session_token {
+                application_scope.save_revoked_session_token(&revoked_session_token);
+            }
+            application_scope.remove_session_sender(&username, &messages_sender);
+            let _ = messages_sender.send(Message::Text(attach_reply_subject_and_serialize(
+                Box::new(LogoutResponse { login: username }),
+                LOGOUT_SUBJECT.to_string(),
+                request_id,
+            )));
+        }
+        ConnectionCommand::SendMessageToAnotherUser {
+            sender_username,
+            receiver_username,
+            content,
+            message_sequence_id,
+            message_sequence_index,
+            messages_sender,
+            request_id,
+        } => {
+            // messages are delivered only if all the previous messages of the sequence were accepted
+            match application_scope.approach_message_sequence(
+                sender_username.clone(),
+                receiver_username.clone(),
+                message_sequence_id,
+                SequenceMessage {
+                    message_sequence_index: message_sequence_index.into(),
+                    content,
+                    request_id: request_id.clone(),
+                },
+                config.reorder_buffer_capacity,
+            ) {
+                Ok(accepted_messages) => {
+                    for accepted_message in accepted_messages {
+                        let private_message_server_metadata = deliver_private_message(
+                            application_scope,
+                            sender_username.clone(),
+                            receiver_username.clone(),
+                            accepted_message.content,
+                        );
+                        // the sender learns the id that the server has assigned to the message
                         let _ = messages_sender.send(Message::Text(
-                            "Exceeded the limit of WebSocket connections".to_string(),
+                            attach_reply_subject_and_serialize(
+                                Box::new(MessageAccepted {
+                                    id: private_message_server_metadata.id,
+                                    receiver_username: receiver_username.clone(),
+                                    datetime: private_message_server_metadata
+                                        .server_time
+                                        .to_string(),
+                                    message_sequence_id,
+                                    message_sequence_index: accepted_message.message_sequence_index
+                                        as u16,
+                                }),
+                                MESSAGE_ACCEPTED_SUBJECT.to_string(),
+                                accepted_message.request_id,
+                            ),
                         ));
-                        let _ = messages_sender.send(Message::Close(None));
                     }
                 }
+                Err(e) => {
+                    let error_code = match e {
+                        MessageSequenceError::ReceiverNotFound => ErrorCode::UserNotFound,
+                        MessageSequenceError::UnexpectedIndex { .. } => {
+                            ErrorCode::OutOfOrderMessage
+                        }
+                        MessageSequenceError::ReorderBufferFull { .. } => {
+                            ErrorCode::ReorderBufferFull
+                        }
+                        _ => ErrorCode::UnknownMessageSequence,
+                    };
+                    let _ = messages_sender.send(Message::Text(prepare_error_response(
+                        error_code,
+                        &e.to_string(),
+                        NEW_MESSAGE_SUBJECT,
+                        request_id,
+                    )));
+                }
             }
-            ConnectionCommand::UnassignConnectionFromUser {
-                username,
-                messages_sender,
-            } => {
-                println!("UnassignConnectionFromUser, username={}", username);
-                application_scope.remove_session_sender(&username, &messages_sender);
+        }
+        ConnectionCommand::InitiateNewPrivateMessageSequence {
+            sender_username,
+            receiver_username,
+            messages_sender,
+            request_id,
+        } => {
+            debug!(
+                "InitiateNewPrivateMessageSequence. sender_username={:?} receiver_username={:?}",
+                &sender_username, &receiver_username
+            );
+            let reply = match application_scope.get_new_message_sequence(
+                sender_username.clone(),
+                receiver_username.clone(),
+                config.maximum_message_sequences,
+            ) {
+                Ok(response) => attach_reply_subject_and_serialize(
+                    Box::new(response),
+                    NEW_PRIVATE_MESSAGE_SEQUENCE_SUBJECT.to_string(),
+                    request_id,
+                ),
+                Err(e) => prepare_error_response(
+                    ErrorCode::UserNotFound,
+                    &e.to_string(),
+                    NEW_PRIVATE_MESSAGE_SEQUENCE_SUBJECT,
+                    request_id,
+                ),
+            };
+            let _ = messages_sender.send(Message::Text(reply));
+        }
+        ConnectionCommand::GetPrivateHistory {
+            reader_username,
+            partner_username,
+            before_id,
+            limit,
+            messages_sender,
+            request_id,
+        } => {
+            let _ = messages_sender.send(Message::Text(attach_reply_subject_and_serialize(
+                Box::new(application_scope.read_private_conversation_history(
+                    reader_username,
+                    partner_username,
+                    before_id,
+                    (limit as usize).min(MAXIMUM_HISTORY_PAGE_SIZE),
+                )),
+                GET_PRIVATE_HISTORY_SUBJECT.to_string(),
+                request_id,
+            )));
+        }
+        ConnectionCommand::EditPrivateMessage {
+            author_username,
+            partner_username,
+            id,
+            content,
+            messages_sender,
+            request_id,
+        } => {
+            match application_scope.edit_private_message(
+                author_username.clone(),
+                partner_username.clone(),
+                id,
+                content.clone(),
+            ) {
+                Ok(edited_at) => notify_conversation_partners(
+                    application_scope,
+                    Box::new(MessageEdited {
+                        id,
+                        sender_username: author_username.clone(),
+                        receiver_username: partner_username.clone(),
+                        content,
+                        edited_datetime: edited_at.to_string(),
+                    }),
+                    MESSAGE_EDITED_SUBJECT,
+                    [&author_username, &partner_username],
+                    &messages_sender,
+                    request_id,
+                ),
+                Err(e) => send_message_modification_error(
+                    &messages_sender,
+                    e,
+                    EDIT_MESSAGE_SUBJECT,
+                    request_id,
+                ),
             }
-            ConnectionCommand::SendMessageToAnotherUser {
-                sender_username,
-                receiver_username,
-                content,
-                message_sequence_id,
-                message_sequence_index
-            } => {
-                let private_message_server_metadata: PrivateMessageServerMetadata =
-                    application_scope.add_message_to_private_conversation(
-                        sender_username.clone(),
-                        receiver_username.clone(),
-                        content.clone(),
+        }
+        ConnectionCommand::DeletePrivateMessage {
+            author_username,
+            partner_username,
+            id,
+            messages_sender,
+            request_id,
+        } => {
+            match application_scope.delete_private_message(
+                author_username.clone(),
+                partner_username.clone(),
+                id,
+            ) {
+                Ok(()) => notify_conversation_partners(
+                    application_scope,
+                    Box::new(MessageDeleted {
+                        id,
+                        sender_username: author_username.clone(),
+                        receiver_username: partner_username.clone(),
+                    }),
+                    MESSAGE_DELETED_SUBJECT,
+                    [&author_username, &partner_username],
+                    &messages_sender,
+                    request_id,
+                ),
+                Err(e) => send_message_modification_error(
+                    &messages_sender,
+                    e,
+                    DELETE_MESSAGE_SUBJECT,
+                    request_id,
+                ),
+            }
+        }
+        ConnectionCommand::GetMessageRevisions {
+            reader_username,
+            partner_username,
+            id,
+            messages_sender,
+            request_id,
+        } => {
+            match application_scope.read_private_message_revisions(
+                reader_username,
+                partner_username.clone(),
+                id,
+            ) {
+                Ok(revisions) => {
+                    let _ =
+                        messages_sender.send(Message::Text(attach_reply_subject_and_serialize(
+                            Box::new(MessageRevisionsResponse {
+                                partner_username,
+                                id,
+                                revisions,
+                            }),
+                            GET_MESSAGE_REVISIONS_SUBJECT.to_string(),
+                            request_id,
+                        )));
+                }
+                Err(e) => send_message_modification_error(
+                    &messages_sender,
+                    e,
+                    GET_MESSAGE_REVISIONS_SUBJECT,
+                    request_id,
+                ),
+            }
+        }
+        ConnectionCommand::MarkPrivateMessagesRead {
+            reader_username,
+            partner_username,
+            id,
+            messages_sender,
+            request_id,
+        } => {
+            match application_scope.mark_private_messages_read(
+                reader_username.clone(),
+                partner_username.clone(),
+                id,
+            ) {
+                Ok((last_read_id, has_moved)) => {
+                    let read_receipt = ReadReceipt {
+                        reader_username: reader_username.clone(),
+                        partner_username: partner_username.clone(),
+                        last_read_id,
+                    };
+                    if has_moved {
+                        notify_conversation_partners(
+                            application_scope,
+                            Box::new(read_receipt),
+                            READ_RECEIPT_SUBJECT,
+                            [&reader_username, &partner_username],
+                            &messages_sender,
+                            request_id,
+                        );
+                    } else {
+                        // nothing has changed, so only the reader learns the current watermark
+                        let _ = messages_sender.send(Message::Text(
+                            attach_reply_subject_and_serialize(
+                                Box::new(read_receipt),
+                                READ_RECEIPT_SUBJECT.to_string(),
+                                request_id,
+                            ),
+                        ));
+                    }
+                }
+                Err(e) => send_message_modification_error(
+                    &messages_sender,
+                    e,
+                    MARK_READ_SUBJECT,
+                    request_id,
+                ),
+            }
+        }
+        ConnectionCommand::GetUnreadCounts {
+            username,
+            messages_sender,
+            request_id,
+        } => {
+            // every shard counts the messages of its conversations and groups
+            let reply = PartialReply::new(application_scope.shard.shard_count());
+            for shard_index in other_shard_indices(application_scope) {
+                application_scope
+                    .shard
+                    .forward(ConnectionCommand::CountUnreadMessages {
+                        shard_index,
+                        username: username.clone(),
+                        reply: reply.clone(),
+                        messages_sender: messages_sender.clone(),
+                        request_id: request_id.clone(),
+                    });
+            }
+            count_unread_messages(
+                application_scope,
+                &username,
+                &reply,
+                &messages_sender,
+                request_id,
+            );
+        }
+        ConnectionCommand::CountUnreadMessages {
+            shard_index: _,
+            username,
+            reply,
+            messages_sender,
+            request_id,
+        } => count_unread_messages(
+            application_scope,
+            &username,
+            &reply,
+            &messages_sender,
+            request_id,
+        ),
+        ConnectionCommand::UpdateTypingStatus {
+            typist_username,
+            partner_username,
+            is_typing,
+        } => {
+            if is_typing {
+                if application_scope.start_typing(
+                    typist_username.clone(),
+                    partner_username.clone(),
+                    Utc::now(),
+                    to_chrono_duration(config.typing_timeout),
+                    to_chrono_duration(config.typing_rate_limit_interval),
+                ) {
+                    relay_typing_status(
+                        application_scope,
+                        typist_username,
+                        &partner_username,
+                        TYPING_STARTED_SUBJECT,
                     );
-                match application_scope.chat_users.get(&receiver_username) {
-                    Some(user_context) => {
-                        let message_obj =
-                            dto::prepare_message_for_from_server_to_client(MessageToSomeone {
-                                id: private_message_server_metadata.id,
-                                content,
-                                sender_username,
-                                datetime: private_message_server_metadata.server_time.to_string(),
-                            });
-                        for sender in user_context.opened_sessions_senders.iter() {
-                            let _ = sender.send(Message::Text(message_obj.clone()));
-                        }
+                }
+            } else if application_scope.stop_typing(&typist_username, &partner_username) {
+                relay_typing_status(
+                    application_scope,
+                    typist_username,
+                    &partner_username,
+                    TYPING_STOPPED_SUBJECT,
+                );
+            }
+        }
+        ConnectionCommand::CreateGroup {
+            owner_username,
+            name,
+            member_usernames,
+            messages_sender,
+            request_id,
+        } => {
+            let group_info = application_scope.create_group(owner_username, name, member_usernames);
+            notify_group_change(
+                application_scope,
+                Ok(group_info),
+                None,
+                CREATE_GROUP_SUBJECT,
+                &messages_sender,
+                request_id,
+            );
+        }
+        ConnectionCommand::InviteToGroup {
+            inviter_username,
+            group_id,
+            username,
+            messages_sender,
+            request_id,
+        } => {
+            let result = application_scope.update_group(group_id, |group_conversation| {
+                group_conversation.invite(&inviter_username, username)
+            });
+            notify_group_change(
+                application_scope,
+                result,
+                None,
+                INVITE_TO_GROUP_SUBJECT,
+                &messages_sender,
+                request_id,
+            );
+        }
+        ConnectionCommand::RemoveFromGroup {
+            remover_username,
+            group_id,
+            username,
+            messages_sender,
+            request_id,
+        } => {
+            let result = application_scope.update_group(group_id, |group_conversation| {
+                group_conversation.remove(&remover_username, &username)
+            });
+            // the removed member learns that he is not in the group anymore
+            notify_group_change(
+                application_scope,
+                result,
+                Some(&username),
+                REMOVE_FROM_GROUP_SUBJECT,
+                &messages_sender,
+                request_id,
+            );
+        }
+        ConnectionCommand::LeaveGroup {
+            username,
+            group_id,
+            messages_sender,
+            request_id,
+        } => {
+            let result = application_scope.update_group(group_id, |group_conversation| {
+                group_conversation.leave(&username)
+            });
+            notify_group_change(
+                application_scope,
+                result,
+                Some(&username),
+                LEAVE_GROUP_SUBJECT,
+                &messages_sender,
+                request_id,
+            );
+        }
+        ConnectionCommand::RenameGroup {
+            renamer_username,
+            group_id,
+            name,
+            messages_sender,
+            request_id,
+        } => {
+            let result = application_scope.update_group(group_id, |group_conversation| {
+                group_conversation.rename(&renamer_username, name)
+            });
+            notify_group_change(
+                application_scope,
+                result,
+                None,
+                RENAME_GROUP_SUBJECT,
+                &messages_sender,
+                request_id,
+            );
+        }
+        ConnectionCommand::SetGroupRole {
+            setter_username,
+            group_id,
+            username,
+            role,
+            messages_sender,
+            request_id,
+        } => {
+            let result = application_scope.update_group(group_id, |group_conversation| {
+                group_conversation.set_role(&setter_username, &username, role)
+            });
+            notify_group_change(
+                application_scope,
+                result,
+                None,
+                SET_GROUP_ROLE_SUBJECT,
+                &messages_sender,
+                request_id,
+            );
+        }
+        ConnectionCommand::InitiateNewGroupMessageSequence {
+            sender_username,
+            group_id,
+            messages_sender,
+            request_id,
+        } => {
+            match application_scope
+                .get_group_mut(group_id)
+                .ok_or(GroupError::GroupNotFound)
+                .and_then(|group_conversation| {
+                    group_conversation.start_new_message_sequence(
+                        &sender_username,
+                        config.maximum_message_sequences,
+                    )
+                }) {
+                Ok(sequence_id) => {
+                    let _ =
+                        messages_sender.send(Message::Text(attach_reply_subject_and_serialize(
+                            Box::new(NewGroupMessageSequenceResponse {
+                                group_id,
+                                sequence_id,
+                            }),
+                            NEW_GROUP_MESSAGE_SEQUENCE_SUBJECT.to_string(),
+                            request_id,
+                        )));
+                }
+                Err(e) => send_group_error(
+                    &messages_sender,
+                    e,
+                    NEW_GROUP_MESSAGE_SEQUENCE_SUBJECT,
+                    request_id,
+                ),
+            }
+        }
+        ConnectionCommand::SendMessageToGroup {
+            sender_username,
+            group_id,
+            content,
+            message_sequence_id,
+            message_sequence_index,
+            messages_sender,
+            request_id,
+        } => {
+            // the same rules as for the private messages
+            match application_scope
+                .get_group_mut(group_id)
+                .ok_or(MessageSequenceError::ConversationNotFound)
+                .and_then(|group_conversation| {
+                    group_conversation.approach_message_sequence(
+                        &sender_username,
+                        message_sequence_id,
+                        SequenceMessage {
+                            message_sequence_index: message_sequence_index.into(),
+                            content,
+                            request_id: request_id.clone(),
+                        },
+                        config.reorder_buffer_capacity,
+                    )
+                }) {
+                Ok(accepted_messages) => {
+                    for accepted_message in accepted_messages {
+                        let group_message = deliver_group_message(
+                            application_scope,
+                            group_id,
+                            sender_username.clone(),
+                            accepted_message.content,
+                        );
+                        let _ = messages_sender.send(Message::Text(
+                            attach_reply_subject_and_serialize(
+                                Box::new(GroupMessageAccepted {
+                                    group_id,
+                                    id: group_message.id,
+                                    datetime: group_message.datetime,
+                                    message_sequence_id,
+                                    message_sequence_index: accepted_message.message_sequence_index
+                                        as u16,
+                                }),
+                                GROUP_MESSAGE_ACCEPTED_SUBJECT.to_string(),
+                                accepted_message.request_id,
+                            ),
+                        ));
                     }
-                    None => {
-                        println!(
-                            "cannot send a message {} to user {} right now because he is not connected",
-                            content, receiver_username
+                }
+                Err(e) => {
+                    let error_code = match e {
+                        MessageSequenceError::ConversationNotFound => ErrorCode::GroupNotFound,
+                        MessageSequenceError::ReceiverNotFound => ErrorCode::UserNotFound,
+                        MessageSequenceError::UnexpectedIndex { .. } => {
+                            ErrorCode::OutOfOrderMessage
+                        }
+                        MessageSequenceError::ReorderBufferFull { .. } => {
+                            ErrorCode::ReorderBufferFull
+                        }
+                        MessageSequenceError::SequenceNotFound { .. } => {
+                            ErrorCode::UnknownMessageSequence
+                        }
+                    };
+                    let _ = messages_sender.send(Message::Text(prepare_error_response(
+                        error_code,
+                        &e.to_string(),
+                        NEW_GROUP_MESSAGE_SUBJECT,
+                        request_id,
+                    )));
+                }
+            }
+        }
+        ConnectionCommand::GetGroupHistory {
+            reader_username,
+            group_id,
+            before_id,
+            limit,
+            messages_sender,
+            request_id,
+        } => {
+            match application_scope
+                .get_group(group_id)
+                .ok_or(GroupError::GroupNotFound)
+                .and_then(|group_conversation| {
+                    group_conversation.read_history(
+                        &reader_username,
+                        before_id,
+                        (limit as usize).min(MAXIMUM_HISTORY_PAGE_SIZE),
+                    )
+                }) {
+                Ok((messages, has_more)) => {
+                    let _ =
+                        messages_sender.send(Message::Text(attach_reply_subject_and_serialize(
+                            Box::new(GroupHistoryResponse {
+                                group_id,
+                                messages,
+                                has_more,
+                            }),
+                            GET_GROUP_HISTORY_SUBJECT.to_string(),
+                            request_id,
+                        )));
+                }
+                Err(e) => {
+                    send_group_error(&messages_sender, e, GET_GROUP_HISTORY_SUBJECT, request_id)
+                }
+            }
+        }
+        ConnectionCommand::MarkGroupMessagesRead {
+            reader_username,
+            group_id,
+            id,
+            messages_sender,
+            request_id,
+        } => {
+            match application_scope
+                .get_group_mut(group_id)
+                .ok_or(GroupError::GroupNotFound)
+                .and_then(|group_conversation| group_conversation.mark_read(&reader_username, id))
+            {
+                Ok((last_read_id, has_moved)) => {
+                    let group_read_receipt = GroupReadReceipt {
+                        group_id,
+                        reader_username,
+                        last_read_id,
+                    };
+                    if has_moved {
+                        let members = application_scope
+                            .get_group(group_id)
+                            .map(|group_conversation| group_conversation.member_usernames())
+                            .unwrap_or_default();
+                        notify_conversation_partners(
+                            application_scope,
+                            Box::new(group_read_receipt),
+                            GROUP_READ_RECEIPT_SUBJECT,
+                            &members,
+                            &messages_sender,
+                            request_id,
                         );
+                    } else {
+                        // nothing has changed, so only the reader learns the current watermark
+                        let _ = messages_sender.send(Message::Text(
+                            attach_reply_subject_and_serialize(
+                                Box::new(group_read_receipt),
+                                GROUP_READ_RECEIPT_SUBJECT.to_string(),
+                                request_id,
+                            ),
+                        ));
                     }
                 }
+                Err(e) => {
+                    send_group_error(&messages_sender, e, MARK_GROUP_READ_SUBJECT, request_id)
+                }
             }
-            ConnectionCommand::InitiateNewPrivateMessageSequence {
+        }
+        ConnectionCommand::RegisterUser {
+            user,
+            messages_sender,
+            request_id,
+        } => {
+            // the username might have been taken after the client session checked it
+            let message = match user_service::add_user(user.clone()) {
+                Ok(()) => {
+                    application_scope.save_user(&user);
+                    attach_reply_subject_and_serialize(
+                        Box::new(RegistrationResponse {
+                            username: user.username,
+                        }),
+                        REGISTER_SUBJECT.to_string(),
+                        request_id,
+                    )
+                }
+                Err(e) => prepare_error_response(
+                    user_registry_error_code(&e),
+                    &e.to_string(),
+                    REGISTER_SUBJECT,
+                    request_id,
+                ),
+            };
+            let _ = messages_sender.send(Message::Text(message));
+        }
+        ConnectionCommand::AddUser {
+            user,
+            messages_sender,
+            request_id,
+            ..
+        } => {
+            let result = user_service::add_user(user.clone()).map(|()| user);
+            if let Ok(user) = &result {
+                application_scope.save_user(user);
+            }
+            reply_with_user_info(&messages_sender, result, ADD_USER_SUBJECT, request_id);
+        }
+        ConnectionCommand::SetUserDisabled {
+            username,
+            is_disabled,
+            messages_sender,
+            request_id,
+            ..
+        } => {
+            let result = user_service::set_user_disabled(&username, is_disabled);
+            if let Ok(user) = &result {
+                application_scope.save_user(user);
+                if user.is_disabled {
+                    // the opened sessions of a disabled user are closed
+                    if let Some(chat_user) = application_scope.chat_users.get(&username) {
+                        for session_sender in &chat_user.opened_sessions_senders {
+                            let _ = session_sender.send(Message::Close(Some(CloseFrame {
+                                code: CloseCode::Policy,
+                                reason: "the user has been disabled".into(),
+                            })));
+                        }
+                    }
+                }
+            }
+            reply_with_user_info(
+                &messages_sender,
+                result,
+                SET_USER_DISABLED_SUBJECT,
+                request_id,
+            );
+        }
+        ConnectionCommand::CreateChannel {
+            creator_username,
+            name,
+            topic,
+            messages_sender,
+            request_id,
+        } => {
+            let result = application_scope.create_channel(creator_username, name, topic);
+            reply_or_send_channel_error(
+                &messages_sender,
+                result,
+                CREATE_CHANNEL_SUBJECT,
+                request_id,
+            );
+        }
+        ConnectionCommand::ListChannels {
+            username: _,
+            messages_sender,
+            request_id,
+        } => {
+            let reply = PartialReply::new(application_scope.shard.shard_count());
+            for shard_index in other_shard_indices(application_scope) {
+                application_scope
+                    .shard
+                    .forward(ConnectionCommand::ListShardChannels {
+                        shard_index,
+                        reply: reply.clone(),
+                        messages_sender: messages_sender.clone(),
+                        request_id: request_id.clone(),
+                    });
+            }
+            list_channels(application_scope, &reply, &messages_sender, request_id);
+        }
+        ConnectionCommand::ListShardChannels {
+            shard_index: _,
+            reply,
+            messages_sender,
+            request_id,
+        } => list_channels(application_scope, &reply, &messages_sender, request_id),
+        ConnectionCommand::GetChannelInfo {
+            username: _,
+            name,
+            messages_sender,
+            request_id,
+        } => {
+            let result = application_scope
+                .get_channel(&name)
+                .map(|channel| channel.to_channel_info(name.clone()));
+            reply_or_send_channel_error(
+                &messages_sender,
+                result,
+                GET_CHANNEL_INFO_SUBJECT,
+                request_id,
+            );
+        }
+        ConnectionCommand::JoinChannel {
+            username,
+            name,
+            history_limit,
+            messages_sender,
+            request_id,
+        } => {
+            let result = application_scope.get_channel_mut(&name).map(|channel| {
+                channel.join(username);
+                JoinChannelResponse {
+                    channel: channel.to_channel_info(name.clone()),
+                    messages: channel.read_last_messages(
+                        (history_limit as usize).min(MAXIMUM_HISTORY_PAGE_SIZE),
+                    ),
+                }
+            });
+            reply_or_send_channel_error(&messages_sender, result, JOIN_CHANNEL_SUBJECT, request_id);
+        }
+        ConnectionCommand::LeaveChannel {
+            username,
+            name,
+            messages_sender,
+            request_id,
+        } => {
+            let result = application_scope
+                .get_channel_mut(&name)
+                .and_then(|channel| {
+                    channel.leave(&username)?;
+                    Ok(channel.to_channel_info(name.clone()))
+                });
+            reply_or_send_channel_error(
+                &messages_sender,
+                result,
+                LEAVE_CHANNEL_SUBJECT,
+                request_id,
+            );
+        }
+        ConnectionCommand::SendMessageToChannel {
+            sender_username,
+            channel_name,
+            content,
+            messages_sender,
+            request_id,
+        } => {
+            let result = application_scope
+                .get_channel_mut(&channel_name)
+                .and_then(|channel| channel.add_message(sender_username.clone(), content));
+            match result {
+                Ok(message_to_someone) => {
+                    broadcast_channel_message(
+                        application_scope,
+                        &channel_name,
+                        &message_to_someone,
+                        &messages_sender,
+                    );
+                    let _ =
+                        messages_sender.send(Message::Text(attach_reply_subject_and_serialize(
+                            Box::new(ChannelMessageAccepted {
+                                channel_name,
+                                id: message_to_someone.id,
+                                datetime: message_to_someone.datetime,
+                            }),
+                            CHANNEL_MESSAGE_ACCEPTED_SUBJECT.to_string(),
+                            request_id,
+                        )));
+                }
+                Err(e) => {
+                    send_channel_error(&messages_sender, e, NEW_CHANNEL_MESSAGE_SUBJECT, request_id)
+                }
+            }
+        }
+        ConnectionCommand::GetPresence {
+            username: _,
+            usernames,
+            messages_sender,
+            request_id,
+        } => {
+            // the presence of every user is kept by his shard
+            let mut usernames_by_shard: BTreeMap<usize, Vec<(usize, String)>> = BTreeMap::new();
+            for (position, username) in usernames.into_iter().enumerate() {
+                usernames_by_shard
+                    .entry(
+                        application_scope
+                            .shard
+                            .shard_index(&ShardKey::User(&username)),
+                    )
+                    .or_default()
+                    .push((position, username));
+            }
+            // this shard adds its part even if it has none of the users
+            let own_usernames = usernames_by_shard
+                .remove(&application_scope.shard.index)
+                .unwrap_or_default();
+            let reply = PartialReply::new(usernames_by_shard.len() + 1);
+            for (_, usernames) in usernames_by_shard {
+                application_scope
+                    .shard
+                    .forward(ConnectionCommand::GetShardPresences {
+                        usernames,
+                        reply: reply.clone(),
+                        messages_sender: messages_sender.clone(),
+                        request_id: request_id.clone(),
+                    });
+            }
+            get_presences(
+                application_scope,
+                own_usernames,
+                &reply,
+                &messages_sender,
+                request_id,
+            );
+        }
+        ConnectionCommand::GetShardPresences {
+            usernames,
+            reply,
+            messages_sender,
+            request_id,
+        } => get_presences(
+            application_scope,
+            usernames,
+            &reply,
+            &messages_sender,
+            request_id,
+        ),
+        ConnectionCommand::DeliverToUsers {
+            usernames,
+            text,
+            except_session,
+            is_ephemeral,
+        } => send_to_users(
+            application_scope,
+            &usernames,
+            &text,
+            except_session.as_ref(),
+            is_ephemeral,
+        ),
+        ConnectionCommand::DeliverPrivateMessage {
+            sender_username,
+            receiver_username,
+            id,
+            message_obj,
+        } => deliver_to_receiver(
+            application_scope,
+            sender_username,
+            &receiver_username,
+            id,
+            &message_obj,
+        ),
+        ConnectionCommand::FlushUndeliveredMessages {
+            username,
+            messages_sender,
+            delivered_count,
+        } => {
+            // the messages wait for the next session if this one has ended
+            if application_scope.has_session(&username, &messages_sender) {
+                application_scope.confirm_undelivered_messages(
+                    &username,
+                    &messages_sender,
+                    delivered_count,
+                );
+                flush_undelivered_messages(application_scope, username, messages_sender);
+            }
+        }
+        ConnectionCommand::DeliverUndeliveredMessages {
+            receiver_username,
+            sender_username,
+            ids,
+            messages_sender,
+        } => {
+            for id in ids.iter() {
+                let Some(message_to_someone) = application_scope.get_undelivered_message(
+                    &receiver_username,
+                    &sender_username,
+                    *id,
+                ) else {
+                    continue;
+                };
+                let message_obj =
+                    dto::prepare_message_for_from_server_to_client(message_to_someone);
+                // the shard of the receiver keeps the messages until another session gets them
+                if messages_sender.send(Message::Text(message_obj)).is_err() {
+                    return;
+                }
+            }
+            application_scope
+                .shard
+                .forward(ConnectionCommand::FlushUndeliveredMessages {
+                    username: receiver_username,
+                    messages_sender,
+                    delivered_count: ids.len(),
+                });
+        }
+        ConnectionCommand::RecordActivity { username } => {
+            refresh_presence(application_scope, config, &username, true);
+        }
+        ConnectionCommand::NotifyPresenceChange {
+            shard_index: _,
+            presence,
+        } => notify_partners_about_presence(application_scope, presence),
+        ConnectionCommand::Shutdown { shard_index: _ } => {
+            for chat_user in application_scope.chat_users.values() {
+                for sender in chat_user.opened_sessions_senders.iter() {
+                    close_for_shutdown(sender, config);
+                }
+            }
+        }
+    }
+}
+
+/// Tells the session that the server is shutting down and closes it. The messages queued before
+/// are still sent.
+pub fn close_for_shutdown(messages_sender: &MessagesSender, config: &ServerConfig) {
+    let _ = messages_sender.send(Message::Text(attach_subject_and_serialize(
+        Box::new(ServerShuttingDown {
+            close_timeout_ms: config.shutdown_timeout.as_millis() as u64,
+        }),
+        SERVER_SHUTTING_DOWN_SUBJECT.to_string(),
+    )));
+    let _ = messages_sender.send(Message::Close(Some(CloseFrame {
+        code: CloseCode::Away,
+        reason: "the server is shutting down".into(),
+    })));
+}
+
+/// Returns the indices of all the shards but this one.
+fn other_shard_indices(application_scope: &ApplicationScope) -> Vec<usize> {
+    (0..application_scope.shard.shard_count())
+        .filter(|shard_index| *shard_index != application_scope.shard.index)
+        .collect()
+}
+
+/// Adds the unread counts of the conversations and groups of this shard to the reply. The reply
+/// is sent once all the shards have added theirs.
+fn count_unread_messages(
+    application_scope: &ApplicationScope,
+    username: &String,
+    reply: &PartialReply<UnreadCountsResponse>,
+    messages_sender: &MessagesSender,
+    request_id: Option<String>,
+) {
+    let Some(parts) = reply.add(UnreadCountsResponse {
+        unread_counts: application_scope.count_unread_private_messages(username),
+        group_unread_counts: application_scope.count_unread_group_messages(username),
+    }) else {
+        return;
+    };
+    let mut unread_counts_response = UnreadCountsResponse {
+        unread_counts: Vec::new(),
+        group_unread_counts: Vec::new(),
+    };
+    for part in parts {
+        unread_counts_response
+            .unread_counts
+            .extend(part.unread_counts);
+        unread_counts_response
+            .group_unread_counts
+            .extend(part.group_unread_counts);
+    }
+    unread_counts_response
+        .group_unread_counts
+        .sort_by_key(|group_unread_count| group_unread_count.group_id);
+    let _ = messages_sender.send(Message::Text(attach_reply_subject_and_serialize(
+        Box::new(unread_counts_response),
+        UNREAD_COUNTS_SUBJECT.to_string(),
+        request_id,
+    )));
+}
+
+/// Adds the channels of this shard to the reply. The reply is sent once all the shards have added
+/// theirs.
+fn list_channels(
+    application_scope: &ApplicationScope,
+    reply: &PartialReply<Vec<ChannelInfo>>,
+    messages_sender: &MessagesSender,
+    request_id: Option<String>,
+) {
+    let Some(parts) = reply.add(application_scope.list_channels()) else {
+        return;
+    };
+    let mut channels: Vec<ChannelInfo> = parts.into_iter().flatten().collect();
+    channels.sort_by(|channel1, channel2| channel1.name.cmp(&channel2.name));
+    let _ = messages_sender.send(Message::Text(attach_reply_subject_and_serialize(
+        Box::new(ChannelListResponse { channels }),
+        LIST_CHANNELS_SUBJECT.to_string(),
+        request_id,
+    )));
+}
+
+/// Adds the presences of the users of this shard to the reply. The reply is sent once all the
+/// shards have added theirs.
+fn get_presences(
+    application_scope: &ApplicationScope,
+    usernames: Vec<(usize, String)>,
+    reply: &PartialReply<Vec<(usize, Presence)>>,
+    messages_sender: &MessagesSender,
+    request_id: Option<String>,
+) {
+    let Some(parts) = reply.add(
+        usernames
+            .into_iter()
+            .map(|(position, username)| (position, application_scope.get_presence(&username)))
+            .collect(),
+    ) else {
+        return;
+    };
+    let mut presences: Vec<(usize, Presence)> = parts.into_iter().flatten().collect();
+    // the presences are listed in the order of the request
+    presences.sort_by_key(|(position, _)| *position);
+    let _ = messages_sender.send(Message::Text(attach_reply_subject_and_serialize(
+        Box::new(GetPresenceResponse {
+            presences: presences
+                .into_iter()
+                .map(|(_, presence)| presence)
+                .collect(),
+        }),
+        GET_PRESENCE_SUBJECT.to_string(),
+        request_id,
+    )));
+}
+
+/// Sends a message to all the opened sessions of the users except `except_session`. The sessions
+/// of the users that belong to other shards receive it through their shards.
+fn send_to_users<'a>(
+    application_scope: &ApplicationScope,
+    usernames: impl IntoIterator<Item = &'a String>,
+    text: &str,
+    except_session: Option<&MessagesSender>,
+    is_ephemeral: bool,
+) {
+    let mut foreign_usernames: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+    for username in usernames {
+        let shard_index = application_scope
+            .shard
+            .shard_index(&ShardKey::User(username));
+        if shard_index != application_scope.shard.index {
+            foreign_usernames
+                .entry(shard_index)
+                .or_default()
+                .push(username.clone());
+            continue;
+        }
+        let Some(chat_user) = application_scope.chat_users.get(username) else {
+            continue;
+        };
+        for sender in chat_user.opened_sessions_senders.iter() {
+            if except_session.is_some_and(|except_session| sender.same_channel(except_session)) {
+                continue;
+            }
+            let message = Message::Text(text.to_string());
+            let _ = if is_ephemeral {
+                sender.send_ephemeral(message)
+            } else {
+                sender.send(message)
+            };
+        }
+    }
+    for (_, usernames) in foreign_usernames {
+        application_scope
+            .shard
+            .forward(ConnectionCommand::DeliverToUsers {
+                usernames,
+                text: text.to_string(),
+                except_session: except_session.cloned(),
+                is_ephemeral,
+            });
+    }
+}
+
+/// Tells all the opened sessions of the conversation partners of the user that his presence has
+/// changed.
+fn notify_presence_change(application_scope: &ApplicationScope, presence: Presence) {
+    // any shard may own a conversation of the user
+    for shard_index in other_shard_indices(application_scope) {
+        application_scope
+            .shard
+            .forward(ConnectionCommand::NotifyPresenceChange {
+                shard_index,
+                presence: presence.clone(),
+            });
+    }
+    notify_partners_about_presence(application_scope, presence);
+}
+
+/// Tells the partners of the conversations that this shard owns that the presence has changed.
+fn notify_partners_about_presence(application_scope: &ApplicationScope, presence: Presence) {
+    let partners = application_scope.get_private_conversation_partners(&presence.username);
+    if partners.is_empty() {
+        return;
+    }
+    let notification =
+        attach_subject_and_serialize(Box::new(presence), PRESENCE_SUBJECT.to_string());
+    send_to_users(application_scope, &partners, &notification, None, true);
+}
+
+/// Tells all the opened sessions of the partner that the typist has started or stopped typing.
+fn relay_typing_status(
+    application_scope: &ApplicationScope,
+    typist_username: String,
+    partner_username: &String,
+    subject: &str,
+) {
+    let notification = attach_subject_and_serialize(
+        Box::new(TypingNotification { typist_username }),
+        subject.to_string(),
+    );
+    send_to_users(
+        application_scope,
+        [partner_username],
+        &notification,
+        None,
+        true,
+    );
+}
+
+/// Sends an event to all the opened sessions of the conversation partners. The session that has
+/// made the request receives the event as the reply to the request.
+fn notify_conversation_partners<'a>(
+    application_scope: &ApplicationScope,
+    event: Box<dyn erased_serde::Serialize>,
+    subject: &str,
+    partners: impl IntoIterator<Item = &'a String>,
+    requester: &MessagesSender,
+    request_id: Option<String>,
+) {
+    let event_json = serde_json::to_value(&*event).unwrap();
+    let notification =
+        attach_subject_and_serialize(Box::new(event_json.clone()), subject.to_string());
+    let _ = requester.send(Message::Text(attach_reply_subject_and_serialize(
+        Box::new(event_json),
+        subject.to_string(),
+        request_id,
+    )));
+    send_to_users(
+        application_scope,
+        partners,
+        &notification,
+        Some(requester),
+        false,
+    );
+}
+
+/// Replies to the request that has changed a group and pushes the group after the change to all
+/// the opened sessions of its members and of `former_member` if he has just left the group.
+fn notify_group_change(
+    application_scope: &ApplicationScope,
+    result: Result<GroupInfo, GroupError>,
+    former_member: Option<&String>,
+    request_subject: &str,
+    requester: &MessagesSender,
+    request_id: Option<String>,
+) {
+    let group_info = match result {
+        Ok(group_info) => group_info,
+        Err(e) => {
+            send_group_error(requester, e, request_subject, request_id);
+            return;
+        }
+    };
+    let _ = requester.send(Message::Text(attach_reply_subject_and_serialize(
+        Box::new(group_info.clone()),
+        request_subject.to_string(),
+        request_id,
+    )));
+    let notification = attach_subject_and_serialize(
+        Box::new(group_info.clone()),
+        GROUP_UPDATED_SUBJECT.to_string(),
+    );
+    send_to_users(
+        application_scope,
+        group_info
+            .members
+            .iter()
+            .map(|member| &member.username)
+            .chain(former_member),
+        &notification,
+        Some(requester),
+        false,
+    );
+}
+
+fn send_group_error(
+    messages_sender: &MessagesSender,
+    e: GroupError,
+    request_subject: &str,
+    request_id: Option<String>,
+) {
+    let error_code = match e {
+        GroupError::GroupNotFound => ErrorCode::GroupNotFound,
+        GroupError::NotGroupMember => ErrorCode::NotGroupMember,
+        GroupError::AlreadyGroupMember => ErrorCode::AlreadyGroupMember,
+        GroupError::InsufficientGroupRole => ErrorCode::InsufficientGroupRole,
+        GroupError::MessageNotFound => ErrorCode::MessageNotFound,
+    };
+    let _ = messages_sender.send(Message::Text(prepare_error_response(
+        error_code,
+        &e.to_string(),
+        request_subject,
+        request_id,
+    )));
+}
+
+fn reply_with_user_info(
+    messages_sender: &MessagesSender,
+    result: Result<StoredUser, UserRegistryError>,
+    request_subject: &str,
+    request_id: Option<String>,
+) {
+    let message = match result {
+        Ok(user) => attach_reply_subject_and_serialize(
+            Box::new(UserInfo {
+                username: user.username,
+                is_admin: user.is_admin,
+                is_disabled: user.is_disabled,
+            }),
+            request_subject.to_string(),
+            request_id,
+        ),
+        Err(e) => prepare_error_response(
+            user_registry_error_code(&e),
+            &e.to_string(),
+            request_subject,
+            request_id,
+        ),
+    };
+    let _ = messages_sender.send(Message::Text(message));
+}
+
+fn user_registry_error_code(e: &UserRegistryError) -> ErrorCode {
+    match e {
+        UserRegistryError::UserAlreadyExists => ErrorCode::UserAlreadyExists,
+        UserRegistryError::UserNotFound => ErrorCode::UserNotFound,
+    }
+}
+
+fn reply_or_send_channel_error<T: serde::Serialize + 'static>(
+    messages_sender: &MessagesSender,
+    result: Result<T, ChannelError>,
+    request_subject: &str,
+    request_id: Option<String>,
+) {
+    match result {
+        Ok(reply) => {
+            let _ = messages_sender.send(Message::Text(attach_reply_subject_and_serialize(
+                Box::new(reply),
+                request_subject.to_string(),
+                request_id,
+            )));
+        }
+        Err(e) => send_channel_error(messages_sender, e, request_subject, request_id),
+    }
+}
+
+fn send_channel_error(
+    messages_sender: &MessagesSender,
+    e: ChannelError,
+    request_subject: &str,
+    request_id: Option<String>,
+) {
+    let error_code = match e {
+        ChannelError::ChannelNotFound => ErrorCode::ChannelNotFound,
+        ChannelError::ChannelAlreadyExists => ErrorCode::ChannelAlreadyExists,
+        ChannelError::InvalidChannelName => ErrorCode::InvalidChannelName,
+        ChannelError::NotChannelSubscriber => ErrorCode::NotChannelSubscriber,
+    };
+    let _ = messages_sender.send(Message::Text(prepare_error_response(
+        error_code,
+        &e.to_string(),
+        request_subject,
+        request_id,
+    )));
+}
+
+fn send_message_modification_error(
+    messages_sender: &MessagesSender,
+    e: MessageModificationError,
+    request_subject: &str,
+    request_id: Option<String>,
+) {
+    let error_code = match e {
+        MessageModificationError::MessageNotFound => ErrorCode::MessageNotFound,
+        MessageModificationError::NotMessageAuthor => ErrorCode::NotMessageAuthor,
+    };
+    let _ = messages_sender.send(Message::Text(prepare_error_response(
+        error_code,
+        &e.to_string(),
+        request_subject,
+        request_id,
+    )));
+}
+
+/// Sends a message that has been stored in the channel to all the opened sessions of the
+/// subscribers except the session of the sender.
+fn broadcast_channel_message(
+    application_scope: &ApplicationScope,
+    channel_name: &String,
+    message_to_someone: &MessageToSomeone,
+    sender_session: &MessagesSender,
+) {
+    let Ok(channel) = application_scope.get_channel(channel_name) else {
+        return;
+    };
+    let notification = attach_subject_and_serialize(
+        Box::new(ChannelMessage {
+            channel_name: channel_name.clone(),
+            id: message_to_someone.id,
+            content: message_to_someone.content.clone(),
+            sender_username: message_to_someone.sender_username.clone(),
+            datetime: message_to_someone.datetime.clone(),
+        }),
+        CHANNEL_MESSAGE_SUBJECT.to_string(),
+    );
+    send_to_users(
+        application_scope,
+        channel.subscribers(),
+        &notification,
+        Some(sender_session),
+        false,
+    );
+}
+
+/// Stores a group message and sends it to all the opened sessions of the other members. The members
+/// who are offline can read it in the history of the group.
+fn deliver_group_message(
+    application_scope: &mut ApplicationScope,
+    group_id: u32,
+    sender_username: String,
+    content: String,
+) -> MessageToSomeone {
+    let group_conversation = application_scope.get_group_mut(group_id).unwrap();
+    let message_to_someone = group_conversation.add_message(sender_username.clone(), content);
+    let members = group_conversation.member_usernames();
+    let notification = attach_subject_and_serialize(
+        Box::new(GroupMessage {
+            group_id,
+            id: message_to_someone.id,
+            content: message_to_someone.content.clone(),
+            sender_username: sender_username.clone(),
+            datetime: message_to_someone.datetime.clone(),
+        }),
+        GROUP_MESSAGE_SUBJECT.to_string(),
+    );
+    send_to_users(
+        application_scope,
+        members.iter().filter(|member| **member != sender_username),
+        &notification,
+        None,
+        false,
+    );
+    message_to_someone
+}
+
+/// Sends the session the messages that were sent to the user while he was offline, a page at a
+/// time. The next page follows when the client has read this one, so a long backlog does not make
+/// the session look like a slow consumer. The shards that own the other conversations send their
+/// messages themselves and then hand the session back.
+fn flush_undelivered_messages(
+    application_scope: &mut ApplicationScope,
+    username: String,
+    messages_sender: MessagesSender,
+) {
+    loop {
+        match application_scope.next_undelivered_messages(
+            &username,
+            &messages_sender,
+            messages_sender.page_capacity(),
+        ) {
+            UndeliveredMessagesPage::CaughtUp => return,
+            UndeliveredMessagesPage::QueueFull => break,
+            UndeliveredMessagesPage::Local {
+                messages,
+                entry_count,
+            } => {
+                for message_to_someone in messages {
+                    let message_obj =
+                        dto::prepare_message_for_from_server_to_client(message_to_someone);
+                    if messages_sender.send(Message::Text(message_obj)).is_err() {
+                        return;
+                    }
+                }
+                application_scope.confirm_undelivered_messages(
+                    &username,
+                    &messages_sender,
+                    entry_count,
+                );
+            }
+            UndeliveredMessagesPage::Foreign { sender, ids } => {
+                application_scope
+                    .shard
+                    .forward(ConnectionCommand::DeliverUndeliveredMessages {
+                        receiver_username: username,
+                        sender_username: sender,
+                        ids,
+                        messages_sender,
+                    });
+                return;
+            }
+        }
+    }
+    // without other shards nobody can bring the command back, so the rest waits for the next
+    // session
+    let Some(router) = application_scope.shard.router().cloned() else {
+        return;
+    };
+    messages_sender.refill_when_drained(move |messages_sender| {
+        if let Some(messages_sender) = messages_sender {
+            let _ = router.send(ConnectionCommand::FlushUndeliveredMessages {
+                username,
+                messages_sender,
+                delivered_count: 0,
+            });
+        }
+    });
+}
+
+/// Stores a private message and sends it to all the opened sessions of the receiver. If the
+/// receiver has no opened sessions, the message will be delivered when he opens one.
+fn deliver_private_message(
+    application_scope: &mut ApplicationScope,
+    sender_username: String,
+    receiver_username: String,
+    content: String,
+) -> PrivateMessageServerMetadata {
+    let private_message_server_metadata: PrivateMessageServerMetadata = application_scope
+        .add_message_to_private_conversation(
+            sender_username.clone(),
+            receiver_username.clone(),
+            content.clone(),
+        );
+    let message_obj = dto::prepare_message_for_from_server_to_client(MessageToSomeone {
+        id: private_message_server_metadata.id,
+        content,
+        sender_username: sender_username.clone(),
+        datetime: private_message_server_metadata.server_time.to_string(),
+        edited_datetime: None,
+        is_deleted: false,
+    });
+    if application_scope
+        .shard
+        .owns(&ShardKey::User(&receiver_username))
+    {
+        deliver_to_receiver(
+            application_scope,
+            sender_username,
+            &receiver_username,
+            private_message_server_metadata.id,
+            &message_obj,
+        );
+    } else {
+        application_scope
+            .shard
+            .forward(ConnectionCommand::DeliverPrivateMessage {
                 sender_username,
                 receiver_username,
+                id: private_message_server_metadata.id,
+                message_obj,
+            });
+    }
+    private_message_server_metadata
+}
+
+/// Sends a stored private message to all the opened sessions of the receiver, who belongs to this
+/// shard. If he has no opened sessions, the message is kept until he opens one. The sessions that
+/// have not received the older undelivered messages yet get it after them.
+fn deliver_to_receiver(
+    application_scope: &mut ApplicationScope,
+    sender_username: String,
+    receiver_username: &String,
+    id: u32,
+    message_obj: &str,
+) {
+    let mut is_delivered = false;
+    let mut is_catching_up = false;
+    if let Some(user_context) = application_scope.chat_users.get(receiver_username) {
+        for sender in user_context.opened_sessions_senders.iter() {
+            if user_context.is_catching_up(sender) {
+                is_catching_up = true;
+            } else if sender.send(Message::Text(message_obj.to_string())).is_ok() {
+                is_delivered = true;
+            }
+        }
+    }
+    if !is_delivered && !is_catching_up {
+        debug!(
+            "cannot send the message {} to user {} right now because he is not connected",
+            id, receiver_username
+        );
+    }
+    if !is_delivered || is_catching_up {
+        application_scope.add_undelivered_message(receiver_username, sender_username, id);
+    }
+}
+
+#[test]
+fn test_messages_are_delivered_in_sequence_order() {
+    use crate::dto::{NewPrivateMessageSequenceResponse, Subject, ERROR_SUBJECT};
+
+    let mut application_scope = ApplicationScope::new();
+    let config = ServerConfig {
+        reorder_buffer_capacity: 2,
+        ..ServerConfig::default()
+    };
+    let (ian_sender, mut ian_receiver) = messages_channel(&config);
+    let (dan_sender, mut dan_receiver) = messages_channel(&config);
+    for (username, messages_sender) in [("ian", &ian_sender), ("dan", &dan_sender)] {
+        process_connection_command(
+            &mut application_scope,
+            &config,
+            ConnectionCommand::AssignConnectionToUser {
+                username: username.to_string(),
+                messages_sender: messages_sender.clone(),
+                acceptance: None,
+            },
+        );
+    }
+    process_connection_command(
+        &mut application_scope,
+        &config,
+        ConnectionCommand::InitiateNewPrivateMessageSequence {
+            sender_username: "ian".to_string(),
+            receiver_username: "dan".to_string(),
+            messages_sender: ian_sender.clone(),
+            request_id: None,
+        },
+    );
+    let sequence_id = match ian_receiver.try_recv() {
+        Some(Message::Text(text)) => {
+            serde_json::from_str::<NewPrivateMessageSequenceResponse>(&text)
+                .unwrap()
+                .sequence_id
+        }
+        other => panic!("a message sequence expected, got {:?}", other),
+    };
+    let send_message = |application_scope: &mut ApplicationScope, index: u16, content: &str| {
+        process_connection_command(
+            application_scope,
+            &config,
+            ConnectionCommand::SendMessageToAnotherUser {
+                sender_username: "ian".to_string(),
+                receiver_username: "dan".to_string(),
+                content: content.to_string(),
+                message_sequence_id: sequence_id,
+                message_sequence_index: index,
+                messages_sender: ian_sender.clone(),
+                request_id: Some(index.to_string()),
+            },
+        );
+    };
+
+    send_message(&mut application_scope, 2, "second");
+    assert!(drain_messages(&mut dan_receiver).is_empty());
+    send_message(&mut application_scope, 1, "first");
+    send_message(&mut application_scope, 1, "first again");
+    send_message(&mut application_scope, 6, "too far ahead");
+
+    let delivered: Vec<MessageToSomeone> = drain_messages(&mut dan_receiver)
+        .into_iter()
+        .map(|message| match message {
+            Message::Text(text) => serde_json::from_str(&text).unwrap(),
+            other => panic!("a text frame expected, got {:?}", other),
+        })
+        .collect();
+    let contents: Vec<&str> = delivered.iter().map(|m| m.content.as_str()).collect();
+    assert_eq!(contents, vec!["first", "second"]);
+
+    // the sender receives the acknowledgements and the errors in the order of the processing
+    let reply_texts: Vec<String> = drain_messages(&mut ian_receiver)
+        .into_iter()
+        .map(|message| match message {
+            Message::Text(text) => text,
+            other => panic!("a text frame expected, got {:?}", other),
+        })
+        .collect();
+    let replies: Vec<(String, Option<String>)> = reply_texts
+        .iter()
+        .map(|text| {
+            let subject: Subject = serde_json::from_str(text).unwrap();
+            (subject.subject, subject.request_id)
+        })
+        .collect();
+    let expected_replies = [
+        (MESSAGE_ACCEPTED_SUBJECT, "1"),
+        (MESSAGE_ACCEPTED_SUBJECT, "2"),
+        (ERROR_SUBJECT, "1"),
+        (ERROR_SUBJECT, "6"),
+    ];
+    assert_eq!(
+        replies,
+        expected_replies
+            .map(|(subject, request_id)| (subject.to_string(), Some(request_id.to_string())))
+    );
+    for (reply_text, delivered_message) in reply_texts.iter().zip(&delivered) {
+        let message_accepted: MessageAccepted = serde_json::from_str(reply_text).unwrap();
+        assert_eq!(message_accepted.id, delivered_message.id);
+        assert_eq!(message_accepted.datetime, delivered_message.datetime);
+        assert_eq!(message_accepted.message_sequence_id, sequence_id);
+    }
+}
+
+#[test]
+fn test_messages_to_offline_users_are_delivered_on_reconnect() {
+    let mut application_scope = ApplicationScope::new();
+    let config = ServerConfig::default();
+    let (ian_sender, mut ian_receiver) = messages_channel(&config);
+    process_connection_command(
+        &mut application_scope,
+        &config,
+        ConnectionCommand::AssignConnectionToUser {
+            username: "ian".to_string(),
+            messages_sender: ian_sender.clone(),
+            acceptance: None,
+        },
+    );
+    let sequence_id = application_scope
+        .get_new_message_sequence(
+            "ian".to_string(),
+            "dan".to_string(),
+            config.maximum_message_sequences,
+        )
+        .unwrap()
+        .sequence_id;
+    // a session of dan that has been closed but has not been unassigned yet
+    let (dead_dan_sender, dead_dan_receiver) = messages_channel(&config);
+    drop(dead_dan_receiver);
+    process_connection_command(
+        &mut application_scope,
+        &config,
+        ConnectionCommand::AssignConnectionToUser {
+            username: "dan".to_string(),
+            messages_sender: dead_dan_sender.clone(),
+            acceptance: None,
+        },
+    );
+    // ian is told that dan has come online
+    assert_eq!(drain_messages(&mut ian_receiver).len(), 1);
+    for (index, content) in [(1, "first"), (2, "second")] {
+        process_connection_command(
+            &mut application_scope,
+            &config,
+            ConnectionCommand::SendMessageToAnotherUser {
+                sender_username: "ian".to_string(),
+                receiver_username: "dan".to_string(),
+                content: content.to_string(),
+                message_sequence_id: sequence_id,
+                message_sequence_index: index,
+                messages_sender: ian_sender.clone(),
+                request_id: None,
+            },
+        );
+    }
+    // the sender does not have to know if the receiver is online
+    assert_eq!(drain_messages(&mut ian_receiver).len(), 2);
+    process_connection_command(
+        &mut application_scope,
+        &config,
+        ConnectionCommand::UnassignConnectionFromUser {
+            username: "dan".to_string(),
+            messages_sender: dead_dan_sender,
+        },
+    );
+
+    let connect_dan = |application_scope: &mut ApplicationScope| {
+        let (dan_sender, mut dan_receiver) = messages_channel(&config);
+        process_connection_command(
+            application_scope,
+            &config,
+            ConnectionCommand::AssignConnectionToUser {
+                username: "dan".to_string(),
+                messages_sender: dan_sender,
+                acceptance: None,
+            },
+        );
+        let contents: Vec<String> = drain_messages(&mut dan_receiver)
+            .into_iter()
+            .map(|message| match message {
+                Message::Text(text) => {
+                    serde_json::from_str::<MessageToSomeone>(&text)
+                        .unwrap()
+                        .content
+                }
+                other => panic!("a text frame expected, got {:?}", other),
+            })
+            .collect();
+        contents
+    };
+    assert_eq!(connect_dan(&mut application_scope), vec!["first", "second"]);
+    // the messages have already been delivered to another session
+    assert_eq!(connect_dan(&mut application_scope), Vec::<String>::new());
+}
+
+#[test]
+fn test_long_backlog_is_delivered_in_pages() {
+    use crate::shard::ConnectionCommandRouter;
+
+    let config = ServerConfig {
+        outbound_queue_capacity: 4,
+        ..ServerConfig::default()
+    };
+    let (router, shard_receivers) = ConnectionCommandRouter::with_shards(1);
+    let mut application_scope =
+        ApplicationScope::load_shards(Box::new(crate::storage::MemoryStorage), &router)
+            .unwrap()
+            .remove(0);
+    let (ian_sender, _ian_receiver) = messages_channel(&ServerConfig::default());
+    process_connection_command(
+        &mut application_scope,
+        &config,
+        ConnectionCommand::AssignConnectionToUser {
+            username: "ian".to_string(),
+            messages_sender: ian_sender.clone(),
+            acceptance: None,
+        },
+    );
+    let sequence_id = application_scope
+        .get_new_message_sequence(
+            "ian".to_string(),
+            "dan".to_string(),
+            config.maximum_message_sequences,
+        )
+        .unwrap()
+        .sequence_id;
+    // far more messages than the outbound queue of dan can hold
+    let backlog: Vec<String> = (1..=20).map(|index| format!("message {}", index)).collect();
+    for (index, content) in backlog.iter().enumerate() {
+        process_connection_command(
+            &mut application_scope,
+            &config,
+            ConnectionCommand::SendMessageToAnotherUser {
+                sender_username: "ian".to_string(),
+                receiver_username: "dan".to_string(),
+                content: content.clone(),
+                message_sequence_id: sequence_id,
+                message_sequence_index: index as u16 + 1,
+                messages_sender: ian_sender.clone(),
+                request_id: None,
+            },
+        );
+    }
+
+    let (dan_sender, mut dan_receiver) = messages_channel(&config);
+    process_connection_command(
+        &mut application_scope,
+        &config,
+        ConnectionCommand::AssignConnectionToUser {
+            username: "dan".to_string(),
+            messages_sender: dan_sender,
+            acceptance: None,
+        },
+    );
+    // the client reads one message at a time and the shard sends the next page when it is asked,
+    // so the session is never closed as a slow consumer
+    let mut contents = Vec::new();
+    while let Some(message) = dan_receiver.try_recv() {
+        match message {
+            Message::Text(text) => contents.push(
+                serde_json::from_str::<MessageToSomeone>(&text)
+                    .unwrap()
+                    .content,
+            ),
+            other => panic!("a text frame expected, got {:?}", other),
+        }
+        for command in shard_receivers[0].try_iter() {
+            process_connection_command(&mut application_scope, &config, command);
+        }
+    }
+    assert_eq!(contents, backlog);
+    assert!(!application_scope.has_undelivered_messages(&"dan".to_string()));
+}
+#[test]
+fn test_new_messages_wait_behind_the_backlog() {
+    use crate::shard::ConnectionCommandRouter;
+
+    let config = ServerConfig {
+        outbound_queue_capacity: 4,
+        ..ServerConfig::default()
+    };
+    let (router, shard_receivers) = ConnectionCommandRouter::with_shards(1);
+    let mut application_scope =
+        ApplicationScope::load_shards(Box::new(crate::storage::MemoryStorage), &router)
+            .unwrap()
+            .remove(0);
+    let (ian_sender, _ian_receiver) = messages_channel(&ServerConfig::default());
+    process_connection_command(
+        &mut application_scope,
+        &config,
+        ConnectionCommand::AssignConnectionToUser {
+            username: "ian".to_string(),
+            messages_sender: ian_sender.clone(),
+            acceptance: None,
+        },
+    );
+    let sequence_id = application_scope
+        .get_new_message_sequence(
+            "ian".to_string(),
+            "dan".to_string(),
+            config.maximum_message_sequences,
+        )
+        .unwrap()
+        .sequence_id;
+    let send_to_dan = |application_scope: &mut ApplicationScope, index: u16| {
+        process_connection_command(
+            application_scope,
+            &config,
+            ConnectionCommand::SendMessageToAnotherUser {
+                sender_username: "ian".to_string(),
+                receiver_username: "dan".to_string(),
+                content: format!("message {}", index),
+                message_sequence_id: sequence_id,
+                message_sequence_index: index,
+                messages_sender: ian_sender.clone(),
+                request_id: None,
+            },
+        );
+    };
+    // several pages of the outbound queue of dan
+    for index in 1..=10 {
+        send_to_dan(&mut application_scope, index);
+    }
+
+    let (dan_sender, mut dan_receiver) = messages_channel(&config);
+    process_connection_command(
+        &mut application_scope,
+        &config,
+        ConnectionCommand::AssignConnectionToUser {
+            username: "dan".to_string(),
+            messages_sender: dan_sender,
+            acceptance: None,
+        },
+    );
+    // a new message arrives while dan has received only the first page
+    send_to_dan(&mut application_scope, 11);
+    let mut ids = Vec::new();
+    while let Some(message) = dan_receiver.try_recv() {
+        match message {
+            Message::Text(text) => {
+                ids.push(serde_json::from_str::<MessageToSomeone>(&text).unwrap().id)
+            }
+            other => panic!("a text frame expected, got {:?}", other),
+        }
+        for command in shard_receivers[0].try_iter() {
+            process_connection_command(&mut application_scope, &config, command);
+        }
+    }
+    assert_eq!(ids, (1..=11).collect::<Vec<u32>>());
+    assert!(!application_scope.has_undelivered_messages(&"dan".to_string()));
+}
+
+#[test]
+fn test_backlog_is_delivered_to_every_opened_session() {
+    use crate::shard::ConnectionCommandRouter;
+
+    let config = ServerConfig {
+        outbound_queue_capacity: 4,
+        ..ServerConfig::default()
+    };
+    let (router, shard_receivers) = ConnectionCommandRouter::with_shards(1);
+    let mut application_scope =
+        ApplicationScope::load_shards(Box::new(crate::storage::MemoryStorage), &router)
+            .unwrap()
+            .remove(0);
+    let (ian_sender, _ian_receiver) = messages_channel(&ServerConfig::default());
+    process_connection_command(
+        &mut application_scope,
+        &config,
+        ConnectionCommand::AssignConnectionToUser {
+            username: "ian".to_string(),
+            messages_sender: ian_sender.clone(),
+            acceptance: None,
+        },
+    );
+    let sequence_id = application_scope
+        .get_new_message_sequence(
+            "ian".to_string(),
+            "dan".to_string(),
+            config.maximum_message_sequences,
+        )
+        .unwrap()
+        .sequence_id;
+    for index in 1..=10 {
+        process_connection_command(
+            &mut application_scope,
+            &config,
+            ConnectionCommand::SendMessageToAnotherUser {
+                sender_username: "ian".to_string(),
+                receiver_username: "dan".to_string(),
+                content: format!("message {}", index),
+                message_sequence_id: sequence_id,
+                message_sequence_index: index,
+                messages_sender: ian_sender.clone(),
+                request_id: None,
+            },
+        );
+    }
+
+    let connect_dan = |application_scope: &mut ApplicationScope| {
+        let (dan_sender, dan_receiver) = messages_channel(&config);
+        process_connection_command(
+            application_scope,
+            &config,
+            ConnectionCommand::AssignConnectionToUser {
+                username: "dan".to_string(),
+                messages_sender: dan_sender,
+                acceptance: None,
+            },
+        );
+        dan_receiver
+    };
+    let mut first_receiver = connect_dan(&mut application_scope);
+    let mut second_receiver = connect_dan(&mut application_scope);
+    let read_all = |messages_receiver: &mut MessagesReceiver,
+                    application_scope: &mut ApplicationScope| {
+        let mut ids = Vec::new();
+        while let Some(message) = messages_receiver.try_recv() {
+            match message {
+                Message::Text(text) => {
+                    ids.push(serde_json::from_str::<MessageToSomeone>(&text).unwrap().id)
+                }
+                other => panic!("a text frame expected, got {:?}", other),
+            }
+            for command in shard_receivers[0].try_iter() {
+                process_connection_command(application_scope, &config, command);
+            }
+        }
+        ids
+    };
+    assert_eq!(
+        read_all(&mut first_receiver, &mut application_scope),
+        (1..=10).collect::<Vec<u32>>()
+    );
+    // the messages are kept until the second session has received them too
+    assert!(application_scope.has_undelivered_messages(&"dan".to_string()));
+    // the first page had been sent to the first session before the second one was opened
+    assert_eq!(
+        read_all(&mut second_receiver, &mut application_scope),
+        (3..=10).collect::<Vec<u32>>()
+    );
+    assert!(!application_scope.has_undelivered_messages(&"dan".to_string()));
+}
+
+#[test]
+fn test_message_changes_are_pushed_to_both_partners() {
+    use crate::dto::Subject;
+
+    let mut application_scope = ApplicationScope::new();
+    let config = ServerConfig::default();
+    let mut connect = |username: &str| {
+        let (messages_sender, messages_receiver) = messages_channel(&config);
+        process_connection_command(
+            &mut application_scope,
+            &config,
+            ConnectionCommand::AssignConnectionToUser {
+                username: username.to_string(),
+                messages_sender: messages_sender.clone(),
+                acceptance: None,
+            },
+        );
+        (messages_sender, messages_receiver)
+    };
+    let (ian_sender, mut ian_receiver) = connect("ian");
+    let (_ian_phone_sender, mut ian_phone_receiver) = connect("ian");
+    let (_dan_sender, mut dan_receiver) = connect("dan");
+    let id = application_scope
+        .add_message_to_private_conversation("ian".to_string(), "dan".to_string(), "hi".to_string())
+        .id;
+    let subjects = |messages_receiver: &mut MessagesReceiver| {
+        drain_messages(messages_receiver)
+            .into_iter()
+            .map(|message| match message {
+                Message::Text(text) => {
+                    let subject: Subject = serde_json::from_str(&text).unwrap();
+                    (subject.subject, subject.request_id)
+                }
+                other => panic!("a text frame expected, got {:?}", other),
+            })
+            .collect::<Vec<(String, Option<String>)>>()
+    };
+
+    for command in [
+        ConnectionCommand::EditPrivateMessage {
+            author_username: "ian".to_string(),
+            partner_username: "dan".to_string(),
+            id,
+            content: "hello".to_string(),
+            messages_sender: ian_sender.clone(),
+            request_id: Some("edit".to_string()),
+        },
+        ConnectionCommand::DeletePrivateMessage {
+            author_username: "ian".to_string(),
+            partner_username: "dan".to_string(),
+            id,
+            messages_sender: ian_sender.clone(),
+            request_id: Some("delete".to_string()),
+        },
+    ] {
+        process_connection_command(&mut application_scope, &config, command);
+    }
+    assert_eq!(
+        subjects(&mut ian_receiver),
+        vec![
+            (MESSAGE_EDITED_SUBJECT.to_string(), Some("edit".to_string())),
+            (
+                MESSAGE_DELETED_SUBJECT.to_string(),
+                Some("delete".to_string())
+            ),
+        ]
+    );
+    for other_session_receiver in [&mut ian_phone_receiver, &mut dan_receiver] {
+        assert_eq!(
+            subjects(other_session_receiver),
+            vec![
+                (MESSAGE_EDITED_SUBJECT.to_string(), None),
+                (MESSAGE_DELETED_SUBJECT.to_string(), None),
+            ]
+        );
+    }
+}
+
+#[test]
+fn test_typing_statuses_are_relayed_to_the_partner() {
+    use crate::dto::Subject;
+
+    let mut application_scope = ApplicationScope::new();
+    let config = ServerConfig {
+        typing_timeout: Duration::ZERO,
+        ..ServerConfig::default()
+    };
+    let mut connect = |username: &str| {
+        let (messages_sender, messages_receiver) = messages_channel(&config);
+        process_connection_command(
+            &mut application_scope,
+            &config,
+            ConnectionCommand::AssignConnectionToUser {
+                username: username.to_string(),
                 messages_sender,
-            } => {
-                print!("InitiateNewPrivateMessageSequence. sender_username={:?} receiver_username={:?}", &sender_username, &receiver_username);
-                let _ = messages_sender.send(Message::Text(attach_subject_and_serialize(
-                    Box::new(application_scope.get_new_message_sequence(
-                        sender_username.clone(),
-                        receiver_username.clone(),
-                    )),
-                    NEW_PRIVATE_MESSAGE_SEQUENCE_SUBJECT.to_string(),
-                )));
+                acceptance: None,
+            },
+        );
+        messages_receiver
+    };
+    let mut ian_receiver = connect("ian");
+    let mut dan_receiver = connect("dan");
+    let mut dan_phone_receiver = connect("dan");
+    let update_typing_status = |is_typing: bool| ConnectionCommand::UpdateTypingStatus {
+        typist_username: "ian".to_string(),
+        partner_username: "dan".to_string(),
+        is_typing,
+    };
+    let subjects = |messages_receiver: &mut MessagesReceiver| {
+        drain_messages(messages_receiver)
+            .into_iter()
+            .map(|message| match message {
+                Message::Text(text) => serde_json::from_str::<Subject>(&text).unwrap().subject,
+                other => panic!("a text frame expected, got {:?}", other),
+            })
+            .collect::<Vec<String>>()
+    };
+
+    for is_typing in [true, true, false, false] {
+        process_connection_command(
+            &mut application_scope,
+            &config,
+            update_typing_status(is_typing),
+        );
+    }
+    let expected_subjects = vec![
+        TYPING_STARTED_SUBJECT.to_string(),
+        TYPING_STOPPED_SUBJECT.to_string(),
+    ];
+    assert_eq!(subjects(&mut dan_receiver), expected_subjects);
+    assert_eq!(subjects(&mut dan_phone_receiver), expected_subjects);
+    assert_eq!(subjects(&mut ian_receiver), Vec::<String>::new());
+
+    // a start that is never stopped expires on the server
+    application_scope = ApplicationScope::new();
+    let (dan_sender, mut dan_receiver) = messages_channel(&config);
+    application_scope.add_session_sender_if_not_exceeded(
+        &"dan".to_string(),
+        dan_sender,
+        MAXIMUM_SESSIONS_PER_USER,
+    );
+    process_connection_command(&mut application_scope, &config, update_typing_status(true));
+    expire_typing_statuses(&mut application_scope, &config);
+    assert_eq!(subjects(&mut dan_receiver), expected_subjects);
+}
+
+#[test]
+fn test_presence_changes_are_pushed_to_conversation_partners() {
+    use crate::dto::{PresenceStatus, Subject};
+
+    let mut application_scope = ApplicationScope::new();
+    let config = ServerConfig::default();
+    application_scope.add_message_to_private_conversation(
+        "ian".to_string(),
+        "dan".to_string(),
+        "hi".to_string(),
+    );
+    let (ian_sender, mut ian_receiver) = messages_channel(&config);
+    let (dan_sender, _dan_receiver) = messages_channel(&config);
+    let (chris_sender, mut chris_receiver) = messages_channel(&config);
+    for (username, messages_sender) in [
+        ("ian", &ian_sender),
+        ("dan", &dan_sender),
+        ("chris", &chris_sender),
+    ] {
+        process_connection_command(
+            &mut application_scope,
+            &config,
+            ConnectionCommand::AssignConnectionToUser {
+                username: username.to_string(),
+                messages_sender: messages_sender.clone(),
+                acceptance: None,
+            },
+        );
+    }
+    process_connection_command(
+        &mut application_scope,
+        &config,
+        ConnectionCommand::UnassignConnectionFromUser {
+            username: "dan".to_string(),
+            messages_sender: dan_sender,
+        },
+    );
+    let presences: Vec<Presence> = drain_messages(&mut ian_receiver)
+        .into_iter()
+        .map(|message| match message {
+            Message::Text(text) => {
+                let subject: Subject = serde_json::from_str(&text).unwrap();
+                assert_eq!(subject.subject, PRESENCE_SUBJECT);
+                serde_json::from_str(&text).unwrap()
+            }
+            other => panic!("a text frame expected, got {:?}", other),
+        })
+        .collect();
+    assert_eq!(
+        presences
+            .iter()
+            .map(|presence| (presence.username.as_str(), presence.status))
+            .collect::<Vec<(&str, PresenceStatus)>>(),
+        vec![
+            ("dan", PresenceStatus::Online),
+            ("dan", PresenceStatus::Offline)
+        ]
+    );
+    assert!(presences[1].last_seen.is_some());
+    // chris has no conversation with dan
+    assert_eq!(drain_messages(&mut chris_receiver).len(), 0);
+
+    process_connection_command(
+        &mut application_scope,
+        &config,
+        ConnectionCommand::GetPresence {
+            username: "chris".to_string(),
+            usernames: vec!["dan".to_string(), "ian".to_string()],
+            messages_sender: chris_sender,
+            request_id: None,
+        },
+    );
+    let Some(Message::Text(text)) = chris_receiver.try_recv() else {
+        panic!("a reply expected");
+    };
+    let response: GetPresenceResponse = serde_json::from_str(&text).unwrap();
+    assert_eq!(response.presences[0], presences[1]);
+    assert_eq!(response.presences[1].status, PresenceStatus::Online);
+}
+
+#[test]
+fn test_group_messages_are_fanned_out_to_members() {
+    use crate::dto::Subject;
+
+    let mut application_scope = ApplicationScope::new();
+    let config = ServerConfig::default();
+    let mut connect = |username: &str| {
+        let (messages_sender, messages_receiver) = messages_channel(&config);
+        process_connection_command(
+            &mut application_scope,
+            &config,
+            ConnectionCommand::AssignConnectionToUser {
+                username: username.to_string(),
+                messages_sender: messages_sender.clone(),
+                acceptance: None,
+            },
+        );
+        (messages_sender, messages_receiver)
+    };
+    let (ian_sender, mut ian_receiver) = connect("ian");
+    let (dan_sender, mut dan_receiver) = connect("dan");
+    let (_chris_sender, mut chris_receiver) = connect("chris");
+    let subjects = |messages_receiver: &mut MessagesReceiver| {
+        drain_messages(messages_receiver)
+            .into_iter()
+            .map(|message| match message {
+                Message::Text(text) => serde_json::from_str::<Subject>(&text).unwrap().subject,
+                other => panic!("a text frame expected, got {:?}", other),
+            })
+            .collect::<Vec<String>>()
+    };
+
+    for command in [
+        ConnectionCommand::CreateGroup {
+            owner_username: "ian".to_string(),
+            name: "team".to_string(),
+            member_usernames: vec!["dan".to_string()],
+            messages_sender: ian_sender.clone(),
+            request_id: None,
+        },
+        ConnectionCommand::InviteToGroup {
+            inviter_username: "ian".to_string(),
+            group_id: 1,
+            username: "chris".to_string(),
+            messages_sender: ian_sender.clone(),
+            request_id: None,
+        },
+        ConnectionCommand::InitiateNewGroupMessageSequence {
+            sender_username: "ian".to_string(),
+            group_id: 1,
+            messages_sender: ian_sender.clone(),
+            request_id: None,
+        },
+        ConnectionCommand::SendMessageToGroup {
+            sender_username: "ian".to_string(),
+            group_id: 1,
+            content: "hi".to_string(),
+            message_sequence_id: 0,
+            message_sequence_index: 1,
+            messages_sender: ian_sender.clone(),
+            request_id: None,
+        },
+        ConnectionCommand::RemoveFromGroup {
+            remover_username: "ian".to_string(),
+            group_id: 1,
+            username: "dan".to_string(),
+            messages_sender: ian_sender.clone(),
+            request_id: None,
+        },
+    ] {
+        process_connection_command(&mut application_scope, &config, command);
+    }
+    assert_eq!(
+        subjects(&mut ian_receiver),
+        vec![
+            CREATE_GROUP_SUBJECT,
+            INVITE_TO_GROUP_SUBJECT,
+            NEW_GROUP_MESSAGE_SEQUENCE_SUBJECT,
+            GROUP_MESSAGE_ACCEPTED_SUBJECT,
+            REMOVE_FROM_GROUP_SUBJECT,
+        ]
+    );
+    assert_eq!(
+        subjects(&mut dan_receiver),
+        vec![
+            GROUP_UPDATED_SUBJECT,
+            GROUP_UPDATED_SUBJECT,
+            GROUP_MESSAGE_SUBJECT,
+            GROUP_UPDATED_SUBJECT,
+        ]
+    );
+    assert_eq!(
+        subjects(&mut chris_receiver),
+        vec![
+            GROUP_UPDATED_SUBJECT,
+            GROUP_MESSAGE_SUBJECT,
+            GROUP_UPDATED_SUBJECT
+        ]
+    );
+
+    // the removed member cannot read the group anymore
+    process_connection_command(
+        &mut application_scope,
+        &config,
+        ConnectionCommand::GetGroupHistory {
+            reader_username: "dan".to_string(),
+            group_id: 1,
+            before_id: None,
+            limit: 10,
+            messages_sender: dan_sender,
+            request_id: None,
+        },
+    );
+    let Some(Message::Text(text)) = dan_receiver.try_recv() else {
+        panic!("a reply expected");
+    };
+    let error_response: dto::ErrorResponse = serde_json::from_str(&text).unwrap();
+    assert_eq!(error_response.code, ErrorCode::GroupNotFound);
+}
+
+#[test]
+fn test_channel_messages_are_broadcast_to_subscribers() {
+    use crate::dto::Subject;
+
+    let mut application_scope = ApplicationScope::new();
+    let config = ServerConfig::default();
+    let mut sessions = Vec::new();
+    for username in ["ian", "dan", "chris"] {
+        let (messages_sender, messages_receiver) = messages_channel(&config);
+        process_connection_command(
+            &mut application_scope,
+            &config,
+            ConnectionCommand::AssignConnectionToUser {
+                username: username.to_string(),
+                messages_sender: messages_sender.clone(),
+                acceptance: None,
+            },
+        );
+        sessions.push((messages_sender, messages_receiver));
+    }
+    let [(ian_sender, ian_receiver), (dan_sender, dan_receiver), (_, chris_receiver)] =
+        &mut sessions[..]
+    else {
+        unreachable!()
+    };
+    let send_message =
+        |sender_username: &str, messages_sender| ConnectionCommand::SendMessageToChannel {
+            sender_username: sender_username.to_string(),
+            channel_name: "rust".to_string(),
+            content: "hi".to_string(),
+            messages_sender,
+            request_id: None,
+        };
+    for command in [
+        ConnectionCommand::CreateChannel {
+            creator_username: "ian".to_string(),
+            name: "rust".to_string(),
+            topic: "all about rust".to_string(),
+            messages_sender: ian_sender.clone(),
+            request_id: None,
+        },
+        send_message("ian", ian_sender.clone()),
+        send_message("dan", dan_sender.clone()),
+        ConnectionCommand::JoinChannel {
+            username: "dan".to_string(),
+            name: "rust".to_string(),
+            history_limit: 10,
+            messages_sender: dan_sender.clone(),
+            request_id: None,
+        },
+        send_message("ian", ian_sender.clone()),
+    ] {
+        process_connection_command(&mut application_scope, &config, command);
+    }
+    let subjects = |messages_receiver: &mut MessagesReceiver| {
+        drain_messages(messages_receiver)
+            .into_iter()
+            .map(|message| match message {
+                Message::Text(text) => serde_json::from_str::<Subject>(&text).unwrap().subject,
+                other => panic!("a text frame expected, got {:?}", other),
+            })
+            .collect::<Vec<String>>()
+    };
+    assert_eq!(
+        subjects(ian_receiver),
+        vec![
+            CREATE_CHANNEL_SUBJECT,
+            CHANNEL_MESSAGE_ACCEPTED_SUBJECT,
+            CHANNEL_MESSAGE_ACCEPTED_SUBJECT,
+        ]
+    );
+    // dan cannot write before joining, and he receives the earlier message on joining
+    let dan_messages: Vec<String> = drain_messages(dan_receiver)
+        .into_iter()
+        .map(|message| message.into_text().unwrap())
+        .collect();
+    assert_eq!(dan_messages.len(), 3);
+    let error_response: dto::ErrorResponse = serde_json::from_str(&dan_messages[0]).unwrap();
+    assert_eq!(error_response.code, ErrorCode::NotChannelSubscriber);
+    let join_channel_response: JoinChannelResponse =
+        serde_json::from_str(&dan_messages[1]).unwrap();
+    assert_eq!(join_channel_response.channel.subscriber_count, 2);
+    assert_eq!(join_channel_response.messages.len(), 1);
+    let channel_message: ChannelMessage = serde_json::from_str(&dan_messages[2]).unwrap();
+    assert_eq!(channel_message.id, 2);
+    assert_eq!(subjects(chris_receiver), Vec::<String>::new());
+}
+
+#[test]
+fn test_shards_deliver_to_each_other() {
+    use crate::dto::{PresenceStatus, Subject};
+    use crate::shard::{shard_index, ConnectionCommandRouter};
+    use crossbeam_channel::Receiver;
+
+    let config = ServerConfig::default();
+    let shard_count = 3;
+    let (router, shard_receivers) = ConnectionCommandRouter::with_shards(shard_count);
+    let mut shards =
+        ApplicationScope::load_shards(Box::new(crate::storage::MemoryStorage), &router).unwrap();
+    // applies the commands that the shards send to each other until none is left
+    let settle = |shards: &mut Vec<ApplicationScope>, shard_receivers: &Vec<Receiver<_>>| loop {
+        let mut is_idle = true;
+        for (application_scope, shard_receiver) in shards.iter_mut().zip(shard_receivers) {
+            while let Ok(command) = shard_receiver.try_recv() {
+                process_connection_command(application_scope, &config, command);
+                is_idle = false;
             }
         }
-        // print hashmap
-        for (key, value) in &application_scope.chat_users {
-            print!(
-                "User {} has {} opened connections. ",
-                key,
-                value.opened_sessions_senders.len()
-            );
+        if is_idle {
+            break;
         }
-        println!();
+    };
+    // the users and their conversation belong to three different shards
+    let usernames = ["ian", "dan", "chris", "eve", "kim", "lee", "max", "sam"];
+    let home_shard = |username: &str| shard_index(&ShardKey::User(username), shard_count);
+    let (ian, dan) = usernames
+        .iter()
+        .flat_map(|user1| usernames.iter().map(move |user2| (user1, user2)))
+        .map(|(user1, user2)| (user1.to_string(), user2.to_string()))
+        .find(|(user1, user2)| {
+            let conversation_shard = shard_index(
+                &ShardKey::Conversation(PrivateConversationPartnersHashmapKey::new(user1, user2)),
+                shard_count,
+            );
+            let mut shard_indices = vec![home_shard(user1), home_shard(user2), conversation_shard];
+            shard_indices.sort();
+            shard_indices.dedup();
+            shard_indices.len() == 3
+        })
+        .unwrap();
+
+    let (ian_sender, mut ian_receiver) = messages_channel(&config);
+    router
+        .send(ConnectionCommand::AssignConnectionToUser {
+            username: ian.clone(),
+            messages_sender: ian_sender.clone(),
+            acceptance: None,
+        })
+        .unwrap();
+    router
+        .send(ConnectionCommand::InitiateNewPrivateMessageSequence {
+            sender_username: ian.clone(),
+            receiver_username: dan.clone(),
+            messages_sender: ian_sender.clone(),
+            request_id: None,
+        })
+        .unwrap();
+    settle(&mut shards, &shard_receivers);
+    let Some(Message::Text(text)) = ian_receiver.try_recv() else {
+        panic!("a new message sequence expected");
+    };
+    let sequence_id = serde_json::from_str::<dto::NewPrivateMessageSequenceResponse>(&text)
+        .unwrap()
+        .sequence_id;
+    let send_to_dan = |index: u16, content: &str| {
+        router
+            .send(ConnectionCommand::SendMessageToAnotherUser {
+                sender_username: ian.clone(),
+                receiver_username: dan.clone(),
+                content: content.to_string(),
+                message_sequence_id: sequence_id,
+                message_sequence_index: index,
+                messages_sender: ian_sender.clone(),
+                request_id: None,
+            })
+            .unwrap();
+    };
+    // dan is offline, so his shard keeps the message
+    send_to_dan(1, "first");
+    settle(&mut shards, &shard_receivers);
+    assert_eq!(drain_messages(&mut ian_receiver).len(), 1);
+    assert!(shards[home_shard(&dan)].chat_users.contains_key(&dan));
+
+    // the shard of the conversation delivers the message when dan comes online
+    let (dan_sender, mut dan_receiver) = messages_channel(&config);
+    router
+        .send(ConnectionCommand::AssignConnectionToUser {
+            username: dan.clone(),
+            messages_sender: dan_sender.clone(),
+            acceptance: None,
+        })
+        .unwrap();
+    settle(&mut shards, &shard_receivers);
+    send_to_dan(2, "second");
+    settle(&mut shards, &shard_receivers);
+    let contents: Vec<String> = drain_messages(&mut dan_receiver)
+        .into_iter()
+        .map(|message| match message {
+            Message::Text(text) => {
+                serde_json::from_str::<MessageToSomeone>(&text)
+                    .unwrap()
+                    .content
+            }
+            other => panic!("a text frame expected, got {:?}", other),
+        })
+        .collect();
+    assert_eq!(contents, vec!["first", "second"]);
+    // ian is told that dan has come online and that his message has been accepted
+    let subjects: Vec<String> = drain_messages(&mut ian_receiver)
+        .into_iter()
+        .map(|message| match message {
+            Message::Text(text) => serde_json::from_str::<Subject>(&text).unwrap().subject,
+            other => panic!("a text frame expected, got {:?}", other),
+        })
+        .collect();
+    assert_eq!(subjects, vec![PRESENCE_SUBJECT, MESSAGE_ACCEPTED_SUBJECT]);
+
+    // every shard adds its part of the replies
+    router
+        .send(ConnectionCommand::GetUnreadCounts {
+            username: dan.clone(),
+            messages_sender: dan_sender.clone(),
+            request_id: None,
+        })
+        .unwrap();
+    router
+        .send(ConnectionCommand::GetPresence {
+            username: dan.clone(),
+            usernames: vec!["nobody".to_string(), ian.clone(), dan.clone()],
+            messages_sender: dan_sender.clone(),
+            request_id: None,
+        })
+        .unwrap();
+    settle(&mut shards, &shard_receivers);
+    let replies = drain_messages(&mut dan_receiver);
+    assert_eq!(replies.len(), 2);
+    let Message::Text(text) = &replies[0] else {
+        panic!("the unread counts expected");
+    };
+    let unread_counts: UnreadCountsResponse = serde_json::from_str(text).unwrap();
+    assert_eq!(unread_counts.unread_counts.len(), 1);
+    assert_eq!(unread_counts.unread_counts[0].partner_username, ian);
+    assert_eq!(unread_counts.unread_counts[0].unread_count, 2);
+    let Message::Text(text) = &replies[1] else {
+        panic!("the presences expected");
+    };
+    let response: GetPresenceResponse = serde_json::from_str(text).unwrap();
+    assert_eq!(
+        response
+            .presences
+            .iter()
+            .map(|presence| (presence.username.as_str(), presence.status))
+            .collect::<Vec<(&str, PresenceStatus)>>(),
+        vec![
+            ("nobody", PresenceStatus::Offline),
+            (ian.as_str(), PresenceStatus::Online),
+            (dan.as_str(), PresenceStatus::Online)
+        ]
+    );
+
+    // the group belongs to the shard of its owner and its messages reach the other shards
+    router
+        .send(ConnectionCommand::CreateGroup {
+            owner_username: ian.clone(),
+            name: "team".to_string(),
+            member_usernames: vec![dan.clone()],
+            messages_sender: ian_sender.clone(),
+            request_id: None,
+        })
+        .unwrap();
+    settle(&mut shards, &shard_receivers);
+    let Some(Message::Text(text)) = ian_receiver.try_recv() else {
+        panic!("the group expected");
+    };
+    let group_info: GroupInfo = serde_json::from_str(&text).unwrap();
+    assert_eq!(
+        shard_index(&ShardKey::Group(group_info.group_id), shard_count),
+        home_shard(&ian)
+    );
+    let Some(Message::Text(text)) = dan_receiver.try_recv() else {
+        panic!("the group update expected");
+    };
+    let subject: Subject = serde_json::from_str(&text).unwrap();
+    assert_eq!(subject.subject, GROUP_UPDATED_SUBJECT);
+}
+
+#[test]
+fn test_shutdown_closes_all_sessions() {
+    use crate::dto::Subject;
+    use crate::shard::ConnectionCommandRouter;
+
+    let config = ServerConfig::default();
+    let (router, mut shard_receivers) = ConnectionCommandRouter::with_shards(1);
+    let mut application_scope = ApplicationScope::new();
+    let mut session_receivers = Vec::new();
+    for username in ["ian", "ian", "dan"] {
+        let (messages_sender, messages_receiver) = messages_channel(&config);
+        process_connection_command(
+            &mut application_scope,
+            &config,
+            ConnectionCommand::AssignConnectionToUser {
+                username: username.to_string(),
+                messages_sender,
+                acceptance: None,
+            },
+        );
+        session_receivers.push(messages_receiver);
     }
+    router
+        .send(ConnectionCommand::Shutdown { shard_index: 0 })
+        .unwrap();
+    // the handler stops after the shutdown although the router could still send commands
+    let handler_config = config.clone();
+    let shard_receiver = shard_receivers.remove(0);
+    std::thread::spawn(move || {
+        handle_connection_commands(shard_receiver, application_scope, handler_config)
+    })
+    .join()
+    .unwrap();
+    for mut session_receiver in session_receivers {
+        let messages = drain_messages(&mut session_receiver);
+        assert_eq!(messages.len(), 2);
+        let Message::Text(text) = &messages[0] else {
+            panic!("the shutdown event expected, got {:?}", messages[0]);
+        };
+        let subject: Subject = serde_json::from_str(text).unwrap();
+        assert_eq!(subject.subject, SERVER_SHUTTING_DOWN_SUBJECT);
+        let shutdown: ServerShuttingDown = serde_json::from_str(text).unwrap();
+        assert_eq!(
+            shutdown.close_timeout_ms,
+            config.shutdown_timeout.as_millis() as u64
+        );
+        assert!(matches!(
+            &messages[1],
+            Message::Close(Some(close_frame)) if close_frame.code == CloseCode::Away
+        ));
+    }
+}
+
+#[test]
+fn test_messages_to_unknown_users_are_refused() {
+    let config = ServerConfig::default();
+    let mut application_scope = ApplicationScope::new();
+    let (ian_sender, mut ian_receiver) = messages_channel(&config);
+    let mut error_code = |command: ConnectionCommand| {
+        process_connection_command(&mut application_scope, &config, command);
+        match drain_messages(&mut ian_receiver).as_slice() {
+            [Message::Text(text)] => {
+                serde_json::from_str::<serde_json::Value>(text).unwrap()["code"].clone()
+            }
+            other => panic!("an error response expected, got {:?}", other),
+        }
+    };
+
+    assert_eq!(
+        error_code(ConnectionCommand::InitiateNewPrivateMessageSequence {
+            sender_username: "ian".to_string(),
+            receiver_username: "nobody".to_string(),
+            messages_sender: ian_sender.clone(),
+            request_id: None,
+        }),
+        "user-not-found"
+    );
+    assert_eq!(
+        error_code(ConnectionCommand::SendMessageToAnotherUser {
+            sender_username: "ian".to_string(),
+            receiver_username: "nobody".to_string(),
+            content: "hello".to_string(),
+            message_sequence_id: 0,
+            message_sequence_index: 1,
+            messages_sender: ian_sender,
+            request_id: None,
+        }),
+        "user-not-found"
+    );
 }
@@ -1,3 +1,4 @@
+use chrono::{DateTime, Utc};
 use erased_serde as erased;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
@@ -25,11 +26,160 @@ pub struct MessageToSomeone {
     pub content: String,
     pub sender_username: String,
     pub datetime: String,
+    /// the time of the last edit of the message. Absent if the message has never been edited.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited_datetime: Option<String>,
+    /// true for the tombstones of deleted messages. Their content is empty.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub is_deleted: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct EditMessageRequest {
+    pub partner_username: String,
+    pub id: u32,
+    pub content: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct DeleteMessageRequest {
+    pub partner_username: String,
+    pub id: u32,
+}
+
+/// The server sends it to all the sessions of both conversation partners when a message is edited.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MessageEdited {
+    pub id: u32,
+    pub sender_username: String,
+    pub receiver_username: String,
+    pub content: String,
+    pub edited_datetime: String,
+}
+
+/// The server sends it to all the sessions of both conversation partners when a message is deleted.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MessageDeleted {
+    pub id: u32,
+    pub sender_username: String,
+    pub receiver_username: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MessageRevisionsRequest {
+    pub partner_username: String,
+    pub id: u32,
+}
+
+/// A content that a message had before it was edited.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MessageRevision {
+    pub content: String,
+    /// when the content was replaced by a newer one.
+    pub replaced_datetime: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MessageRevisionsResponse {
+    pub partner_username: String,
+    pub id: u32,
+    /// the previous contents of the message, oldest first.
+    pub revisions: Vec<MessageRevision>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PrivateHistoryRequest {
+    pub partner_username: String,
+    /// only the messages with lower ids are returned. The latest messages are returned if it is absent.
+    #[serde(default)]
+    pub before_id: Option<u32>,
+    /// the maximum number of messages in the response.
+    pub limit: u16,
+}
+
+/// A page of the history of a private conversation.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PrivateHistoryResponse {
+    pub partner_username: String,
+    /// the messages of the page, oldest first.
+    pub messages: Vec<MessageToSomeone>,
+    /// true if there are older messages than the messages of the page.
+    pub has_more: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MarkReadRequest {
+    pub partner_username: String,
+    /// the id of the last message that the user has read.
+    pub id: u32,
+}
+
+/// The server sends it to the sessions of both conversation partners when one of them reads
+/// messages of the conversation.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ReadReceipt {
+    pub reader_username: String,
+    pub partner_username: String,
+    /// all the messages with ids up to this one have been read.
+    pub last_read_id: u32,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct UnreadCount {
+    pub partner_username: String,
+    pub last_read_id: u32,
+    /// how many messages from the partner the user has not read.
+    pub unread_count: u32,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct UnreadCountsResponse {
+    pub unread_counts: Vec<UnreadCount>,
+    #[serde(default)]
+    pub group_unread_counts: Vec<GroupUnreadCount>,
+}
+
+/// The server sends it to the sender of a message when the message is accepted.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MessageAccepted {
+    /// the id that the server has assigned to the message.
+    pub id: u32,
+    pub receiver_username: String,
+    /// the same as in MessageToSomeone.
+    pub datetime: String,
+    pub message_sequence_id: u32,
+    pub message_sequence_index: u16,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Subject {
     pub subject: String,
+    /// An optional id chosen by the client. The server echoes it in every reply to the request, so
+    /// the client can match replies with requests without waiting for each reply.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// The reply to authenticate and resume requests.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AuthenticationResponse {
+    pub login: String,
+    /// lets the client authenticate with a resume request, e.g. after reconnecting, without
+    /// sending the password again.
+    pub session_token: String,
+    pub session_token_expires_at: DateTime<Utc>,
+}
+
+/// A client sends it instead of the login credentials to authenticate with a session token.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ResumeRequest {
+    pub session_token: String,
+}
+
+/// The reply to a logout request. The session token of the connection cannot be used any more.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LogoutResponse {
+    pub login: String,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -43,15 +193,488 @@ pub struct NewPrivateMessageSequenceResponse {
     pub sequence_id: u32,
 }
 
-pub const MESSAGE_SUBJECT: &'static str = "message";
-pub const AUTHENTICATE_SUBJECT: &'static str = "authenticate";
-pub const NEW_MESSAGE_SUBJECT: &'static str = "new-message";
-pub const NEW_PRIVATE_MESSAGE_SEQUENCE_SUBJECT: &'static str = "new-private-message-sequence";
+/// The client sends it with the typing-started and typing-stopped subjects.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TypingRequest {
+    pub partner_username: String,
+}
+
+/// The server relays it to the sessions of the partner with the typing-started and typing-stopped
+/// subjects. It is not stored anywhere.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TypingNotification {
+    pub typist_username: String,
+}
+
+/// What a member of a group conversation is allowed to do. The owner can do everything, admins
+/// can invite, remove members and rename the group, members can only talk and leave.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GroupRole {
+    Member,
+    Admin,
+    Owner,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct GroupMemberInfo {
+    pub username: String,
+    pub role: GroupRole,
+}
+
+/// The server sends it in reply to the requests that change a group and pushes it with the
+/// group-updated subject to the members and to the users who have just left the group.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct GroupInfo {
+    pub group_id: u32,
+    pub name: String,
+    /// in the order in which the members have joined.
+    pub members: Vec<GroupMemberInfo>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CreateGroupRequest {
+    pub name: String,
+    /// the users who are invited to the group along with its creator.
+    #[serde(default)]
+    pub member_usernames: Vec<String>,
+}
+
+/// The client sends it with the invite-to-group and remove-from-group subjects.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GroupMemberRequest {
+    pub group_id: u32,
+    pub username: String,
+}
+
+/// The client sends it with the leave-group and new-group-message-sequence subjects.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GroupRequest {
+    pub group_id: u32,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RenameGroupRequest {
+    pub group_id: u32,
+    pub name: String,
+}
+
+/// Only the owner can change roles. Making another member the owner turns the current owner into an
+/// admin.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SetGroupRoleRequest {
+    pub group_id: u32,
+    pub username: String,
+    pub role: GroupRole,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct NewGroupMessageSequenceResponse {
+    pub group_id: u32,
+    pub sequence_id: u32,
+}
+
+/// A client sends it to the server to write to a group. The same as MessageFromSomeone.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MessageToGroup {
+    pub group_id: u32,
+    pub message_sequence_id: u32,
+    pub message_sequence_index: u16,
+    pub content: String,
+}
+
+/// The server sends it to the other members of the group.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GroupMessage {
+    pub group_id: u32,
+    pub id: u32,
+    pub content: String,
+    pub sender_username: String,
+    pub datetime: String,
+}
+
+/// The server sends it to the sender of a group message when the message is accepted.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GroupMessageAccepted {
+    pub group_id: u32,
+    /// the id that the server has assigned to the message.
+    pub id: u32,
+    pub datetime: String,
+    pub message_sequence_id: u32,
+    pub message_sequence_index: u16,
+}
+
+/// The same as PrivateHistoryRequest but for a group.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GroupHistoryRequest {
+    pub group_id: u32,
+    #[serde(default)]
+    pub before_id: Option<u32>,
+    pub limit: u16,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GroupHistoryResponse {
+    pub group_id: u32,
+    /// oldest first.
+    pub messages: Vec<MessageToSomeone>,
+    pub has_more: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MarkGroupReadRequest {
+    pub group_id: u32,
+    pub id: u32,
+}
+
+/// The server sends it to all members of the group when the read watermark of a member moves.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GroupReadReceipt {
+    pub group_id: u32,
+    pub reader_username: String,
+    pub last_read_id: u32,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GroupUnreadCount {
+    pub group_id: u32,
+    pub last_read_id: u32,
+    /// how many messages from the other members the user has not read.
+    pub unread_count: u32,
+}
+
+/// The same as MessageSequenceGapExpired but for a group.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GroupMessageSequenceGapExpired {
+    pub group_id: u32,
+    pub message_sequence_id: u32,
+    pub missing_indices: Vec<u32>,
+    pub discarded_indices: Vec<u32>,
+}
+
+/// The metadata of a public channel.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ChannelInfo {
+    pub name: String,
+    pub topic: String,
+    pub created_by: String,
+    pub created_at: String,
+    pub subscriber_count: u32,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CreateChannelRequest {
+    pub name: String,
+    #[serde(default)]
+    pub topic: String,
+}
+
+/// The client sends it with the leave-channel and get-channel-info subjects.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChannelRequest {
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct JoinChannelRequest {
+    pub name: String,
+    /// how many recent messages of the channel the user wants to receive. 0 if none.
+    #[serde(default)]
+    pub history_limit: u16,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct JoinChannelResponse {
+    pub channel: ChannelInfo,
+    /// the recent messages of the channel, oldest first.
+    pub messages: Vec<MessageToSomeone>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChannelListResponse {
+    /// sorted by name.
+    pub channels: Vec<ChannelInfo>,
+}
+
+/// A client sends it to the server to write to a channel that he has joined.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MessageToChannel {
+    pub channel_name: String,
+    pub content: String,
+}
+
+/// The server sends it to all the subscribers of the channel except the sender.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChannelMessage {
+    pub channel_name: String,
+    pub id: u32,
+    pub content: String,
+    pub sender_username: String,
+    pub datetime: String,
+}
+
+/// The server sends it to the sender of a channel message when the message is accepted.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ChannelMessageAccepted {
+    pub channel_name: String,
+    /// the id that the server has assigned to the message.
+    pub id: u32,
+    pub datetime: String,
+}
+
+/// Whether a user can be reached at the moment.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PresenceStatus {
+    /// The user has opened sessions and has recently done something in them.
+    Online,
+    /// The user has opened sessions but has not done anything in them for a while.
+    Away,
+    /// The user has no opened sessions.
+    #[default]
+    Offline,
+}
+
+/// The server pushes it with the presence subject to the users who have a conversation with the
+/// user whose presence has changed. It is also a part of the reply to get-presence.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Presence {
+    pub username: String,
+    pub status: PresenceStatus,
+    /// when the last session of an offline user was closed. None if the user is not offline or has
+    /// never been seen.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GetPresenceRequest {
+    pub usernames: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GetPresenceResponse {
+    /// in the order of the requested usernames.
+    pub presences: Vec<Presence>,
+}
+
+/// A client sends it to create an account. The account can be used for authentication right away.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RegistrationRequest {
+    pub username: String,
+    pub password: String,
+    /// required if the server accepts only invited users.
+    #[serde(default)]
+    pub invite_code: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RegistrationResponse {
+    pub username: String,
+}
+
+/// An administrator sends it to add a user.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AddUserRequest {
+    pub username: String,
+    pub password: String,
+    #[serde(default)]
+    pub is_admin: bool,
+}
+
+/// An administrator sends it to disable or enable a user. Disabled users cannot authenticate.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct SetUserDisabledRequest {
+    pub username: String,
+    pub is_disabled: bool,
+}
+
+/// The reply to add-user and set-user-disabled.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct UserInfo {
+    pub username: String,
+    pub is_admin: bool,
+    pub is_disabled: bool,
+}
+
+/// How many ephemeral events have been dropped and how many sessions have been disconnected because
+/// the sessions of the user did not read their messages fast enough.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct UserOutboundQueueMetrics {
+    pub username: String,
+    pub dropped_frames: u64,
+    pub forced_disconnects: u64,
+}
+
+/// The reply to get-outbound-queue-metrics. Only the users whose sessions have been slow are listed.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct OutboundQueueMetricsResponse {
+    pub users: Vec<UserOutboundQueueMetrics>,
+}
+
+/// The server sends it to every session right before the close frame when it is shutting down. The
+/// messages queued before it are still delivered.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ServerShuttingDown {
+    /// how long the server waits for the clients to close their connections, in milliseconds.
+    pub close_timeout_ms: u64,
+}
+
+/// The server sends it to the sender of a message sequence when some messages of the sequence have
+/// not arrived in time. The messages that were waiting for them are discarded and must be resent.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct MessageSequenceGapExpired {
+    pub receiver_username: String,
+    pub message_sequence_id: u32,
+    pub missing_indices: Vec<u32>,
+    pub discarded_indices: Vec<u32>,
+}
+
+/// Machine-readable reason of a failed request. Clients should rely on it instead of the message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorCode {
+    /// The frame is not a valid JSON or does not match the structure expected for its subject.
+    MalformedRequest,
+    /// The subject is not supported by the server.
+    UnknownSubject,
+    /// The request requires the connection to be authenticated first.
+    NotAuthenticated,
+    /// The connection is already authenticated. The client should log out before authenticating
+    /// again.
+    AlreadyAuthenticated,
+    /// Wrong login or password.
+    InvalidCredentials,
+    /// Too many authentication attempts have failed for the login or the address of the client
+    /// recently. The message tells when the client can try again.
+    TooManyFailedLogins,
+    /// The user has already reached the maximum number of WebSocket connections.
+    TooManySessions,
+    /// The message refers to a message sequence that the sender has never received or that has been
+    /// forgotten.
+    UnknownMessageSequence,
+    /// The message is a duplicate of an already received message of its sequence.
+    OutOfOrderMessage,
+    /// Too many messages of the sequence are already waiting for a missing message.
+    ReorderBufferFull,
+    /// The message does not exist or has been deleted.
+    MessageNotFound,
+    /// Only the author of a message can change it.
+    NotMessageAuthor,
+    /// The group does not exist or the user is not its member.
+    GroupNotFound,
+    /// The user that the request refers to is not a member of the group.
+    NotGroupMember,
+    /// The user is already a member of the group.
+    AlreadyGroupMember,
+    /// The role of the user in the group does not allow the request.
+    InsufficientGroupRole,
+    /// The channel does not exist.
+    ChannelNotFound,
+    /// A channel with the same name already exists.
+    ChannelAlreadyExists,
+    /// The name is not allowed as a channel name.
+    InvalidChannelName,
+    /// The request requires the user to join the channel first.
+    NotChannelSubscriber,
+    /// Only administrators can make the request.
+    NotAdmin,
+    /// A user with the same username already exists.
+    UserAlreadyExists,
+    /// The user does not exist.
+    UserNotFound,
+    /// The username is empty, too long or contains characters other than letters, digits, '-', '_'
+    /// and '.'.
+    InvalidUsername,
+    /// The password is too short or too long.
+    InvalidPassword,
+    /// The server does not accept registrations.
+    RegistrationClosed,
+    /// The server accepts only invited users and the invite code is missing or wrong.
+    InvalidInviteCode,
+    /// The session token is malformed, forged or revoked, or its user cannot authenticate any more.
+    InvalidSessionToken,
+    /// The session token has expired. The client should authenticate with the password.
+    SessionTokenExpired,
+}
+
+/// The server sends it when it cannot process a request.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ErrorResponse {
+    pub code: ErrorCode,
+    /// human-readable description of the error. It may change at any time.
+    pub message: String,
+    /// the subject of the request that failed.
+    pub request_subject: String,
+}
+
+pub const MESSAGE_SUBJECT: &str = "message";
+pub const AUTHENTICATE_SUBJECT: &str = "authenticate";
+pub const RESUME_SUBJECT: &str = "resume";
+pub const LOGOUT_SUBJECT: &str = "logout";
+pub const NEW_MESSAGE_SUBJECT: &str = "new-message";
+pub const NEW_PRIVATE_MESSAGE_SEQUENCE_SUBJECT: &str = "new-private-message-sequence";
+pub const GET_PRIVATE_HISTORY_SUBJECT: &str = "get-private-history";
+pub const EDIT_MESSAGE_SUBJECT: &str = "edit-message";
+pub const DELETE_MESSAGE_SUBJECT: &str = "delete-message";
+pub const GET_MESSAGE_REVISIONS_SUBJECT: &str = "get-message-revisions";
+pub const MESSAGE_EDITED_SUBJECT: &str = "message-edited";
+pub const MESSAGE_DELETED_SUBJECT: &str = "message-deleted";
+pub const MARK_READ_SUBJECT: &str = "mark-read";
+pub const READ_RECEIPT_SUBJECT: &str = "read-receipt";
+pub const UNREAD_COUNTS_SUBJECT: &str = "unread-counts";
+pub const TYPING_STARTED_SUBJECT: &str = "typing-started";
+pub const TYPING_STOPPED_SUBJECT: &str = "typing-stopped";
+pub const PRESENCE_SUBJECT: &str = "presence";
+pub const GET_PRESENCE_SUBJECT: &str = "get-presence";
+pub const CREATE_GROUP_SUBJECT: &str = "create-group";
+pub const INVITE_TO_GROUP_SUBJECT: &str = "invite-to-group";
+pub const REMOVE_FROM_GROUP_SUBJECT: &str = "remove-from-group";
+pub const LEAVE_GROUP_SUBJECT: &str = "leave-group";
+pub const RENAME_GROUP_SUBJECT: &str = "rename-group";
+pub const SET_GROUP_ROLE_SUBJECT: &str = "set-group-role";
+pub const GROUP_UPDATED_SUBJECT: &str = "group-updated";
+pub const NEW_GROUP_MESSAGE_SEQUENCE_SUBJECT: &str = "new-group-message-sequence";
+pub const NEW_GROUP_MESSAGE_SUBJECT: &str = "new-group-message";
+pub const GROUP_MESSAGE_SUBJECT: &str = "group-message";
+pub const GROUP_MESSAGE_ACCEPTED_SUBJECT: &str = "group-message-accepted";
+pub const GROUP_MESSAGE_SEQUENCE_GAP_EXPIRED_SUBJECT: &str = "group-message-sequence-gap-expired";
+pub const GET_GROUP_HISTORY_SUBJECT: &str = "get-group-history";
+pub const MARK_GROUP_READ_SUBJECT: &str = "mark-group-read";
+pub const GROUP_READ_RECEIPT_SUBJECT: &str = "group-read-receipt";
+pub const CREATE_CHANNEL_SUBJECT: &str = "create-channel";
+pub const LIST_CHANNELS_SUBJECT: &str = "list-channels";
+pub const GET_CHANNEL_INFO_SUBJECT: &str = "get-channel-info";
+pub const JOIN_CHANNEL_SUBJECT: &str = "join-channel";
+pub const LEAVE_CHANNEL_SUBJECT: &str = "leave-channel";
+pub const NEW_CHANNEL_MESSAGE_SUBJECT: &str = "new-channel-message";
+pub const CHANNEL_MESSAGE_SUBJECT: &str = "channel-message";
+pub const CHANNEL_MESSAGE_ACCEPTED_SUBJECT: &str = "channel-message-accepted";
+pub const REGISTER_SUBJECT: &str = "register";
+pub const ADD_USER_SUBJECT: &str = "add-user";
+pub const SET_USER_DISABLED_SUBJECT: &str = "set-user-disabled";
+pub const GET_OUTBOUND_QUEUE_METRICS_SUBJECT: &str = "get-outbound-queue-metrics";
+pub const SERVER_SHUTTING_DOWN_SUBJECT: &str = "server-shutting-down";
+pub const ERROR_SUBJECT: &str = "error";
+pub const MESSAGE_ACCEPTED_SUBJECT: &str = "message-accepted";
+pub const MESSAGE_SEQUENCE_GAP_EXPIRED_SUBJECT: &str = "message-sequence-gap-expired";
 
 /// Accepts 2 objects: a struct with the main data for the request and a string with the message subject.
 pub fn attach_subject_and_serialize(json_main_data: Box<dyn erased::Serialize>, subject: String) -> String {
-    let message_subject = Subject { subject };
-    let message_json = serde_json::to_value(&json_main_data.deref()).unwrap();
+    attach_reply_subject_and_serialize(json_main_data, subject, None)
+}
+
+/// The same as attach_subject_and_serialize but it also echoes the id of the request being answered.
+pub fn attach_reply_subject_and_serialize(
+    json_main_data: Box<dyn erased::Serialize>,
+    subject: String,
+    request_id: Option<String>,
+) -> String {
+    let message_subject = Subject {
+        subject,
+        request_id,
+    };
+    let message_json = serde_json::to_value(json_main_data.deref()).unwrap();
     let message_subject_json = serde_json::to_value(&message_subject).unwrap();
     // jsons are cooked
     // Merge the JSON objects
@@ -69,3 +692,48 @@ pub fn prepare_message_for_from_server_to_client(message_to_someone: MessageToSo
         MESSAGE_SUBJECT.to_string(),
     )
 }
+
+/// Generates a string that the server sends to a client when his request cannot be processed.
+pub fn prepare_error_response(
+    code: ErrorCode,
+    message: &str,
+    request_subject: &str,
+    request_id: Option<String>,
+) -> String {
+    attach_reply_subject_and_serialize(
+        Box::new(ErrorResponse {
+            code,
+            message: message.to_string(),
+            request_subject: request_subject.to_string(),
+        }),
+        ERROR_SUBJECT.to_string(),
+        request_id,
+    )
+}
+
+#[test]
+fn test_prepare_error_response() {
+    let json: Value = serde_json::from_str(&prepare_error_response(
+        ErrorCode::UnknownSubject,
+        "unknown subject",
+        "say-hello",
+        Some("42".to_string()),
+    ))
+    .unwrap();
+    assert_eq!(json["subject"], ERROR_SUBJECT);
+    assert_eq!(json["code"], "unknown-subject");
+    assert_eq!(json["request_subject"], "say-hello");
+    assert_eq!(json["request_id"], "42");
+}
+
+#[test]
+fn test_request_id_is_omitted_when_absent() {
+    let json: Value = serde_json::from_str(&attach_subject_and_serialize(
+        Box::new(NewPrivateMessageSequenceRequest {
+            receiver_username: "dan".to_string(),
+        }),
+        NEW_PRIVATE_MESSAGE_SEQUENCE_SUBJECT.to_string(),
+    ))
+    .unwrap();
+    assert!(json.get("request_id").is_none());
+}
@@ -0,0 +1,535 @@
+use crate::dto::{GroupInfo, GroupMemberInfo, GroupRole, GroupUnreadCount, MessageToSomeone};
+use crate::user_context::{
+    ExpiredReorderBuffer, MessageSequenceError, MessageSequences, SequenceMessage,
+};
+use chrono::{DateTime, Utc};
+use std::fmt;
+
+/// Represents a message of a group conversation in the server internal memory.
+struct GroupMessage {
+    /// the author of the message.
+    sender: String,
+    content: String,
+    /// the datetime when the message was registered on the server.
+    server_time: DateTime<Utc>,
+}
+
+/// Contains data related to the group conversation but these data are relevant only to one of the
+/// members.
+struct GroupMember {
+    username: String,
+    role: GroupRole,
+    /// the message sequences that the member sends.
+    message_sequences: MessageSequences,
+    /// the highest id of the messages that the member has read. 0 if he has read nothing.
+    last_read_message_id: u32,
+}
+
+impl GroupMember {
+    fn new(username: String, role: GroupRole) -> Self {
+        GroupMember {
+            username,
+            role,
+            message_sequences: MessageSequences::new(),
+            last_read_message_id: 0,
+        }
+    }
+}
+
+/// A conversation of any number of users. The members who join later can read the messages that
+/// were written before they joined.
+pub struct GroupConversation {
+    name: String,
+    /// in the order in which they have joined. There is always exactly one owner.
+    members: Vec<GroupMember>,
+    /// Defines from which index the array "messages" start. The same as in PrivateConversation.
+    id_offset: u32,
+    messages: Vec<GroupMessage>,
+}
+
+/// The reason why a request to a group cannot be fulfilled.
+#[derive(Debug, PartialEq, Eq)]
+pub enum GroupError {
+    /// the group does not exist or the requester is not its member.
+    GroupNotFound,
+    /// the user that the request refers to is not a member.
+    NotGroupMember,
+    /// the user that should join the group is already its member.
+    AlreadyGroupMember,
+    /// the role of the requester does not allow the request.
+    InsufficientGroupRole,
+    /// the message does not exist.
+    MessageNotFound,
+}
+
+impl fmt::Display for GroupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GroupError::GroupNotFound => write!(f, "the group does not exist"),
+            GroupError::NotGroupMember => write!(f, "the user is not a member of the group"),
+            GroupError::AlreadyGroupMember => {
+                write!(f, "the user is already a member of the group")
+            }
+            GroupError::InsufficientGroupRole => {
+                write!(f, "the role in the group does not allow it")
+            }
+            GroupError::MessageNotFound => write!(f, "the message does not exist"),
+        }
+    }
+}
+
+impl GroupConversation {
+    /// Creates a group owned by `owner`. The invited users become its members.
+    pub fn new(owner: String, name: String, invited: Vec<String>) -> Self {
+        let mut group_conversation = GroupConversation {
+            name,
+            members: vec![GroupMember::new(owner, GroupRole::Owner)],
+            id_offset: 0,
+            messages: Vec::new(),
+        };
+        for username in invited {
+            if group_conversation.get_member(&username).is_none() {
+                group_conversation
+                    .members
+                    .push(GroupMember::new(username, GroupRole::Member));
+            }
+        }
+        group_conversation
+    }
+
+    /// Converts the group to the form that is sent to the clients.
+    pub fn to_group_info(&self, group_id: u32) -> GroupInfo {
+        GroupInfo {
+            group_id,
+            name: self.name.clone(),
+            members: self
+                .members
+                .iter()
+                .map(|member| GroupMemberInfo {
+                    username: member.username.clone(),
+                    role: member.role,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn is_member(&self, username: &String) -> bool {
+        self.get_member(username).is_some()
+    }
+
+    pub fn member_usernames(&self) -> Vec<String> {
+        self.members
+            .iter()
+            .map(|member| member.username.clone())
+            .collect()
+    }
+
+    /// Adds a new member. Only admins and the owner can do it.
+    pub fn invite(&mut self, inviter: &String, username: String) -> Result<(), GroupError> {
+        self.check_role(inviter, GroupRole::Admin)?;
+        if self.is_member(&username) {
+            return Err(GroupError::AlreadyGroupMember);
+        }
+        self.members
+            .push(GroupMember::new(username, GroupRole::Member));
+        Ok(())
+    }
+
+    /// Removes a member. The remover must have a higher role than the removed member.
+    pub fn remove(&mut self, remover: &String, username: &String) -> Result<(), GroupError> {
+        let remover_role = self.check_role(remover, GroupRole::Admin)?;
+        let index = self
+            .member_index(username)
+            .ok_or(GroupError::NotGroupMember)?;
+        if self.members[index].role >= remover_role {
+            return Err(GroupError::InsufficientGroupRole);
+        }
+        self.members.remove(index);
+        Ok(())
+    }
+
+    /// Removes the member from the group. If the owner leaves, the admin who has joined first
+    /// becomes the owner, or the member who has joined first if there are no admins.
+    pub fn leave(&mut self, username: &String) -> Result<(), GroupError> {
+        let index = self
+            .member_index(username)
+            .ok_or(GroupError::GroupNotFound)?;
+        let member = self.members.remove(index);
+        if member.role == GroupRole::Owner && !self.members.is_empty() {
+            let successor_index = self
+                .members
+                .iter()
+                .position(|member| member.role == GroupRole::Admin)
+                .unwrap_or(0);
+            self.members[successor_index].role = GroupRole::Owner;
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// Only admins and the owner can rename the group.
+    pub fn rename(&mut self, renamer: &String, name: String) -> Result<(), GroupError> {
+        self.check_role(renamer, GroupRole::Admin)?;
+        self.name = name;
+        Ok(())
+    }
+
+    /// Changes the role of a member. Only the owner can do it, and he cannot change his own role.
+    /// Making another member the owner turns the current owner into an admin.
+    pub fn set_role(
+        &mut self,
+        setter: &String,
+        username: &String,
+        role: GroupRole,
+    ) -> Result<(), GroupError> {
+        self.check_role(setter, GroupRole::Owner)?;
+        if setter == username {
+            return Err(GroupError::InsufficientGroupRole);
+        }
+        let index = self
+            .member_index(username)
+            .ok_or(GroupError::NotGroupMember)?;
+        self.members[index].role = role;
+        if role == GroupRole::Owner {
+            let setter_index = self.member_index(setter).unwrap();
+            self.members[setter_index].role = GroupRole::Admin;
+        }
+        Ok(())
+    }
+
+    pub fn start_new_message_sequence(
+        &mut self,
+        sender: &String,
+        maximum_message_sequences: u32,
+    ) -> Result<u32, GroupError> {
+        let member = self
+            .get_member_mut(sender)
+            .ok_or(GroupError::GroupNotFound)?;
+        Ok(member
+            .message_sequences
+            .start_new_message_sequence(maximum_message_sequences))
+    }
+
+    /// The same as ApplicationScope::approach_message_sequence but for a member of the group.
+    pub fn approach_message_sequence(
+        &mut self,
+        sender: &String,
+        message_sequence_id: u32,
+        sequence_message: SequenceMessage,
+        reorder_buffer_capacity: u32,
+    ) -> Result<Vec<SequenceMessage>, MessageSequenceError> {
+        let member = self
+            .get_member_mut(sender)
+            .ok_or(MessageSequenceError::ConversationNotFound)?;
+        member.message_sequences.approach(
+            message_sequence_id,
+            sequence_message,
+            reorder_buffer_capacity,
+        )
+    }
+
+    /// Discards the buffered messages that have been waiting for too long. Returns them together
+    /// with the members who have sent them.
+    pub fn expire_reorder_buffers(
+        &mut self,
+        deadline: DateTime<Utc>,
+    ) -> Vec<(String, ExpiredReorderBuffer)> {
+        let mut expired_reorder_buffers = Vec::new();
+        for member in self.members.iter_mut() {
+            for expired_reorder_buffer in member.message_sequences.expire_reorder_buffers(deadline)
+            {
+                expired_reorder_buffers.push((member.username.clone(), expired_reorder_buffer));
+            }
+        }
+        expired_reorder_buffers
+    }
+
+    /// Stores a message of a member and returns it in the form that is sent to the clients.
+    pub fn add_message(&mut self, sender: String, content: String) -> MessageToSomeone {
+        let group_message = GroupMessage {
+            sender,
+            content,
+            server_time: Utc::now(),
+        };
+        let message_to_someone = MessageToSomeone {
+            id: self.id_offset + self.messages.len() as u32 + 1,
+            content: group_message.content.clone(),
+            sender_username: group_message.sender.clone(),
+            datetime: group_message.server_time.to_string(),
+            edited_datetime: None,
+            is_deleted: false,
+        };
+        self.messages.push(group_message);
+        message_to_someone
+    }
+
+    /// The same as ApplicationScope::read_private_conversation_history but for a member of the
+    /// group. Returns the messages and true if there are older ones.
+    pub fn read_history(
+        &self,
+        reader: &String,
+        before_id: Option<u32>,
+        limit: usize,
+    ) -> Result<(Vec<MessageToSomeone>, bool), GroupError> {
+        if !self.is_member(reader) {
+            return Err(GroupError::GroupNotFound);
+        }
+        // the message with id N is stored at the index N - id_offset - 1
+        let end_index = match before_id {
+            Some(before_id) => {
+                (before_id.saturating_sub(self.id_offset + 1) as usize).min(self.messages.len())
+            }
+            None => self.messages.len(),
+        };
+        let start_index = end_index.saturating_sub(limit);
+        let messages = self.messages[start_index..end_index]
+            .iter()
+            .enumerate()
+            .map(|(index, group_message)| MessageToSomeone {
+                id: self.id_offset + (start_index + index) as u32 + 1,
+                content: group_message.content.clone(),
+                sender_username: group_message.sender.clone(),
+                datetime: group_message.server_time.to_string(),
+                edited_datetime: None,
+                is_deleted: false,
+            })
+            .collect();
+        Ok((messages, start_index > 0))
+    }
+
+    /// Marks the messages up to the message with the given id as read by the member. The read
+    /// watermark never moves backwards. Returns the watermark and true if it has moved.
+    pub fn mark_read(&mut self, reader: &String, id: u32) -> Result<(u32, bool), GroupError> {
+        let last_id = self.id_offset + self.messages.len() as u32;
+        let member = self
+            .get_member_mut(reader)
+            .ok_or(GroupError::GroupNotFound)?;
+        if id == 0 || id > last_id {
+            return Err(GroupError::MessageNotFound);
+        }
+        if id > member.last_read_message_id {
+            member.last_read_message_id = id;
+            Ok((id, true))
+        } else {
+            Ok((member.last_read_message_id, false))
+        }
+    }
+
+    /// Counts the messages of the other members that the member has not read. None if the user is
+    /// not a member.
+    pub fn count_unread(&self, group_id: u32, username: &String) -> Option<GroupUnreadCount> {
+        let member = self.get_member(username)?;
+        let first_unread_index =
+            member.last_read_message_id.saturating_sub(self.id_offset) as usize;
+        let unread_count = self
+            .messages
+            .iter()
+            .skip(first_unread_index)
+            .filter(|group_message| &group_message.sender != username)
+            .count();
+        Some(GroupUnreadCount {
+            group_id,
+            last_read_id: member.last_read_message_id,
+            unread_count: unread_count as u32,
+        })
+    }
+
+    /// Returns the role of the member if it is at least `minimum_role`.
+    fn check_role(
+        &self,
+        username: &String,
+        minimum_role: GroupRole,
+    ) -> Result<GroupRole, GroupError> {
+        let member = self.get_member(username).ok_or(GroupError::GroupNotFound)?;
+        if member.role < minimum_role {
+            return Err(GroupError::InsufficientGroupRole);
+        }
+        Ok(member.role)
+    }
+
+    fn member_index(&self, username: &String) -> Option<usize> {
+        self.members
+            .iter()
+            .position(|member| &member.username == username)
+    }
+
+    fn get_member(&self, username: &String) -> Option<&GroupMember> {
+        self.members
+            .iter()
+            .find(|member| &member.username == username)
+    }
+
+    fn get_member_mut(&mut self, username: &String) -> Option<&mut GroupMember> {
+        self.members
+            .iter_mut()
+            .find(|member| &member.username == username)
+    }
+}
+
+#[test]
+fn test_group_membership() {
+    let (ian, dan, chris, bob) = (
+        "ian".to_string(),
+        "dan".to_string(),
+        "chris".to_string(),
+        "bob".to_string(),
+    );
+    let mut group_conversation = GroupConversation::new(
+        ian.clone(),
+        "team".to_string(),
+        vec![dan.clone(), ian.clone()],
+    );
+    let roles = |group_conversation: &GroupConversation| {
+        group_conversation
+            .to_group_info(1)
+            .members
+            .into_iter()
+            .map(|member| (member.username, member.role))
+            .collect::<Vec<(String, GroupRole)>>()
+    };
+    assert_eq!(
+        roles(&group_conversation),
+        vec![
+            (ian.clone(), GroupRole::Owner),
+            (dan.clone(), GroupRole::Member)
+        ]
+    );
+
+    // members cannot manage the group
+    assert_eq!(
+        group_conversation.invite(&dan, chris.clone()),
+        Err(GroupError::InsufficientGroupRole)
+    );
+    assert_eq!(
+        group_conversation.rename(&chris, "x".to_string()),
+        Err(GroupError::GroupNotFound)
+    );
+    assert_eq!(
+        group_conversation.set_role(&ian, &dan, GroupRole::Admin),
+        Ok(())
+    );
+    assert_eq!(group_conversation.invite(&dan, chris.clone()), Ok(()));
+    assert_eq!(
+        group_conversation.invite(&dan, chris.clone()),
+        Err(GroupError::AlreadyGroupMember)
+    );
+    assert_eq!(group_conversation.invite(&dan, bob.clone()), Ok(()));
+    // an admin can remove members but not the owner
+    assert_eq!(group_conversation.remove(&dan, &bob), Ok(()));
+    assert_eq!(
+        group_conversation.remove(&dan, &ian),
+        Err(GroupError::InsufficientGroupRole)
+    );
+    assert_eq!(
+        group_conversation.remove(&dan, &bob),
+        Err(GroupError::NotGroupMember)
+    );
+    assert_eq!(
+        group_conversation.set_role(&dan, &chris, GroupRole::Admin),
+        Err(GroupError::InsufficientGroupRole)
+    );
+
+    // the first admin inherits the group from the owner who leaves
+    assert_eq!(group_conversation.leave(&ian), Ok(()));
+    assert_eq!(
+        roles(&group_conversation),
+        vec![
+            (dan.clone(), GroupRole::Owner),
+            (chris.clone(), GroupRole::Member)
+        ]
+    );
+    assert_eq!(
+        group_conversation.set_role(&dan, &chris, GroupRole::Owner),
+        Ok(())
+    );
+    assert_eq!(
+        roles(&group_conversation),
+        vec![
+            (dan.clone(), GroupRole::Admin),
+            (chris.clone(), GroupRole::Owner)
+        ]
+    );
+    assert_eq!(group_conversation.leave(&chris), Ok(()));
+    assert_eq!(group_conversation.leave(&dan), Ok(()));
+    assert!(group_conversation.is_empty());
+}
+
+#[test]
+fn test_group_messages_and_read_state() {
+    let (ian, dan, chris) = ("ian".to_string(), "dan".to_string(), "chris".to_string());
+    let mut group_conversation =
+        GroupConversation::new(ian.clone(), "team".to_string(), vec![dan.clone()]);
+    // every member has his own message sequences
+    assert_eq!(
+        group_conversation.start_new_message_sequence(&ian, 64),
+        Ok(0)
+    );
+    assert_eq!(
+        group_conversation.start_new_message_sequence(&dan, 64),
+        Ok(0)
+    );
+    assert_eq!(
+        group_conversation.start_new_message_sequence(&chris, 64),
+        Err(GroupError::GroupNotFound)
+    );
+    let sequence_message = |message_sequence_index: u32| SequenceMessage {
+        message_sequence_index,
+        content: "hi".to_string(),
+        request_id: None,
+    };
+    assert_eq!(
+        group_conversation
+            .approach_message_sequence(&ian, 0, sequence_message(2), 32)
+            .map(|accepted_messages| accepted_messages.len()),
+        Ok(0)
+    );
+    assert_eq!(
+        group_conversation
+            .approach_message_sequence(&ian, 0, sequence_message(1), 32)
+            .map(|accepted_messages| accepted_messages.len()),
+        Ok(2)
+    );
+    assert_eq!(
+        group_conversation
+            .approach_message_sequence(&chris, 0, sequence_message(1), 32)
+            .map(|accepted_messages| accepted_messages.len()),
+        Err(MessageSequenceError::ConversationNotFound)
+    );
+
+    for sender in [&ian, &dan, &ian] {
+        group_conversation.add_message(sender.clone(), "hi".to_string());
+    }
+    let (messages, has_more) = group_conversation.read_history(&dan, Some(3), 1).unwrap();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].id, 2);
+    assert_eq!(messages[0].sender_username, dan);
+    assert!(has_more);
+    assert_eq!(
+        group_conversation
+            .read_history(&chris, None, 10)
+            .map(|_| ()),
+        Err(GroupError::GroupNotFound)
+    );
+
+    // the own messages of the member are never unread
+    let unread_count = |group_conversation: &GroupConversation, username: &String| {
+        group_conversation
+            .count_unread(1, username)
+            .unwrap()
+            .unread_count
+    };
+    assert_eq!(unread_count(&group_conversation, &dan), 2);
+    assert_eq!(unread_count(&group_conversation, &ian), 1);
+    assert_eq!(group_conversation.mark_read(&dan, 1), Ok((1, true)));
+    assert_eq!(group_conversation.mark_read(&dan, 1), Ok((1, false)));
+    assert_eq!(
+        group_conversation.mark_read(&dan, 4),
+        Err(GroupError::MessageNotFound)
+    );
+    assert_eq!(unread_count(&group_conversation, &dan), 1);
+    assert_eq!(unread_count(&group_conversation, &ian), 1);
+    assert!(group_conversation.count_unread(1, &chris).is_none());
+}
@@ -0,0 +1,618 @@
+use crate::private_conversation_partners::PrivateConversationPartnersHashmapKey;
+use crate::storage::{
+    Storage, StorageError, StoredPrivateConversation, StoredPrivateMessage,
+    StoredRevokedSessionToken, StoredUndeliveredMessage, StoredUser,
+};
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+const JOURNAL_FILE_NAME: &str = "journal.log";
+const SNAPSHOT_FILE_NAME: &str = "snapshot.bin";
+const SNAPSHOT_TEMPORARY_FILE_NAME: &str = "snapshot.tmp";
+/// Every record starts with the length of its payload and the CRC32 checksum of the payload.
+const RECORD_HEADER_LENGTH: usize = 8;
+
+/// A value that has been saved. Each save method of JournalStorage appends one event.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+enum JournalEvent {
+    User {
+        user: StoredUser,
+    },
+    PrivateConversation {
+        private_conversation: StoredPrivateConversation,
+    },
+    PrivateMessage {
+        partners: PrivateConversationPartnersHashmapKey,
+        private_message: StoredPrivateMessage,
+    },
+    MessageSequence {
+        partners: PrivateConversationPartnersHashmapKey,
+        is_partner1: bool,
+        message_sequence_id: u32,
+        accepted_count: u32,
+    },
+    ForgottenMessageSequences {
+        partners: PrivateConversationPartnersHashmapKey,
+        is_partner1: bool,
+        message_sequence_id_offset: u32,
+    },
+    LastReadMessageId {
+        partners: PrivateConversationPartnersHashmapKey,
+        is_partner1: bool,
+        last_read_message_id: u32,
+    },
+    UndeliveredMessages {
+        username: String,
+        undelivered_messages: Vec<StoredUndeliveredMessage>,
+    },
+    RevokedSessionToken {
+        revoked_session_token: StoredRevokedSessionToken,
+    },
+}
+
+/// A journal event together with its number. The numbers let the replay skip the events that are
+/// already in the snapshot.
+#[derive(Serialize, Deserialize)]
+struct JournalRecord {
+    number: u64,
+    event: JournalEvent,
+}
+
+/// Everything that the journal storage keeps.
+#[derive(Default, Serialize, Deserialize)]
+struct StoredState {
+    users: BTreeMap<String, StoredUser>,
+    private_conversations: Vec<StoredPrivateConversation>,
+    /// the indices of the private conversations by their partners.
+    #[serde(skip)]
+    private_conversation_indices: HashMap<PrivateConversationPartnersHashmapKey, usize>,
+    undelivered_messages: BTreeMap<String, Vec<StoredUndeliveredMessage>>,
+    /// the expiration times of the revoked session tokens by their ids.
+    #[serde(default)]
+    revoked_session_tokens: BTreeMap<String, DateTime<Utc>>,
+}
+
+/// The state as it was after the event with the number last_event_number.
+#[derive(Default, Deserialize)]
+struct Snapshot {
+    last_event_number: u64,
+    state: StoredState,
+}
+
+/// The same as Snapshot but borrows the state, so it can be written without a copy.
+#[derive(Serialize)]
+struct SnapshotToWrite<'a> {
+    last_event_number: u64,
+    state: &'a StoredState,
+}
+
+impl StoredState {
+    fn apply(&mut self, event: JournalEvent) -> Result<(), StorageError> {
+        match event {
+            JournalEvent::User { user } => {
+                self.users.insert(user.username.clone(), user);
+            }
+            JournalEvent::PrivateConversation {
+                private_conversation,
+            } => match self
+                .private_conversation_indices
+                .get(&private_conversation.partners)
+            {
+                Some(&index) => self.private_conversations[index] = private_conversation,
+                None => {
+                    self.private_conversation_indices.insert(
+                        private_conversation.partners.clone(),
+                        self.private_conversations.len(),
+                    );
+                    self.private_conversations.push(private_conversation);
+                }
+            },
+            JournalEvent::PrivateMessage {
+                partners,
+                private_message,
+            } => {
+                let private_conversation = self.get_private_conversation_mut(&partners)?;
+                // the message with id N is stored at the index N - id_offset - 1
+                let index = private_message
+                    .id
+                    .checked_sub(private_conversation.id_offset + 1)
+                    .map(|index| index as usize);
+                put_at(
+                    &mut private_conversation.messages,
+                    index,
+                    private_message,
+                    "message",
+                )?;
+            }
+            JournalEvent::MessageSequence {
+                partners,
+                is_partner1,
+                message_sequence_id,
+                accepted_count,
+            } => {
+                let private_conversation = self.get_private_conversation_mut(&partners)?;
+                let partner_state = if is_partner1 {
+                    &mut private_conversation.partner1_state
+                } else {
+                    &mut private_conversation.partner2_state
+                };
+                let index = message_sequence_id
+                    .checked_sub(partner_state.message_sequence_id_offset)
+                    .map(|index| index as usize);
+                put_at(
+                    &mut partner_state.message_sequence_state,
+                    index,
+                    accepted_count,
+                    "message sequence",
+                )?;
+            }
+            JournalEvent::ForgottenMessageSequences {
+                partners,
+                is_partner1,
+                message_sequence_id_offset,
+            } => {
+                let private_conversation = self.get_private_conversation_mut(&partners)?;
+                let partner_state = if is_partner1 {
+                    &mut private_conversation.partner1_state
+                } else {
+                    &mut private_conversation.partner2_state
+                };
+                let forgotten_count = message_sequence_id_offset
+                    .checked_sub(partner_state.message_sequence_id_offset)
+                    .map(|forgotten_count| forgotten_count as usize)
+                    .filter(|forgotten_count| {
+                        *forgotten_count <= partner_state.message_sequence_state.len()
+                    })
+                    .ok_or_else(|| {
+                        StorageError::Corrupted(
+                            "the message sequences are forgotten out of order".to_string(),
+                        )
+                    })?;
+                partner_state
+                    .message_sequence_state
+                    .drain(..forgotten_count);
+                partner_state.message_sequence_id_offset = message_sequence_id_offset;
+            }
+            JournalEvent::LastReadMessageId {
+                partners,
+                is_partner1,
+                last_read_message_id,
+            } => {
+                let private_conversation = self.get_private_conversation_mut(&partners)?;
+                if is_partner1 {
+                    private_conversation.partner1_state.last_read_message_id = last_read_message_id;
+                } else {
+                    private_conversation.partner2_state.last_read_message_id = last_read_message_id;
+                }
+            }
+            JournalEvent::UndeliveredMessages {
+                username,
+                undelivered_messages,
+            } => {
+                if undelivered_messages.is_empty() {
+                    self.undelivered_messages.remove(&username);
+                } else {
+                    self.undelivered_messages
+                        .insert(username, undelivered_messages);
+                }
+            }
+            JournalEvent::RevokedSessionToken {
+                revoked_session_token,
+            } => {
+                // the expired tokens cannot be used anyway
+                let now = Utc::now();
+                self.revoked_session_tokens
+                    .retain(|_, expires_at| *expires_at > now);
+                self.revoked_session_tokens.insert(
+                    revoked_session_token.token_id,
+                    revoked_session_token.expires_at,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// private function
+    fn get_private_conversation_mut(
+        &mut self,
+        partners: &PrivateConversationPartnersHashmapKey,
+    ) -> Result<&mut StoredPrivateConversation, StorageError> {
+        match self.private_conversation_indices.get(partners) {
+            Some(&index) => Ok(&mut self.private_conversations[index]),
+            None => Err(StorageError::Corrupted(format!(
+                "the conversation of {} and {} is not stored",
+                partners.partner1, partners.partner2
+            ))),
+        }
+    }
+
+    /// Restores the indices that are not saved in the snapshot.
+    fn index_private_conversations(&mut self) {
+        self.private_conversation_indices = self
+            .private_conversations
+            .iter()
+            .enumerate()
+            .map(|(index, private_conversation)| (private_conversation.partners.clone(), index))
+            .collect();
+    }
+}
+
+/// Replaces the item at the index or appends it if the index is right after the last item.
+fn put_at<T>(
+    items: &mut Vec<T>,
+    index: Option<usize>,
+    item: T,
+    item_name: &str,
+) -> Result<(), StorageError> {
+    match index {
+        Some(index) if index < items.len() => items[index] = item,
+        Some(index) if index == items.len() => items.push(item),
+        _ => {
+            return Err(StorageError::Corrupted(format!(
+                "a {} is saved out of order",
+                item_name
+            )))
+        }
+    }
+    Ok(())
+}
+
+/// Frames the payload, so the damaged records can be detected when they are read.
+fn encode_record(payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(RECORD_HEADER_LENGTH + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    record.extend_from_slice(payload);
+    record
+}
+
+/// Returns the payload of the record at the beginning of the bytes and the length of the record.
+/// None if the record is incomplete or its checksum does not match.
+fn decode_record(bytes: &[u8]) -> Option<(&[u8], usize)> {
+    let header = bytes.get(..RECORD_HEADER_LENGTH)?;
+    let payload_length = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+    let payload = bytes.get(RECORD_HEADER_LENGTH..RECORD_HEADER_LENGTH + payload_length)?;
+    if crc32fast::hash(payload) != checksum {
+        return None;
+    }
+    Some((payload, RECORD_HEADER_LENGTH + payload_length))
+}
+
+/// Appends every change to a journal file. Every snapshot_interval events the whole state is
+/// written to a snapshot file and the journal is emptied. On startup the snapshot is loaded and
+/// the journal is replayed on top of it. A damaged tail of the journal, e.g. a record that was
+/// being written when the server crashed, is cut off.
+pub struct JournalStorage {
+    dir: PathBuf,
+    journal: File,
+    /// the state after all the events of the journal.
+    state: StoredState,
+    last_event_number: u64,
+    events_since_snapshot: u32,
+    snapshot_interval: u32,
+}
+
+impl JournalStorage {
+    /// Opens the journal in the directory, creating the directory if it does not exist, and
+    /// replays it.
+    pub fn open(dir: &Path, snapshot_interval: u32) -> Result<Self, StorageError> {
+        fs::create_dir_all(dir)?;
+        let mut snapshot = match fs::read(dir.join(SNAPSHOT_FILE_NAME)) {
+            Ok(bytes) => {
+                let (payload, _) = decode_record(&bytes).ok_or_else(|| {
+                    StorageError::Corrupted(
+                        "the checksum of the snapshot does not match".to_string(),
+                    )
+                })?;
+                serde_json::from_slice::<Snapshot>(payload)?
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Snapshot::default(),
+            Err(e) => return Err(e.into()),
+        };
+        snapshot.state.index_private_conversations();
+
+        let journal_path = dir.join(JOURNAL_FILE_NAME);
+        let bytes = match fs::read(&journal_path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut intact_length = 0;
+        let mut events_since_snapshot = 0;
+        while intact_length < bytes.len() {
+            let Some((payload, record_length)) = decode_record(&bytes[intact_length..]) else {
+                break;
+            };
+            let Ok(record) = serde_json::from_slice::<JournalRecord>(payload) else {
+                break;
+            };
+            // the events up to the snapshot are there if the journal was not emptied in time
+            if record.number > snapshot.last_event_number {
+                snapshot.state.apply(record.event)?;
+                snapshot.last_event_number = record.number;
+                events_since_snapshot += 1;
+            }
+            intact_length += record_length;
+        }
+
+        let journal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&journal_path)?;
+        if intact_length < bytes.len() {
+            warn!(
+                "Cutting off {} damaged bytes at the end of the journal {}",
+                bytes.len() - intact_length,
+                journal_path.display()
+            );
+            journal.set_len(intact_length as u64)?;
+            journal.sync_all()?;
+        }
+        Ok(JournalStorage {
+            dir: dir.to_path_buf(),
+            journal,
+            state: snapshot.state,
+            last_event_number: snapshot.last_event_number,
+            events_since_snapshot,
+            snapshot_interval,
+        })
+    }
+
+    /// Writes the event to the journal and applies it to the state.
+    fn append(&mut self, event: JournalEvent) -> Result<(), StorageError> {
+        let record = JournalRecord {
+            number: self.last_event_number + 1,
+            event,
+        };
+        self.journal
+            .write_all(&encode_record(&serde_json::to_vec(&record)?))?;
+        self.journal.sync_data()?;
+        self.last_event_number = record.number;
+        self.state.apply(record.event)?;
+        self.events_since_snapshot += 1;
+        if self.events_since_snapshot >= self.snapshot_interval {
+            self.write_snapshot()?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the snapshot with the current state and empties the journal. The snapshot is
+    /// written to a temporary file first, so a crash never leaves a half-written snapshot.
+    fn write_snapshot(&mut self) -> Result<(), StorageError> {
+        let payload = serde_json::to_vec(&SnapshotToWrite {
+            last_event_number: self.last_event_number,
+            state: &self.state,
+        })?;
+        let temporary_path = self.dir.join(SNAPSHOT_TEMPORARY_FILE_NAME);
+        let mut temporary_file = File::create(&temporary_path)?;
+        temporary_file.write_all(&encode_record(&payload))?;
+        temporary_file.sync_all()?;
+        fs::rename(&temporary_path, self.dir.join(SNAPSHOT_FILE_NAME))?;
+        File::open(&self.dir)?.sync_all()?;
+        self.journal.set_len(0)?;
+        self.journal.sync_all()?;
+        self.events_since_snapshot = 0;
+        Ok(())
+    }
+}
+
+impl Storage for JournalStorage {
+    fn load_users(&mut self) -> Result<Vec<StoredUser>, StorageError> {
+        Ok(self.state.users.values().cloned().collect())
+    }
+
+    fn save_user(&mut self, user: &StoredUser) -> Result<(), StorageError> {
+        self.append(JournalEvent::User { user: user.clone() })
+    }
+
+    fn load_private_conversations(
+        &mut self,
+    ) -> Result<Vec<StoredPrivateConversation>, StorageError> {
+        Ok(self.state.private_conversations.clone())
+    }
+
+    fn save_private_conversation(
+        &mut self,
+        private_conversation: &StoredPrivateConversation,
+    ) -> Result<(), StorageError> {
+        self.append(JournalEvent::PrivateConversation {
+            private_conversation: private_conversation.clone(),
+        })
+    }
+
+    fn save_private_message(
+        &mut self,
+        partners: &PrivateConversationPartnersHashmapKey,
+        private_message: &StoredPrivateMessage,
+    ) -> Result<(), StorageError> {
+        self.append(JournalEvent::PrivateMessage {
+            partners: partners.clone(),
+            private_message: private_message.clone(),
+        })
+    }
+
+    fn save_message_sequence(
+        &mut self,
+        partners: &PrivateConversationPartnersHashmapKey,
+        is_partner1: bool,
+        message_sequence_id: u32,
+        accepted_count: u32,
+    ) -> Result<(), StorageError> {
+        self.append(JournalEvent::MessageSequence {
+            partners: partners.clone(),
+            is_partner1,
+            message_sequence_id,
+            accepted_count,
+        })
+    }
+
+    fn forget_message_sequences(
+        &mut self,
+        partners: &PrivateConversationPartnersHashmapKey,
+        is_partner1: bool,
+        message_sequence_id_offset: u32,
+    ) -> Result<(), StorageError> {
+        self.append(JournalEvent::ForgottenMessageSequences {
+            partners: partners.clone(),
+            is_partner1,
+            message_sequence_id_offset,
+        })
+    }
+
+    fn save_last_read_message_id(
+        &mut self,
+        partners: &PrivateConversationPartnersHashmapKey,
+        is_partner1: bool,
+        last_read_message_id: u32,
+    ) -> Result<(), StorageError> {
+        self.append(JournalEvent::LastReadMessageId {
+            partners: partners.clone(),
+            is_partner1,
+            last_read_message_id,
+        })
+    }
+
+    fn load_undelivered_messages(
+        &mut self,
+    ) -> Result<Vec<(String, Vec<StoredUndeliveredMessage>)>, StorageError> {
+        Ok(self
+            .state
+            .undelivered_messages
+            .iter()
+            .map(|(username, undelivered_messages)| {
+                (username.clone(), undelivered_messages.clone())
+            })
+            .collect())
+    }
+
+    fn save_undelivered_messages(
+        &mut self,
+        username: &str,
+        undelivered_messages: &[StoredUndeliveredMessage],
+    ) -> Result<(), StorageError> {
+        self.append(JournalEvent::UndeliveredMessages {
+            username: username.to_string(),
+            undelivered_messages: undelivered_messages.to_vec(),
+        })
+    }
+
+    fn load_revoked_session_tokens(
+        &mut self,
+    ) -> Result<Vec<StoredRevokedSessionToken>, StorageError> {
+        Ok(self
+            .state
+            .revoked_session_tokens
+            .iter()
+            .map(|(token_id, expires_at)| StoredRevokedSessionToken {
+                token_id: token_id.clone(),
+                expires_at: *expires_at,
+            })
+            .collect())
+    }
+
+    fn save_revoked_session_token(
+        &mut self,
+        revoked_session_token: &StoredRevokedSessionToken,
+    ) -> Result<(), StorageError> {
+        self.append(JournalEvent::RevokedSessionToken {
+            revoked_session_token: revoked_session_token.clone(),
+        })
+    }
+}
+
+#[test]
+fn test_journal_storage() {
+    use crate::storage::StoredPartnerState;
+
+    let dir = std::env::temp_dir().join(format!("puchat-journal-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let partners = PrivateConversationPartnersHashmapKey {
+        partner1: "dan".to_string(),
+        partner2: "ian".to_string(),
+    };
+    let private_message = |id: u32| StoredPrivateMessage {
+        id,
+        is_sender_user1: true,
+        content: format!("message {}", id),
+        server_time: Utc::now(),
+        is_deleted: false,
+        edited_at: None,
+        revisions: Vec::new(),
+    };
+    let revoked_token_expires_at = Utc::now() + chrono::Duration::hours(1);
+    let journal_path = dir.join(JOURNAL_FILE_NAME);
+    let journal_length = {
+        // a snapshot is written after the fourth event, so the last two messages stay in the
+        // journal
+        let mut storage = JournalStorage::open(&dir, 4).unwrap();
+        storage
+            .save_private_conversation(&StoredPrivateConversation {
+                partners: partners.clone(),
+                id_offset: 0,
+                messages: Vec::new(),
+                partner1_state: StoredPartnerState::default(),
+                partner2_state: StoredPartnerState::default(),
+            })
+            .unwrap();
+        for id in 1..=5 {
+            storage
+                .save_private_message(&partners, &private_message(id))
+                .unwrap();
+        }
+        let journal_length = fs::metadata(&journal_path).unwrap().len();
+        storage
+            .save_last_read_message_id(&partners, false, 5)
+            .unwrap();
+        journal_length
+    };
+
+    // the last record was being written when the server crashed
+    let mut bytes = fs::read(&journal_path).unwrap();
+    bytes.truncate(bytes.len() - 3);
+    bytes.extend_from_slice(b"garbage");
+    fs::write(&journal_path, &bytes).unwrap();
+    {
+        let mut storage = JournalStorage::open(&dir, 4).unwrap();
+        assert_eq!(fs::metadata(&journal_path).unwrap().len(), journal_length);
+        let private_conversations = storage.load_private_conversations().unwrap();
+        assert_eq!(private_conversations[0].messages.len(), 5);
+        assert_eq!(
+            private_conversations[0].partner2_state.last_read_message_id,
+            0
+        );
+        // the journal can be continued after the damaged tail is cut off
+        storage
+            .save_last_read_message_id(&partners, false, 4)
+            .unwrap();
+        storage
+            .save_revoked_session_token(&StoredRevokedSessionToken {
+                token_id: "revoked".to_string(),
+                expires_at: revoked_token_expires_at,
+            })
+            .unwrap();
+    }
+
+    let mut storage = JournalStorage::open(&dir, 4).unwrap();
+    let private_conversations = storage.load_private_conversations().unwrap();
+    assert_eq!(private_conversations.len(), 1);
+    assert_eq!(private_conversations[0].messages[4].content, "message 5");
+    assert_eq!(
+        private_conversations[0].partner2_state.last_read_message_id,
+        4
+    );
+    assert_eq!(
+        storage.load_revoked_session_tokens().unwrap(),
+        vec![StoredRevokedSessionToken {
+            token_id: "revoked".to_string(),
+            expires_at: revoked_token_expires_at,
+        }]
+    );
+    let _ = fs::remove_dir_all(&dir);
+}
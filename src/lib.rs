@@ -1,6 +1,17 @@
+pub mod channel;
+pub mod client_session;
+pub mod config;
+pub mod connection_handler;
 pub mod dto;
-pub mod util;
-mod connection_handler;
-mod user_context;
+pub mod group_conversation;
+pub mod journal;
+pub mod login_throttle;
+pub mod outbound_queue;
 pub mod private_conversation_partners;
-// if we do not do this, we won't be able to see src/dto.rs in src/bin/simple-client.rs, for example
\ No newline at end of file
+pub mod session_token;
+pub mod shard;
+pub mod storage;
+pub mod user_context;
+pub mod user_service;
+pub mod util;
+// if we do not do this, we won't be able to see src/dto.rs in src/bin/simple-client.rs, for example
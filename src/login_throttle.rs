@@ -0,0 +1,221 @@
+use crate::config::ServerConfig;
+use log::warn;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The failed authentication attempts of all the connections.
+static LOGIN_THROTTLE: Lazy<Mutex<LoginThrottle>> =
+    Lazy::new(|| Mutex::new(LoginThrottle::default()));
+
+/// The least number of sources that are kept before the forgotten ones are removed.
+const MINIMUM_PRUNING_SIZE: usize = 1024;
+
+/// What the failed authentication attempts are counted for.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum LoginAttemptSource {
+    Username(String),
+    Ip(IpAddr),
+}
+
+impl fmt::Display for LoginAttemptSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoginAttemptSource::Username(username) => write!(f, "the username {}", username),
+            LoginAttemptSource::Ip(ip) => write!(f, "the IP address {}", ip),
+        }
+    }
+}
+
+struct FailedLogins {
+    count: u32,
+    last_failure: Instant,
+    /// the password is not checked for the source until this time.
+    blocked_until: Instant,
+}
+
+/// Counts the failed authentication attempts per username and per IP address. After every failure
+/// the source has to wait before the next attempt, twice as long as after the previous failure,
+/// until it reaches its threshold and is locked out.
+#[derive(Default)]
+pub struct LoginThrottle {
+    failed_logins: HashMap<LoginAttemptSource, FailedLogins>,
+    /// the forgotten sources are removed when there are this many sources.
+    pruning_size: usize,
+}
+
+impl LoginThrottle {
+    /// Returns how long the client has to wait before its password can be checked. None if the
+    /// password can be checked right away.
+    pub fn remaining_block(&self, username: &str, ip: IpAddr, now: Instant) -> Option<Duration> {
+        [
+            LoginAttemptSource::Username(username.to_string()),
+            LoginAttemptSource::Ip(ip),
+        ]
+        .iter()
+        .filter_map(|source| self.failed_logins.get(source))
+        .map(|failed_logins| failed_logins.blocked_until.saturating_duration_since(now))
+        .filter(|remaining_block| !remaining_block.is_zero())
+        .max()
+    }
+
+    pub fn record_failure(
+        &mut self,
+        username: &str,
+        ip: IpAddr,
+        config: &ServerConfig,
+        now: Instant,
+    ) {
+        for (source, lockout_threshold) in [
+            (
+                LoginAttemptSource::Username(username.to_string()),
+                config.username_lockout_threshold,
+            ),
+            (LoginAttemptSource::Ip(ip), config.ip_lockout_threshold),
+        ] {
+            let failed_logins = self
+                .failed_logins
+                .entry(source.clone())
+                .or_insert(FailedLogins {
+                    count: 0,
+                    last_failure: now,
+                    blocked_until: now,
+                });
+            if now.saturating_duration_since(failed_logins.last_failure)
+                >= config.login_lockout_duration
+            {
+                failed_logins.count = 0;
+            }
+            failed_logins.count += 1;
+            failed_logins.last_failure = now;
+            let block = if failed_logins.count >= lockout_threshold {
+                warn!(
+                    "Locking out {} for {:?} after {} failed authentication attempts",
+                    source, config.login_lockout_duration, failed_logins.count
+                );
+                config.login_lockout_duration
+            } else {
+                config
+                    .login_backoff_base
+                    .saturating_mul(2u32.saturating_pow(failed_logins.count - 1))
+                    .min(config.login_lockout_duration)
+            };
+            failed_logins.blocked_until = now + block;
+        }
+        if self.failed_logins.len() >= self.pruning_size {
+            self.failed_logins.retain(|_, failed_logins| {
+                now.saturating_duration_since(failed_logins.last_failure)
+                    < config.login_lockout_duration
+            });
+            self.pruning_size = (self.failed_logins.len() * 2).max(MINIMUM_PRUNING_SIZE);
+        }
+    }
+
+    /// Forgets the failed attempts of the username. The failed attempts of the IP address are kept,
+    /// so an attacker cannot reset them by authenticating as another user.
+    pub fn record_success(&mut self, username: &str) {
+        self.failed_logins
+            .remove(&LoginAttemptSource::Username(username.to_string()));
+    }
+}
+
+/// Returns how long the client has to wait before its password can be checked. None if the
+/// password can be checked right away.
+pub fn login_block(username: &str, ip: IpAddr) -> Option<Duration> {
+    LOGIN_THROTTLE
+        .lock()
+        .unwrap()
+        .remaining_block(username, ip, Instant::now())
+}
+
+pub fn record_failed_login(username: &str, ip: IpAddr, config: &ServerConfig) {
+    LOGIN_THROTTLE
+        .lock()
+        .unwrap()
+        .record_failure(username, ip, config, Instant::now());
+}
+
+pub fn record_successful_login(username: &str) {
+    LOGIN_THROTTLE.lock().unwrap().record_success(username);
+}
+
+#[test]
+fn test_login_throttle() {
+    let config = ServerConfig {
+        login_backoff_base: Duration::from_secs(1),
+        username_lockout_threshold: 3,
+        ip_lockout_threshold: 5,
+        login_lockout_duration: Duration::from_secs(60),
+        ..ServerConfig::default()
+    };
+    let ip = IpAddr::from([192, 0, 2, 1]);
+    let other_ip = IpAddr::from([192, 0, 2, 2]);
+    let start = Instant::now();
+    let mut login_throttle = LoginThrottle::default();
+    assert_eq!(login_throttle.remaining_block("ian", ip, start), None);
+
+    // the backoff doubles with every failure
+    login_throttle.record_failure("ian", ip, &config, start);
+    assert_eq!(
+        login_throttle.remaining_block("ian", other_ip, start),
+        Some(Duration::from_secs(1))
+    );
+    assert_eq!(
+        login_throttle.remaining_block("dan", ip, start),
+        Some(Duration::from_secs(1))
+    );
+    assert_eq!(login_throttle.remaining_block("dan", other_ip, start), None);
+    let now = start + Duration::from_secs(1);
+    assert_eq!(login_throttle.remaining_block("ian", ip, now), None);
+    login_throttle.record_failure("ian", ip, &config, now);
+    assert_eq!(
+        login_throttle.remaining_block("ian", ip, now),
+        Some(Duration::from_secs(2))
+    );
+
+    // the third failure locks the username out
+    let now = now + Duration::from_secs(2);
+    login_throttle.record_failure("ian", ip, &config, now);
+    assert_eq!(
+        login_throttle.remaining_block("ian", other_ip, now),
+        Some(Duration::from_secs(60))
+    );
+    assert_eq!(
+        login_throttle.remaining_block("dan", ip, now),
+        Some(Duration::from_secs(4))
+    );
+
+    // a success forgets the failures of the username but not of the IP address
+    let now = now + Duration::from_secs(10);
+    login_throttle.record_success("ian");
+    login_throttle.record_failure("ian", ip, &config, now);
+    assert_eq!(
+        login_throttle.remaining_block("ian", other_ip, now),
+        Some(Duration::from_secs(1))
+    );
+    assert_eq!(
+        login_throttle.remaining_block("dan", ip, now),
+        Some(Duration::from_secs(8))
+    );
+    let now = now + Duration::from_secs(8);
+    login_throttle.record_failure("dan", ip, &config, now);
+    assert_eq!(
+        login_throttle.remaining_block("dan", other_ip, now),
+        Some(Duration::from_secs(1))
+    );
+    assert_eq!(
+        login_throttle.remaining_block("chris", ip, now),
+        Some(Duration::from_secs(60))
+    );
+
+    // the failures are forgotten after the lockout duration without new ones
+    let now = now + Duration::from_secs(60);
+    login_throttle.record_failure("dan", ip, &config, now);
+    assert_eq!(
+        login_throttle.remaining_block("chris", ip, now),
+        Some(Duration::from_secs(1))
+    );
+}
@@ -1,24 +1,33 @@
-mod connection_handler;
-mod dto;
-mod private_conversation_partners;
-mod user_context;
-mod user_service;
-mod util;
-
-use dto::{LoginCredentials, MessageFromSomeone, MessageToSomeone, Subject, MESSAGE_SUBJECT};
+use chrono::Utc;
 use futures::stream::SplitSink;
 use futures::{SinkExt, StreamExt};
-use log::error;
-use std::collections::HashMap;
+use log::{error, info, warn};
+use rust_pr::client_session::{ClientSession, FrameHandlingResult};
+use rust_pr::config::ServerConfig;
+use rust_pr::connection_handler::{handle_connection_commands, ConnectionCommand};
+use rust_pr::outbound_queue::{messages_channel, MessagesReceiver};
+use rust_pr::session_token;
+use rust_pr::shard::ConnectionCommandRouter;
+use rust_pr::storage::open_storage;
+use rust_pr::user_context::ApplicationScope;
+use rust_pr::user_service;
 use std::env;
 use std::net::SocketAddr;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::{sleep_until, timeout};
 use tokio_tungstenite::{accept_async, tungstenite::protocol::Message, WebSocketStream};
 
-use crate::connection_handler::{handle_connection_commands, ConnectionCommand};
-use crate::dto::NewPrivateMessageSequenceRequest;
-use crossbeam_channel::unbounded;
-use serde_json::{json, Value};
+/// How long the writer task tries to send the close frame and the other queued messages to a client
+/// that does not read.
+const CLOSE_FRAME_TIMEOUT: Duration = Duration::from_secs(1);
 
 #[tokio::main]
 async fn main() {
@@ -31,35 +40,153 @@ async fn main() {
         .unwrap_or_else(|| "127.0.0.1:8080".to_string());
     let addr: SocketAddr = addr.parse().expect("Invalid address");
 
+    // a server with a mistyped setting could lose data, e.g. with the memory storage
+    let config = match ServerConfig::from_env() {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            process::exit(1);
+        }
+    };
+
     // Create the TCP listener
     let listener = TcpListener::bind(&addr).await.expect("Failed to bind");
 
     println!("Listening on: {}", addr);
 
-    let (connection_command_sender, connection_command_receiver) = unbounded::<ConnectionCommand>();
+    // restore the state that has survived the previous run
+    let mut storage = open_storage(&config).expect("Failed to open the storage");
+    user_service::load_users(storage.as_mut(), config.users_file.as_deref())
+        .expect("Failed to load the users");
+    session_token::load_revoked_session_tokens(storage.as_mut(), Utc::now())
+        .expect("Failed to load the revoked session tokens");
+    // the state is split among the shards, each of them applying its commands on its own thread
+    let (connection_command_router, connection_command_receivers) =
+        ConnectionCommandRouter::with_shards(config.shard_count);
+    let application_scopes = ApplicationScope::load_shards(storage, &connection_command_router)
+        .expect("Failed to load the state");
 
     // listening to answers from handlers
-    tokio::spawn(handle_connection_commands(connection_command_receiver));
+    let mut shard_threads = Vec::new();
+    for (shard_index, (connection_command_receiver, application_scope)) in
+        connection_command_receivers
+            .into_iter()
+            .zip(application_scopes)
+            .enumerate()
+    {
+        let handler_config = ServerConfig::clone(&config);
+        let shard_thread = thread::Builder::new()
+            .name(format!("connection-commands-{}", shard_index))
+            .spawn(move || {
+                handle_connection_commands(
+                    connection_command_receiver,
+                    application_scope,
+                    handler_config,
+                )
+            })
+            .expect("Failed to start the connection command handler");
+        shard_threads.push(shard_thread);
+    }
 
-    while let Ok((stream, _)) = listener.accept().await {
-        // Spawn a new task for each connection
-        tokio::spawn(handle_connection(stream, connection_command_sender.clone()));
+    // the connections learn from it that the server is shutting down
+    let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+    let mut connections = JoinSet::new();
+    let shutdown_requested = shutdown_signal();
+    tokio::pin!(shutdown_requested);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer_address)) => {
+                    // Spawn a new task for each connection
+                    connections.spawn(handle_connection(
+                        stream,
+                        peer_address,
+                        connection_command_router.clone(),
+                        config.clone(),
+                        shutdown_receiver.clone(),
+                    ));
+                }
+                Err(e) => {
+                    error!("Failed to accept a connection: {}", e);
+                    break;
+                }
+            },
+            // the connections that have ended are forgotten
+            Some(_) = connections.join_next() => {}
+            _ = &mut shutdown_requested => break,
+        }
     }
+
+    info!("Shutting down");
+    drop(listener);
+    let _ = shutdown_sender.send(true);
+    for shard_index in 0..connection_command_router.shard_count() {
+        let _ = connection_command_router.send(ConnectionCommand::Shutdown { shard_index });
+    }
+    // the sessions send their queued messages and the close frames until the deadline
+    let all_closed = async { while connections.join_next().await.is_some() {} };
+    if timeout(config.shutdown_timeout, all_closed).await.is_err() {
+        warn!(
+            "{} connections have not been closed in time and are dropped",
+            connections.len()
+        );
+        connections.abort_all();
+    }
+    for shard_thread in shard_threads {
+        let _ = shard_thread.join();
+    }
+    info!("The server has shut down");
 }
 
-/// Sends a stream of messages to a WebSocket connection.
+/// Completes when the process is asked to stop with SIGINT or SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        signal(SignalKind::terminate())
+            .expect("Failed to listen to SIGTERM")
+            .recv()
+            .await
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<Option<()>>();
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+}
+
+/// Sends a stream of messages to a WebSocket connection. Stops when the session is disconnected
+/// because it is slow, even if the client does not read the message that is being sent.
 async fn send_ws_messages_from_stream(
     mut ws_sender: SplitSink<WebSocketStream<TcpStream>, Message>,
-    messages_receiver: crossbeam_channel::Receiver<Message>,
+    mut messages_receiver: MessagesReceiver,
 ) {
-    for message in messages_receiver {
-        ws_sender.send(message).await.expect("TODO: panic message");
+    while let Some(message) = messages_receiver.recv().await {
+        let result = tokio::select! {
+            result = ws_sender.send(message) => result,
+            close_frame = messages_receiver.disconnected() => {
+                // the client is not reading, so the close frame may not get through either
+                let _ = timeout(
+                    CLOSE_FRAME_TIMEOUT,
+                    ws_sender.send(Message::Close(Some(close_frame))),
+                )
+                .await;
+                break;
+            }
+        };
+        if let Err(e) = result {
+            error!("Failed to send a message to the WebSocket: {}", e);
+            break;
+        }
     }
 }
 
 async fn handle_connection(
     stream: TcpStream,
-    connection_command_sender: crossbeam_channel::Sender<ConnectionCommand>,
+    peer_address: SocketAddr,
+    connection_command_router: ConnectionCommandRouter,
+    config: Arc<ServerConfig>,
+    mut shutdown_receiver: watch::Receiver<bool>,
 ) {
     // Accept the WebSocket connection
     let ws_stream = match accept_async(stream).await {
@@ -74,124 +201,73 @@ async fn handle_connection(
     let (ws_sender, mut ws_receiver) = ws_stream.split();
 
     // it lets this connection receive messages from other connections
-    let (messages_sender, messages_receiver) = unbounded::<Message>();
-    tokio::spawn(send_ws_messages_from_stream(ws_sender, messages_receiver));
-
-    let mut current_username: String = String::new();
-    let unsubscribe_closure =
-        |username: String, messages_sender: crossbeam_channel::Sender<Message>| {
-            if !username.is_empty() {
-                // send a command to unsubscribe
-                let _ =
-                    connection_command_sender.send(ConnectionCommand::UnassignConnectionFromUser {
-                        username,
-                        messages_sender,
-                    });
+    let (messages_sender, messages_receiver) = messages_channel(&config);
+    let mut writer = tokio::spawn(send_ws_messages_from_stream(ws_sender, messages_receiver));
+
+    let mut client_session = ClientSession::new(
+        messages_sender,
+        connection_command_router,
+        config,
+        peer_address,
+    );
+
+    // Handle incoming messages until the client, the writer task or the heartbeat ends the
+    // connection
+    let mut is_shutting_down = false;
+    loop {
+        let msg = tokio::select! {
+            // the requests are handled in order, so a request that waits for another thread
+            // holds up the next frames
+            msg = ws_receiver.next(), if !client_session.is_waiting_for_callback() => msg,
+            _ = sleep_until(client_session.next_timer().into()) => {
+                if client_session.handle_timer(Instant::now())
+                    == FrameHandlingResult::Close
+                {
+                    break;
+                }
+                continue;
+            }
+            result = client_session.run_next_callback() => {
+                if result == FrameHandlingResult::Close {
+                    break;
+                }
+                continue;
+            }
+            _ = &mut writer => None,
+            _ = shutdown_receiver.changed(), if !is_shutting_down => {
+                // the session keeps reading until the client answers the close frame
+                is_shutting_down = true;
+                client_session.shut_down();
+                continue;
             }
         };
-
-    // Handle incoming messages
-    while let Some(msg) = ws_receiver.next().await {
+        let Some(msg) = msg else {
+            break;
+        };
         match msg {
             Ok(Message::Text(content)) => {
-                println!("Incoming message {:?}", &content);
-                let subject: Subject =
-                    serde_json::from_str(&content).expect("JSON was not well-formatted");
-                println!("subject: {:#?}", &subject.subject);
-                match subject.subject.as_str() {
-                    dto::AUTHENTICATE_SUBJECT => {
-                        let login_credentials: LoginCredentials =
-                            serde_json::from_str(&content).expect("JSON was not well-formatted");
-                        let is_password_correct = user_service::are_credentials_correct(
-                            &login_credentials.login,
-                            &login_credentials.password,
-                        );
-                        println!("is_password_correct = {}", is_password_correct);
-                        if is_password_correct {
-                            let _ = messages_sender
-                                .send(Message::Text("authentication successful".to_owned()));
-                            current_username = login_credentials.login;
-                            let _ = connection_command_sender.send(
-                                ConnectionCommand::AssignConnectionToUser {
-                                    username: current_username.clone(),
-                                    messages_sender: messages_sender.clone(),
-                                },
-                            );
-                        } else {
-                            let _ = messages_sender.send(Message::Text(
-                                "provide correct login and password for authentication".to_owned(),
-                            ));
-                        }
-                    }
-                    dto::NEW_MESSAGE_SUBJECT => {
-                        println!("NEW_MESSAGE_SUBJECT request");
-                        if current_username.is_empty() {
-                            let _ = messages_sender.send(Message::Text(
-                                "you should authorize before sending messages to other users"
-                                    .to_owned(),
-                            ));
-                        } else {
-                            // parse the message
-                            let new_message: MessageFromSomeone = serde_json::from_str(&content)
-                                .expect("JSON was not well-formatted");
-                            let _ = connection_command_sender.send(
-                                ConnectionCommand::SendMessageToAnotherUser {
-                                    sender_username: current_username.clone(),
-                                    receiver_username: new_message.receiver,
-                                    content: new_message.content,
-                                    message_sequence_id: new_message.message_sequence_id,
-                                    message_sequence_index: new_message.message_sequence_index,
-                                },
-                            );
-                        }
-                    }
-                    dto::NEW_PRIVATE_MESSAGE_SEQUENCE_SUBJECT => {
-                        println!("NEW_PRIVATE_MESSAGE_SEQUENCE_SUBJECT request");
-                        if current_username.is_empty() {
-                            let _ = messages_sender.send(Message::Text(
-                                "you should authorize before making this type of request"
-                                    .to_owned(),
-                            ));
-                        } else {
-                            match serde_json::from_str::<NewPrivateMessageSequenceRequest>(&content)
-                            {
-                                Ok(request) => {
-                                    let _ = connection_command_sender.send(
-                                        ConnectionCommand::InitiateNewPrivateMessageSequence {
-                                            sender_username: current_username.clone(),
-                                            receiver_username: request.receiver_username,
-                                            messages_sender: messages_sender.clone(),
-                                        },
-                                    );
-                                }
-                                Err(e) => {
-                                    eprintln!("Failed to parse JSON: {}", e);
-                                    //TODO Handle the error appropriately, e.g., return a message with an error code
-                                }
-                            };
-                        }
-                    }
-                    _ => {
-                        let _ = messages_sender.send(Message::Text("unknown subject".to_owned()));
-                        // Close the WebSocket connection gracefully
-                        let _ = messages_sender.send(Message::Close(None));
-                        println!("Close frame sent because the subject was unknown");
-                        //sender.shutdown().await.unwrap();
-                    }
+                if client_session.handle_text_frame(&content) == FrameHandlingResult::Close {
+                    break;
                 }
             }
+            Ok(Message::Pong(_)) => client_session.handle_pong(),
             Ok(Message::Close(_)) => {
                 println!("The client wants to gracefully close the session");
-                unsubscribe_closure(current_username, messages_sender);
                 break;
             }
-            Ok(_) => print!("OK_"),
+            // tungstenite answers the pings itself, and the binary frames are not used
+            Ok(_) => {}
             Err(e) => {
                 error!("Error: {}", e);
-                unsubscribe_closure(current_username, messages_sender);
                 break;
             }
         }
-        println!("end of function handle_connection");
+    }
+    // the session must be unregistered no matter how the connection ended
+    client_session.unsubscribe();
+    // the messages that are still queued, e.g. the close frame, are sent before the task ends.
+    // The client may be gone or may not read, so the writer gets a deadline.
+    if !writer.is_finished() && timeout(CLOSE_FRAME_TIMEOUT, &mut writer).await.is_err() {
+        writer.abort();
     }
 }
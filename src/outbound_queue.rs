@@ -0,0 +1,488 @@
+use crate::config::{ServerConfig, SlowConsumerPolicy};
+use log::warn;
+use once_cell::sync::Lazy;
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+use tungstenite::Message;
+
+/// The close code of the connections that the server closes because the client does not read its
+/// messages fast enough.
+pub const SLOW_CONSUMER_CLOSE_CODE: CloseCode = CloseCode::Library(4008);
+
+/// The counters of the slow sessions of every user that has had one. A session looks up the
+/// counters of its user once and then updates them without the lock, so the shards that send to
+/// slow sessions do not wait for each other.
+static OUTBOUND_QUEUE_METRICS: Lazy<Mutex<BTreeMap<String, Arc<OutboundQueueCounters>>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// The live version of OutboundQueueMetrics.
+#[derive(Default)]
+struct OutboundQueueCounters {
+    dropped_frames: AtomicU64,
+    forced_disconnects: AtomicU64,
+}
+
+/// What the slow sessions of a user have cost.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OutboundQueueMetrics {
+    /// the ephemeral events that have been dropped before they were sent.
+    pub dropped_frames: u64,
+    /// the sessions that have been disconnected because they did not keep up.
+    pub forced_disconnects: u64,
+}
+
+/// The reason why a message has not been queued.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SendError {
+    /// The session has ended or has been disconnected.
+    Disconnected,
+}
+
+struct QueuedMessage {
+    message: Message,
+    /// ephemeral events, like typing notifications, can be dropped when the client is slow.
+    is_ephemeral: bool,
+}
+
+/// Sends the next page of messages that are not urgent, e.g. the messages that have waited for an
+/// offline user. It gets a sender of the session, or None if the session has ended before the
+/// client has read the previous page.
+type Refill = Box<dyn FnOnce(Option<MessagesSender>) + Send>;
+
+struct OutboundQueueState {
+    messages: VecDeque<QueuedMessage>,
+    /// since when the queue has been at its capacity. None if it is not full.
+    full_since: Option<Instant>,
+    /// the user of the session, so the costs of a slow session are counted for him.
+    username: Option<String>,
+    /// the counters of the user, once the session has been slow.
+    counters: Option<Arc<OutboundQueueCounters>>,
+    /// how many MessagesSender values exist.
+    sender_count: usize,
+    is_receiver_dropped: bool,
+    /// Some if the session has been disconnected because it is slow.
+    close_frame: Option<CloseFrame<'static>>,
+    /// called when the client has read most of the queued messages.
+    refills: Vec<Refill>,
+}
+
+struct OutboundQueue {
+    state: Mutex<OutboundQueueState>,
+    capacity: usize,
+    policy: SlowConsumerPolicy,
+    grace_period: Duration,
+    /// wakes up the writer task when there is a new message or no sender is left.
+    message_added: Notify,
+    /// wakes up the writer task when the session is disconnected because it is slow.
+    disconnected: Notify,
+}
+
+/// The sending end of the outbound queue of a session. Sending never blocks, so the connection
+/// handler can send from its thread. The queue is bounded: when the client does not read its
+/// messages, the oldest ephemeral events are dropped or the session is disconnected, depending on
+/// the slow consumer policy.
+pub struct MessagesSender {
+    queue: Arc<OutboundQueue>,
+}
+
+/// The receiving end of the outbound queue of a session. The writer task of the session awaits the
+/// messages without blocking the async runtime.
+pub struct MessagesReceiver {
+    queue: Arc<OutboundQueue>,
+}
+
+pub fn messages_channel(config: &ServerConfig) -> (MessagesSender, MessagesReceiver) {
+    let queue = Arc::new(OutboundQueue {
+        state: Mutex::new(OutboundQueueState {
+            messages: VecDeque::new(),
+            full_since: None,
+            username: None,
+            counters: None,
+            sender_count: 1,
+            is_receiver_dropped: false,
+            close_frame: None,
+            refills: Vec::new(),
+        }),
+        capacity: config.outbound_queue_capacity.max(1),
+        policy: config.slow_consumer_policy,
+        grace_period: config.slow_consumer_grace_period,
+        message_added: Notify::new(),
+        disconnected: Notify::new(),
+    });
+    (
+        MessagesSender {
+            queue: queue.clone(),
+        },
+        MessagesReceiver { queue },
+    )
+}
+
+impl MessagesSender {
+    pub fn send(&self, message: Message) -> Result<(), SendError> {
+        self.push(message, false, Instant::now())
+    }
+
+    /// Sends an event that the client can do without, like a typing notification. If the client
+    /// is slow, the event may be dropped.
+    pub fn send_ephemeral(&self, message: Message) -> Result<(), SendError> {
+        self.push(message, true, Instant::now())
+    }
+
+    pub fn same_channel(&self, other: &MessagesSender) -> bool {
+        Arc::ptr_eq(&self.queue, &other.queue)
+    }
+
+    /// Tells the queue whose session it belongs to. None after the user has logged out.
+    pub fn set_username(&self, username: Option<String>) {
+        let mut state = self.queue.state.lock().unwrap();
+        state.username = username;
+        state.counters = None;
+    }
+
+    /// How many more messages may be queued before the queue is half full. The messages that are
+    /// not urgent are sent in pages of this size, so the live messages still have room.
+    pub fn page_capacity(&self) -> usize {
+        let state = self.queue.state.lock().unwrap();
+        (self.queue.capacity / 2)
+            .max(1)
+            .saturating_sub(state.messages.len())
+    }
+
+    /// Calls `refill` once the client has read most of the queued messages, so that it can send
+    /// the next page. If the session ends before, `refill` gets None.
+    pub fn refill_when_drained(
+        &self,
+        refill: impl FnOnce(Option<MessagesSender>) + Send + 'static,
+    ) {
+        let mut state = self.queue.state.lock().unwrap();
+        if state.is_receiver_dropped || state.close_frame.is_some() {
+            drop(state);
+            refill(None);
+        } else if state.messages.len() <= self.queue.capacity / 4 {
+            drop(state);
+            refill(Some(self.clone()));
+        } else {
+            state.refills.push(Box::new(refill));
+        }
+    }
+
+    /// private function
+    fn push(&self, message: Message, is_ephemeral: bool, now: Instant) -> Result<(), SendError> {
+        let queue = &self.queue;
+        let mut state = queue.state.lock().unwrap();
+        if state.is_receiver_dropped || state.close_frame.is_some() {
+            return Err(SendError::Disconnected);
+        }
+        if state.messages.len() >= queue.capacity {
+            let may_drop = queue.policy == SlowConsumerPolicy::DropOldestEphemeral;
+            let oldest_ephemeral = state
+                .messages
+                .iter()
+                .position(|queued| queued.is_ephemeral)
+                .filter(|_| may_drop);
+            if let Some(oldest_ephemeral) = oldest_ephemeral {
+                state.messages.remove(oldest_ephemeral);
+                count(&mut state, |counters| &counters.dropped_frames);
+            } else if may_drop && is_ephemeral {
+                // there is no older event to drop, so the new one is dropped
+                count(&mut state, |counters| &counters.dropped_frames);
+                return Ok(());
+            } else {
+                // The messages above the capacity are kept during the grace period, so a client
+                // that catches up loses nothing, but never more than twice the capacity.
+                let full_since = *state.full_since.get_or_insert(now);
+                if now.saturating_duration_since(full_since) >= queue.grace_period
+                    || state.messages.len() >= queue.capacity * 2
+                {
+                    disconnect(queue, &mut state);
+                    return Err(SendError::Disconnected);
+                }
+            }
+        }
+        state.messages.push_back(QueuedMessage {
+            message,
+            is_ephemeral,
+        });
+        if state.messages.len() >= queue.capacity {
+            state.full_since.get_or_insert(now);
+        }
+        queue.message_added.notify_one();
+        Ok(())
+    }
+}
+
+impl Clone for MessagesSender {
+    fn clone(&self) -> Self {
+        self.queue.state.lock().unwrap().sender_count += 1;
+        MessagesSender {
+            queue: self.queue.clone(),
+        }
+    }
+}
+
+impl Drop for MessagesSender {
+    fn drop(&mut self) {
+        let mut state = self.queue.state.lock().unwrap();
+        state.sender_count -= 1;
+        if state.sender_count == 0 {
+            // the writer task has to see that nothing more will come
+            self.queue.message_added.notify_one();
+        }
+    }
+}
+
+impl MessagesReceiver {
+    /// Waits for the next message. None when the queue is empty and no sender is left, or when
+    /// the session has been disconnected and its close frame has been taken.
+    pub async fn recv(&mut self) -> Option<Message> {
+        loop {
+            if let Some(message) = self.try_recv() {
+                return Some(message);
+            }
+            {
+                let state = self.queue.state.lock().unwrap();
+                if state.sender_count == 0 || state.close_frame.is_some() {
+                    return None;
+                }
+            }
+            // a notification that comes before this point is stored as a permit
+            self.queue.message_added.notified().await;
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Option<Message> {
+        let mut state = self.queue.state.lock().unwrap();
+        let queued = state.messages.pop_front()?;
+        if state.messages.len() < self.queue.capacity {
+            state.full_since = None;
+        }
+        if state.messages.len() <= self.queue.capacity / 4 && !state.refills.is_empty() {
+            let refills = std::mem::take(&mut state.refills);
+            state.sender_count += refills.len();
+            drop(state);
+            for refill in refills {
+                refill(Some(MessagesSender {
+                    queue: self.queue.clone(),
+                }));
+            }
+        }
+        Some(queued.message)
+    }
+
+    /// Waits until the session is disconnected because it is slow, and returns the close frame
+    /// that tells the client why.
+    pub async fn disconnected(&self) -> CloseFrame<'static> {
+        loop {
+            let notified = self.queue.disconnected.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if let Some(close_frame) = self.queue.state.lock().unwrap().close_frame.clone() {
+                return close_frame;
+            }
+            notified.await;
+        }
+    }
+}
+
+impl Drop for MessagesReceiver {
+    fn drop(&mut self) {
+        let mut state = self.queue.state.lock().unwrap();
+        state.is_receiver_dropped = true;
+        state.messages.clear();
+        let refills = std::mem::take(&mut state.refills);
+        drop(state);
+        for refill in refills {
+            refill(None);
+        }
+    }
+}
+
+/// Forgets the queued messages and leaves only the close frame for the writer task.
+fn disconnect(queue: &OutboundQueue, state: &mut OutboundQueueState) {
+    warn!(
+        "Disconnecting a session of {} that has not read {} messages for {:?}",
+        state
+            .username
+            .as_deref()
+            .unwrap_or("an unauthenticated client"),
+        state.messages.len(),
+        state
+            .full_since
+            .map(|full_since| full_since.elapsed())
+            .unwrap_or_default()
+    );
+    count(state, |counters| &counters.forced_disconnects);
+    let close_frame = CloseFrame {
+        code: SLOW_CONSUMER_CLOSE_CODE,
+        reason: "the client does not read its messages fast enough".into(),
+    };
+    state.messages.clear();
+    state.messages.push_back(QueuedMessage {
+        message: Message::Close(Some(close_frame.clone())),
+        is_ephemeral: false,
+    });
+    state.close_frame = Some(close_frame);
+    queue.message_added.notify_one();
+    queue.disconnected.notify_waiters();
+}
+
+/// private function
+fn count(
+    state: &mut OutboundQueueState,
+    counter: impl FnOnce(&OutboundQueueCounters) -> &AtomicU64,
+) {
+    let Some(username) = &state.username else {
+        return;
+    };
+    let counters = state.counters.get_or_insert_with(|| {
+        OUTBOUND_QUEUE_METRICS
+            .lock()
+            .unwrap()
+            .entry(username.clone())
+            .or_default()
+            .clone()
+    });
+    counter(counters).fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the counters of the users whose sessions have been slow, ordered by username.
+pub fn outbound_queue_metrics() -> Vec<(String, OutboundQueueMetrics)> {
+    OUTBOUND_QUEUE_METRICS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(username, counters)| {
+            (
+                username.clone(),
+                OutboundQueueMetrics {
+                    dropped_frames: counters.dropped_frames.load(Ordering::Relaxed),
+                    forced_disconnects: counters.forced_disconnects.load(Ordering::Relaxed),
+                },
+            )
+        })
+        .collect()
+}
+
+/// Returns the messages that are waiting in the queue.
+#[cfg(test)]
+pub fn drain_messages(messages_receiver: &mut MessagesReceiver) -> Vec<Message> {
+    std::iter::from_fn(|| messages_receiver.try_recv()).collect()
+}
+
+#[test]
+fn test_outbound_queue_policies() {
+    let text = |message: &Message| message.to_text().unwrap().to_string();
+    let metrics_of = |username: &str| {
+        outbound_queue_metrics()
+            .into_iter()
+            .find(|(metrics_username, _)| metrics_username == username)
+            .map(|(_, metrics)| metrics)
+            .unwrap_or_default()
+    };
+    let config = ServerConfig {
+        outbound_queue_capacity: 3,
+        slow_consumer_policy: SlowConsumerPolicy::DropOldestEphemeral,
+        slow_consumer_grace_period: Duration::from_secs(10),
+        ..ServerConfig::default()
+    };
+    let start = Instant::now();
+
+    // the oldest ephemeral events make room for the new messages
+    let (messages_sender, mut messages_receiver) = messages_channel(&config);
+    messages_sender.set_username(Some("slow-reader".to_string()));
+    for (content, is_ephemeral) in [
+        ("typing 1", true),
+        ("message 1", false),
+        ("typing 2", true),
+        ("message 2", false),
+        ("typing 3", true),
+        ("message 3", false),
+        ("typing 4", true),
+        ("message 4", false),
+    ] {
+        assert_eq!(
+            messages_sender.push(Message::Text(content.to_string()), is_ephemeral, start),
+            Ok(())
+        );
+    }
+    assert_eq!(
+        drain_messages(&mut messages_receiver)
+            .iter()
+            .map(text)
+            .collect::<Vec<String>>(),
+        vec!["message 1", "message 2", "message 3", "message 4"]
+    );
+    assert_eq!(
+        metrics_of("slow-reader"),
+        OutboundQueueMetrics {
+            dropped_frames: 4,
+            forced_disconnects: 0,
+        }
+    );
+
+    // a queue that stays full for the grace period is disconnected
+    let config = ServerConfig {
+        slow_consumer_policy: SlowConsumerPolicy::Disconnect,
+        ..config
+    };
+    let (messages_sender, mut messages_receiver) = messages_channel(&config);
+    messages_sender.set_username(Some("stuck-reader".to_string()));
+    for index in 0..4 {
+        let message = Message::Text(format!("typing {}", index));
+        assert_eq!(messages_sender.push(message, true, start), Ok(()));
+    }
+    assert_eq!(
+        messages_receiver.try_recv().map(|m| text(&m)),
+        Some("typing 0".to_string())
+    );
+    assert_eq!(
+        messages_sender.push(Message::Text("late".to_string()), false, start),
+        Ok(())
+    );
+    assert_eq!(
+        messages_sender.push(
+            Message::Text("too late".to_string()),
+            false,
+            start + Duration::from_secs(10)
+        ),
+        Err(SendError::Disconnected)
+    );
+    assert_eq!(
+        messages_sender.send(Message::Text("after".to_string())),
+        Err(SendError::Disconnected)
+    );
+    let close_frame = futures::executor::block_on(messages_receiver.disconnected());
+    assert_eq!(close_frame.code, SLOW_CONSUMER_CLOSE_CODE);
+    assert_eq!(
+        futures::executor::block_on(messages_receiver.recv()),
+        Some(Message::Close(Some(close_frame)))
+    );
+    assert_eq!(futures::executor::block_on(messages_receiver.recv()), None);
+    assert_eq!(metrics_of("stuck-reader").forced_disconnects, 1);
+
+    // nothing is kept above twice the capacity, even during the grace period
+    let (messages_sender, _messages_receiver) = messages_channel(&config);
+    for _ in 0..6 {
+        let message = Message::Text("message".to_string());
+        assert_eq!(messages_sender.push(message, false, start), Ok(()));
+    }
+    assert_eq!(
+        messages_sender.push(Message::Text("message".to_string()), false, start),
+        Err(SendError::Disconnected)
+    );
+
+    // the writer task stops when no sender is left
+    let (messages_sender, mut messages_receiver) = messages_channel(&config);
+    let _ = messages_sender.send(Message::Text("last".to_string()));
+    drop(messages_sender);
+    assert_eq!(
+        futures::executor::block_on(messages_receiver.recv()),
+        Some(Message::Text("last".to_string()))
+    );
+    assert_eq!(futures::executor::block_on(messages_receiver.recv()), None);
+}
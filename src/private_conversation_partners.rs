@@ -1,12 +1,24 @@
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
 use std::hash::{Hash, Hasher};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PrivateConversationPartnersHashmapKey {
     pub partner1: String,
     pub partner2: String,
 }
 
+impl PrivateConversationPartnersHashmapKey {
+    /// Returns the key of the conversation between the users, whatever their order is.
+    pub fn new(user1: &String, user2: &String) -> Self {
+        let (partner1, partner2) = if compare_usernames(user1, user2) {
+            (user1.clone(), user2.clone())
+        } else {
+            (user2.clone(), user1.clone())
+        };
+        PrivateConversationPartnersHashmapKey { partner1, partner2 }
+    }
+}
+
 /// In the future we might want to change the implementation. That's why we need this function.
 pub fn compare_usernames(partner1: &String, partner2: &String) -> bool {
     partner1 < partner2
@@ -16,7 +28,7 @@ impl PartialEq for PrivateConversationPartnersHashmapKey {
     fn eq(&self, other: &Self) -> bool {
         // Ensure equality regardless of the order of partners
         (self.partner1 == other.partner1 && self.partner2 == other.partner2)
-            // || (self.partner1 == other.partner2 && self.partner2 == other.partner1)
+            || (self.partner1 == other.partner2 && self.partner2 == other.partner1)
     }
 }
 
@@ -34,6 +46,8 @@ impl Hash for PrivateConversationPartnersHashmapKey {
 
 #[test]
 fn test_private_conversation_partners() {
+    use std::collections::HashMap;
+
     let key1 = PrivateConversationPartnersHashmapKey {
         partner1: "Alice".to_string(),
         partner2: "Bob".to_string(),
@@ -0,0 +1,200 @@
+use crate::storage::{Storage, StorageError, StoredRevokedSessionToken};
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::RwLock;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The ids of the revoked session tokens that have not expired yet, with their expiration times.
+static REVOKED_SESSION_TOKENS: Lazy<RwLock<HashMap<String, DateTime<Utc>>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// What a session token says about its holder. A client that presents a valid token is
+/// authenticated as the user without sending the password again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionToken {
+    /// a random id that lets the token be revoked.
+    pub token_id: String,
+    pub username: String,
+    /// whole seconds, so the time survives the round trip through the string form.
+    pub expires_at: DateTime<Utc>,
+}
+
+/// The reason why a session token is not accepted.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SessionTokenError {
+    Malformed,
+    InvalidSignature,
+    Expired,
+    Revoked,
+}
+
+impl fmt::Display for SessionTokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionTokenError::Malformed => write!(f, "the session token is malformed"),
+            SessionTokenError::InvalidSignature => {
+                write!(f, "the signature of the session token is not valid")
+            }
+            SessionTokenError::Expired => write!(f, "the session token has expired"),
+            SessionTokenError::Revoked => write!(f, "the session token has been revoked"),
+        }
+    }
+}
+
+impl SessionToken {
+    pub fn new(username: &str, now: DateTime<Utc>, lifetime: chrono::Duration) -> Self {
+        let mut token_id = [0; 16];
+        OsRng.fill_bytes(&mut token_id);
+        SessionToken {
+            token_id: hex::encode(token_id),
+            username: username.to_string(),
+            expires_at: now
+                .checked_add_signed(lifetime)
+                .and_then(|expires_at| DateTime::from_timestamp(expires_at.timestamp(), 0))
+                .unwrap_or(DateTime::<Utc>::MAX_UTC),
+        }
+    }
+
+    /// Returns the string form of the token: `token_id.expires_at.username.signature`, where
+    /// expires_at is in Unix seconds and the username and the HMAC-SHA256 signature of everything
+    /// before it are hex-encoded.
+    pub fn sign(&self, key: &[u8]) -> String {
+        let payload = format!(
+            "{}.{}.{}",
+            self.token_id,
+            self.expires_at.timestamp(),
+            hex::encode(&self.username)
+        );
+        let signature = hex::encode(new_mac(key, &payload).finalize().into_bytes());
+        format!("{}.{}", payload, signature)
+    }
+
+    /// Checks the signature, the expiration time and the revocation of the token. The signature
+    /// is compared in constant time.
+    pub fn verify(
+        token: &str,
+        key: &[u8],
+        now: DateTime<Utc>,
+    ) -> Result<SessionToken, SessionTokenError> {
+        let (payload, signature) = token.rsplit_once('.').ok_or(SessionTokenError::Malformed)?;
+        let signature = hex::decode(signature).map_err(|_| SessionTokenError::Malformed)?;
+        new_mac(key, payload)
+            .verify_slice(&signature)
+            .map_err(|_| SessionTokenError::InvalidSignature)?;
+
+        let mut fields = payload.split('.');
+        let (Some(token_id), Some(expires_at), Some(username), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(SessionTokenError::Malformed);
+        };
+        let expires_at = expires_at
+            .parse()
+            .ok()
+            .and_then(|expires_at| DateTime::from_timestamp(expires_at, 0))
+            .ok_or(SessionTokenError::Malformed)?;
+        let username = hex::decode(username)
+            .ok()
+            .and_then(|username| String::from_utf8(username).ok())
+            .ok_or(SessionTokenError::Malformed)?;
+        if expires_at <= now {
+            return Err(SessionTokenError::Expired);
+        }
+        if REVOKED_SESSION_TOKENS
+            .read()
+            .unwrap()
+            .contains_key(token_id)
+        {
+            return Err(SessionTokenError::Revoked);
+        }
+        Ok(SessionToken {
+            token_id: token_id.to_string(),
+            username,
+            expires_at,
+        })
+    }
+
+    /// Makes the token unusable and returns it in the form that the caller should save to the
+    /// storage. The revoked tokens that have expired are forgotten.
+    pub fn revoke(&self, now: DateTime<Utc>) -> StoredRevokedSessionToken {
+        let mut revoked_session_tokens = REVOKED_SESSION_TOKENS.write().unwrap();
+        revoked_session_tokens.retain(|_, expires_at| *expires_at > now);
+        revoked_session_tokens.insert(self.token_id.clone(), self.expires_at);
+        StoredRevokedSessionToken {
+            token_id: self.token_id.clone(),
+            expires_at: self.expires_at,
+        }
+    }
+}
+
+/// private function
+fn new_mac(key: &[u8], payload: &str) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(payload.as_bytes());
+    mac
+}
+
+/// Restores the revocations that have been saved before the restart, so the tokens signed with
+/// the same key stay revoked.
+pub fn load_revoked_session_tokens(
+    storage: &mut dyn Storage,
+    now: DateTime<Utc>,
+) -> Result<(), StorageError> {
+    let mut revoked_session_tokens = REVOKED_SESSION_TOKENS.write().unwrap();
+    for revoked_session_token in storage.load_revoked_session_tokens()? {
+        if revoked_session_token.expires_at > now {
+            revoked_session_tokens.insert(
+                revoked_session_token.token_id,
+                revoked_session_token.expires_at,
+            );
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_session_tokens() {
+    let now = Utc::now();
+    let key = b"test key";
+    let session_token = SessionToken::new("ian.smith", now, chrono::Duration::hours(1));
+    let token = session_token.sign(key);
+    assert_eq!(
+        SessionToken::verify(&token, key, now),
+        Ok(session_token.clone())
+    );
+
+    assert_eq!(
+        SessionToken::verify(&token, b"other key", now),
+        Err(SessionTokenError::InvalidSignature)
+    );
+    let forged_token = token.replacen(&hex::encode("ian.smith"), &hex::encode("dan.smith"), 1);
+    assert_eq!(
+        SessionToken::verify(&forged_token, key, now),
+        Err(SessionTokenError::InvalidSignature)
+    );
+    assert_eq!(
+        SessionToken::verify("garbage", key, now),
+        Err(SessionTokenError::Malformed)
+    );
+    assert_eq!(
+        SessionToken::verify(&token, key, now + chrono::Duration::hours(2)),
+        Err(SessionTokenError::Expired)
+    );
+
+    session_token.revoke(now);
+    assert_eq!(
+        SessionToken::verify(&token, key, now),
+        Err(SessionTokenError::Revoked)
+    );
+    let other_session_token = SessionToken::new("ian.smith", now, chrono::Duration::hours(1));
+    assert_eq!(
+        SessionToken::verify(&other_session_token.sign(key), key, now),
+        Ok(other_session_token)
+    );
+}
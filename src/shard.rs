@@ -0,0 +1,189 @@
+use crate::connection_handler::ConnectionCommand;
+use crate::private_conversation_partners::PrivateConversationPartnersHashmapKey;
+use crossbeam_channel::{unbounded, Receiver, SendError, Sender};
+use log::error;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+/// What decides which shard owns the state that a command reads or changes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ShardKey<'a> {
+    /// the opened sessions, the presence and the undelivered messages of the user.
+    User(&'a str),
+    /// a private conversation together with its message sequences and typing indicators.
+    Conversation(PrivateConversationPartnersHashmapKey),
+    /// the shards take turns creating groups, so the id of a group tells which shard owns it.
+    Group(u32),
+    Channel(&'a str),
+    /// a shard that has been chosen explicitly, e.g. for a part of a request that every shard
+    /// answers.
+    Index(usize),
+}
+
+/// Returns the index of the shard that owns the state of the key.
+pub fn shard_index(key: &ShardKey, shard_count: usize) -> usize {
+    match key {
+        ShardKey::User(username) | ShardKey::Channel(username) => {
+            hash_to_shard(username, shard_count)
+        }
+        // the hash does not depend on the order of the partners
+        ShardKey::Conversation(partners) => hash_to_shard(partners, shard_count),
+        ShardKey::Group(group_id) => group_id.wrapping_sub(1) as usize % shard_count,
+        ShardKey::Index(index) => index % shard_count,
+    }
+}
+
+/// private function
+fn hash_to_shard(key: &impl Hash, shard_count: usize) -> usize {
+    // the hasher is created with the same keys every time, so all threads agree on the shard
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % shard_count as u64) as usize
+}
+
+/// Sends the connection commands to the shards that own the state they concern.
+#[derive(Clone)]
+pub struct ConnectionCommandRouter {
+    shard_senders: Vec<Sender<ConnectionCommand>>,
+}
+
+impl ConnectionCommandRouter {
+    pub fn new(shard_senders: Vec<Sender<ConnectionCommand>>) -> Self {
+        assert!(
+            !shard_senders.is_empty(),
+            "there must be at least one shard"
+        );
+        ConnectionCommandRouter { shard_senders }
+    }
+
+    /// Creates the command channels of `shard_count` shards. Returns the router and the receivers
+    /// of the shards in the order of their indices.
+    pub fn with_shards(shard_count: usize) -> (Self, Vec<Receiver<ConnectionCommand>>) {
+        let (shard_senders, shard_receivers) = (0..shard_count.max(1)).map(|_| unbounded()).unzip();
+        (Self::new(shard_senders), shard_receivers)
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shard_senders.len()
+    }
+
+    pub fn send(&self, command: ConnectionCommand) -> Result<(), SendError<ConnectionCommand>> {
+        let shard_index = shard_index(&command.shard_key(), self.shard_count());
+        self.shard_senders[shard_index].send(command)
+    }
+}
+
+/// What a shard of ApplicationScope knows about itself and the other shards.
+pub struct ShardContext {
+    pub index: usize,
+    /// None if the shard is the only one.
+    router: Option<ConnectionCommandRouter>,
+}
+
+impl ShardContext {
+    /// The context of an ApplicationScope that owns the whole state.
+    pub fn unsharded() -> Self {
+        ShardContext {
+            index: 0,
+            router: None,
+        }
+    }
+
+    pub fn new(index: usize, router: ConnectionCommandRouter) -> Self {
+        ShardContext {
+            index,
+            router: Some(router),
+        }
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.router
+            .as_ref()
+            .map_or(1, |router| router.shard_count())
+    }
+
+    pub fn shard_index(&self, key: &ShardKey) -> usize {
+        shard_index(key, self.shard_count())
+    }
+
+    /// Returns true if this shard owns the state of the key.
+    pub fn owns(&self, key: &ShardKey) -> bool {
+        self.shard_index(key) == self.index
+    }
+
+    /// Returns the router of all the shards. None if the shard is the only one.
+    pub fn router(&self) -> Option<&ConnectionCommandRouter> {
+        self.router.as_ref()
+    }
+
+    /// Hands a command over to the shard that owns the state it concerns.
+    pub fn forward(&self, command: ConnectionCommand) {
+        match &self.router {
+            Some(router) => {
+                if router.send(command).is_err() {
+                    error!("The shard has stopped, so a command has been lost");
+                }
+            }
+            None => error!("A command cannot be forwarded because there are no other shards"),
+        }
+    }
+}
+
+/// A reply that is put together from the parts that several shards add. The shard that adds the
+/// last part sends the reply.
+pub struct PartialReply<T> {
+    /// how many parts are missing and the parts that have been added.
+    parts: Mutex<(usize, Vec<T>)>,
+}
+
+impl<T> PartialReply<T> {
+    pub fn new(part_count: usize) -> Arc<Self> {
+        Arc::new(PartialReply {
+            parts: Mutex::new((part_count, Vec::with_capacity(part_count))),
+        })
+    }
+
+    /// Adds a part. Returns all the parts if it is the last one.
+    pub fn add(&self, part: T) -> Option<Vec<T>> {
+        let mut parts = self.parts.lock().unwrap();
+        parts.1.push(part);
+        parts.0 -= 1;
+        if parts.0 == 0 {
+            Some(std::mem::take(&mut parts.1))
+        } else {
+            None
+        }
+    }
+}
+
+#[test]
+fn test_shard_index() {
+    let partners = PrivateConversationPartnersHashmapKey {
+        partner1: "dan".to_string(),
+        partner2: "ian".to_string(),
+    };
+    let swapped_partners = PrivateConversationPartnersHashmapKey {
+        partner1: "ian".to_string(),
+        partner2: "dan".to_string(),
+    };
+    for shard_count in 1..8 {
+        assert_eq!(
+            shard_index(&ShardKey::Conversation(partners.clone()), shard_count),
+            shard_index(
+                &ShardKey::Conversation(swapped_partners.clone()),
+                shard_count
+            )
+        );
+        assert!(shard_index(&ShardKey::User("ian"), shard_count) < shard_count);
+    }
+    // every shard gets its turn to own a group
+    let shard_count = 3;
+    let group_shards: Vec<usize> = (1..=6)
+        .map(|group_id| shard_index(&ShardKey::Group(group_id), shard_count))
+        .collect();
+    assert_eq!(group_shards, vec![0, 1, 2, 0, 1, 2]);
+
+    let parts = PartialReply::new(2);
+    assert_eq!(parts.add(1), None);
+    assert_eq!(parts.add(2), Some(vec![1, 2]));
+}
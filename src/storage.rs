@@ -0,0 +1,1009 @@
+use crate::config::{ServerConfig, StorageKind};
+use crate::journal::JournalStorage;
+use crate::private_conversation_partners::PrivateConversationPartnersHashmapKey;
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// A registered user as it is kept in the storage.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredUser {
+    pub username: String,
+    /// the salted hash of the password in the PHC string format.
+    pub password_hash: String,
+    /// administrators can add and disable users.
+    #[serde(default)]
+    pub is_admin: bool,
+    /// disabled users cannot authenticate.
+    #[serde(default)]
+    pub is_disabled: bool,
+}
+
+/// A content that a private message had before it was edited.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredMessageRevision {
+    pub content: String,
+    pub replaced_at: DateTime<Utc>,
+}
+
+/// A private message as it is kept in the storage.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredPrivateMessage {
+    pub id: u32,
+    pub is_sender_user1: bool,
+    pub content: String,
+    pub server_time: DateTime<Utc>,
+    pub is_deleted: bool,
+    pub edited_at: Option<DateTime<Utc>>,
+    pub revisions: Vec<StoredMessageRevision>,
+}
+
+/// The data of a private conversation that is relevant only to one of the partners. The reorder
+/// buffers are not stored: the messages that wait in them on restart are lost, as if they had
+/// expired.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredPartnerState {
+    pub message_sequence_id_offset: u32,
+    /// how many messages of each message sequence have been accepted, starting from the sequence
+    /// with id message_sequence_id_offset.
+    pub message_sequence_state: Vec<u32>,
+    pub last_read_message_id: u32,
+}
+
+/// A private conversation as it is kept in the storage.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredPrivateConversation {
+    pub partners: PrivateConversationPartnersHashmapKey,
+    pub id_offset: u32,
+    /// the messages ordered by their ids.
+    pub messages: Vec<StoredPrivateMessage>,
+    pub partner1_state: StoredPartnerState,
+    pub partner2_state: StoredPartnerState,
+}
+
+/// A private message that has not been delivered to any session of its receiver yet.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredUndeliveredMessage {
+    pub sender: String,
+    pub id: u32,
+}
+
+/// A session token that has been revoked before it expired.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredRevokedSessionToken {
+    pub token_id: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// The reason why the storage cannot load or save the data.
+#[derive(Debug)]
+pub enum StorageError {
+    Io(std::io::Error),
+    Sqlite(rusqlite::Error),
+    /// the stored data cannot be turned back into the state of the server.
+    Corrupted(String),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::Io(e) => write!(f, "I/O error: {}", e),
+            StorageError::Sqlite(e) => write!(f, "SQLite error: {}", e),
+            StorageError::Corrupted(details) => {
+                write!(f, "the stored data is corrupted: {}", details)
+            }
+        }
+    }
+}
+
+impl From<std::io::Error> for StorageError {
+    fn from(e: std::io::Error) -> Self {
+        StorageError::Io(e)
+    }
+}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(e: rusqlite::Error) -> Self {
+        StorageError::Sqlite(e)
+    }
+}
+
+impl From<serde_json::Error> for StorageError {
+    fn from(e: serde_json::Error) -> Self {
+        StorageError::Corrupted(e.to_string())
+    }
+}
+
+/// Keeps the users, the private conversations and the state of their message sequences between
+/// restarts of the server. The state is loaded once at startup and every change is written through
+/// as soon as it is made in ApplicationScope. The groups and the channels are not stored.
+pub trait Storage: Send {
+    fn load_users(&mut self) -> Result<Vec<StoredUser>, StorageError>;
+
+    /// Adds the user or replaces the stored one with the same username.
+    fn save_user(&mut self, user: &StoredUser) -> Result<(), StorageError>;
+
+    fn load_private_conversations(
+        &mut self,
+    ) -> Result<Vec<StoredPrivateConversation>, StorageError>;
+
+    /// Registers a new private conversation together with its messages and message sequences.
+    fn save_private_conversation(
+        &mut self,
+        private_conversation: &StoredPrivateConversation,
+    ) -> Result<(), StorageError>;
+
+    /// Adds the message or replaces the stored one with the same id, e.g. after an edit.
+    fn save_private_message(
+        &mut self,
+        partners: &PrivateConversationPartnersHashmapKey,
+        private_message: &StoredPrivateMessage,
+    ) -> Result<(), StorageError>;
+
+    /// Remembers how many messages of the message sequence of one partner have been accepted.
+    fn save_message_sequence(
+        &mut self,
+        partners: &PrivateConversationPartnersHashmapKey,
+        is_partner1: bool,
+        message_sequence_id: u32,
+        accepted_count: u32,
+    ) -> Result<(), StorageError>;
+
+    /// Forgets the message sequences of one partner whose ids are lower than
+    /// message_sequence_id_offset.
+    fn forget_message_sequences(
+        &mut self,
+        partners: &PrivateConversationPartnersHashmapKey,
+        is_partner1: bool,
+        message_sequence_id_offset: u32,
+    ) -> Result<(), StorageError>;
+
+    fn save_last_read_message_id(
+        &mut self,
+        partners: &PrivateConversationPartnersHashmapKey,
+        is_partner1: bool,
+        last_read_message_id: u32,
+    ) -> Result<(), StorageError>;
+
+    /// Returns the undelivered messages of every user who has some, oldest first.
+    fn load_undelivered_messages(
+        &mut self,
+    ) -> Result<Vec<(String, Vec<StoredUndeliveredMessage>)>, StorageError>;
+
+    /// Replaces the undelivered messages of the user.
+    fn save_undelivered_messages(
+        &mut self,
+        username: &str,
+        undelivered_messages: &[StoredUndeliveredMessage],
+    ) -> Result<(), StorageError>;
+
+    /// Returns the revoked session tokens. The ones that have expired may be among them.
+    fn load_revoked_session_tokens(
+        &mut self,
+    ) -> Result<Vec<StoredRevokedSessionToken>, StorageError>;
+
+    /// Adds the revoked session token. The storage may forget the revoked tokens that have
+    /// expired.
+    fn save_revoked_session_token(
+        &mut self,
+        revoked_session_token: &StoredRevokedSessionToken,
+    ) -> Result<(), StorageError>;
+
+    /// Returns true if the storage keeps nothing, so the shards do not need to share it.
+    fn is_volatile(&self) -> bool {
+        false
+    }
+}
+
+/// Opens the storage that is selected in the config.
+pub fn open_storage(config: &ServerConfig) -> Result<Box<dyn Storage>, StorageError> {
+    match config.storage {
+        StorageKind::Memory => Ok(Box::new(MemoryStorage)),
+        StorageKind::Sqlite => Ok(Box::new(SqliteStorage::open(&config.sqlite_path)?)),
+        StorageKind::Journal => Ok(Box::new(JournalStorage::open(
+            &config.journal_dir,
+            config.journal_snapshot_interval,
+        )?)),
+    }
+}
+
+/// Keeps nothing: the state lives only in ApplicationScope and is lost on restart.
+pub struct MemoryStorage;
+
+impl Storage for MemoryStorage {
+    fn is_volatile(&self) -> bool {
+        true
+    }
+
+    fn load_users(&mut self) -> Result<Vec<StoredUser>, StorageError> {
+        Ok(Vec::new())
+    }
+
+    fn save_user(&mut self, _user: &StoredUser) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    fn load_private_conversations(
+        &mut self,
+    ) -> Result<Vec<StoredPrivateConversation>, StorageError> {
+        Ok(Vec::new())
+    }
+
+    fn save_private_conversation(
+        &mut self,
+        _private_conversation: &StoredPrivateConversation,
+    ) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    fn save_private_message(
+        &mut self,
+        _partners: &PrivateConversationPartnersHashmapKey,
+        _private_message: &StoredPrivateMessage,
+    ) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    fn save_message_sequence(
+        &mut self,
+        _partners: &PrivateConversationPartnersHashmapKey,
+        _is_partner1: bool,
+        _message_sequence_id: u32,
+        _accepted_count: u32,
+    ) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    fn forget_message_sequences(
+        &mut self,
+        _partners: &PrivateConversationPartnersHashmapKey,
+        _is_partner1: bool,
+        _message_sequence_id_offset: u32,
+    ) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    fn save_last_read_message_id(
+        &mut self,
+        _partners: &PrivateConversationPartnersHashmapKey,
+        _is_partner1: bool,
+        _last_read_message_id: u32,
+    ) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    fn load_undelivered_messages(
+        &mut self,
+    ) -> Result<Vec<(String, Vec<StoredUndeliveredMessage>)>, StorageError> {
+        Ok(Vec::new())
+    }
+
+    fn save_undelivered_messages(
+        &mut self,
+        _username: &str,
+        _undelivered_messages: &[StoredUndeliveredMessage],
+    ) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    fn load_revoked_session_tokens(
+        &mut self,
+    ) -> Result<Vec<StoredRevokedSessionToken>, StorageError> {
+        Ok(Vec::new())
+    }
+
+    fn save_revoked_session_token(
+        &mut self,
+        _revoked_session_token: &StoredRevokedSessionToken,
+    ) -> Result<(), StorageError> {
+        Ok(())
+    }
+}
+
+/// Keeps the data in an SQLite database file.
+pub struct SqliteStorage {
+    connection: Connection,
+}
+
+const SQLITE_SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS users (
+        username TEXT PRIMARY KEY,
+        password_hash TEXT NOT NULL,
+        is_admin INTEGER NOT NULL,
+        is_disabled INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS private_conversations (
+        id INTEGER PRIMARY KEY,
+        partner1 TEXT NOT NULL,
+        partner2 TEXT NOT NULL,
+        id_offset INTEGER NOT NULL,
+        partner1_message_sequence_id_offset INTEGER NOT NULL,
+        partner2_message_sequence_id_offset INTEGER NOT NULL,
+        partner1_last_read_message_id INTEGER NOT NULL,
+        partner2_last_read_message_id INTEGER NOT NULL,
+        UNIQUE (partner1, partner2)
+    );
+    CREATE TABLE IF NOT EXISTS private_messages (
+        conversation_id INTEGER NOT NULL REFERENCES private_conversations (id),
+        id INTEGER NOT NULL,
+        is_sender_user1 INTEGER NOT NULL,
+        content TEXT NOT NULL,
+        server_time TEXT NOT NULL,
+        is_deleted INTEGER NOT NULL,
+        edited_at TEXT,
+        revisions TEXT NOT NULL,
+        PRIMARY KEY (conversation_id, id)
+    );
+    CREATE TABLE IF NOT EXISTS message_sequences (
+        conversation_id INTEGER NOT NULL REFERENCES private_conversations (id),
+        is_partner1 INTEGER NOT NULL,
+        id INTEGER NOT NULL,
+        accepted_count INTEGER NOT NULL,
+        PRIMARY KEY (conversation_id, is_partner1, id)
+    );
+    CREATE TABLE IF NOT EXISTS undelivered_messages (
+        username TEXT PRIMARY KEY,
+        messages TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS revoked_session_tokens (
+        token_id TEXT PRIMARY KEY,
+        expires_at TEXT NOT NULL
+    );
+";
+
+impl SqliteStorage {
+    /// Opens the database file and creates the tables that do not exist yet.
+    pub fn open(path: &Path) -> Result<Self, StorageError> {
+        let connection = Connection::open(path)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.execute_batch(SQLITE_SCHEMA)?;
+        Ok(SqliteStorage { connection })
+    }
+
+    /// private function
+    fn find_private_conversation_id(
+        &self,
+        partners: &PrivateConversationPartnersHashmapKey,
+    ) -> Result<i64, StorageError> {
+        self.connection
+            .query_row(
+                "SELECT id FROM private_conversations WHERE partner1 = ?1 AND partner2 = ?2",
+                params![partners.partner1, partners.partner2],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or_else(|| {
+                StorageError::Corrupted(format!(
+                    "the conversation of {} and {} is not stored",
+                    partners.partner1, partners.partner2
+                ))
+            })
+    }
+
+    /// private function
+    fn insert_private_message(
+        connection: &Connection,
+        conversation_id: i64,
+        private_message: &StoredPrivateMessage,
+    ) -> Result<(), StorageError> {
+        connection.execute(
+            "INSERT OR REPLACE INTO private_messages
+                (conversation_id, id, is_sender_user1, content, server_time, is_deleted, edited_at,
+                 revisions)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                conversation_id,
+                private_message.id,
+                private_message.is_sender_user1,
+                private_message.content,
+                private_message.server_time,
+                private_message.is_deleted,
+                private_message.edited_at,
+                serde_json::to_string(&private_message.revisions)?,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// private function
+    fn insert_message_sequence(
+        connection: &Connection,
+        conversation_id: i64,
+        is_partner1: bool,
+        message_sequence_id: u32,
+        accepted_count: u32,
+    ) -> Result<(), StorageError> {
+        connection.execute(
+            "INSERT OR REPLACE INTO message_sequences (conversation_id, is_partner1, id, accepted_count)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![conversation_id, is_partner1, message_sequence_id, accepted_count],
+        )?;
+        Ok(())
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn load_users(&mut self) -> Result<Vec<StoredUser>, StorageError> {
+        let mut statement = self.connection.prepare(
+            "SELECT username, password_hash, is_admin, is_disabled FROM users ORDER BY username",
+        )?;
+        let users = statement
+            .query_map([], |row| {
+                Ok(StoredUser {
+                    username: row.get(0)?,
+                    password_hash: row.get(1)?,
+                    is_admin: row.get(2)?,
+                    is_disabled: row.get(3)?,
+                })
+            })?
+            .collect::<Result<Vec<StoredUser>, rusqlite::Error>>()?;
+        Ok(users)
+    }
+
+    fn save_user(&mut self, user: &StoredUser) -> Result<(), StorageError> {
+        self.connection.execute(
+            "INSERT OR REPLACE INTO users (username, password_hash, is_admin, is_disabled)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![
+                user.username,
+                user.password_hash,
+                user.is_admin,
+                user.is_disabled
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn load_private_conversations(
+        &mut self,
+    ) -> Result<Vec<StoredPrivateConversation>, StorageError> {
+        let mut private_conversations = BTreeMap::new();
+        let mut statement = self.connection.prepare(
+            "SELECT id, partner1, partner2, id_offset, partner1_message_sequence_id_offset,
+                    partner2_message_sequence_id_offset, partner1_last_read_message_id,
+                    partner2_last_read_message_id
+             FROM private_conversations",
+        )?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let conversation_id: i64 = row.get(0)?;
+            private_conversations.insert(
+                conversation_id,
+                StoredPrivateConversation {
+                    partners: PrivateConversationPartnersHashmapKey {
+                        partner1: row.get(1)?,
+                        partner2: row.get(2)?,
+                    },
+                    id_offset: row.get(3)?,
+                    messages: Vec::new(),
+                    partner1_state: StoredPartnerState {
+                        message_sequence_id_offset: row.get(4)?,
+                        message_sequence_state: Vec::new(),
+                        last_read_message_id: row.get(6)?,
+                    },
+                    partner2_state: StoredPartnerState {
+                        message_sequence_id_offset: row.get(5)?,
+                        message_sequence_state: Vec::new(),
+                        last_read_message_id: row.get(7)?,
+                    },
+                },
+            );
+        }
+
+        let mut statement = self.connection.prepare(
+            "SELECT conversation_id, id, is_sender_user1, content, server_time, is_deleted,
+                    edited_at, revisions
+             FROM private_messages ORDER BY conversation_id, id",
+        )?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let conversation_id: i64 = row.get(0)?;
+            let revisions: String = row.get(7)?;
+            let private_message = StoredPrivateMessage {
+                id: row.get(1)?,
+                is_sender_user1: row.get(2)?,
+                content: row.get(3)?,
+                server_time: row.get(4)?,
+                is_deleted: row.get(5)?,
+                edited_at: row.get(6)?,
+                revisions: serde_json::from_str(&revisions)?,
+            };
+            let private_conversation =
+                private_conversations
+                    .get_mut(&conversation_id)
+                    .ok_or_else(|| {
+                        StorageError::Corrupted(format!(
+                            "the message {} belongs to the unknown conversation {}",
+                            private_message.id, conversation_id
+                        ))
+                    })?;
+            private_conversation.messages.push(private_message);
+        }
+
+        let mut statement = self.connection.prepare(
+            "SELECT conversation_id, is_partner1, id, accepted_count
+             FROM message_sequences ORDER BY conversation_id, is_partner1, id",
+        )?;
+        let mut rows = statement.query([])?;
+        while let Some(row) = rows.next()? {
+            let conversation_id: i64 = row.get(0)?;
+            let is_partner1: bool = row.get(1)?;
+            let message_sequence_id: u32 = row.get(2)?;
+            let private_conversation =
+                private_conversations
+                    .get_mut(&conversation_id)
+                    .ok_or_else(|| {
+                        StorageError::Corrupted(format!(
+                            "the message sequence {} belongs to the unknown conversation {}",
+                            message_sequence_id, conversation_id
+                        ))
+                    })?;
+            let partner_state = if is_partner1 {
+                &mut private_conversation.partner1_state
+            } else {
+                &mut private_conversation.partner2_state
+            };
+            // the ids of the sequences of one partner have no gaps
+            let expected_id = partner_state.message_sequence_id_offset
+                + partner_state.message_sequence_state.len() as u32;
+            if message_sequence_id != expected_id {
+                return Err(StorageError::Corrupted(format!(
+                    "the message sequence {} is stored instead of {} in the conversation {}",
+                    message_sequence_id, expected_id, conversation_id
+                )));
+            }
+            partner_state.message_sequence_state.push(row.get(3)?);
+        }
+        Ok(private_conversations.into_values().collect())
+    }
+
+    fn save_private_conversation(
+        &mut self,
+        private_conversation: &StoredPrivateConversation,
+    ) -> Result<(), StorageError> {
+        let transaction = self.connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO private_conversations
+                (partner1, partner2, id_offset, partner1_message_sequence_id_offset,
+                 partner2_message_sequence_id_offset, partner1_last_read_message_id,
+                 partner2_last_read_message_id)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                private_conversation.partners.partner1,
+                private_conversation.partners.partner2,
+                private_conversation.id_offset,
+                private_conversation
+                    .partner1_state
+                    .message_sequence_id_offset,
+                private_conversation
+                    .partner2_state
+                    .message_sequence_id_offset,
+                private_conversation.partner1_state.last_read_message_id,
+                private_conversation.partner2_state.last_read_message_id,
+            ],
+        )?;
+        let conversation_id = transaction.last_insert_rowid();
+        for private_message in &private_conversation.messages {
+            Self::insert_private_message(&transaction, conversation_id, private_message)?;
+        }
+        for (is_partner1, partner_state) in [
+            (true, &private_conversation.partner1_state),
+            (false, &private_conversation.partner2_state),
+        ] {
+            for (index, accepted_count) in partner_state.message_sequence_state.iter().enumerate() {
+                Self::insert_message_sequence(
+                    &transaction,
+                    conversation_id,
+                    is_partner1,
+                    partner_state.message_sequence_id_offset + index as u32,
+                    *accepted_count,
+                )?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn save_private_message(
+        &mut self,
+        partners: &PrivateConversationPartnersHashmapKey,
+        private_message: &StoredPrivateMessage,
+    ) -> Result<(), StorageError> {
+        let conversation_id = self.find_private_conversation_id(partners)?;
+        Self::insert_private_message(&self.connection, conversation_id, private_message)
+    }
+
+    fn save_message_sequence(
+        &mut self,
+        partners: &PrivateConversationPartnersHashmapKey,
+        is_partner1: bool,
+        message_sequence_id: u32,
+        accepted_count: u32,
+    ) -> Result<(), StorageError> {
+        let conversation_id = self.find_private_conversation_id(partners)?;
+        Self::insert_message_sequence(
+            &self.connection,
+            conversation_id,
+            is_partner1,
+            message_sequence_id,
+            accepted_count,
+        )
+    }
+
+    fn forget_message_sequences(
+        &mut self,
+        partners: &PrivateConversationPartnersHashmapKey,
+        is_partner1: bool,
+        message_sequence_id_offset: u32,
+    ) -> Result<(), StorageError> {
+        let conversation_id = self.find_private_conversation_id(partners)?;
+        let sql = if is_partner1 {
+            "UPDATE private_conversations SET partner1_message_sequence_id_offset = ?2
+             WHERE id = ?1"
+        } else {
+            "UPDATE private_conversations SET partner2_message_sequence_id_offset = ?2
+             WHERE id = ?1"
+        };
+        let transaction = self.connection.transaction()?;
+        transaction.execute(sql, params![conversation_id, message_sequence_id_offset])?;
+        transaction.execute(
+            "DELETE FROM message_sequences
+             WHERE conversation_id = ?1 AND is_partner1 = ?2 AND id < ?3",
+            params![conversation_id, is_partner1, message_sequence_id_offset],
+        )?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn save_last_read_message_id(
+        &mut self,
+        partners: &PrivateConversationPartnersHashmapKey,
+        is_partner1: bool,
+        last_read_message_id: u32,
+    ) -> Result<(), StorageError> {
+        let sql = if is_partner1 {
+            "UPDATE private_conversations SET partner1_last_read_message_id = ?3
+             WHERE partner1 = ?1 AND partner2 = ?2"
+        } else {
+            "UPDATE private_conversations SET partner2_last_read_message_id = ?3
+             WHERE partner1 = ?1 AND partner2 = ?2"
+        };
+        self.connection.execute(
+            sql,
+            params![partners.partner1, partners.partner2, last_read_message_id],
+        )?;
+        Ok(())
+    }
+
+    fn load_undelivered_messages(
+        &mut self,
+    ) -> Result<Vec<(String, Vec<StoredUndeliveredMessage>)>, StorageError> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT username, messages FROM undelivered_messages")?;
+        let rows = statement
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<Result<Vec<(String, String)>, rusqlite::Error>>()?;
+        let mut undelivered_messages = Vec::new();
+        for (username, messages) in rows {
+            undelivered_messages.push((username, serde_json::from_str(&messages)?));
+        }
+        Ok(undelivered_messages)
+    }
+
+    fn save_undelivered_messages(
+        &mut self,
+        username: &str,
+        undelivered_messages: &[StoredUndeliveredMessage],
+    ) -> Result<(), StorageError> {
+        if undelivered_messages.is_empty() {
+            self.connection.execute(
+                "DELETE FROM undelivered_messages WHERE username = ?1",
+                params![username],
+            )?;
+        } else {
+            self.connection.execute(
+                "INSERT OR REPLACE INTO undelivered_messages (username, messages) VALUES (?1, ?2)",
+                params![username, serde_json::to_string(undelivered_messages)?],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn load_revoked_session_tokens(
+        &mut self,
+    ) -> Result<Vec<StoredRevokedSessionToken>, StorageError> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT token_id, expires_at FROM revoked_session_tokens")?;
+        let revoked_session_tokens = statement
+            .query_map([], |row| {
+                Ok(StoredRevokedSessionToken {
+                    token_id: row.get(0)?,
+                    expires_at: row.get(1)?,
+                })
+            })?
+            .collect::<Result<Vec<StoredRevokedSessionToken>, rusqlite::Error>>()?;
+        Ok(revoked_session_tokens)
+    }
+
+    fn save_revoked_session_token(
+        &mut self,
+        revoked_session_token: &StoredRevokedSessionToken,
+    ) -> Result<(), StorageError> {
+        self.connection.execute(
+            "DELETE FROM revoked_session_tokens WHERE expires_at <= ?1",
+            params![Utc::now()],
+        )?;
+        self.connection.execute(
+            "INSERT OR REPLACE INTO revoked_session_tokens (token_id, expires_at) VALUES (?1, ?2)",
+            params![
+                revoked_session_token.token_id,
+                revoked_session_token.expires_at
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+/// Lets several shards of ApplicationScope write to the same storage. The writes are made one at
+/// a time.
+#[derive(Clone)]
+pub struct SharedStorage(Arc<Mutex<Box<dyn Storage>>>);
+
+impl SharedStorage {
+    pub fn new(storage: Box<dyn Storage>) -> Self {
+        SharedStorage(Arc::new(Mutex::new(storage)))
+    }
+
+    /// private function
+    fn lock(&self) -> MutexGuard<'_, Box<dyn Storage>> {
+        // a shard that has panicked while writing leaves the storage usable for the others
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Storage for SharedStorage {
+    fn load_users(&mut self) -> Result<Vec<StoredUser>, StorageError> {
+        self.lock().load_users()
+    }
+
+    fn save_user(&mut self, user: &StoredUser) -> Result<(), StorageError> {
+        self.lock().save_user(user)
+    }
+
+    fn load_private_conversations(
+        &mut self,
+    ) -> Result<Vec<StoredPrivateConversation>, StorageError> {
+        self.lock().load_private_conversations()
+    }
+
+    fn save_private_conversation(
+        &mut self,
+        private_conversation: &StoredPrivateConversation,
+    ) -> Result<(), StorageError> {
+        self.lock().save_private_conversation(private_conversation)
+    }
+
+    fn save_private_message(
+        &mut self,
+        partners: &PrivateConversationPartnersHashmapKey,
+        private_message: &StoredPrivateMessage,
+    ) -> Result<(), StorageError> {
+        self.lock().save_private_message(partners, private_message)
+    }
+
+    fn save_message_sequence(
+        &mut self,
+        partners: &PrivateConversationPartnersHashmapKey,
+        is_partner1: bool,
+        message_sequence_id: u32,
+        accepted_count: u32,
+    ) -> Result<(), StorageError> {
+        self.lock()
+            .save_message_sequence(partners, is_partner1, message_sequence_id, accepted_count)
+    }
+
+    fn forget_message_sequences(
+        &mut self,
+        partners: &PrivateConversationPartnersHashmapKey,
+        is_partner1: bool,
+        message_sequence_id_offset: u32,
+    ) -> Result<(), StorageError> {
+        self.lock()
+            .forget_message_sequences(partners, is_partner1, message_sequence_id_offset)
+    }
+
+    fn save_last_read_message_id(
+        &mut self,
+        partners: &PrivateConversationPartnersHashmapKey,
+        is_partner1: bool,
+        last_read_message_id: u32,
+    ) -> Result<(), StorageError> {
+        self.lock()
+            .save_last_read_message_id(partners, is_partner1, last_read_message_id)
+    }
+
+    fn load_undelivered_messages(
+        &mut self,
+    ) -> Result<Vec<(String, Vec<StoredUndeliveredMessage>)>, StorageError> {
+        self.lock().load_undelivered_messages()
+    }
+
+    fn save_undelivered_messages(
+        &mut self,
+        username: &str,
+        undelivered_messages: &[StoredUndeliveredMessage],
+    ) -> Result<(), StorageError> {
+        self.lock()
+            .save_undelivered_messages(username, undelivered_messages)
+    }
+
+    fn load_revoked_session_tokens(
+        &mut self,
+    ) -> Result<Vec<StoredRevokedSessionToken>, StorageError> {
+        self.lock().load_revoked_session_tokens()
+    }
+
+    fn save_revoked_session_token(
+        &mut self,
+        revoked_session_token: &StoredRevokedSessionToken,
+    ) -> Result<(), StorageError> {
+        self.lock().save_revoked_session_token(revoked_session_token)
+    }
+}
+
+#[cfg(test)]
+pub fn temporary_sqlite_path(name: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("puchat-{}-{}.sqlite3", name, std::process::id()));
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+    }
+    path
+}
+
+#[test]
+fn test_sqlite_storage() {
+    let path = temporary_sqlite_path("storage");
+    let partners = PrivateConversationPartnersHashmapKey {
+        partner1: "dan".to_string(),
+        partner2: "ian".to_string(),
+    };
+    let first_message = StoredPrivateMessage {
+        id: 1,
+        is_sender_user1: true,
+        content: "hi".to_string(),
+        server_time: Utc::now(),
+        is_deleted: false,
+        edited_at: None,
+        revisions: Vec::new(),
+    };
+    let revoked_token_expires_at =
+        DateTime::from_timestamp(Utc::now().timestamp() + 3600, 0).unwrap();
+    {
+        let mut storage = SqliteStorage::open(&path).unwrap();
+        storage
+            .save_user(&StoredUser {
+                username: "ian".to_string(),
+                password_hash: "$argon2id$v=19$m=19456,t=2,p=1$c2FsdA$aGFzaA".to_string(),
+                is_admin: true,
+                is_disabled: false,
+            })
+            .unwrap();
+        storage
+            .save_private_conversation(&StoredPrivateConversation {
+                partners: partners.clone(),
+                id_offset: 0,
+                messages: vec![first_message.clone()],
+                partner1_state: StoredPartnerState::default(),
+                partner2_state: StoredPartnerState::default(),
+            })
+            .unwrap();
+        storage
+            .save_message_sequence(&partners, false, 0, 0)
+            .unwrap();
+        storage
+            .save_message_sequence(&partners, false, 1, 0)
+            .unwrap();
+        storage
+            .save_message_sequence(&partners, false, 0, 2)
+            .unwrap();
+        storage
+            .save_last_read_message_id(&partners, false, 1)
+            .unwrap();
+        // an edit replaces the stored message
+        let edited_message = StoredPrivateMessage {
+            content: "hello".to_string(),
+            edited_at: Some(Utc::now()),
+            revisions: vec![StoredMessageRevision {
+                content: "hi".to_string(),
+                replaced_at: Utc::now(),
+            }],
+            ..first_message.clone()
+        };
+        storage
+            .save_private_message(&partners, &edited_message)
+            .unwrap();
+        storage
+            .save_undelivered_messages(
+                "ian",
+                &[StoredUndeliveredMessage {
+                    sender: "dan".to_string(),
+                    id: 1,
+                }],
+            )
+            .unwrap();
+        storage.save_undelivered_messages("dan", &[]).unwrap();
+        storage
+            .save_revoked_session_token(&StoredRevokedSessionToken {
+                token_id: "expired".to_string(),
+                expires_at: Utc::now() - chrono::Duration::hours(1),
+            })
+            .unwrap();
+        storage
+            .save_revoked_session_token(&StoredRevokedSessionToken {
+                token_id: "revoked".to_string(),
+                expires_at: revoked_token_expires_at,
+            })
+            .unwrap();
+    }
+
+    // everything is still there after the database is reopened
+    let mut storage = SqliteStorage::open(&path).unwrap();
+    assert_eq!(
+        storage.load_users().unwrap(),
+        vec![StoredUser {
+            username: "ian".to_string(),
+            password_hash: "$argon2id$v=19$m=19456,t=2,p=1$c2FsdA$aGFzaA".to_string(),
+            is_admin: true,
+            is_disabled: false,
+        }]
+    );
+    let private_conversations = storage.load_private_conversations().unwrap();
+    assert_eq!(private_conversations.len(), 1);
+    let private_conversation = &private_conversations[0];
+    assert_eq!(private_conversation.partners, partners);
+    assert_eq!(private_conversation.messages.len(), 1);
+    assert_eq!(private_conversation.messages[0].content, "hello");
+    assert_eq!(private_conversation.messages[0].revisions.len(), 1);
+    assert_eq!(
+        private_conversation.messages[0].server_time,
+        first_message.server_time
+    );
+    assert_eq!(
+        private_conversation.partner2_state,
+        StoredPartnerState {
+            message_sequence_id_offset: 0,
+            message_sequence_state: vec![2, 0],
+            last_read_message_id: 1,
+        }
+    );
+    assert_eq!(
+        private_conversation.partner1_state,
+        StoredPartnerState::default()
+    );
+    assert_eq!(
+        storage.load_undelivered_messages().unwrap(),
+        vec![(
+            "ian".to_string(),
+            vec![StoredUndeliveredMessage {
+                sender: "dan".to_string(),
+                id: 1,
+            }]
+        )]
+    );
+    // the expired revocation is forgotten when the next one is saved
+    assert_eq!(
+        storage.load_revoked_session_tokens().unwrap(),
+        vec![StoredRevokedSessionToken {
+            token_id: "revoked".to_string(),
+            expires_at: revoked_token_expires_at,
+        }]
+    );
+}
@@ -1,11 +1,30 @@
-use crate::dto::NewPrivateMessageSequenceResponse;
+use crate::channel::{is_valid_channel_name, Channel, ChannelError};
+#[cfg(test)]
+use crate::config::ServerConfig;
+use crate::dto::{
+    ChannelInfo, GroupInfo, GroupUnreadCount, MessageRevision, MessageToSomeone,
+    NewPrivateMessageSequenceResponse, Presence, PresenceStatus, PrivateHistoryResponse,
+    UnreadCount,
+};
+use crate::group_conversation::{GroupConversation, GroupError};
+#[cfg(test)]
+use crate::outbound_queue::messages_channel;
+use crate::outbound_queue::MessagesSender;
 use crate::private_conversation_partners::{
     compare_usernames, PrivateConversationPartnersHashmapKey,
 };
+use crate::shard::{ConnectionCommandRouter, ShardContext, ShardKey};
+use crate::storage::{
+    MemoryStorage, SharedStorage, Storage, StorageError, StoredMessageRevision, StoredPartnerState,
+    StoredPrivateConversation, StoredPrivateMessage, StoredRevokedSessionToken,
+    StoredUndeliveredMessage, StoredUser,
+};
 use crate::user_context::AddSessionResult::{Success, TooManySessions};
+use crate::user_service;
 use chrono::{DateTime, Utc};
-use std::collections::HashMap;
-use tungstenite::Message;
+use log::error;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
 
 /// Metadata that the server add to a private message after the server receives the message.
 pub struct PrivateMessageServerMetadata {
@@ -25,6 +44,17 @@ struct PrivateMessage {
     server_time: DateTime<Utc>,
     /// True for deleted messages.
     is_deleted: bool,
+    /// the time of the last edit. None if the message has never been edited.
+    edited_at: Option<DateTime<Utc>>,
+    /// the previous contents of the message, oldest first.
+    revisions: Vec<PrivateMessageRevision>,
+}
+
+/// A content that a private message had before it was edited.
+struct PrivateMessageRevision {
+    content: String,
+    /// when the content was replaced by a newer one.
+    replaced_at: DateTime<Utc>,
 }
 
 impl PrivateMessage {
@@ -34,6 +64,57 @@ impl PrivateMessage {
             content,
             server_time: Utc::now(),
             is_deleted: false,
+            edited_at: None,
+            revisions: Vec::new(),
+        }
+    }
+
+    /// Converts the message to the form that is sent to the clients.
+    fn to_message_to_someone(&self, id: u32, sender_username: String) -> MessageToSomeone {
+        MessageToSomeone {
+            id,
+            content: self.content.clone(),
+            sender_username,
+            datetime: self.server_time.to_string(),
+            edited_datetime: self.edited_at.map(|edited_at| edited_at.to_string()),
+            is_deleted: self.is_deleted,
+        }
+    }
+
+    fn to_stored(&self, id: u32) -> StoredPrivateMessage {
+        StoredPrivateMessage {
+            id,
+            is_sender_user1: self.is_sender_user1,
+            content: self.content.clone(),
+            server_time: self.server_time,
+            is_deleted: self.is_deleted,
+            edited_at: self.edited_at,
+            revisions: self
+                .revisions
+                .iter()
+                .map(|revision| StoredMessageRevision {
+                    content: revision.content.clone(),
+                    replaced_at: revision.replaced_at,
+                })
+                .collect(),
+        }
+    }
+
+    fn from_stored(stored_private_message: StoredPrivateMessage) -> Self {
+        PrivateMessage {
+            is_sender_user1: stored_private_message.is_sender_user1,
+            content: stored_private_message.content,
+            server_time: stored_private_message.server_time,
+            is_deleted: stored_private_message.is_deleted,
+            edited_at: stored_private_message.edited_at,
+            revisions: stored_private_message
+                .revisions
+                .into_iter()
+                .map(|revision| PrivateMessageRevision {
+                    content: revision.content,
+                    replaced_at: revision.replaced_at,
+                })
+                .collect(),
         }
     }
 }
@@ -58,11 +139,127 @@ impl PrivateConversation {
             user2_specific_data: PrivateConversationOnePartnerSpecificData::new(),
         }
     }
+
+    /// Finds a message by its id. The message with id N is stored at the index N - id_offset - 1.
+    fn get_message(&self, id: u32) -> Option<&PrivateMessage> {
+        let index = id.checked_sub(self.id_offset + 1)?;
+        self.messages.get(index as usize)
+    }
+
+    fn get_message_mut(&mut self, id: u32) -> Option<&mut PrivateMessage> {
+        let index = id.checked_sub(self.id_offset + 1)?;
+        self.messages.get_mut(index as usize)
+    }
+
+    fn to_stored(
+        &self,
+        partners: PrivateConversationPartnersHashmapKey,
+    ) -> StoredPrivateConversation {
+        StoredPrivateConversation {
+            partners,
+            id_offset: self.id_offset,
+            messages: self
+                .messages
+                .iter()
+                .enumerate()
+                .map(|(index, private_message)| {
+                    private_message.to_stored(self.id_offset + index as u32 + 1)
+                })
+                .collect(),
+            partner1_state: self.user1_specific_data.to_stored(),
+            partner2_state: self.user2_specific_data.to_stored(),
+        }
+    }
+
+    fn from_stored(
+        stored_private_conversation: StoredPrivateConversation,
+    ) -> Result<Self, StorageError> {
+        let id_offset = stored_private_conversation.id_offset;
+        let mut messages = Vec::new();
+        for stored_private_message in stored_private_conversation.messages {
+            // the message with id N must be stored at the index N - id_offset - 1
+            let expected_id = id_offset + messages.len() as u32 + 1;
+            if stored_private_message.id != expected_id {
+                return Err(StorageError::Corrupted(format!(
+                    "the message {} of {} and {} is stored instead of {}",
+                    stored_private_message.id,
+                    stored_private_conversation.partners.partner1,
+                    stored_private_conversation.partners.partner2,
+                    expected_id
+                )));
+            }
+            messages.push(PrivateMessage::from_stored(stored_private_message));
+        }
+        Ok(PrivateConversation {
+            id_offset,
+            messages,
+            user1_specific_data: PrivateConversationOnePartnerSpecificData::from_stored(
+                stored_private_conversation.partner1_state,
+            ),
+            user2_specific_data: PrivateConversationOnePartnerSpecificData::from_stored(
+                stored_private_conversation.partner2_state,
+            ),
+        })
+    }
+}
+
+/// Returns the key of the conversation of the two users and true if the first user is partner1.
+fn private_conversation_key(
+    user: String,
+    partner: String,
+) -> (PrivateConversationPartnersHashmapKey, bool) {
+    let is_user_partner1 = compare_usernames(&user, &partner);
+    let (partner1, partner2) = if is_user_partner1 {
+        (user, partner)
+    } else {
+        (partner, user)
+    };
+    (
+        PrivateConversationPartnersHashmapKey { partner1, partner2 },
+        is_user_partner1,
+    )
 }
 
 /// Contains data related to the private conversation but these data are relevant only to one of the
 /// two conversation partners.
 struct PrivateConversationOnePartnerSpecificData {
+    /// the message sequences that the partner sends.
+    message_sequences: MessageSequences,
+    /// the highest id of the messages that the partner has read. 0 if he has read nothing.
+    last_read_message_id: u32,
+}
+
+impl PrivateConversationOnePartnerSpecificData {
+    pub fn new() -> Self {
+        PrivateConversationOnePartnerSpecificData {
+            message_sequences: MessageSequences::new(),
+            last_read_message_id: 0,
+        }
+    }
+
+    fn to_stored(&self) -> StoredPartnerState {
+        StoredPartnerState {
+            message_sequence_id_offset: self.message_sequences.message_sequence_id_offset,
+            message_sequence_state: self.message_sequences.message_sequence_state.clone(),
+            last_read_message_id: self.last_read_message_id,
+        }
+    }
+
+    fn from_stored(stored_partner_state: StoredPartnerState) -> Self {
+        PrivateConversationOnePartnerSpecificData {
+            message_sequences: MessageSequences {
+                message_sequence_id_offset: stored_partner_state.message_sequence_id_offset,
+                message_sequence_state: stored_partner_state.message_sequence_state,
+                reorder_buffers: HashMap::new(),
+            },
+            last_read_message_id: stored_partner_state.last_read_message_id,
+        }
+    }
+}
+
+/// The message sequences of one sender in one conversation.
+#[derive(Default)]
+pub struct MessageSequences {
     /// id offset of the message sequences
     message_sequence_id_offset: u32,
     /// the fulfillment of the message sequences.
@@ -70,42 +267,430 @@ struct PrivateConversationOnePartnerSpecificData {
     /// message is lost. We do not want the user to see the second or any other consecutive messages
     /// until the previous are delivered.
     message_sequence_state: Vec<u32>,
+    /// Messages that arrived before some of the previous messages of their sequence.
+    /// The key is the message sequence id.
+    reorder_buffers: HashMap<u32, ReorderBuffer>,
 }
 
-impl PrivateConversationOnePartnerSpecificData {
+/// The messages of a sequence that have been discarded because a previous message has not arrived
+/// in time.
+pub struct ExpiredReorderBuffer {
+    pub message_sequence_id: u32,
+    pub missing_indices: Vec<u32>,
+    pub discarded_indices: Vec<u32>,
+}
+
+impl MessageSequences {
     pub fn new() -> Self {
-        PrivateConversationOnePartnerSpecificData {
+        MessageSequences {
             message_sequence_id_offset: 0,
             message_sequence_state: Vec::new(),
+            reorder_buffers: HashMap::new(),
+        }
+    }
+
+    /// Starts a new message sequence and returns its id. Only the last maximum_message_sequences
+    /// sequences are kept: the oldest ones are forgotten, so their messages are no longer accepted.
+    pub fn start_new_message_sequence(&mut self, maximum_message_sequences: u32) -> u32 {
+        let kept_count = maximum_message_sequences.max(1) as usize - 1;
+        if self.message_sequence_state.len() > kept_count {
+            let forgotten_count = self.message_sequence_state.len() - kept_count;
+            self.message_sequence_state.drain(..forgotten_count);
+            self.message_sequence_id_offset += forgotten_count as u32;
+            let message_sequence_id_offset = self.message_sequence_id_offset;
+            self.reorder_buffers.retain(|message_sequence_id, _| {
+                *message_sequence_id >= message_sequence_id_offset
+            });
         }
+        self.message_sequence_state.push(0);
+        // the number 1 is 0
+        self.message_sequence_id_offset + self.message_sequence_state.len() as u32 - 1
+    }
+
+    /// Returns how many messages of the message sequence have been accepted.
+    pub fn accepted_count(&self, message_sequence_id: u32) -> Option<u32> {
+        let index_in_state_arr =
+            message_sequence_id.checked_sub(self.message_sequence_id_offset)?;
+        self.message_sequence_state
+            .get(index_in_state_arr as usize)
+            .copied()
     }
+
+    /// Registers a message of a message sequence. If the message directly follows the last accepted
+    /// message of the sequence, it is accepted together with the buffered messages that follow it.
+    /// Returns the accepted messages in the order of the sequence. A message that is ahead of the
+    /// expected one is buffered and nothing is returned for it.
+    pub fn approach(
+        &mut self,
+        message_sequence_id: u32,
+        sequence_message: SequenceMessage,
+        reorder_buffer_capacity: u32,
+    ) -> Result<Vec<SequenceMessage>, MessageSequenceError> {
+        let message_sequence_index = sequence_message.message_sequence_index;
+        let how_many_messages_already_sent = message_sequence_id
+            .checked_sub(self.message_sequence_id_offset)
+            .and_then(|index_in_state_arr| {
+                self.message_sequence_state
+                    .get_mut(index_in_state_arr as usize)
+            });
+        let Some(how_many_messages_already_sent) = how_many_messages_already_sent else {
+            return Err(MessageSequenceError::SequenceNotFound {
+                message_sequence_id,
+            });
+        };
+        let expected_index = *how_many_messages_already_sent + 1;
+        let reorder_buffers = &mut self.reorder_buffers;
+        if message_sequence_index == expected_index {
+            // the message fills the gap, so the buffered messages that follow it are released
+            let mut accepted_messages = vec![sequence_message];
+            if let Some(reorder_buffer) = reorder_buffers.get_mut(&message_sequence_id) {
+                while let Some(buffered_message) = reorder_buffer
+                    .messages
+                    .remove(&(expected_index + accepted_messages.len() as u32))
+                {
+                    accepted_messages.push(buffered_message);
+                }
+                if reorder_buffer.messages.is_empty() {
+                    reorder_buffers.remove(&message_sequence_id);
+                } else {
+                    // the buffered messages are waiting for another gap now
+                    reorder_buffer.waiting_since = Utc::now();
+                }
+            }
+            *how_many_messages_already_sent += accepted_messages.len() as u32;
+            Ok(accepted_messages)
+        } else if message_sequence_index < expected_index
+            || reorder_buffers
+                .get(&message_sequence_id)
+                .is_some_and(|b| b.messages.contains_key(&message_sequence_index))
+        {
+            Err(MessageSequenceError::UnexpectedIndex {
+                message_sequence_id,
+                expected_index,
+            })
+        } else if message_sequence_index - expected_index > reorder_buffer_capacity {
+            Err(MessageSequenceError::ReorderBufferFull {
+                message_sequence_id,
+                expected_index,
+            })
+        } else {
+            reorder_buffers
+                .entry(message_sequence_id)
+                .or_insert_with(|| ReorderBuffer {
+                    messages: BTreeMap::new(),
+                    waiting_since: Utc::now(),
+                })
+                .messages
+                .insert(message_sequence_index, sequence_message);
+            Ok(Vec::new())
+        }
+    }
+
+    /// Discards the buffered messages of the sequences that have been waiting for a missing message
+    /// since before `deadline`.
+    pub fn expire_reorder_buffers(&mut self, deadline: DateTime<Utc>) -> Vec<ExpiredReorderBuffer> {
+        let expired_ids: Vec<u32> = self
+            .reorder_buffers
+            .iter()
+            .filter(|(_, reorder_buffer)| reorder_buffer.waiting_since < deadline)
+            .map(|(message_sequence_id, _)| *message_sequence_id)
+            .collect();
+        let mut expired_reorder_buffers = Vec::new();
+        for message_sequence_id in expired_ids {
+            let reorder_buffer = self.reorder_buffers.remove(&message_sequence_id).unwrap();
+            let expected_index = self.message_sequence_state
+                [(message_sequence_id - self.message_sequence_id_offset) as usize]
+                + 1;
+            let discarded_indices: Vec<u32> = reorder_buffer.messages.keys().copied().collect();
+            let last_discarded_index = *discarded_indices.last().unwrap();
+            let missing_indices = (expected_index..last_discarded_index)
+                .filter(|index| !reorder_buffer.messages.contains_key(index))
+                .collect();
+            expired_reorder_buffers.push(ExpiredReorderBuffer {
+                message_sequence_id,
+                missing_indices,
+                discarded_indices,
+            });
+        }
+        expired_reorder_buffers
+    }
+}
+
+/// A message that belongs to a message sequence.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SequenceMessage {
+    pub message_sequence_index: u32,
+    pub content: String,
+    /// the id of the request that brought the message.
+    pub request_id: Option<String>,
+}
+
+/// Holds the messages of one sequence until the missing previous messages arrive.
+struct ReorderBuffer {
+    /// the messages by their message sequence index.
+    messages: BTreeMap<u32, SequenceMessage>,
+    /// when the first message of the buffer arrived.
+    waiting_since: DateTime<Utc>,
+}
+
+/// A message sequence whose missing messages have not arrived in time. The messages that were
+/// waiting for them have been discarded.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ExpiredMessageSequence {
+    pub sender: String,
+    pub receiver: String,
+    pub message_sequence_id: u32,
+    pub missing_indices: Vec<u32>,
+    pub discarded_indices: Vec<u32>,
+}
+
+/// Whether one user is typing to another one. It is kept out of the conversation because it is
+/// never persisted.
+struct TypingStatus {
+    /// when the indicator goes off unless the typist repeats it. None if the typist is not typing.
+    expires_at: Option<DateTime<Utc>>,
+    /// when the partner was last told that the typist started typing.
+    last_relayed_start: DateTime<Utc>,
+}
+
+/// The same as ExpiredMessageSequence but for a group.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ExpiredGroupMessageSequence {
+    pub sender: String,
+    pub group_id: u32,
+    pub message_sequence_id: u32,
+    pub missing_indices: Vec<u32>,
+    pub discarded_indices: Vec<u32>,
+}
+
+/// A private message that waits for its receiver to open a session, or that an opened session of
+/// the receiver has not received yet.
+struct UndeliveredMessage {
+    /// the author of the message.
+    sender: String,
+    /// the id of the message in the private conversation.
+    id: u32,
+}
+
+/// An opened session that has not received all the undelivered messages of its user yet.
+struct CatchingUpSession {
+    messages_sender: MessagesSender,
+    /// the position of the next undelivered message that the session has to receive, counted like
+    /// undelivered_messages_offset.
+    next_undelivered_message: u64,
+}
+
+/// The next undelivered messages that a session should receive.
+pub enum UndeliveredMessagesPage {
+    /// The session has received all the undelivered messages of its user.
+    CaughtUp,
+    /// The outbound queue of the session has no room for more messages.
+    QueueFull,
+    /// Messages of conversations that the shard owns, oldest first. entry_count also counts the
+    /// messages that have been deleted meanwhile.
+    Local {
+        messages: Vec<MessageToSomeone>,
+        entry_count: usize,
+    },
+    /// The ids of the messages that one sender has sent in a conversation that another shard owns,
+    /// oldest first.
+    Foreign { sender: String, ids: Vec<u32> },
 }
 
 /// The data of one user.
+#[derive(Default)]
 pub struct ChatUser {
     // the currently opened sessions of the user.
-    pub opened_sessions_senders: Vec<crossbeam_channel::Sender<Message>>,
+    pub opened_sessions_senders: Vec<MessagesSender>,
+    /// the messages that were sent to the user while he had no opened sessions, oldest first. They
+    /// are kept until every opened session has received them.
+    undelivered_messages: VecDeque<UndeliveredMessage>,
+    /// how many undelivered messages have been forgotten since the server has started.
+    undelivered_messages_offset: u64,
+    /// the opened sessions that still receive the undelivered messages. The new messages wait
+    /// behind the undelivered ones for them.
+    catching_up_sessions: Vec<CatchingUpSession>,
+    /// the presence that the conversation partners of the user have last been told about.
+    presence_status: PresenceStatus,
+    /// when the user last did something in any of his sessions.
+    last_activity_at: Option<DateTime<Utc>>,
+    /// when the last session of the user was closed.
+    last_seen_at: Option<DateTime<Utc>>,
 }
 
 impl ChatUser {
     pub fn new() -> Self {
         ChatUser {
             opened_sessions_senders: Vec::new(),
+            undelivered_messages: VecDeque::new(),
+            undelivered_messages_offset: 0,
+            catching_up_sessions: Vec::new(),
+            presence_status: PresenceStatus::Offline,
+            last_activity_at: None,
+            last_seen_at: None,
+        }
+    }
+
+    fn to_presence(&self, username: String) -> Presence {
+        Presence {
+            username,
+            status: self.presence_status,
+            last_seen: match self.presence_status {
+                PresenceStatus::Offline => self
+                    .last_seen_at
+                    .map(|last_seen_at| last_seen_at.to_string()),
+                _ => None,
+            },
+        }
+    }
+
+    /// private function
+    fn add_session(&mut self, messages_sender: MessagesSender) {
+        // the session receives the messages that have waited for the user before the new ones
+        if !self.undelivered_messages.is_empty() {
+            self.catching_up_sessions.push(CatchingUpSession {
+                messages_sender: messages_sender.clone(),
+                next_undelivered_message: self.undelivered_messages_offset,
+            });
+        }
+        self.opened_sessions_senders.push(messages_sender);
+    }
+
+    /// Returns true if the session has not received all the undelivered messages yet.
+    pub fn is_catching_up(&self, messages_sender: &MessagesSender) -> bool {
+        self.catching_up_sessions.iter().any(|catching_up_session| {
+            catching_up_session
+                .messages_sender
+                .same_channel(messages_sender)
+        })
+    }
+
+    /// Derives the presence from the opened sessions and the last activity. Returns true if it
+    /// differs from the one the conversation partners have been told about.
+    fn refresh_presence(&mut self, now: DateTime<Utc>, away_timeout: chrono::Duration) -> bool {
+        let presence_status = if self.opened_sessions_senders.is_empty() {
+            PresenceStatus::Offline
+        } else if self
+            .last_activity_at
+            .is_none_or(|last_activity_at| now - last_activity_at >= away_timeout)
+        {
+            PresenceStatus::Away
+        } else {
+            PresenceStatus::Online
+        };
+        if presence_status == self.presence_status {
+            return false;
+        }
+        if presence_status == PresenceStatus::Offline {
+            self.last_seen_at = Some(now);
         }
+        self.presence_status = presence_status;
+        true
     }
 }
 
-/// The data about all users.
+/// The data about the users and the conversations. The state may be split among several shards,
+/// each of them owning a part of the users, private conversations, groups and channels.
 pub struct ApplicationScope {
     pub chat_users: HashMap<String, ChatUser>,
-    pub private_conversations: HashMap<PrivateConversationPartnersHashmapKey, PrivateConversation>,
+    private_conversations: HashMap<PrivateConversationPartnersHashmapKey, PrivateConversation>,
+    /// the typing indicators by the typist and the partner.
+    typing_statuses: HashMap<(String, String), TypingStatus>,
+    group_conversations: HashMap<u32, GroupConversation>,
+    /// the id of the last group that this shard has created. 0 if it has created none.
+    last_group_id: u32,
+    /// the public channels by their names.
+    channels: BTreeMap<String, Channel>,
+    /// keeps the private conversations and the undelivered messages between restarts.
+    storage: Box<dyn Storage>,
+    /// which part of the state this shard owns.
+    pub shard: ShardContext,
+}
+
+impl Default for ApplicationScope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The reason why a message was not accepted into its message sequence.
+#[derive(Debug, PartialEq, Eq)]
+pub enum MessageSequenceError {
+    /// the users have never talked to each other.
+    ConversationNotFound,
+    /// the receiver is not a user of the server.
+    ReceiverNotFound,
+    /// the sender has never received a message sequence with such id.
+    SequenceNotFound { message_sequence_id: u32 },
+    /// the message is a duplicate.
+    UnexpectedIndex {
+        message_sequence_id: u32,
+        expected_index: u32,
+    },
+    /// the message is too far ahead of the expected one to wait for the messages in between.
+    ReorderBufferFull {
+        message_sequence_id: u32,
+        expected_index: u32,
+    },
+}
+
+impl fmt::Display for MessageSequenceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageSequenceError::ConversationNotFound => {
+                write!(f, "the conversation does not exist")
+            }
+            MessageSequenceError::ReceiverNotFound => write!(f, "the receiver does not exist"),
+            MessageSequenceError::SequenceNotFound {
+                message_sequence_id,
+            } => write!(
+                f,
+                "the sequence with id {} does not exist",
+                message_sequence_id
+            ),
+            MessageSequenceError::UnexpectedIndex {
+                message_sequence_id,
+                expected_index,
+            } => write!(
+                f,
+                "the index {} expected for the sequence with id {}",
+                expected_index, message_sequence_id
+            ),
+            MessageSequenceError::ReorderBufferFull {
+                message_sequence_id,
+                expected_index,
+            } => write!(
+                f,
+                "too many messages of the sequence with id {} are waiting for the index {}",
+                message_sequence_id, expected_index
+            ),
+        }
+    }
+}
+
+/// The reason why a private message cannot be changed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum MessageModificationError {
+    /// the message does not exist or has been deleted.
+    MessageNotFound,
+    /// only the author of a message can change it.
+    NotMessageAuthor,
+}
+
+impl fmt::Display for MessageModificationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageModificationError::MessageNotFound => write!(f, "the message does not exist"),
+            MessageModificationError::NotMessageAuthor => {
+                write!(f, "only the author of the message can change it")
+            }
+        }
+    }
 }
 
 pub enum AddSessionResult {
     Success,
-    TooManySessions {
-        messages_sender: crossbeam_channel::Sender<Message>,
-    },
+    TooManySessions { messages_sender: MessagesSender },
 }
 
 impl ApplicationScope {
@@ -113,20 +698,90 @@ impl ApplicationScope {
         ApplicationScope {
             chat_users: HashMap::new(),
             private_conversations: HashMap::new(),
+            typing_statuses: HashMap::new(),
+            group_conversations: HashMap::new(),
+            last_group_id: 0,
+            channels: BTreeMap::new(),
+            storage: Box::new(MemoryStorage),
+            shard: ShardContext::unsharded(),
+        }
+    }
+
+    /// Restores the private conversations and the undelivered messages that the storage keeps.
+    /// The changes made to them later are written to the storage.
+    pub fn load(mut storage: Box<dyn Storage>) -> Result<Self, StorageError> {
+        let mut shards = [ApplicationScope::new()];
+        Self::restore(&mut shards, storage.as_mut())?;
+        let [mut application_scope] = shards;
+        application_scope.storage = storage;
+        Ok(application_scope)
+    }
+
+    /// Restores the state like `load` and splits it among the shards that the router sends the
+    /// commands to. The shards share the storage.
+    pub fn load_shards(
+        storage: Box<dyn Storage>,
+        router: &ConnectionCommandRouter,
+    ) -> Result<Vec<Self>, StorageError> {
+        let is_volatile = storage.is_volatile();
+        let mut storage = SharedStorage::new(storage);
+        let mut shards: Vec<ApplicationScope> = (0..router.shard_count())
+            .map(|index| ApplicationScope {
+                // the shards do not wait for each other to write to a storage that keeps nothing
+                storage: if is_volatile {
+                    Box::new(MemoryStorage)
+                } else {
+                    Box::new(storage.clone())
+                },
+                shard: ShardContext::new(index, router.clone()),
+                ..ApplicationScope::new()
+            })
+            .collect();
+        Self::restore(&mut shards, &mut storage)?;
+        Ok(shards)
+    }
+
+    /// private function
+    fn restore(
+        shards: &mut [ApplicationScope],
+        storage: &mut dyn Storage,
+    ) -> Result<(), StorageError> {
+        for stored_private_conversation in storage.load_private_conversations()? {
+            let partners = stored_private_conversation.partners.clone();
+            let shard_index = shards[0]
+                .shard
+                .shard_index(&ShardKey::Conversation(partners.clone()));
+            shards[shard_index].private_conversations.insert(
+                partners,
+                PrivateConversation::from_stored(stored_private_conversation)?,
+            );
+        }
+        for (username, stored_undelivered_messages) in storage.load_undelivered_messages()? {
+            let mut chat_user = ChatUser::new();
+            chat_user.undelivered_messages = stored_undelivered_messages
+                .into_iter()
+                .map(|stored_undelivered_message| UndeliveredMessage {
+                    sender: stored_undelivered_message.sender,
+                    id: stored_undelivered_message.id,
+                })
+                .collect();
+            let shard_index = shards[0].shard.shard_index(&ShardKey::User(&username));
+            shards[shard_index].chat_users.insert(username, chat_user);
         }
+        Ok(())
     }
 
     pub fn add_session_sender_if_not_exceeded(
         &mut self,
         username: &String,
-        messages_sender: crossbeam_channel::Sender<Message>,
+        messages_sender: MessagesSender,
         maximum_sessions_allowed: i32,
     ) -> AddSessionResult {
         match self.chat_users.get_mut(username) {
             None => {
                 // create a new conversation partner
                 let mut chat_user: ChatUser = ChatUser::new();
-                chat_user.opened_sessions_senders.push(messages_sender);
+                chat_user.add_session(messages_sender);
                 // register the new conversation partner
                 self.chat_users.insert(username.clone(), chat_user);
                 Success
@@ -137,27 +792,316 @@ impl ApplicationScope {
                 {
                     TooManySessions { messages_sender }
                 } else {
-                    conversation_partner
-                        .opened_sessions_senders
-                        .push(messages_sender);
+                    conversation_partner.add_session(messages_sender);
                     Success
                 }
             }
         }
     }
 
-    pub fn remove_session_sender(
-        &mut self,
-        username: &String,
-        messages_sender: &crossbeam_channel::Sender<Message>,
-    ) {
+    pub fn remove_session_sender(&mut self, username: &String, messages_sender: &MessagesSender) {
         match self.chat_users.get_mut(username) {
             None => {}
             Some(conversation_partner) => {
                 conversation_partner
                     .opened_sessions_senders
-                    .retain(|s| !s.same_channel(&messages_sender));
+                    .retain(|s| !s.same_channel(messages_sender));
+                conversation_partner
+                    .catching_up_sessions
+                    .retain(|s| !s.messages_sender.same_channel(messages_sender));
+                self.forget_received_undelivered_messages(username);
+            }
+        }
+    }
+
+    /// Returns true if the session is one of the opened sessions of the user.
+    pub fn has_session(&self, username: &String, messages_sender: &MessagesSender) -> bool {
+        self.chat_users.get(username).is_some_and(|chat_user| {
+            chat_user
+                .opened_sessions_senders
+                .iter()
+                .any(|opened_session| opened_session.same_channel(messages_sender))
+        })
+    }
+
+    /// Remembers that the user has just done something, so he is not shown as away.
+    pub fn record_activity(&mut self, username: &String, now: DateTime<Utc>) {
+        if let Some(chat_user) = self.chat_users.get_mut(username) {
+            chat_user.last_activity_at = Some(now);
+        }
+    }
+
+    /// Brings the presence of the user up to date. Returns the new presence if it has changed, so
+    /// the conversation partners can be told about it.
+    pub fn refresh_presence(
+        &mut self,
+        username: &String,
+        now: DateTime<Utc>,
+        away_timeout: chrono::Duration,
+    ) -> Option<Presence> {
+        let chat_user = self.chat_users.get_mut(username)?;
+        if chat_user.refresh_presence(now, away_timeout) {
+            Some(chat_user.to_presence(username.clone()))
+        } else {
+            None
+        }
+    }
+
+    /// Brings the presence of all users up to date, e.g. the users who have become away or whose
+    /// sessions have gone. Returns the presences that have changed.
+    pub fn refresh_presences(
+        &mut self,
+        now: DateTime<Utc>,
+        away_timeout: chrono::Duration,
+    ) -> Vec<Presence> {
+        self.chat_users
+            .iter_mut()
+            .filter_map(|(username, chat_user)| {
+                if chat_user.refresh_presence(now, away_timeout) {
+                    Some(chat_user.to_presence(username.clone()))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the presence of the user. Unknown users are offline.
+    pub fn get_presence(&self, username: &String) -> Presence {
+        match self.chat_users.get(username) {
+            Some(chat_user) => chat_user.to_presence(username.clone()),
+            None => Presence {
+                username: username.clone(),
+                status: PresenceStatus::Offline,
+                last_seen: None,
+            },
+        }
+    }
+
+    /// Returns the users who have a private conversation with the user.
+    pub fn get_private_conversation_partners(&self, username: &String) -> Vec<String> {
+        self.private_conversations
+            .keys()
+            .filter_map(|private_conversation_partners| {
+                if &private_conversation_partners.partner1 == username {
+                    Some(private_conversation_partners.partner2.clone())
+                } else if &private_conversation_partners.partner2 == username {
+                    Some(private_conversation_partners.partner1.clone())
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Remembers that a message must be delivered to the user when he opens a session.
+    pub fn add_undelivered_message(&mut self, receiver: &str, sender: String, id: u32) {
+        self.chat_users
+            .entry(receiver.to_string())
+            .or_default()
+            .undelivered_messages
+            .push_back(UndeliveredMessage { sender, id });
+        self.save_undelivered_messages(receiver);
+    }
+
+    /// Returns true if messages wait for the user to open a session or for his opened sessions to
+    /// receive them.
+    pub fn has_undelivered_messages(&self, username: &String) -> bool {
+        self.chat_users
+            .get(username)
+            .is_some_and(|chat_user| !chat_user.undelivered_messages.is_empty())
+    }
+
+    /// Returns the undelivered messages that the session should receive next, at most page_size of
+    /// them. The session stops catching up once it has received all of them.
+    pub fn next_undelivered_messages(
+        &mut self,
+        username: &String,
+        messages_sender: &MessagesSender,
+        page_size: usize,
+    ) -> UndeliveredMessagesPage {
+        let Some(chat_user) = self.chat_users.get_mut(username) else {
+            return UndeliveredMessagesPage::CaughtUp;
+        };
+        let Some(catching_up_session) =
+            chat_user
+                .catching_up_sessions
+                .iter()
+                .find(|catching_up_session| {
+                    catching_up_session
+                        .messages_sender
+                        .same_channel(messages_sender)
+                })
+        else {
+            return UndeliveredMessagesPage::CaughtUp;
+        };
+        let start = (catching_up_session.next_undelivered_message
+            - chat_user.undelivered_messages_offset) as usize;
+        if start >= chat_user.undelivered_messages.len() {
+            chat_user
+                .catching_up_sessions
+                .retain(|s| !s.messages_sender.same_channel(messages_sender));
+            self.forget_received_undelivered_messages(username);
+            return UndeliveredMessagesPage::CaughtUp;
+        }
+        if page_size == 0 {
+            return UndeliveredMessagesPage::QueueFull;
+        }
+        let mut undelivered_messages = chat_user
+            .undelivered_messages
+            .range(start..)
+            .take(page_size)
+            .peekable();
+        let first_sender = &undelivered_messages.peek().unwrap().sender;
+        let partners = PrivateConversationPartnersHashmapKey::new(first_sender, username);
+        if !self.shard.owns(&ShardKey::Conversation(partners)) {
+            let sender = first_sender.clone();
+            let ids = undelivered_messages
+                .take_while(|undelivered_message| undelivered_message.sender == sender)
+                .map(|undelivered_message| undelivered_message.id)
+                .collect();
+            return UndeliveredMessagesPage::Foreign { sender, ids };
+        }
+        let mut messages = Vec::new();
+        let mut entry_count = 0;
+        for undelivered_message in undelivered_messages {
+            let partners =
+                PrivateConversationPartnersHashmapKey::new(&undelivered_message.sender, username);
+            if !self.shard.owns(&ShardKey::Conversation(partners)) {
+                break;
+            }
+            // the messages that do not exist anymore or that the receiver does not need to see are
+            // skipped
+            if let Some(message_to_someone) = find_deliverable_message(
+                &self.private_conversations,
+                username,
+                &undelivered_message.sender,
+                undelivered_message.id,
+            ) {
+                messages.push(message_to_someone);
             }
+            entry_count += 1;
+        }
+        UndeliveredMessagesPage::Local {
+            messages,
+            entry_count,
+        }
+    }
+
+    /// Records that the session has received the next delivered_count undelivered messages, and
+    /// forgets the messages that all the opened sessions have received.
+    pub fn confirm_undelivered_messages(
+        &mut self,
+        username: &String,
+        messages_sender: &MessagesSender,
+        delivered_count: usize,
+    ) {
+        let Some(chat_user) = self.chat_users.get_mut(username) else {
+            return;
+        };
+        if let Some(catching_up_session) =
+            chat_user
+                .catching_up_sessions
+                .iter_mut()
+                .find(|catching_up_session| {
+                    catching_up_session
+                        .messages_sender
+                        .same_channel(messages_sender)
+                })
+        {
+            catching_up_session.next_undelivered_message += delivered_count as u64;
+        }
+        self.forget_received_undelivered_messages(username);
+    }
+
+    /// Returns the private message that has waited for the receiver, unless it does not exist
+    /// anymore or has been deleted.
+    pub fn get_undelivered_message(
+        &self,
+        receiver: &String,
+        sender: &String,
+        id: u32,
+    ) -> Option<MessageToSomeone> {
+        find_deliverable_message(&self.private_conversations, receiver, sender, id)
+    }
+
+    /// Writes the user to the storage. The users themselves are kept by user_service.
+    pub fn save_user(&mut self, user: &StoredUser) {
+        log_storage_error(self.storage.save_user(user));
+    }
+
+    pub fn save_revoked_session_token(
+        &mut self,
+        revoked_session_token: &StoredRevokedSessionToken,
+    ) {
+        log_storage_error(
+            self.storage
+                .save_revoked_session_token(revoked_session_token),
+        );
+    }
+
+    /// private function
+    fn forget_received_undelivered_messages(&mut self, username: &String) {
+        let Some(chat_user) = self.chat_users.get_mut(username) else {
+            return;
+        };
+        let received_count = match chat_user
+            .catching_up_sessions
+            .iter()
+            .map(|catching_up_session| catching_up_session.next_undelivered_message)
+            .min()
+        {
+            Some(next_undelivered_message) => {
+                (next_undelivered_message - chat_user.undelivered_messages_offset) as usize
+            }
+            // the opened sessions have received all the messages, otherwise they wait for the next
+            // session
+            None if !chat_user.opened_sessions_senders.is_empty() => {
+                chat_user.undelivered_messages.len()
+            }
+            None => 0,
+        };
+        if received_count == 0 {
+            return;
+        }
+        chat_user.undelivered_messages.drain(..received_count);
+        chat_user.undelivered_messages_offset += received_count as u64;
+        self.save_undelivered_messages(username);
+    }
+
+    /// private function
+    fn save_undelivered_messages(&mut self, username: &str) {
+        let stored_undelivered_messages: Vec<StoredUndeliveredMessage> = self
+            .chat_users
+            .get(username)
+            .map(|chat_user| {
+                chat_user
+                    .undelivered_messages
+                    .iter()
+                    .map(|undelivered_message| StoredUndeliveredMessage {
+                        sender: undelivered_message.sender.clone(),
+                        id: undelivered_message.id,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        log_storage_error(
+            self.storage
+                .save_undelivered_messages(username, &stored_undelivered_messages),
+        );
+    }
+
+    /// private function
+    fn save_private_message(&mut self, partners: &PrivateConversationPartnersHashmapKey, id: u32) {
+        if let Some(private_message) = self
+            .private_conversations
+            .get(partners)
+            .and_then(|private_conversation| private_conversation.get_message(id))
+        {
+            log_storage_error(
+                self.storage
+                    .save_private_message(partners, &private_message.to_stored(id)),
+            );
         }
     }
 
@@ -168,8 +1112,8 @@ impl ApplicationScope {
         content: String,
     ) -> PrivateMessageServerMetadata {
         let is_sender_partner1: bool = compare_usernames(&sender, &receiver);
-        let new_private_message = PrivateMessage::new(is_sender_partner1.clone(), content);
-        let server_time = new_private_message.server_time.clone();
+        let new_private_message = PrivateMessage::new(is_sender_partner1, content);
+        let server_time = new_private_message.server_time;
         let (partner1, partner2) = if is_sender_partner1 {
             (sender, receiver)
         } else {
@@ -184,16 +1128,21 @@ impl ApplicationScope {
             None => {
                 let mut new_private_conversation = PrivateConversation::new();
                 new_private_conversation.messages.push(new_private_message);
+                log_storage_error(self.storage.save_private_conversation(
+                    &new_private_conversation.to_stored(private_conversation_partners.clone()),
+                ));
                 self.private_conversations
                     .insert(private_conversation_partners, new_private_conversation);
                 PrivateMessageServerMetadata { id: 1, server_time }
             }
             Some(private_messages) => {
                 private_messages.messages.push(new_private_message);
-                PrivateMessageServerMetadata {
-                    id: private_messages.id_offset + private_messages.messages.len() as u32,
-                    server_time,
-                }
+                let id = private_messages.id_offset + private_messages.messages.len() as u32;
+                log_storage_error(self.storage.save_private_message(
+                    &private_conversation_partners,
+                    &private_messages.messages.last().unwrap().to_stored(id),
+                ));
+                PrivateMessageServerMetadata { id, server_time }
             }
         }
     }
@@ -202,7 +1151,8 @@ impl ApplicationScope {
         &mut self,
         sender: String,
         receiver: String,
-    ) -> NewPrivateMessageSequenceResponse {
+        maximum_message_sequences: u32,
+    ) -> Result<NewPrivateMessageSequenceResponse, MessageSequenceError> {
         let is_sender_partner1: bool = compare_usernames(&sender, &receiver);
         let (partner1, partner2) = if is_sender_partner1 {
             (sender, receiver.clone())
@@ -215,55 +1165,315 @@ impl ApplicationScope {
             .private_conversations
             .get_mut(&private_conversation_partners)
         {
+            // the users are never removed, so only a new conversation needs the global user list
+            None if !user_service::user_exists(&receiver) => {
+                Err(MessageSequenceError::ReceiverNotFound)
+            }
             None => {
                 let mut private_conversation = PrivateConversation::new();
-                let response = Self::get_new_message_sequence_from_conversation(
+                let (response, _) = Self::get_new_message_sequence_from_conversation(
                     is_sender_partner1,
                     &mut private_conversation,
                     receiver.clone(),
+                    maximum_message_sequences,
                 );
+                log_storage_error(self.storage.save_private_conversation(
+                    &private_conversation.to_stored(private_conversation_partners.clone()),
+                ));
                 self.private_conversations
                     .insert(private_conversation_partners, private_conversation);
-                response
+                Ok(response)
+            }
+            Some(private_conversation) => {
+                let (response, message_sequence_id_offset) =
+                    Self::get_new_message_sequence_from_conversation(
+                        is_sender_partner1,
+                        private_conversation,
+                        receiver,
+                        maximum_message_sequences,
+                    );
+                if let Some(message_sequence_id_offset) = message_sequence_id_offset {
+                    log_storage_error(self.storage.forget_message_sequences(
+                        &private_conversation_partners,
+                        is_sender_partner1,
+                        message_sequence_id_offset,
+                    ));
+                }
+                log_storage_error(self.storage.save_message_sequence(
+                    &private_conversation_partners,
+                    is_sender_partner1,
+                    response.sequence_id,
+                    0,
+                ));
+                Ok(response)
             }
-            Some(private_conversation) => Self::get_new_message_sequence_from_conversation(
-                is_sender_partner1,
-                private_conversation,
-                receiver,
-            ),
         }
     }
 
-    /// private function
+    /// private function. Also returns the new id offset of the sequences of the sender if the
+    /// oldest ones have been forgotten.
     fn get_new_message_sequence_from_conversation(
         is_sender_partner1: bool,
         private_conversation: &mut PrivateConversation,
         receiver: String,
-    ) -> NewPrivateMessageSequenceResponse {
+        maximum_message_sequences: u32,
+    ) -> (NewPrivateMessageSequenceResponse, Option<u32>) {
         let one_partner_data: &mut PrivateConversationOnePartnerSpecificData = if is_sender_partner1
         {
             &mut private_conversation.user1_specific_data
         } else {
             &mut private_conversation.user2_specific_data
         };
-        one_partner_data.message_sequence_state.push(0);
-        // the number 1 is 0
-        let sequence_id = one_partner_data.message_sequence_id_offset
-            + one_partner_data.message_sequence_state.len() as u32
-            - 1;
-        NewPrivateMessageSequenceResponse {
+        let message_sequences = &mut one_partner_data.message_sequences;
+        let previous_message_sequence_id_offset = message_sequences.message_sequence_id_offset;
+        let sequence_id = message_sequences.start_new_message_sequence(maximum_message_sequences);
+        let response = NewPrivateMessageSequenceResponse {
             sequence_id,
             receiver_username: receiver,
+        };
+        if message_sequences.message_sequence_id_offset == previous_message_sequence_id_offset {
+            (response, None)
+        } else {
+            (response, Some(message_sequences.message_sequence_id_offset))
+        }
+    }
+
+    /// Reads a page of the history of a private conversation: the last `limit` messages whose ids
+    /// are lower than `before_id` (or the last messages if there is no `before_id`), oldest first.
+    /// Deleted messages are returned as tombstones without content.
+    pub fn read_private_conversation_history(
+        &self,
+        reader: String,
+        partner: String,
+        before_id: Option<u32>,
+        limit: usize,
+    ) -> PrivateHistoryResponse {
+        let (partner1, partner2) = if compare_usernames(&reader, &partner) {
+            (reader, partner.clone())
+        } else {
+            (partner.clone(), reader)
+        };
+        let mut response = PrivateHistoryResponse {
+            partner_username: partner,
+            messages: Vec::new(),
+            has_more: false,
+        };
+        let private_conversation_partners =
+            PrivateConversationPartnersHashmapKey { partner1, partner2 };
+        let Some(private_conversation) = self
+            .private_conversations
+            .get(&private_conversation_partners)
+        else {
+            return response;
+        };
+        // the message with id N is stored at the index N - id_offset - 1
+        let end_index = match before_id {
+            Some(before_id) => (before_id.saturating_sub(private_conversation.id_offset + 1)
+                as usize)
+                .min(private_conversation.messages.len()),
+            None => private_conversation.messages.len(),
+        };
+        for (index, private_message) in private_conversation.messages[..end_index]
+            .iter()
+            .enumerate()
+            .rev()
+            .take(limit)
+        {
+            let sender_username = if private_message.is_sender_user1 {
+                &private_conversation_partners.partner1
+            } else {
+                &private_conversation_partners.partner2
+            };
+            response
+                .messages
+                .push(private_message.to_message_to_someone(
+                    private_conversation.id_offset + index as u32 + 1,
+                    sender_username.clone(),
+                ));
+        }
+        response.has_more = end_index > limit;
+        response.messages.reverse();
+        response
+    }
+
+    /// Replaces the content of a private message. Only the author can do it.
+    /// Returns the time of the edit.
+    pub fn edit_private_message(
+        &mut self,
+        author: String,
+        partner: String,
+        id: u32,
+        content: String,
+    ) -> Result<DateTime<Utc>, MessageModificationError> {
+        let (private_conversation_partners, is_author_partner1) =
+            private_conversation_key(author, partner);
+        let private_message = self.get_own_private_message_mut(
+            &private_conversation_partners,
+            is_author_partner1,
+            id,
+        )?;
+        let edited_at = Utc::now();
+        let previous_content = std::mem::replace(&mut private_message.content, content);
+        private_message.revisions.push(PrivateMessageRevision {
+            content: previous_content,
+            replaced_at: edited_at,
+        });
+        private_message.edited_at = Some(edited_at);
+        self.save_private_message(&private_conversation_partners, id);
+        Ok(edited_at)
+    }
+
+    /// Marks a private message as deleted and forgets its content. Only the author can do it.
+    pub fn delete_private_message(
+        &mut self,
+        author: String,
+        partner: String,
+        id: u32,
+    ) -> Result<(), MessageModificationError> {
+        let (private_conversation_partners, is_author_partner1) =
+            private_conversation_key(author, partner);
+        let private_message = self.get_own_private_message_mut(
+            &private_conversation_partners,
+            is_author_partner1,
+            id,
+        )?;
+        private_message.is_deleted = true;
+        private_message.content = String::new();
+        private_message.revisions.clear();
+        self.save_private_message(&private_conversation_partners, id);
+        Ok(())
+    }
+
+    /// Marks the messages of a private conversation up to the message with the given id as read by
+    /// the reader. The read watermark never moves backwards. Returns the watermark and true if it
+    /// has moved.
+    pub fn mark_private_messages_read(
+        &mut self,
+        reader: String,
+        partner: String,
+        id: u32,
+    ) -> Result<(u32, bool), MessageModificationError> {
+        let (private_conversation_partners, is_reader_partner1) =
+            private_conversation_key(reader, partner);
+        let private_conversation = self
+            .private_conversations
+            .get_mut(&private_conversation_partners)
+            .filter(|private_conversation| private_conversation.get_message(id).is_some())
+            .ok_or(MessageModificationError::MessageNotFound)?;
+        let reader_data = if is_reader_partner1 {
+            &mut private_conversation.user1_specific_data
+        } else {
+            &mut private_conversation.user2_specific_data
+        };
+        if id > reader_data.last_read_message_id {
+            reader_data.last_read_message_id = id;
+            log_storage_error(self.storage.save_last_read_message_id(
+                &private_conversation_partners,
+                is_reader_partner1,
+                id,
+            ));
+            Ok((id, true))
+        } else {
+            Ok((reader_data.last_read_message_id, false))
+        }
+    }
+
+    /// Counts the messages that the user has not read, for each of his private conversations.
+    pub fn count_unread_private_messages(&self, username: &String) -> Vec<UnreadCount> {
+        let mut unread_counts = Vec::new();
+        for (private_conversation_partners, private_conversation) in &self.private_conversations {
+            let (reader_data, is_reader_partner1, partner) =
+                if &private_conversation_partners.partner1 == username {
+                    (
+                        &private_conversation.user1_specific_data,
+                        true,
+                        &private_conversation_partners.partner2,
+                    )
+                } else if &private_conversation_partners.partner2 == username {
+                    (
+                        &private_conversation.user2_specific_data,
+                        false,
+                        &private_conversation_partners.partner1,
+                    )
+                } else {
+                    continue;
+                };
+            // the messages with ids up to the watermark are read
+            let first_unread_index = reader_data
+                .last_read_message_id
+                .saturating_sub(private_conversation.id_offset)
+                as usize;
+            let unread_count = private_conversation
+                .messages
+                .iter()
+                .skip(first_unread_index)
+                .filter(|private_message| {
+                    private_message.is_sender_user1 != is_reader_partner1
+                        && !private_message.is_deleted
+                })
+                .count();
+            unread_counts.push(UnreadCount {
+                partner_username: partner.clone(),
+                last_read_id: reader_data.last_read_message_id,
+                unread_count: unread_count as u32,
+            });
         }
+        unread_counts
     }
 
+    /// Returns the previous contents of a private message, oldest first.
+    pub fn read_private_message_revisions(
+        &self,
+        reader: String,
+        partner: String,
+        id: u32,
+    ) -> Result<Vec<MessageRevision>, MessageModificationError> {
+        let (private_conversation_partners, _) = private_conversation_key(reader, partner);
+        let private_message = self
+            .private_conversations
+            .get(&private_conversation_partners)
+            .and_then(|private_conversation| private_conversation.get_message(id))
+            .filter(|private_message| !private_message.is_deleted)
+            .ok_or(MessageModificationError::MessageNotFound)?;
+        Ok(private_message
+            .revisions
+            .iter()
+            .map(|revision| MessageRevision {
+                content: revision.content.clone(),
+                replaced_datetime: revision.replaced_at.to_string(),
+            })
+            .collect())
+    }
+
+    /// private function
+    fn get_own_private_message_mut(
+        &mut self,
+        private_conversation_partners: &PrivateConversationPartnersHashmapKey,
+        is_author_partner1: bool,
+        id: u32,
+    ) -> Result<&mut PrivateMessage, MessageModificationError> {
+        let private_message = self
+            .private_conversations
+            .get_mut(private_conversation_partners)
+            .and_then(|private_conversation| private_conversation.get_message_mut(id))
+            .filter(|private_message| !private_message.is_deleted)
+            .ok_or(MessageModificationError::MessageNotFound)?;
+        if private_message.is_sender_user1 != is_author_partner1 {
+            return Err(MessageModificationError::NotMessageAuthor);
+        }
+        Ok(private_message)
+    }
+
+    /// Registers a message of a message sequence of the private conversation. See
+    /// MessageSequences::approach.
     pub fn approach_message_sequence(
         &mut self,
         sender: String,
         receiver: String,
         message_sequence_id: u32,
-        message_sequence_index: u32,
-    ) -> Result<(), String> {
+        sequence_message: SequenceMessage,
+        reorder_buffer_capacity: u32,
+    ) -> Result<Vec<SequenceMessage>, MessageSequenceError> {
         let is_sender_partner1: bool = compare_usernames(&sender, &receiver);
         let (partner1, partner2) = if is_sender_partner1 {
             (sender, receiver.clone())
@@ -276,49 +1486,903 @@ impl ApplicationScope {
             .private_conversations
             .get_mut(&private_conversation_partners)
         {
-            None => Err("the conversation does not exist".to_string()),
+            // the users are never removed, so the receiver of an existing conversation exists
+            None if !user_service::user_exists(&receiver) => {
+                Err(MessageSequenceError::ReceiverNotFound)
+            }
+            None => Err(MessageSequenceError::ConversationNotFound),
             Some(private_conversation) => {
-                let mut private_conversation_one_partner_specific_data = if is_sender_partner1 {
+                let private_conversation_one_partner_specific_data = if is_sender_partner1 {
                     &mut private_conversation.user1_specific_data
                 } else {
                     &mut private_conversation.user2_specific_data
                 };
-                let index_in_state_arr: usize = (message_sequence_id
-                    - private_conversation_one_partner_specific_data.message_sequence_id_offset)
-                    as usize;
-                if let Some(how_many_messages_already_sent) =
-                    private_conversation_one_partner_specific_data
-                        .message_sequence_state
-                        .get_mut(index_in_state_arr)
+                let message_sequences =
+                    &mut private_conversation_one_partner_specific_data.message_sequences;
+                let accepted_messages = message_sequences.approach(
+                    message_sequence_id,
+                    sequence_message,
+                    reorder_buffer_capacity,
+                )?;
+                if !accepted_messages.is_empty() {
+                    log_storage_error(
+                        self.storage.save_message_sequence(
+                            &private_conversation_partners,
+                            is_sender_partner1,
+                            message_sequence_id,
+                            message_sequences
+                                .accepted_count(message_sequence_id)
+                                .unwrap(),
+                        ),
+                    );
+                }
+                Ok(accepted_messages)
+            }
+        }
+    }
+
+    /// Discards the buffered messages of the sequences that have been waiting for a missing message
+    /// since before `deadline`. Returns the expired sequences, so their senders can be notified.
+    pub fn expire_reorder_buffers(
+        &mut self,
+        deadline: DateTime<Utc>,
+    ) -> Vec<ExpiredMessageSequence> {
+        let mut expired_message_sequences = Vec::new();
+        for (private_conversation_partners, private_conversation) in &mut self.private_conversations
+        {
+            for (one_partner_data, sender, receiver) in [
+                (
+                    &mut private_conversation.user1_specific_data,
+                    &private_conversation_partners.partner1,
+                    &private_conversation_partners.partner2,
+                ),
+                (
+                    &mut private_conversation.user2_specific_data,
+                    &private_conversation_partners.partner2,
+                    &private_conversation_partners.partner1,
+                ),
+            ] {
+                for expired_reorder_buffer in one_partner_data
+                    .message_sequences
+                    .expire_reorder_buffers(deadline)
                 {
-                    // check if the next index is the index we intend to extend
-                    if *how_many_messages_already_sent + 1 == message_sequence_index {
-                        *how_many_messages_already_sent += 1;
-                        Ok(())
-                    } else {
-                        Err(format!(
-                            "another index for the sequence with id {} expected",
-                            message_sequence_id
-                        ))
-                    }
-                } else {
-                    Err(format!(
-                        "the sequence with id {} does not exist",
-                        message_sequence_id
-                    ))
+                    expired_message_sequences.push(ExpiredMessageSequence {
+                        sender: sender.clone(),
+                        receiver: receiver.clone(),
+                        message_sequence_id: expired_reorder_buffer.message_sequence_id,
+                        missing_indices: expired_reorder_buffer.missing_indices,
+                        discarded_indices: expired_reorder_buffer.discarded_indices,
+                    });
                 }
             }
         }
+        expired_message_sequences
+    }
+
+    /// Creates a group owned by `owner` and returns it.
+    pub fn create_group(&mut self, owner: String, name: String, invited: Vec<String>) -> GroupInfo {
+        // the shards take turns, so that the id tells which shard owns the group
+        self.last_group_id = match self.last_group_id {
+            0 => self.shard.index as u32 + 1,
+            last_group_id => last_group_id + self.shard.shard_count() as u32,
+        };
+        let group_conversation = GroupConversation::new(owner, name, invited);
+        let group_info = group_conversation.to_group_info(self.last_group_id);
+        self.group_conversations
+            .insert(self.last_group_id, group_conversation);
+        group_info
+    }
+
+    pub fn get_group(&self, group_id: u32) -> Option<&GroupConversation> {
+        self.group_conversations.get(&group_id)
+    }
+
+    pub fn get_group_mut(&mut self, group_id: u32) -> Option<&mut GroupConversation> {
+        self.group_conversations.get_mut(&group_id)
+    }
+
+    /// Applies a change to the group and returns the group after the change. The group is deleted
+    /// when its last member leaves.
+    pub fn update_group(
+        &mut self,
+        group_id: u32,
+        update: impl FnOnce(&mut GroupConversation) -> Result<(), GroupError>,
+    ) -> Result<GroupInfo, GroupError> {
+        let group_conversation = self
+            .group_conversations
+            .get_mut(&group_id)
+            .ok_or(GroupError::GroupNotFound)?;
+        update(group_conversation)?;
+        let group_info = group_conversation.to_group_info(group_id);
+        if group_conversation.is_empty() {
+            self.group_conversations.remove(&group_id);
+        }
+        Ok(group_info)
+    }
+
+    /// Counts the messages that the user has not read, for each of his groups.
+    pub fn count_unread_group_messages(&self, username: &String) -> Vec<GroupUnreadCount> {
+        let mut group_unread_counts: Vec<GroupUnreadCount> = self
+            .group_conversations
+            .iter()
+            .filter_map(|(group_id, group_conversation)| {
+                group_conversation.count_unread(*group_id, username)
+            })
+            .collect();
+        group_unread_counts.sort_by_key(|group_unread_count| group_unread_count.group_id);
+        group_unread_counts
+    }
+
+    /// The same as expire_reorder_buffers but for the groups.
+    pub fn expire_group_reorder_buffers(
+        &mut self,
+        deadline: DateTime<Utc>,
+    ) -> Vec<ExpiredGroupMessageSequence> {
+        let mut expired_message_sequences = Vec::new();
+        for (group_id, group_conversation) in &mut self.group_conversations {
+            for (sender, expired_reorder_buffer) in
+                group_conversation.expire_reorder_buffers(deadline)
+            {
+                expired_message_sequences.push(ExpiredGroupMessageSequence {
+                    sender,
+                    group_id: *group_id,
+                    message_sequence_id: expired_reorder_buffer.message_sequence_id,
+                    missing_indices: expired_reorder_buffer.missing_indices,
+                    discarded_indices: expired_reorder_buffer.discarded_indices,
+                });
+            }
+        }
+        expired_message_sequences
+    }
+
+    /// Creates a public channel that its creator has already joined.
+    pub fn create_channel(
+        &mut self,
+        creator: String,
+        name: String,
+        topic: String,
+    ) -> Result<ChannelInfo, ChannelError> {
+        if !is_valid_channel_name(&name) {
+            return Err(ChannelError::InvalidChannelName);
+        }
+        if self.channels.contains_key(&name) {
+            return Err(ChannelError::ChannelAlreadyExists);
+        }
+        let channel = Channel::new(creator, topic);
+        let channel_info = channel.to_channel_info(name.clone());
+        self.channels.insert(name, channel);
+        Ok(channel_info)
+    }
+
+    /// Returns the metadata of all channels sorted by name.
+    pub fn list_channels(&self) -> Vec<ChannelInfo> {
+        self.channels
+            .iter()
+            .map(|(name, channel)| channel.to_channel_info(name.clone()))
+            .collect()
+    }
+
+    pub fn get_channel(&self, name: &String) -> Result<&Channel, ChannelError> {
+        self.channels.get(name).ok_or(ChannelError::ChannelNotFound)
+    }
+
+    pub fn get_channel_mut(&mut self, name: &String) -> Result<&mut Channel, ChannelError> {
+        self.channels
+            .get_mut(name)
+            .ok_or(ChannelError::ChannelNotFound)
+    }
+
+    /// Turns on or prolongs the typing indicator of `typist` for `partner`. Returns true if the
+    /// partner should be told about it. A start is relayed at most once per `rate_limit_interval`,
+    /// and a start that comes too soon after the previous one is dropped if the typist is not
+    /// typing.
+    pub fn start_typing(
+        &mut self,
+        typist: String,
+        partner: String,
+        now: DateTime<Utc>,
+        timeout: chrono::Duration,
+        rate_limit_interval: chrono::Duration,
+    ) -> bool {
+        match self
+            .typing_statuses
+            .get_mut(&(typist.clone(), partner.clone()))
+        {
+            Some(typing_status) if now - typing_status.last_relayed_start < rate_limit_interval => {
+                if typing_status.expires_at.is_some() {
+                    typing_status.expires_at = Some(now + timeout);
+                }
+                false
+            }
+            Some(typing_status) => {
+                typing_status.expires_at = Some(now + timeout);
+                typing_status.last_relayed_start = now;
+                true
+            }
+            None => {
+                self.typing_statuses.insert(
+                    (typist, partner),
+                    TypingStatus {
+                        expires_at: Some(now + timeout),
+                        last_relayed_start: now,
+                    },
+                );
+                true
+            }
+        }
+    }
+
+    /// Turns off the typing indicator of `typist` for `partner`. Returns true if the partner should
+    /// be told about it, i.e. the indicator was on.
+    pub fn stop_typing(&mut self, typist: &str, partner: &str) -> bool {
+        match self
+            .typing_statuses
+            .get_mut(&(typist.to_string(), partner.to_string()))
+        {
+            Some(typing_status) => typing_status.expires_at.take().is_some(),
+            None => false,
+        }
+    }
+
+    /// Turns off the typing indicators that have not been repeated in time and forgets the ones
+    /// that no longer matter for the rate limiting. Returns the typists and the partners of the
+    /// expired indicators.
+    pub fn expire_typing_statuses(
+        &mut self,
+        now: DateTime<Utc>,
+        rate_limit_interval: chrono::Duration,
+    ) -> Vec<(String, String)> {
+        let mut expired_typing_statuses = Vec::new();
+        self.typing_statuses
+            .retain(
+                |(typist, partner), typing_status| match typing_status.expires_at {
+                    Some(expires_at) if expires_at <= now => {
+                        expired_typing_statuses.push((typist.clone(), partner.clone()));
+                        typing_status.expires_at = None;
+                        now - typing_status.last_relayed_start < rate_limit_interval
+                    }
+                    Some(_) => true,
+                    None => now - typing_status.last_relayed_start < rate_limit_interval,
+                },
+            );
+        expired_typing_statuses
+    }
+}
+
+/// Returns the private message unless it does not exist anymore or has been deleted.
+fn find_deliverable_message(
+    private_conversations: &HashMap<PrivateConversationPartnersHashmapKey, PrivateConversation>,
+    receiver: &String,
+    sender: &String,
+    id: u32,
+) -> Option<MessageToSomeone> {
+    private_conversations
+        .get(&PrivateConversationPartnersHashmapKey::new(
+            sender, receiver,
+        ))
+        .and_then(|private_conversation| private_conversation.get_message(id))
+        .filter(|private_message| !private_message.is_deleted)
+        .map(|private_message| private_message.to_message_to_someone(id, sender.clone()))
+}
+
+/// The state in memory stays valid when it cannot be saved, so the error is only logged.
+fn log_storage_error(result: Result<(), StorageError>) {
+    if let Err(e) = result {
+        error!("Failed to save the state: {}", e);
+    }
+}
+
+#[cfg(test)]
+fn sequence_message(message_sequence_index: u32) -> SequenceMessage {
+    SequenceMessage {
+        message_sequence_index,
+        content: format!("message {}", message_sequence_index),
+        request_id: Some(message_sequence_index.to_string()),
     }
 }
 
-// partner1 is the reader!
-// pub fn read_last_messages_of_private_conversation(&self, partner1: &String, partner2: &String, n: u16) -> Option<PrivateConversation> {
-//     match self.conversation_partners.get(partner1) {
-//         Some(conversation_partner) => {
-//             conversation_partner.
-//         }
-//         None => {}
-//     }
-// }
-// }
+#[test]
+fn test_approach_message_sequence() {
+    let mut application_scope = ApplicationScope::new();
+    let (ian, dan) = ("ian".to_string(), "dan".to_string());
+    let mut approach = |sender: &String, receiver: &String, sequence_id: u32, index: u32| {
+        application_scope.approach_message_sequence(
+            sender.clone(),
+            receiver.clone(),
+            sequence_id,
+            sequence_message(index),
+            0,
+        )
+    };
+    assert_eq!(
+        approach(&ian, &dan, 0, 1),
+        Err(MessageSequenceError::ConversationNotFound)
+    );
+
+    let sequence_id = application_scope
+        .get_new_message_sequence(ian.clone(), dan.clone(), 64)
+        .unwrap()
+        .sequence_id;
+    let mut approach = |sender: &String, receiver: &String, sequence_id: u32, index: u32| {
+        application_scope.approach_message_sequence(
+            sender.clone(),
+            receiver.clone(),
+            sequence_id,
+            sequence_message(index),
+            0,
+        )
+    };
+    // a gap cannot be buffered when the buffer capacity is 0
+    assert_eq!(
+        approach(&ian, &dan, sequence_id, 2),
+        Err(MessageSequenceError::ReorderBufferFull {
+            message_sequence_id: sequence_id,
+            expected_index: 1,
+        })
+    );
+    assert_eq!(
+        approach(&ian, &dan, sequence_id, 1),
+        Ok(vec![sequence_message(1)])
+    );
+    // a duplicate
+    assert_eq!(
+        approach(&ian, &dan, sequence_id, 1),
+        Err(MessageSequenceError::UnexpectedIndex {
+            message_sequence_id: sequence_id,
+            expected_index: 2,
+        })
+    );
+    assert_eq!(
+        approach(&ian, &dan, sequence_id, 2),
+        Ok(vec![sequence_message(2)])
+    );
+    // unknown sequence ids
+    assert_eq!(
+        approach(&ian, &dan, sequence_id + 1, 1),
+        Err(MessageSequenceError::SequenceNotFound {
+            message_sequence_id: sequence_id + 1,
+        })
+    );
+    // the sequences of one partner cannot be used by the other one
+    assert_eq!(
+        approach(&dan, &ian, sequence_id, 3),
+        Err(MessageSequenceError::SequenceNotFound {
+            message_sequence_id: sequence_id,
+        })
+    );
+}
+
+#[test]
+fn test_reorder_buffer() {
+    let mut application_scope = ApplicationScope::new();
+    let (ian, dan) = ("ian".to_string(), "dan".to_string());
+    let sequence_id = application_scope
+        .get_new_message_sequence(dan.clone(), ian.clone(), 64)
+        .unwrap()
+        .sequence_id;
+    let mut approach = |index: u32| {
+        application_scope.approach_message_sequence(
+            dan.clone(),
+            ian.clone(),
+            sequence_id,
+            sequence_message(index),
+            4,
+        )
+    };
+    assert_eq!(approach(3), Ok(vec![]));
+    assert_eq!(approach(2), Ok(vec![]));
+    assert_eq!(
+        approach(2),
+        Err(MessageSequenceError::UnexpectedIndex {
+            message_sequence_id: sequence_id,
+            expected_index: 1,
+        })
+    );
+    assert_eq!(
+        approach(6),
+        Err(MessageSequenceError::ReorderBufferFull {
+            message_sequence_id: sequence_id,
+            expected_index: 1,
+        })
+    );
+    assert_eq!(approach(5), Ok(vec![]));
+    assert_eq!(
+        approach(1),
+        Ok(vec![
+            sequence_message(1),
+            sequence_message(2),
+            sequence_message(3)
+        ])
+    );
+    assert_eq!(approach(7), Ok(vec![]));
+
+    // nothing has waited long enough yet
+    let long_ago = Utc::now() - chrono::Duration::minutes(1);
+    assert_eq!(application_scope.expire_reorder_buffers(long_ago), vec![]);
+    let expired =
+        application_scope.expire_reorder_buffers(Utc::now() + chrono::Duration::seconds(1));
+    assert_eq!(
+        expired,
+        vec![ExpiredMessageSequence {
+            sender: dan.clone(),
+            receiver: ian.clone(),
+            message_sequence_id: sequence_id,
+            missing_indices: vec![4, 6],
+            discarded_indices: vec![5, 7],
+        }]
+    );
+    // the sender can resend the messages starting from the first missing one
+    assert_eq!(
+        application_scope.approach_message_sequence(dan, ian, sequence_id, sequence_message(4), 4),
+        Ok(vec![sequence_message(4)])
+    );
+}
+
+#[test]
+fn test_read_private_conversation_history() {
+    let mut application_scope = ApplicationScope::new();
+    let (ian, dan) = ("ian".to_string(), "dan".to_string());
+    let history =
+        application_scope.read_private_conversation_history(ian.clone(), dan.clone(), None, 2);
+    assert!(history.messages.is_empty());
+    assert!(!history.has_more);
+
+    for index in 1..=5 {
+        let (sender, receiver) = if index % 2 == 0 {
+            (dan.clone(), ian.clone())
+        } else {
+            (ian.clone(), dan.clone())
+        };
+        application_scope.add_message_to_private_conversation(
+            sender,
+            receiver,
+            format!("message {}", index),
+        );
+    }
+    let ids = |history: &PrivateHistoryResponse| -> Vec<u32> {
+        history.messages.iter().map(|message| message.id).collect()
+    };
+
+    let history =
+        application_scope.read_private_conversation_history(ian.clone(), dan.clone(), None, 2);
+    assert_eq!(ids(&history), vec![4, 5]);
+    assert_eq!(history.messages[0].sender_username, dan);
+    assert_eq!(history.messages[1].content, "message 5");
+    assert!(history.has_more);
+
+    let history =
+        application_scope.read_private_conversation_history(dan.clone(), ian.clone(), Some(4), 2);
+    assert_eq!(history.partner_username, ian);
+    assert_eq!(ids(&history), vec![2, 3]);
+    assert!(history.has_more);
+
+    let history =
+        application_scope.read_private_conversation_history(ian.clone(), dan.clone(), Some(2), 2);
+    assert_eq!(ids(&history), vec![1]);
+    assert!(!history.has_more);
+
+    // the ids keep their meaning when the beginning of the conversation is not in the memory
+    let private_conversation = application_scope
+        .private_conversations
+        .get_mut(&PrivateConversationPartnersHashmapKey {
+            partner1: dan.clone(),
+            partner2: ian.clone(),
+        })
+        .unwrap();
+    private_conversation.messages.drain(..2);
+    private_conversation.id_offset = 2;
+    let history = application_scope.read_private_conversation_history(ian, dan, Some(100), 10);
+    assert_eq!(ids(&history), vec![3, 4, 5]);
+    assert!(!history.has_more);
+}
+
+#[test]
+fn test_edit_and_delete_private_messages() {
+    let mut application_scope = ApplicationScope::new();
+    let (ian, dan) = ("ian".to_string(), "dan".to_string());
+    let id = application_scope
+        .add_message_to_private_conversation(ian.clone(), dan.clone(), "helo".to_string())
+        .id;
+
+    assert_eq!(
+        application_scope.edit_private_message(dan.clone(), ian.clone(), id, "hacked".to_string()),
+        Err(MessageModificationError::NotMessageAuthor)
+    );
+    assert_eq!(
+        application_scope.edit_private_message(ian.clone(), dan.clone(), id + 1, "?".to_string()),
+        Err(MessageModificationError::MessageNotFound)
+    );
+    application_scope
+        .edit_private_message(ian.clone(), dan.clone(), id, "hello".to_string())
+        .unwrap();
+    application_scope
+        .edit_private_message(ian.clone(), dan.clone(), id, "hello!".to_string())
+        .unwrap();
+    let revisions: Vec<String> = application_scope
+        .read_private_message_revisions(dan.clone(), ian.clone(), id)
+        .unwrap()
+        .into_iter()
+        .map(|revision| revision.content)
+        .collect();
+    assert_eq!(revisions, vec!["helo", "hello"]);
+    let history =
+        application_scope.read_private_conversation_history(dan.clone(), ian.clone(), None, 10);
+    assert_eq!(history.messages[0].content, "hello!");
+    assert!(history.messages[0].edited_datetime.is_some());
+
+    assert_eq!(
+        application_scope.delete_private_message(dan.clone(), ian.clone(), id),
+        Err(MessageModificationError::NotMessageAuthor)
+    );
+    assert_eq!(
+        application_scope.delete_private_message(ian.clone(), dan.clone(), id),
+        Ok(())
+    );
+    assert_eq!(
+        application_scope.delete_private_message(ian.clone(), dan.clone(), id),
+        Err(MessageModificationError::MessageNotFound)
+    );
+    assert_eq!(
+        application_scope.edit_private_message(ian.clone(), dan.clone(), id, "back".to_string()),
+        Err(MessageModificationError::MessageNotFound)
+    );
+    // a tombstone is left in the history
+    let history = application_scope.read_private_conversation_history(dan, ian, None, 10);
+    assert_eq!(history.messages.len(), 1);
+    assert!(history.messages[0].is_deleted);
+    assert!(history.messages[0].content.is_empty());
+}
+
+#[test]
+fn test_read_receipts_and_unread_counts() {
+    let mut application_scope = ApplicationScope::new();
+    let (ian, dan, chris) = ("ian".to_string(), "dan".to_string(), "chris".to_string());
+    assert_eq!(
+        application_scope.mark_private_messages_read(ian.clone(), dan.clone(), 1),
+        Err(MessageModificationError::MessageNotFound)
+    );
+    for (sender, receiver) in [
+        (&dan, &ian),
+        (&ian, &dan),
+        (&dan, &ian),
+        (&dan, &ian),
+        (&chris, &ian),
+    ] {
+        application_scope.add_message_to_private_conversation(
+            sender.clone(),
+            receiver.clone(),
+            "hi".to_string(),
+        );
+    }
+    let unread_count = |application_scope: &ApplicationScope, partner: &String| -> u32 {
+        application_scope
+            .count_unread_private_messages(&ian)
+            .into_iter()
+            .find(|unread_count| &unread_count.partner_username == partner)
+            .unwrap()
+            .unread_count
+    };
+    assert_eq!(unread_count(&application_scope, &dan), 3);
+    assert_eq!(unread_count(&application_scope, &chris), 1);
+
+    assert_eq!(
+        application_scope.mark_private_messages_read(ian.clone(), dan.clone(), 3),
+        Ok((3, true))
+    );
+    assert_eq!(unread_count(&application_scope, &dan), 1);
+    // the watermark does not move backwards
+    assert_eq!(
+        application_scope.mark_private_messages_read(ian.clone(), dan.clone(), 2),
+        Ok((3, false))
+    );
+    assert_eq!(
+        application_scope.mark_private_messages_read(ian.clone(), dan.clone(), 5),
+        Err(MessageModificationError::MessageNotFound)
+    );
+    // deleted messages are not counted
+    application_scope
+        .delete_private_message(dan.clone(), ian.clone(), 4)
+        .unwrap();
+    assert_eq!(unread_count(&application_scope, &dan), 0);
+    // the watermark of one partner does not affect the other one
+    let dan_unread_counts = application_scope.count_unread_private_messages(&dan);
+    assert_eq!(dan_unread_counts.len(), 1);
+    assert_eq!(dan_unread_counts[0].unread_count, 1);
+}
+
+#[test]
+fn test_typing_statuses() {
+    let mut application_scope = ApplicationScope::new();
+    let (ian, dan) = ("ian".to_string(), "dan".to_string());
+    let timeout = chrono::Duration::seconds(5);
+    let rate_limit_interval = chrono::Duration::seconds(1);
+    let start = Utc::now();
+    let at = |milliseconds: i64| start + chrono::Duration::milliseconds(milliseconds);
+    let start_typing = |application_scope: &mut ApplicationScope, now: DateTime<Utc>| {
+        application_scope.start_typing(ian.clone(), dan.clone(), now, timeout, rate_limit_interval)
+    };
+
+    assert!(start_typing(&mut application_scope, at(0)));
+    // repeated starts only prolong the indicator until the interval passes
+    assert!(!start_typing(&mut application_scope, at(500)));
+    assert!(start_typing(&mut application_scope, at(1000)));
+    assert!(application_scope.stop_typing(&ian, &dan));
+    assert!(!application_scope.stop_typing(&ian, &dan));
+    // a noisy client cannot flip the indicator faster than the interval
+    assert!(!start_typing(&mut application_scope, at(1500)));
+    assert!(!application_scope.stop_typing(&ian, &dan));
+    assert!(start_typing(&mut application_scope, at(2000)));
+    // the partner's direction is independent
+    assert!(!application_scope.stop_typing(&dan, &ian));
+
+    assert_eq!(
+        application_scope.expire_typing_statuses(at(6999), rate_limit_interval),
+        vec![]
+    );
+    assert_eq!(
+        application_scope.expire_typing_statuses(at(7000), rate_limit_interval),
+        vec![(ian.clone(), dan.clone())]
+    );
+    assert!(!application_scope.stop_typing(&ian, &dan));
+    assert!(application_scope.typing_statuses.is_empty());
+}
+
+#[test]
+fn test_presence() {
+    let mut application_scope = ApplicationScope::new();
+    let (ian, dan, chris) = ("ian".to_string(), "dan".to_string(), "chris".to_string());
+    let away_timeout = chrono::Duration::minutes(5);
+    let start = Utc::now();
+    let at = |minutes: i64| start + chrono::Duration::minutes(minutes);
+    let status = |presence: Option<Presence>| presence.map(|presence| presence.status);
+
+    assert_eq!(
+        application_scope.get_presence(&ian).status,
+        PresenceStatus::Offline
+    );
+    let (messages_sender, _messages_receiver) = messages_channel(&ServerConfig::default());
+    application_scope.add_session_sender_if_not_exceeded(&ian, messages_sender.clone(), 2);
+    application_scope.record_activity(&ian, at(0));
+    assert_eq!(
+        status(application_scope.refresh_presence(&ian, at(0), away_timeout)),
+        Some(PresenceStatus::Online)
+    );
+    assert_eq!(
+        application_scope.refresh_presence(&ian, at(1), away_timeout),
+        None
+    );
+    assert_eq!(
+        application_scope
+            .refresh_presences(at(5), away_timeout)
+            .into_iter()
+            .map(|presence| (presence.username, presence.status))
+            .collect::<Vec<(String, PresenceStatus)>>(),
+        vec![(ian.clone(), PresenceStatus::Away)]
+    );
+    application_scope.record_activity(&ian, at(6));
+    assert_eq!(
+        status(application_scope.refresh_presence(&ian, at(6), away_timeout)),
+        Some(PresenceStatus::Online)
+    );
+
+    application_scope.remove_session_sender(&ian, &messages_sender);
+    let presence = application_scope
+        .refresh_presence(&ian, at(7), away_timeout)
+        .unwrap();
+    assert_eq!(presence.status, PresenceStatus::Offline);
+    assert_eq!(presence.last_seen, Some(at(7).to_string()));
+    assert_eq!(application_scope.get_presence(&ian), presence);
+
+    application_scope.add_message_to_private_conversation(
+        ian.clone(),
+        dan.clone(),
+        "hi".to_string(),
+    );
+    application_scope.add_message_to_private_conversation(
+        chris.clone(),
+        ian.clone(),
+        "hi".to_string(),
+    );
+    let mut partners = application_scope.get_private_conversation_partners(&ian);
+    partners.sort();
+    assert_eq!(partners, vec![chris.clone(), dan.clone()]);
+    assert_eq!(
+        application_scope.get_private_conversation_partners(&dan),
+        vec![ian.clone()]
+    );
+}
+
+#[test]
+fn test_state_survives_restart() {
+    use crate::storage::{temporary_sqlite_path, SqliteStorage};
+
+    let path = temporary_sqlite_path("restart");
+    let (ian, dan) = ("ian".to_string(), "dan".to_string());
+    let mut application_scope =
+        ApplicationScope::load(Box::new(SqliteStorage::open(&path).unwrap())).unwrap();
+    let first_sequence = application_scope
+        .get_new_message_sequence(ian.clone(), dan.clone(), 64)
+        .unwrap();
+    let second_sequence = application_scope
+        .get_new_message_sequence(ian.clone(), dan.clone(), 64)
+        .unwrap();
+    for message_sequence_index in 1..=2 {
+        application_scope
+            .approach_message_sequence(
+                ian.clone(),
+                dan.clone(),
+                second_sequence.sequence_id,
+                sequence_message(message_sequence_index),
+                0,
+            )
+            .unwrap();
+        application_scope.add_message_to_private_conversation(
+            ian.clone(),
+            dan.clone(),
+            format!("message {}", message_sequence_index),
+        );
+    }
+    application_scope
+        .edit_private_message(ian.clone(), dan.clone(), 1, "edited".to_string())
+        .unwrap();
+    application_scope
+        .delete_private_message(ian.clone(), dan.clone(), 2)
+        .unwrap();
+    application_scope.add_message_to_private_conversation(
+        dan.clone(),
+        ian.clone(),
+        "reply".to_string(),
+    );
+    application_scope
+        .mark_private_messages_read(dan.clone(), ian.clone(), 2)
+        .unwrap();
+    application_scope.add_undelivered_message(&ian, dan.clone(), 3);
+    drop(application_scope);
+
+    let mut application_scope =
+        ApplicationScope::load(Box::new(SqliteStorage::open(&path).unwrap())).unwrap();
+    let history =
+        application_scope.read_private_conversation_history(dan.clone(), ian.clone(), None, 10);
+    assert_eq!(
+        history
+            .messages
+            .iter()
+            .map(|message| (message.id, message.content.as_str(), message.is_deleted))
+            .collect::<Vec<(u32, &str, bool)>>(),
+        vec![(1, "edited", false), (2, "", true), (3, "reply", false)]
+    );
+    assert_eq!(
+        application_scope
+            .read_private_message_revisions(dan.clone(), ian.clone(), 1)
+            .unwrap()[0]
+            .content,
+        "message 1"
+    );
+    // the ids continue from where they stopped
+    assert_eq!(
+        application_scope
+            .add_message_to_private_conversation(ian.clone(), dan.clone(), "again".to_string())
+            .id,
+        4
+    );
+    // the sequences continue from where they stopped too
+    assert_eq!(
+        application_scope.approach_message_sequence(
+            ian.clone(),
+            dan.clone(),
+            second_sequence.sequence_id,
+            sequence_message(2),
+            0,
+        ),
+        Err(MessageSequenceError::UnexpectedIndex {
+            message_sequence_id: second_sequence.sequence_id,
+            expected_index: 3,
+        })
+    );
+    assert_eq!(
+        application_scope
+            .approach_message_sequence(
+                ian.clone(),
+                dan.clone(),
+                first_sequence.sequence_id,
+                sequence_message(1),
+                0,
+            )
+            .unwrap()
+            .len(),
+        1
+    );
+    assert_eq!(
+        application_scope
+            .get_new_message_sequence(ian.clone(), dan.clone(), 64)
+            .unwrap()
+            .sequence_id,
+        second_sequence.sequence_id + 1
+    );
+    assert_eq!(
+        application_scope.count_unread_private_messages(&dan)[0].last_read_id,
+        2
+    );
+    let (ian_sender, _ian_receiver) = messages_channel(&ServerConfig::default());
+    application_scope.add_session_sender_if_not_exceeded(&ian, ian_sender.clone(), 1);
+    match application_scope.next_undelivered_messages(&ian, &ian_sender, 10) {
+        UndeliveredMessagesPage::Local { messages, .. } => assert_eq!(
+            messages
+                .iter()
+                .map(|message| message.content.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["reply"]
+        ),
+        _ => panic!("the undelivered message expected"),
+    }
+}
+
+#[test]
+fn test_oldest_message_sequences_are_forgotten() {
+    use crate::journal::JournalStorage;
+    use crate::storage::{temporary_sqlite_path, SqliteStorage};
+
+    let (ian, dan) = ("ian".to_string(), "dan".to_string());
+    let sqlite_path = temporary_sqlite_path("forgotten-sequences");
+    let journal_dir =
+        std::env::temp_dir().join(format!("puchat-forgotten-sequences-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&journal_dir);
+    let open_storage = |storage_name: &str| -> Box<dyn Storage> {
+        match storage_name {
+            "sqlite" => Box::new(SqliteStorage::open(&sqlite_path).unwrap()),
+            // the journal is replayed, as there is no snapshot before 1000 events
+            _ => Box::new(JournalStorage::open(&journal_dir, 1000).unwrap()),
+        }
+    };
+    for storage_name in ["sqlite", "journal"] {
+        let mut application_scope = ApplicationScope::load(open_storage(storage_name)).unwrap();
+        let new_sequence_id = |application_scope: &mut ApplicationScope| {
+            application_scope
+                .get_new_message_sequence(ian.clone(), dan.clone(), 2)
+                .unwrap()
+                .sequence_id
+        };
+        assert_eq!(new_sequence_id(&mut application_scope), 0);
+        assert_eq!(new_sequence_id(&mut application_scope), 1);
+        assert_eq!(
+            application_scope.approach_message_sequence(
+                ian.clone(),
+                dan.clone(),
+                0,
+                sequence_message(2),
+                4
+            ),
+            Ok(vec![])
+        );
+        // the third sequence makes the server forget the first one with its buffered message
+        assert_eq!(new_sequence_id(&mut application_scope), 2);
+        assert_eq!(
+            application_scope.expire_reorder_buffers(Utc::now() + chrono::Duration::seconds(1)),
+            vec![]
+        );
+        drop(application_scope);
+
+        let mut application_scope = ApplicationScope::load(open_storage(storage_name)).unwrap();
+        assert_eq!(
+            application_scope.approach_message_sequence(
+                ian.clone(),
+                dan.clone(),
+                0,
+                sequence_message(1),
+                4
+            ),
+            Err(MessageSequenceError::SequenceNotFound {
+                message_sequence_id: 0
+            })
+        );
+        assert_eq!(
+            application_scope
+                .approach_message_sequence(ian.clone(), dan.clone(), 1, sequence_message(1), 4)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(new_sequence_id(&mut application_scope), 3);
+    }
+    let _ = std::fs::remove_dir_all(&journal_dir);
+}
@@ -1,24 +1,329 @@
+use crate::storage::{Storage, StorageError, StoredUser};
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use log::warn;
 use once_cell::sync::Lazy;
 use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::RwLock;
+
+/// The users that the tests authenticate as. Their passwords are the same as their usernames.
+#[cfg(test)]
+const TEST_USERS: [(&str, &str); 3] = [
+    (
+        "ian",
+        "$argon2id$v=19$m=19456,t=2,p=1$iVc7JsRp3//W141oNXgvDQ$M/R34OmstzKxS++mavvTrDUE7JyRyVMN2YbhwnkGtOQ",
+    ),
+    (
+        "dan",
+        "$argon2id$v=19$m=19456,t=2,p=1$ZwEiAgqbjP0k7c/tHsWMaQ$J6H8pf+9rUZbnOcYRjHBIeeNeN1NOlm39kyNZFZo7vA",
+    ),
+    (
+        "chris",
+        "$argon2id$v=19$m=19456,t=2,p=1$QiU9qyw0nhuUvgZHvYBcgQ$ebJ4E5ll7FA3sDeTiqua/oI0F+5SE6KHpSSVV7/NPkQ",
+    ),
+];
+
+/// The password of unknown users is checked against this hash, so the response time does not
+/// tell whether a user exists.
+const DUMMY_PASSWORD_HASH: &str =
+    "$argon2id$v=19$m=19456,t=2,p=1$/xt/bttfWamFVNJPN4Oftg$3+ENyeap3WzPU2FdJjbwfS8drVVThd4q1Qe4bXz3bmU";
 
 /**
 * Define the map as a global static variable.
 */
-static USER_TO_PASSWORD: Lazy<HashMap<&str, &str>> = Lazy::new(|| {
-    let mut map = HashMap::new();
-    map.insert("ian", "ian");
-    map.insert("dan", "dan");
-    map.insert("chris", "chris");
-    map
-});
+static USERS: Lazy<RwLock<HashMap<String, StoredUser>>> =
+    Lazy::new(|| RwLock::new(initial_users()));
+
+/// There are no users until they are loaded from the storage or the users file.
+#[cfg(not(test))]
+fn initial_users() -> HashMap<String, StoredUser> {
+    HashMap::new()
+}
+
+/// The tests authenticate as the test users without loading any.
+#[cfg(test)]
+fn initial_users() -> HashMap<String, StoredUser> {
+    TEST_USERS
+        .iter()
+        .map(|(username, password_hash)| {
+            (
+                username.to_string(),
+                StoredUser {
+                    username: username.to_string(),
+                    password_hash: password_hash.to_string(),
+                    is_admin: false,
+                    is_disabled: false,
+                },
+            )
+        })
+        .collect()
+}
+
+/// The longest allowed username.
+pub const MAXIMUM_USERNAME_LENGTH: usize = 32;
+pub const MINIMUM_PASSWORD_LENGTH: usize = 8;
+/// Longer passwords are rejected, so nobody can make the server hash megabytes.
+pub const MAXIMUM_PASSWORD_LENGTH: usize = 128;
+
+/// The reason why the user registry cannot be changed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum UserRegistryError {
+    UserAlreadyExists,
+    UserNotFound,
+}
+
+impl fmt::Display for UserRegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UserRegistryError::UserAlreadyExists => write!(f, "the user already exists"),
+            UserRegistryError::UserNotFound => write!(f, "the user does not exist"),
+        }
+    }
+}
+
+/// Replaces the known users with the ones that the storage keeps. The users of the users file, if
+/// there is one, are added to the storage unless it already keeps users with the same usernames,
+/// so the changes that the administrators make with the requests survive restarts.
+pub fn load_users(
+    storage: &mut dyn Storage,
+    users_file: Option<&Path>,
+) -> Result<(), StorageError> {
+    let stored_users = storage.load_users()?;
+    let file_users = match users_file {
+        Some(users_file) => parse_users_file(&fs::read_to_string(users_file)?)?,
+        None => Vec::new(),
+    };
+    if stored_users.is_empty() && file_users.is_empty() {
+        warn!("There are no users, so only the registered ones will be able to authenticate");
+    }
+    let (users, new_users) = merge_users(stored_users, file_users);
+    for user in &new_users {
+        storage.save_user(user)?;
+    }
+    *USERS.write().unwrap() = users;
+    Ok(())
+}
+
+/// Adds the users of the users file that the storage does not keep yet to the stored ones.
+/// Returns all the users and the added ones.
+fn merge_users(
+    stored_users: Vec<StoredUser>,
+    file_users: Vec<StoredUser>,
+) -> (HashMap<String, StoredUser>, Vec<StoredUser>) {
+    let mut users: HashMap<String, StoredUser> = stored_users
+        .into_iter()
+        .map(|user| (user.username.clone(), user))
+        .collect();
+    let mut new_users = Vec::new();
+    for user in file_users {
+        if !users.contains_key(&user.username) {
+            users.insert(user.username.clone(), user.clone());
+            new_users.push(user);
+        }
+    }
+    (users, new_users)
+}
+
+/// Parses the lines of the form `username:password_hash[:admin][:disabled]`. Empty lines and the
+/// lines starting with '#' are skipped.
+fn parse_users_file(content: &str) -> Result<Vec<StoredUser>, StorageError> {
+    let mut users = Vec::new();
+    for (line_index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid_line = |details: &str| {
+            StorageError::Corrupted(format!(
+                "the line {} of the users file {}",
+                line_index + 1,
+                details
+            ))
+        };
+        let mut fields = line.split(':');
+        let (Some(username), Some(password_hash)) = (fields.next(), fields.next()) else {
+            return Err(invalid_line("has no password hash"));
+        };
+        if PasswordHash::new(password_hash).is_err() {
+            return Err(invalid_line("has an invalid password hash"));
+        }
+        let mut user = StoredUser {
+            username: username.to_string(),
+            password_hash: password_hash.to_string(),
+            is_admin: false,
+            is_disabled: false,
+        };
+        for flag in fields {
+            match flag {
+                "admin" => user.is_admin = true,
+                "disabled" => user.is_disabled = true,
+                _ => return Err(invalid_line(&format!("has an unknown flag {}", flag))),
+            }
+        }
+        users.push(user);
+    }
+    Ok(users)
+}
+
+pub fn is_valid_username(username: &str) -> bool {
+    // Check if the string is not empty and contains only alphanumeric characters
+    !username.is_empty()
+        && username.chars().count() <= MAXIMUM_USERNAME_LENGTH
+        && username
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+pub fn is_valid_password(password: &str) -> bool {
+    (MINIMUM_PASSWORD_LENGTH..=MAXIMUM_PASSWORD_LENGTH).contains(&password.chars().count())
+}
+
+/// Returns the salted hash of the password in the PHC string format.
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("the default Argon2 parameters are valid")
+        .to_string()
+}
+
+/// Checks the password in constant time.
+fn verify_password(password: &str, password_hash: &str) -> bool {
+    PasswordHash::new(password_hash).is_ok_and(|password_hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &password_hash)
+            .is_ok()
+    })
+}
 
 pub fn are_credentials_correct(username: &str, password: &str) -> bool {
-    println!(
-        "are_credentials_correct called. username: {}, password: {}",
-        username, password
-    );
-    match USER_TO_PASSWORD.get(username) {
-        Some(&password_from_db) => password_from_db.eq(password),
-        None => false,
+    let user = USERS.read().unwrap().get(username).cloned();
+    match user {
+        Some(user) => verify_password(password, &user.password_hash) && !user.is_disabled,
+        None => {
+            verify_password(password, DUMMY_PASSWORD_HASH);
+            false
+        }
+    }
+}
+
+/// Returns true if the user exists and has not been disabled.
+pub fn is_user_enabled(username: &str) -> bool {
+    USERS
+        .read()
+        .unwrap()
+        .get(username)
+        .is_some_and(|user| !user.is_disabled)
+}
+
+pub fn user_exists(username: &str) -> bool {
+    USERS.read().unwrap().contains_key(username)
+}
+
+pub fn is_admin(username: &str) -> bool {
+    USERS
+        .read()
+        .unwrap()
+        .get(username)
+        .is_some_and(|user| user.is_admin && !user.is_disabled)
+}
+
+/// Adds a user. The caller is responsible for saving the user to the storage.
+pub fn add_user(user: StoredUser) -> Result<(), UserRegistryError> {
+    let mut users = USERS.write().unwrap();
+    if users.contains_key(&user.username) {
+        return Err(UserRegistryError::UserAlreadyExists);
     }
+    users.insert(user.username.clone(), user);
+    Ok(())
+}
+
+/// Disables or enables a user and returns the changed user, so the caller can save it.
+pub fn set_user_disabled(
+    username: &str,
+    is_disabled: bool,
+) -> Result<StoredUser, UserRegistryError> {
+    let mut users = USERS.write().unwrap();
+    let user = users
+        .get_mut(username)
+        .ok_or(UserRegistryError::UserNotFound)?;
+    user.is_disabled = is_disabled;
+    Ok(user.clone())
+}
+
+#[test]
+fn test_user_registry() {
+    assert!(are_credentials_correct("ian", "ian"));
+    assert!(!are_credentials_correct("ian", "dan"));
+    assert!(!are_credentials_correct("nobody", "nobody"));
+
+    let password_hash = hash_password("secret");
+    assert!(password_hash.starts_with("$argon2id$"));
+    assert_ne!(password_hash, hash_password("secret"));
+    let user = StoredUser {
+        username: "registry-test".to_string(),
+        password_hash,
+        is_admin: true,
+        is_disabled: false,
+    };
+    assert_eq!(add_user(user.clone()), Ok(()));
+    assert_eq!(add_user(user), Err(UserRegistryError::UserAlreadyExists));
+    assert!(are_credentials_correct("registry-test", "secret"));
+    assert!(is_admin("registry-test"));
+
+    let user = set_user_disabled("registry-test", true).unwrap();
+    assert!(user.is_disabled);
+    assert!(!are_credentials_correct("registry-test", "secret"));
+    assert!(!is_admin("registry-test"));
+    assert_eq!(
+        set_user_disabled("nobody", true),
+        Err(UserRegistryError::UserNotFound)
+    );
+
+    let users = parse_users_file(&format!(
+        "# comment\n\nroot:{}:admin\nguest:{}:disabled\n",
+        TEST_USERS[0].1, TEST_USERS[1].1
+    ))
+    .unwrap();
+    assert_eq!(
+        users
+            .iter()
+            .map(|user| (user.username.as_str(), user.is_admin, user.is_disabled))
+            .collect::<Vec<(&str, bool, bool)>>(),
+        vec![("root", true, false), ("guest", false, true)]
+    );
+    assert!(parse_users_file("root").is_err());
+    assert!(parse_users_file("root:plain-password").is_err());
+    assert!(parse_users_file(&format!("root:{}:owner", TEST_USERS[0].1)).is_err());
+
+    // the users file does not undo what the administrators have changed, e.g. enabled a user
+    let mut enabled_guest = users[1].clone();
+    enabled_guest.is_disabled = false;
+    let (merged_users, new_users) = merge_users(vec![enabled_guest.clone()], users.clone());
+    assert_eq!(merged_users["guest"], enabled_guest);
+    assert!(merged_users["root"].is_admin);
+    assert_eq!(
+        new_users
+            .iter()
+            .map(|user| user.username.as_str())
+            .collect::<Vec<&str>>(),
+        vec!["root"]
+    );
+}
+
+#[test]
+fn test_username_and_password_rules() {
+    assert!(is_valid_username("ian.smith-2_x"));
+    assert!(is_valid_username("Иван"));
+    assert!(!is_valid_username(""));
+    assert!(!is_valid_username("ian smith"));
+    assert!(!is_valid_username("ian:admin"));
+    assert!(!is_valid_username(&"a".repeat(MAXIMUM_USERNAME_LENGTH + 1)));
+    assert!(is_valid_password("12345678"));
+    assert!(!is_valid_password("1234567"));
+    assert!(!is_valid_password(&"a".repeat(MAXIMUM_PASSWORD_LENGTH + 1)));
 }
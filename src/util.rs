@@ -1,4 +1,5 @@
 use chrono::Utc;
+use std::time::Duration;
 
 // utility functions
 
@@ -10,4 +11,9 @@ pub fn current_time_millis_as_string() -> String {
     let millis = now.timestamp_millis();
     // Convert the milliseconds to a string
     millis.to_string()
-}
\ No newline at end of file
+}
+
+/// Converts a duration from the configuration. Too long durations are treated as infinite.
+pub fn to_chrono_duration(duration: Duration) -> chrono::Duration {
+    chrono::Duration::from_std(duration).unwrap_or(chrono::Duration::MAX)
+}